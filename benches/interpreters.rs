@@ -0,0 +1,27 @@
+//! Compare the speed of the tree-walking interpreter with the
+//! bytecode interpreter. Run with `cargo bench`.
+
+extern crate bfc;
+
+use std::time::Instant;
+
+use bfc::bfir::parse;
+use bfc::bytecode::{compile_to_bytecode, run_bytecode};
+use bfc::interpreter::interpret;
+
+/// Count down from 255 in three nested loops. We don't optimise, as
+/// the optimiser would replace the loops with multiplications.
+const COUNTING: &str = "-[>-[>-[>+<-]<-]<-]";
+
+fn main() {
+    let instrs = parse(COUNTING).unwrap();
+
+    let start = Instant::now();
+    interpret(&instrs, &mut &b""[..], &mut vec![]).unwrap();
+    println!("interpreter: {:?}", start.elapsed());
+
+    let start = Instant::now();
+    let bytecode = compile_to_bytecode(&instrs);
+    run_bytecode(&bytecode, &mut &b""[..], &mut vec![]).unwrap();
+    println!("bytecode:    {:?}", start.elapsed());
+}
@@ -0,0 +1,31 @@
+//! Time parsing large programs. Run with `cargo bench --bench parse`.
+
+extern crate bfc;
+
+use std::time::Instant;
+
+use bfc::bfir::parse;
+
+/// How many times we parse each program, so the timings are stable.
+const ITERATIONS: u32 = 20;
+
+fn time_parse(name: &str, source: &str) {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        parse(source).unwrap();
+    }
+    println!("{:<13} {:?} per parse", name, start.elapsed() / ITERATIONS);
+}
+
+fn main() {
+    time_parse("mandelbrot:", include_str!("../sample_programs/mandelbrot.bf"));
+
+    // About 1 MB of commands, with a loop every few bytes.
+    let commands = "[->+<]>+.".repeat((1 << 20) / 9);
+    time_parse("1 MB code:", &commands);
+
+    // About 1 MB of mostly non-ASCII comments, which we never need to
+    // decode.
+    let comments = "+ größer als →\n".repeat((1 << 20) / 19);
+    time_parse("1 MB comment:", &comments);
+}
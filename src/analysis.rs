@@ -0,0 +1,191 @@
+//! Static analyses that find likely mistakes in BF programs.
+
+use bfir::{AstNode, Position};
+use bfir::AstNode::*;
+
+#[cfg(test)]
+use std::num::Wrapping;
+#[cfg(test)]
+use bfir::parse;
+#[cfg(test)]
+use peephole::optimize;
+
+/// Return true if `instrs` certainly don't modify the cell at
+/// `offset` from the starting cell, and the pointer ends up back at
+/// the starting cell.
+fn preserves_cell(instrs: &[AstNode], offset: isize) -> bool {
+    let mut cell_ptr: isize = 0;
+
+    for instr in instrs {
+        match *instr {
+            Increment { offset: instr_offset, .. } |
+            Set { offset: instr_offset, .. } => {
+                if cell_ptr + instr_offset == offset {
+                    return false;
+                }
+            }
+            PointerIncrement { amount, .. } => {
+                cell_ptr += amount;
+            }
+            MultiplyMove { ref changes, .. } => {
+                // The current cell is zeroed, and the others are
+                // modified.
+                if cell_ptr == offset ||
+                   changes.keys().any(|change_offset| cell_ptr + change_offset == offset) {
+                    return false;
+                }
+            }
+            Move { offset: move_offset, .. } => {
+                if cell_ptr == offset || cell_ptr + move_offset == offset {
+                    return false;
+                }
+            }
+            // The current cell and the temporary cell end up as
+            // they started.
+            MultiplyCopy { ref changes, .. } => {
+                if changes.keys().any(|change_offset| cell_ptr + change_offset == offset) {
+                    return false;
+                }
+            }
+            Read { .. } => {
+                if cell_ptr == offset {
+                    return false;
+                }
+            }
+            Write { .. } | Breakpoint { .. } => {}
+            // We don't know how far a loop moves the pointer, so be
+            // conservative.
+            Loop { .. } | ScanZero { .. } => return false,
+        }
+    }
+
+    cell_ptr == 0
+}
+
+/// Return the position of every loop that can never terminate once
+/// entered.
+///
+/// This is deliberately conservative. We only consider empty loops,
+/// and loops whose body starts by setting the current cell to a
+/// nonzero value, and then provably doesn't change that cell
+/// again. For example, `[[-]+>+<]`, once optimised.
+pub fn detect_infinite_loops(instrs: &[AstNode]) -> Vec<Position> {
+    let mut positions = vec![];
+
+    for instr in instrs {
+        if let Loop { ref body, position } = *instr {
+            if body.is_empty() {
+                if let Some(position) = position {
+                    positions.push(position);
+                }
+            }
+
+            if let Some(&Set { amount, offset: 0, .. }) = body.first() {
+                if amount.0 != 0 && preserves_cell(&body[1..], 0) {
+                    if let Some(position) = position {
+                        positions.push(position);
+                    }
+                }
+            }
+
+            positions.extend(detect_infinite_loops(body));
+        }
+    }
+
+    positions
+}
+
+#[test]
+fn detect_set_at_loop_start() {
+    let instrs = optimize(parse("+[[-]+>+<]").unwrap(), &None).0;
+    assert_eq!(detect_infinite_loops(&instrs),
+               vec![Position { start: 1, end: 9 }]);
+}
+
+#[test]
+fn detect_nested_infinite_loop() {
+    let instrs = vec![Loop {
+                          body: vec![Read { position: None },
+                                     Loop {
+                                         body: vec![Set {
+                                                        amount: Wrapping(1),
+                                                        offset: 0,
+                                                        position: None,
+                                                    },
+                                                    Write { position: None }],
+                                         position: Some(Position { start: 2, end: 8 }),
+                                     }],
+                          position: Some(Position { start: 0, end: 9 }),
+                      }];
+    assert_eq!(detect_infinite_loops(&instrs),
+               vec![Position { start: 2, end: 8 }]);
+}
+
+#[test]
+fn ignore_loop_that_changes_cell_after_set() {
+    let instrs = vec![Loop {
+                          body: vec![Set {
+                                         amount: Wrapping(1),
+                                         offset: 0,
+                                         position: None,
+                                     },
+                                     Increment {
+                                         amount: Wrapping(-1),
+                                         offset: 0,
+                                         position: None,
+                                     }],
+                          position: Some(Position { start: 0, end: 5 }),
+                      }];
+    assert_eq!(detect_infinite_loops(&instrs), vec![]);
+}
+
+#[test]
+fn ignore_loop_that_reads_after_set() {
+    let instrs = vec![Loop {
+                          body: vec![Set {
+                                         amount: Wrapping(1),
+                                         offset: 0,
+                                         position: None,
+                                     },
+                                     Read { position: None }],
+                          position: Some(Position { start: 0, end: 5 }),
+                      }];
+    assert_eq!(detect_infinite_loops(&instrs), vec![]);
+}
+
+#[test]
+fn ignore_loop_with_net_movement() {
+    // The loop checks a different cell each iteration.
+    let instrs = vec![Loop {
+                          body: vec![Set {
+                                         amount: Wrapping(1),
+                                         offset: 0,
+                                         position: None,
+                                     },
+                                     PointerIncrement {
+                                         amount: 1,
+                                         position: None,
+                                     }],
+                          position: Some(Position { start: 0, end: 5 }),
+                      }];
+    assert_eq!(detect_infinite_loops(&instrs), vec![]);
+}
+
+#[test]
+fn ignore_loop_setting_zero() {
+    let instrs = optimize(parse("+[[-]>+<]").unwrap(), &None).0;
+    assert_eq!(detect_infinite_loops(&instrs), vec![]);
+}
+
+#[test]
+fn detect_empty_loop() {
+    let instrs = parse("+[]").unwrap();
+    assert_eq!(detect_infinite_loops(&instrs),
+               vec![Position { start: 1, end: 2 }]);
+}
+
+#[test]
+fn ignore_dead_empty_loop_once_optimised() {
+    let instrs = optimize(parse(",[-][]").unwrap(), &None).0;
+    assert_eq!(detect_infinite_loops(&instrs), vec![]);
+}
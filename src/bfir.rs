@@ -5,9 +5,21 @@
 //! It also provides functions for generating ASTs from source code,
 //! producing good error messages on malformed inputs.
 
-use std::fmt;
-use std::num::Wrapping;
-use std::collections::HashMap;
+use core::cmp;
+use core::fmt;
+use core::mem;
+use core::ops::Range;
+use core::slice;
+use core::num::Wrapping;
+use alloc::borrow::ToOwned;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
+use std::io;
+
+use bounds::MAX_CELL_INDEX;
 
 use self::AstNode::*;
 
@@ -17,7 +29,8 @@ use self::AstNode::*;
 pub type Cell = Wrapping<i8>;
 
 /// An inclusive range used for tracking positions in source code.
-#[derive(PartialEq, Eq, Clone, Copy)]
+/// Positions are byte offsets, which is what `diagnostics` expects.
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
 pub struct Position {
     pub start: usize,
     pub end: usize,
@@ -63,6 +76,26 @@ impl Combine<Option<Position>> for Option<Position> {
     }
 }
 
+/// The position of an instruction made by merging instructions, such
+/// as `+++` becoming a single increment. The result spans from the
+/// first source position consumed to the last, including any comments
+/// in between, so diagnostics after optimisation highlight all the
+/// source that the instruction came from.
+///
+/// Unlike `combine`, we keep a position if only one side has one.
+pub fn merged_position(pos1: Option<Position>, pos2: Option<Position>) -> Option<Position> {
+    match (pos1, pos2) {
+        (Some(pos1), Some(pos2)) => {
+            Some(Position {
+                start: cmp::min(pos1.start, pos2.start),
+                end: cmp::max(pos1.end, pos2.end),
+            })
+        }
+        (Some(pos), None) | (None, Some(pos)) => Some(pos),
+        (None, None) => None,
+    }
+}
+
 /// `AstNode` represents a node in our BF AST.
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum AstNode {
@@ -93,34 +126,126 @@ pub enum AstNode {
         position: Option<Position>,
     },
     MultiplyMove {
-        changes: HashMap<isize, Cell>,
+        changes: BTreeMap<isize, Cell>,
+        position: Option<Position>,
+    },
+    // Add the current cell to the cell at `offset`, then zero the
+    // current cell, as in `[->+<]`. This is a multiply move with one
+    // change of 1, which BF programs use to move values between
+    // variables. `offset` is never zero.
+    Move {
+        offset: isize,
+        position: Option<Position>,
+    },
+    // Add the current cell times each factor to the cell at each
+    // offset, but leave the current cell alone. This is a multiply
+    // move that includes `temp`, followed by moving `temp` back, as
+    // in `[->+>+<<]>>[-<<+>>]<<`. `temp` is zero before and after.
+    MultiplyCopy {
+        changes: BTreeMap<isize, Cell>,
+        temp: isize,
+        position: Option<Position>,
+    },
+    // Move the pointer by `step` until it reaches a zero cell, as in
+    // `[>]` or `[<<]`. `step` is never zero, and we only check every
+    // `step`th cell.
+    ScanZero {
+        step: isize,
+        position: Option<Position>,
+    },
+    // A `#` in the source, for debuggers to inspect the tape. We
+    // only generate this if the parser is asked to.
+    Breakpoint {
         position: Option<Position>,
     },
 }
 
-fn fmt_with_indent(instr: &AstNode, indent: i32, f: &mut fmt::Formatter) {
+impl AstNode {
+    /// The instruction for a single BF command character, without a
+    /// position. Returns None for comment characters, and for `[` and
+    /// `]`, as a loop needs both brackets and its body.
+    pub fn from_char(c: char) -> Option<AstNode> {
+        match c {
+            '+' => {
+                Some(Increment {
+                    amount: Wrapping(1),
+                    offset: 0,
+                    position: None,
+                })
+            }
+            '-' => {
+                Some(Increment {
+                    amount: Wrapping(-1),
+                    offset: 0,
+                    position: None,
+                })
+            }
+            '>' => {
+                Some(PointerIncrement {
+                    amount: 1,
+                    position: None,
+                })
+            }
+            '<' => {
+                Some(PointerIncrement {
+                    amount: -1,
+                    position: None,
+                })
+            }
+            ',' => Some(Read { position: None }),
+            '.' => Some(Write { position: None }),
+            _ => None,
+        }
+    }
+
+    /// The number of instructions in this node. A loop counts as one,
+    /// plus every instruction in its body at any depth, and anything
+    /// else counts as one.
+    pub fn node_count(&self) -> usize {
+        match *self {
+            Loop { ref body, .. } => 1 + node_count(body),
+            _ => 1,
+        }
+    }
+}
+
+fn fmt_with_indent(instr: &AstNode, indent: i32, f: &mut fmt::Formatter) -> fmt::Result {
     for _ in 0..indent {
-        let _ = write!(f, "  ");
+        write!(f, "  ")?;
     }
 
     match instr {
         &Loop { body: ref loop_body, position, .. } => {
-            let _ = write!(f, "Loop position: {:?}", position);
+            write!(f, "Loop position: {:?}", position)?;
 
             for loop_instr in loop_body {
-                let _ = write!(f, "\n");
-                fmt_with_indent(loop_instr, indent + 1, f);
+                write!(f, "\n")?;
+                fmt_with_indent(loop_instr, indent + 1, f)?;
             }
+            Ok(())
         }
-        instr => {
-            let _ = write!(f, "{:?}", instr);
-        }
+        instr => write!(f, "{:?}", instr),
     }
 }
 
 impl fmt::Display for AstNode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        fmt_with_indent(self, 0, f);
+        fmt_with_indent(self, 0, f)
+    }
+}
+
+/// A listing of a whole BF program, so we can display all its
+/// instructions.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Listing(pub Vec<AstNode>);
+
+impl fmt::Display for Listing {
+    /// Write each top-level instruction on its own line.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for instr in &self.0 {
+            fmt_with_indent(instr, 0, f)?;
+            writeln!(f)?;
+        }
         Ok(())
     }
 }
@@ -134,269 +259,2013 @@ pub fn get_position(instr: &AstNode) -> Option<Position> {
         Loop { position, .. } => position,
         Set { position, .. } => position,
         MultiplyMove { position, .. } => position,
+        Move { position, .. } => position,
+        MultiplyCopy { position, .. } => position,
+        ScanZero { position, .. } => position,
+        Breakpoint { position } => position,
     }
 }
 
-#[derive(Debug)]
-pub struct ParseError {
-    pub message: String,
-    pub position: Position,
+/// Return true if `a` and `b` are the same kind of instruction,
+/// whatever their amounts, offsets and positions.
+pub fn same_kind(a: &AstNode, b: &AstNode) -> bool {
+    mem::discriminant(a) == mem::discriminant(b)
 }
 
-/// Given a string of BF source code, parse and return our BF IR
-/// representation. If parsing fails, return an Info describing what
-/// went wrong.
-pub fn parse(source: &str) -> Result<Vec<AstNode>, ParseError> {
-    // AstNodes in the current loop (or toplevel).
-    let mut instructions = vec![];
-    // Contains the instructions of open parent loops (or toplevel),
-    // and the starting indices of the loops.
-    let mut stack = vec![];
+/// Return true if `instrs` has the same kinds of instruction, in the
+/// same order, as `shape`. This is handy for recognising loop bodies
+/// before looking at their amounts. Loop bodies aren't compared.
+pub fn has_shape(instrs: &[AstNode], shape: &[AstNode]) -> bool {
+    instrs.len() == shape.len() && instrs.iter().zip(shape).all(|(a, b)| same_kind(a, b))
+}
 
-    for (index, c) in source.chars().enumerate() {
-        match c {
-            '+' => {
-                instructions.push(Increment {
-                    amount: Wrapping(1),
-                    offset: 0,
-                    position: Some(Position {
-                        start: index,
-                        end: index,
-                    }),
-                })
-            }
-            '-' => {
-                instructions.push(Increment {
-                    amount: Wrapping(-1),
-                    offset: 0,
-                    position: Some(Position {
-                        start: index,
-                        end: index,
-                    }),
-                })
-            }
-            '>' => {
-                instructions.push(PointerIncrement {
-                    amount: 1,
-                    position: Some(Position {
-                        start: index,
-                        end: index,
-                    }),
-                })
-            }
-            '<' => {
-                instructions.push(PointerIncrement {
-                    amount: -1,
-                    position: Some(Position {
-                        start: index,
-                        end: index,
-                    }),
-                })
-            }
-            ',' => {
-                instructions.push(Read {
-                    position: Some(Position {
-                        start: index,
-                        end: index,
-                    }),
-                })
-            }
-            '.' => {
-                instructions.push(Write {
-                    position: Some(Position {
-                        start: index,
-                        end: index,
-                    }),
-                })
-            }
-            '[' => {
-                stack.push((instructions, index));
-                instructions = vec![];
+/// Apply `f` to every sequence of instructions in `instrs`, including
+/// all loop bodies. This works bottom-up, so `f` always sees loop
+/// bodies that have already been transformed.
+///
+/// This saves peephole passes from recursing into loops themselves.
+pub fn map_instructions<F>(instrs: Vec<AstNode>, f: &F) -> Vec<AstNode>
+    where F: Fn(Vec<AstNode>) -> Vec<AstNode>
+{
+    let instrs = instrs.into_iter()
+        .map(|instr| {
+            match instr {
+                Loop { body, position } => {
+                    Loop {
+                        body: map_instructions(body, f),
+                        position: position,
+                    }
+                }
+                other => other,
             }
-            ']' => {
-                if let Some((mut parent_instr, open_index)) = stack.pop() {
-                    parent_instr.push(Loop {
-                        body: instructions,
-                        position: Some(Position {
-                            start: open_index,
-                            end: index,
-                        }),
-                    });
-                    instructions = parent_instr;
-                } else {
-                    return Err(ParseError {
-                        message: "This ] has no matching [".to_owned(),
-                        position: Position {
-                            start: index,
-                            end: index,
-                        },
-                    });
+        })
+        .collect();
+    f(instrs)
+}
+
+/// An iterator over instructions and all their loop bodies, see
+/// `iter_flat`.
+struct FlatIter<'a> {
+    /// The remaining instructions at each nesting depth.
+    stack: Vec<slice::Iter<'a, AstNode>>,
+}
+
+impl<'a> Iterator for FlatIter<'a> {
+    type Item = (usize, &'a AstNode);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(mut instrs) = self.stack.pop() {
+            if let Some(instr) = instrs.next() {
+                let depth = self.stack.len();
+                self.stack.push(instrs);
+                if let Loop { ref body, .. } = *instr {
+                    self.stack.push(body.iter());
                 }
+                return Some((depth, instr));
             }
-            _ => (),
         }
+        None
     }
-
-    if !stack.is_empty() {
-        let pos = stack.last().unwrap().1;
-        return Err(ParseError {
-            message: "This [ has no matching ]".to_owned(),
-            position: Position {
-                start: pos,
-                end: pos,
-            },
-        });
-    }
-
-    Ok(instructions)
 }
 
-#[test]
-fn parse_increment() {
-    assert_eq!(parse("+").unwrap(),
-               [Increment {
-                    amount: Wrapping(1),
-                    offset: 0,
-                    position: Some(Position { start: 0, end: 0 }),
-                }]);
-    assert_eq!(parse("++").unwrap(),
-               [Increment {
-                    amount: Wrapping(1),
-                    offset: 0,
-                    position: Some(Position { start: 0, end: 0 }),
-                },
-                Increment {
-                    amount: Wrapping(1),
-                    offset: 0,
-                    position: Some(Position { start: 1, end: 1 }),
-                }]);
+/// Iterate over every instruction in `instrs` in source order,
+/// including the instructions inside loops, along with how many loops
+/// each instruction is nested inside. A loop is yielded before
+/// its body.
+///
+/// This saves read-only analyses from recursing into loops
+/// themselves.
+pub fn iter_flat(instrs: &[AstNode]) -> impl Iterator<Item = (usize, &AstNode)> {
+    FlatIter { stack: vec![instrs.iter()] }
 }
 
-#[test]
-fn parse_decrement() {
-    assert_eq!(parse("-").unwrap(),
-               [Increment {
-                    amount: Wrapping(-1),
-                    offset: 0,
-                    position: Some(Position { start: 0, end: 0 }),
-                }]);
+/// Join `programs` into a single program, running each in turn. This
+/// saves generators that build programs in pieces from re-parsing
+/// the combined source. `peephole::concat_normalized` also merges
+/// instructions where the pieces meet.
+pub fn concat(programs: &[Vec<AstNode>]) -> Vec<AstNode> {
+    programs.iter().flat_map(|program| program.iter().cloned()).collect()
 }
 
-#[test]
-fn parse_pointer_increment() {
-    assert_eq!(parse(">").unwrap(),
-               [PointerIncrement {
-                    amount: 1,
-                    position: Some(Position { start: 0, end: 0 }),
-                }]);
+/// The number of instructions in `instrs`, including instructions
+/// inside loops, as `AstNode::node_count`.
+pub fn node_count(instrs: &[AstNode]) -> usize {
+    instrs.iter().map(AstNode::node_count).sum()
 }
 
-#[test]
-fn parse_pointer_decrement() {
-    assert_eq!(parse("<").unwrap(),
-               [PointerIncrement {
-                    amount: -1,
-                    position: Some(Position { start: 0, end: 0 }),
-                }]);
+/// The number of consecutive Write instructions at the start of
+/// `instrs`. A run of writes outputs the same cell repeatedly, so
+/// backends can output it all at once.
+pub fn write_run_length(instrs: &[AstNode]) -> usize {
+    instrs.iter().take_while(|instr| matches!(**instr, Write { .. })).count()
 }
 
-#[test]
-fn parse_read() {
-    assert_eq!(parse(",").unwrap(),
-               [Read { position: Some(Position { start: 0, end: 0 }) }]);
+/// Lines of generated code, counting from 1, paired with the byte
+/// range of BF source that each line came from. Backends return this
+/// alongside their code, so users can step through the output.
+pub type SourceMap = Vec<(usize, Range<usize>)>;
+
+/// The byte range of source that `instrs` came from, if we know it.
+pub fn source_span(instrs: &[AstNode]) -> Option<Range<usize>> {
+    instrs.iter()
+        .map(get_position)
+        .fold(None, merged_position)
+        .map(|position| position.start..position.end + 1)
 }
 
-#[test]
-fn parse_write() {
-    assert_eq!(parse(".").unwrap(),
-               [Write { position: Some(Position { start: 0, end: 0 }) }]);
+/// Build a SourceMap for `generated`, given the byte offset in
+/// `generated` where the code for each span starts. The offsets may
+/// be in any order.
+pub fn source_map_from_offsets(generated: &str,
+                               mut offsets: Vec<(usize, Range<usize>)>)
+                               -> SourceMap {
+    offsets.sort_by_key(|&(offset, _)| offset);
+
+    let mut line = 1;
+    let mut counted = 0;
+    offsets.into_iter()
+        .map(|(offset, span)| {
+            line += generated[counted..offset].matches('\n').count();
+            counted = offset;
+            (line, span)
+        })
+        .collect()
 }
 
-#[test]
-fn parse_empty_loop() {
-    let expected = [Loop {
-                        body: vec![],
-                        position: Some(Position { start: 0, end: 1 }),
-                    }];
-    assert_eq!(parse("[]").unwrap(), expected);
+/// What went wrong when parsing. Indexes are the same as the error's
+/// `position`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// A `[` with no `]` after it.
+    UnmatchedOpen { index: usize },
+    /// A `]` with no `[` before it.
+    UnmatchedClose { index: usize },
+    /// A `[` more than `depth` loops deep.
+    NestingTooDeep { depth: usize, index: usize },
+    /// A character that isn't a command, whitespace or a comment
+    /// character, in strict mode.
+    UnexpectedChar { c: char, index: usize },
+    /// We couldn't read the source. `message` says why.
+    Io,
 }
 
-#[test]
-fn parse_simple_loop() {
-    let loop_body = vec![Increment {
-                             amount: Wrapping(1),
-                             offset: 0,
-                             position: Some(Position { start: 1, end: 1 }),
-                         }];
-    let expected = [Loop {
-                        body: loop_body,
-                        position: Some(Position { start: 0, end: 2 }),
-                    }];
-    assert_eq!(parse("[+]").unwrap(), expected);
+#[derive(Debug)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    /// A description of `kind` for people, which we show in Display.
+    pub message: String,
+    pub position: Position,
+    /// Where `position` is in human terms. This is only available
+    /// when we have the whole source, so `parse_reader` doesn't set
+    /// it.
+    pub location: Option<SourceLocation>,
 }
 
-#[test]
-fn parse_complex_loop() {
-    let loop_body = vec![Read { position: Some(Position { start: 2, end: 2 }) },
-                         Increment {
-                             amount: Wrapping(1),
-                             offset: 0,
-                             position: Some(Position { start: 3, end: 3 }),
-                         }];
-    let expected = [Write { position: Some(Position { start: 0, end: 0 }) },
-                    Loop {
-                        body: loop_body,
-                        position: Some(Position { start: 1, end: 4 }),
-                    },
-                    Increment {
-                        amount: Wrapping(-1),
-                        offset: 0,
-                        position: Some(Position { start: 5, end: 5 }),
-                    }];
-    assert_eq!(parse(".[,+]-").unwrap(), expected);
+/// A point in BF source code, as a person reading it would describe
+/// it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    /// The line number, starting from 1.
+    pub line: usize,
+    /// The column in characters, starting from 1.
+    pub column: usize,
+    /// The text of the line, without its newline.
+    pub line_text: String,
 }
 
-#[test]
-fn parse_unbalanced_loop() {
-    assert!(parse("[").is_err());
-    assert!(parse("]").is_err());
-    assert!(parse("][").is_err());
-    assert!(parse("[][").is_err());
+impl SourceLocation {
+    /// Find the location of the byte `index` in `source`, by counting
+    /// the newlines before it.
+    pub fn new(source: &str, index: usize) -> Self {
+        let before = &source[..index];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[index..].find('\n').map_or(source.len(), |i| index + i);
+
+        SourceLocation {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            line_text: source[line_start..line_end].to_owned(),
+        }
+    }
 }
 
-#[test]
-fn parse_comment() {
-    assert_eq!(parse("foo! ").unwrap(), []);
+impl ParseError {
+    /// Fill in the location of this error, from the source that we
+    /// were parsing.
+    pub fn with_source(mut self, source: &str) -> Self {
+        self.location = Some(SourceLocation::new(source, self.position.start));
+        self
+    }
 }
 
-#[test]
-fn test_combine_pos() {
-    let pos1 = Some(Position { start: 1, end: 2 });
-    let pos2 = Some(Position { start: 3, end: 4 });
+impl fmt::Display for ParseError {
+    /// Show the line and column of the error, with the line of source
+    /// and a caret under the offending character.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let location = match self.location {
+            Some(ref location) => location,
+            None => return write!(f, "byte {}: {}", self.position.start, self.message),
+        };
 
-    assert_eq!(pos1.combine(pos2), Some(Position { start: 1, end: 4 }));
+        let offending = location.line_text.chars().nth(location.column - 1).unwrap_or(' ');
+        writeln!(f,
+                 "line {}, column {}: {} (found '{}')",
+                 location.line,
+                 location.column,
+                 self.message,
+                 offending)?;
+
+        let start = location.line_text
+            .char_indices()
+            .nth(location.column - 1)
+            .map_or(location.line_text.len(), |(i, _)| i);
+        write!(f,
+               "{}",
+               snippet(&location.line_text, start..start + offending.len_utf8()))
+    }
 }
 
-#[test]
-fn test_combine_order() {
-    let pos1 = Some(Position { start: 3, end: 4 });
-    let pos2 = Some(Position { start: 1, end: 2 });
+/// Show the line of `source` containing the byte range `span`, with
+/// carets under the span. Spans that continue onto later lines are
+/// only underlined up to the end of their first line.
+pub fn snippet(source: &str, span: Range<usize>) -> String {
+    let start = cmp::min(span.start, source.len());
+    let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+    let end = cmp::max(cmp::min(span.end, line_end), start);
 
-    assert_eq!(pos1.combine(pos2), Some(Position { start: 1, end: 4 }));
-}
+    // Keep tabs, so the carets line up however they're displayed.
+    let padding: String = source[line_start..start]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    // Always show a caret, even for empty spans.
+    let carets = cmp::max(source[start..end].chars().count(), 1);
 
-#[test]
-fn test_combine_pos_not_consecutive() {
-    let pos1 = Some(Position { start: 1, end: 2 });
-    let pos2 = Some(Position { start: 4, end: 5 });
+    format!("{}\n{}{}",
+            &source[line_start..line_end],
+            padding,
+            "^".repeat(carets))
+}
 
-    assert_eq!(pos1.combine(pos2), Some(Position { start: 4, end: 5 }));
+/// Given a string of BF source code, parse and return our BF IR
+/// representation. If parsing fails, return an Info describing what
+/// went wrong.
+pub fn parse(source: &str) -> Result<Vec<AstNode>, ParseError> {
+    let (instrs, _) = parse_with_options(source, &ParseOptions::default())?;
+    Ok(instrs)
 }
 
-#[test]
-fn test_combine_pos_overlap() {
-    let pos1 = Some(Position { start: 1, end: 1 });
-    let pos2 = Some(Position { start: 1, end: 3 });
+/// The deepest loop nesting we accept by default. Our passes recurse
+/// on loop bodies, so this protects against stack overflows on
+/// adversarial input, whilst being far deeper than real programs.
+pub const DEFAULT_MAX_NESTING: usize = 1000;
+
+/// Settings for the parser, including extensions to standard BF that
+/// it can recognise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Treat `#` as a Breakpoint instruction rather than a comment.
+    pub recognize_breakpoints: bool,
+    /// The deepest loop nesting allowed before we report an error.
+    pub max_nesting: usize,
+    /// Return every comment character along with the instructions.
+    pub collect_comments: bool,
+    /// Reject any character that isn't a command, whitespace, or in
+    /// `comment_chars`, so stray characters don't go unnoticed.
+    pub strict: bool,
+    /// The characters, besides whitespace, that strict mode allows as
+    /// comments.
+    pub comment_chars: &'static str,
+    /// The character for each command, so we can parse dialects of BF.
+    pub char_map: CharMap,
+}
+
+impl Default for ParseOptions {
+    /// We only recognise the eight standard commands, allow
+    /// `DEFAULT_MAX_NESTING` levels of loops, and discard comments,
+    /// whatever characters they contain.
+    fn default() -> Self {
+        ParseOptions {
+            recognize_breakpoints: false,
+            max_nesting: DEFAULT_MAX_NESTING,
+            collect_comments: false,
+            strict: false,
+            comment_chars: "",
+            char_map: CharMap::default(),
+        }
+    }
+}
+
+/// The character used for each of the eight BF commands, so we can
+/// parse dialects and obfuscated variants that only differ from BF in
+/// their characters. Commands must be single ASCII characters, so
+/// dialects with multi-character tokens, such as Ook!, need
+/// translating first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CharMap {
+    pub increment: u8,
+    pub decrement: u8,
+    pub right: u8,
+    pub left: u8,
+    pub read: u8,
+    pub write: u8,
+    pub loop_start: u8,
+    pub loop_end: u8,
+}
+
+impl Default for CharMap {
+    /// The standard BF characters.
+    fn default() -> Self {
+        CharMap {
+            increment: b'+',
+            decrement: b'-',
+            right: b'>',
+            left: b'<',
+            read: b',',
+            write: b'.',
+            loop_start: b'[',
+            loop_end: b']',
+        }
+    }
+}
+
+impl CharMap {
+    /// The standard BF character for the command that `byte` stands
+    /// for. Standard characters that this map doesn't use become 0,
+    /// so they're treated as comments. Any other byte is unchanged.
+    fn translate(&self, byte: u8) -> u8 {
+        let commands = [(self.increment, b'+'),
+                        (self.decrement, b'-'),
+                        (self.right, b'>'),
+                        (self.left, b'<'),
+                        (self.read, b','),
+                        (self.write, b'.'),
+                        (self.loop_start, b'['),
+                        (self.loop_end, b']')];
+        for &(c, command) in &commands {
+            if byte == c {
+                return command;
+            }
+        }
+
+        if b"+-><,.[]".contains(&byte) { 0 } else { byte }
+    }
+}
+
+fn nesting_error(max_nesting: usize, index: usize) -> ParseError {
+    ParseError {
+        kind: ParseErrorKind::NestingTooDeep {
+            depth: max_nesting,
+            index: index,
+        },
+        message: format!("This [ is nested more than {} loops deep", max_nesting),
+        position: Position {
+            start: index,
+            end: index,
+        },
+        location: None,
+    }
+}
+
+/// A character in BF source code that isn't a BF command, along with
+/// its byte offset.
+pub type Comment = (usize, char);
+
+/// Parse BF source code, as `parse`, but with the behaviour specified
+/// in `options`. The comments are empty unless
+/// `options.collect_comments` is set.
+pub fn parse_with_options(source: &str,
+                          options: &ParseOptions)
+                          -> Result<(Vec<AstNode>, Vec<Comment>), ParseError> {
+    let mut parser = Parser::new(options);
+    let mut comments = vec![];
+    let translate = options.char_map != CharMap::default();
+
+    // All the BF commands are ASCII, so we can work on bytes and
+    // avoid decoding UTF-8. Anything else is a comment.
+    for (index, &byte) in source.as_bytes().iter().enumerate() {
+        let byte = if translate { options.char_map.translate(byte) } else { byte };
+        let is_command = parser.push(index, byte).map_err(|e| e.with_source(source))?;
+        // Only look at comments from the first byte of each character.
+        if is_command || !source.is_char_boundary(index) {
+            continue;
+        }
+
+        let c = source[index..].chars().next().unwrap();
+        if options.strict && !c.is_whitespace() && !options.comment_chars.contains(c) {
+            let error = ParseError {
+                kind: ParseErrorKind::UnexpectedChar {
+                    c: c,
+                    index: index,
+                },
+                message: format!("Unexpected character '{}'", c),
+                position: Position {
+                    start: index,
+                    end: index + c.len_utf8() - 1,
+                },
+                location: None,
+            };
+            return Err(error.with_source(source));
+        }
+        if options.collect_comments {
+            comments.push((index, c));
+        }
+    }
+
+    let instrs = parser.finish().map_err(|e| e.with_source(source))?;
+    Ok((instrs, comments))
+}
+
+/// Parse BF source code, as `parse`, but also return every comment
+/// character, so tools like formatters can preserve them.
+pub fn parse_with_comments(source: &str) -> Result<(Vec<AstNode>, Vec<Comment>), ParseError> {
+    let options = ParseOptions { collect_comments: true, ..ParseOptions::default() };
+    parse_with_options(source, &options)
+}
+
+/// Parse a program that's followed by its input, separated by the
+/// first `sep` in `source`, as in many BF test corpora. For example,
+/// `,.!hi` is the program `,.` with input "hi" when `sep` is '!'.
+///
+/// If there's no separator, the input is empty.
+pub fn parse_program_and_input(source: &str,
+                               sep: char)
+                               -> Result<(Vec<AstNode>, String), ParseError> {
+    let (program, input) = match source.find(sep) {
+        Some(index) => (&source[..index], &source[index + sep.len_utf8()..]),
+        None => (source, ""),
+    };
+    Ok((parse(program)?, input.to_owned()))
+}
+
+/// Parse several independent programs from `source`, which are
+/// separated by lines containing only `boundary`. This lets test
+/// suites store many programs in a single file.
+///
+/// Each module is parsed separately, so positions are relative to the
+/// start of their module. Errors say which module they're in,
+/// counting from zero.
+pub fn parse_modules(source: &str, boundary: &str) -> Result<Vec<Vec<AstNode>>, ParseError> {
+    let mut regions = vec![];
+    let mut region_start = 0;
+    let mut line_start = 0;
+    for line in source.split('\n') {
+        let line_end = line_start + line.len();
+        if line.trim_end_matches('\r') == boundary {
+            regions.push(&source[region_start..line_start]);
+            region_start = cmp::min(line_end + 1, source.len());
+        }
+        line_start = line_end + 1;
+    }
+    regions.push(&source[region_start..]);
+
+    regions.into_iter()
+        .enumerate()
+        .map(|(index, region)| {
+            parse(region).map_err(|error| {
+                ParseError { message: format!("Module {}: {}", index, error.message), ..error }
+            })
+        })
+        .collect()
+}
+
+/// Parse BF source code from `reader`, without reading the whole
+/// program into memory first.
+#[cfg(feature = "std")]
+pub fn parse_reader<R: io::Read>(mut reader: R) -> Result<Vec<AstNode>, ParseError> {
+    let mut parser = Parser::new(&ParseOptions::default());
+    let mut buf = [0; 8192];
+    let mut index = 0;
+
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(bytes_read) => {
+                for &byte in &buf[..bytes_read] {
+                    parser.push(index, byte)?;
+                    index += 1;
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                return Err(ParseError {
+                    kind: ParseErrorKind::Io,
+                    message: format!("Could not read source: {}", e),
+                    position: Position {
+                        start: index,
+                        end: index,
+                    },
+                    location: None,
+                });
+            }
+        }
+    }
+
+    parser.finish()
+}
+
+/// Parse BF source code lazily, one top-level instruction at a
+/// time. A loop is only returned once we've parsed all of it.
+///
+/// After an error, such as unbalanced brackets, the iterator
+/// returns nothing more.
+pub fn parse_iter<'a>(source: &'a str) -> impl Iterator<Item = Result<AstNode, ParseError>> + 'a {
+    ParseIter {
+        source: source,
+        index: 0,
+        parser: Parser::new(&ParseOptions::default()),
+        finished: false,
+    }
+}
+
+struct ParseIter<'a> {
+    source: &'a str,
+    /// The index of the next byte to parse.
+    index: usize,
+    parser: Parser,
+    finished: bool,
+}
+
+impl<'a> Iterator for ParseIter<'a> {
+    type Item = Result<AstNode, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        let bytes = self.source.as_bytes();
+        while self.index < bytes.len() {
+            let index = self.index;
+            self.index += 1;
+
+            if let Err(e) = self.parser.push(index, bytes[index]) {
+                self.finished = true;
+                return Some(Err(e.with_source(self.source)));
+            }
+            // We're not inside a loop, so any instruction is complete.
+            if self.parser.stack.is_empty() {
+                if let Some(instr) = self.parser.instructions.pop() {
+                    return Some(Ok(instr));
+                }
+            }
+        }
+
+        self.finished = true;
+        match self.parser.check_closed() {
+            Ok(()) => None,
+            Err(e) => Some(Err(e.with_source(self.source))),
+        }
+    }
+}
+
+/// Check that the brackets in `source` are balanced, reporting the
+/// same errors as `parse`. This is cheaper than parsing, as it only
+/// tracks the positions of open loops.
+pub fn validate(source: &str) -> Result<(), ParseError> {
+    validate_brackets(source).map_err(|e| e.with_source(source))
+}
+
+fn validate_brackets(source: &str) -> Result<(), ParseError> {
+    let mut open_indices = vec![];
+
+    for (index, &byte) in source.as_bytes().iter().enumerate() {
+        match byte {
+            b'[' => {
+                if open_indices.len() == DEFAULT_MAX_NESTING {
+                    return Err(nesting_error(DEFAULT_MAX_NESTING, index));
+                }
+                open_indices.push(index);
+            }
+            b']' if open_indices.pop().is_none() => {
+                return Err(ParseError {
+                    kind: ParseErrorKind::UnmatchedClose { index: index },
+                    message: "This ] has no matching [".to_owned(),
+                    position: Position {
+                        start: index,
+                        end: index,
+                    },
+                    location: None,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(&index) = open_indices.last() {
+        return Err(ParseError {
+            kind: ParseErrorKind::UnmatchedOpen { index: index },
+            message: "This [ has no matching ]".to_owned(),
+            position: Position {
+                start: index,
+                end: index,
+            },
+            location: None,
+        });
+    }
+
+    Ok(())
+}
+
+/// A problem with IR that couldn't have come from parsing and
+/// optimising BF, such as IR built by hand or read from a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IrError {
+    pub message: String,
+    pub position: Option<Position>,
+}
+
+impl fmt::Display for IrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.position {
+            Some(position) => write!(f, "byte {}: {}", position.start, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Which invariants `validate_ir` checks, beyond the ones that all IR
+/// must satisfy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IrChecks {
+    /// Reject increments and pointer increments of zero, which
+    /// `peephole::normalize` always removes.
+    pub normalized: bool,
+    /// Reject loops with empty bodies.
+    pub non_empty_loops: bool,
+}
+
+/// Check that `instrs` satisfies the invariants that our passes and
+/// backends assume. Parsed or optimised IR always passes; this is for
+/// IR built by hand or deserialized with `json` or `binary`.
+///
+/// Every ScanZero must have a nonzero step, a MultiplyMove can't
+/// change the cell it moves from, and no offset or pointer movement
+/// can be further than `MAX_CELL_INDEX` cells. `checks` enables
+/// stricter checks. We return every problem we find, not just the
+/// first.
+pub fn validate_ir(instrs: &[AstNode], checks: IrChecks) -> Result<(), Vec<IrError>> {
+    let mut errors = vec![];
+    collect_ir_errors(instrs, checks, &mut errors);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn collect_ir_errors(instrs: &[AstNode], checks: IrChecks, errors: &mut Vec<IrError>) {
+    for instr in instrs {
+        let message = match *instr {
+            ScanZero { step: 0, .. } => Some("ScanZero has a step of zero"),
+            MultiplyMove { ref changes, .. } if changes.contains_key(&0) => {
+                Some("MultiplyMove changes the cell it moves from")
+            }
+            Move { offset: 0, .. } => Some("Move moves a cell onto itself"),
+            MultiplyCopy { ref changes, temp, .. } if changes.contains_key(&0) || temp == 0 => {
+                Some("MultiplyCopy changes the cell it copies from")
+            }
+            MultiplyCopy { ref changes, temp, .. } if changes.contains_key(&temp) => {
+                Some("MultiplyCopy changes its temporary cell")
+            }
+            Increment { amount: Wrapping(0), .. } if checks.normalized => {
+                Some("Increment by zero in normalized IR")
+            }
+            PointerIncrement { amount: 0, .. } if checks.normalized => {
+                Some("PointerIncrement by zero in normalized IR")
+            }
+            Loop { ref body, .. } if body.is_empty() && checks.non_empty_loops => {
+                Some("Loop has an empty body")
+            }
+            _ => None,
+        };
+        if let Some(message) = message {
+            errors.push(IrError {
+                message: message.to_owned(),
+                position: get_position(instr),
+            });
+        }
+
+        if let Some(offset) = max_offset(instr) {
+            if offset > MAX_CELL_INDEX {
+                errors.push(IrError {
+                    message: format!("Offset of {} is larger than the maximum of {}",
+                                     offset,
+                                     MAX_CELL_INDEX),
+                    position: get_position(instr),
+                });
+            }
+        }
+
+        if let Loop { ref body, .. } = *instr {
+            collect_ir_errors(body, checks, errors);
+        }
+    }
+}
+
+/// The largest distance from the current cell that `instr` touches
+/// or moves the pointer by, in either direction.
+fn max_offset(instr: &AstNode) -> Option<usize> {
+    match *instr {
+        Increment { offset, .. } |
+        Set { offset, .. } |
+        Move { offset, .. } => Some(offset.unsigned_abs()),
+        PointerIncrement { amount, .. } => Some(amount.unsigned_abs()),
+        ScanZero { step, .. } => Some(step.unsigned_abs()),
+        MultiplyMove { ref changes, .. } => changes.keys().map(|k| k.unsigned_abs()).max(),
+        MultiplyCopy { ref changes, temp, .. } => {
+            changes.keys().map(|k| k.unsigned_abs()).chain(Some(temp.unsigned_abs())).max()
+        }
+        Read { .. } | Write { .. } | Loop { .. } | Breakpoint { .. } => None,
+    }
+}
+
+/// A parser that consumes BF source one byte at a time.
+struct Parser {
+    /// AstNodes in the current loop (or toplevel).
+    instructions: Vec<AstNode>,
+    /// Contains the instructions of open parent loops (or toplevel),
+    /// and the starting indices of the loops.
+    stack: Vec<(Vec<AstNode>, usize)>,
+    recognize_breakpoints: bool,
+    max_nesting: usize,
+}
+
+impl Parser {
+    fn new(options: &ParseOptions) -> Self {
+        Parser {
+            instructions: vec![],
+            stack: vec![],
+            recognize_breakpoints: options.recognize_breakpoints,
+            max_nesting: options.max_nesting,
+        }
+    }
+
+    /// Parse the byte at `index` in the source. Returns false if the
+    /// byte isn't a BF command (i.e. it's a comment).
+    fn push(&mut self, index: usize, byte: u8) -> Result<bool, ParseError> {
+        let position = Some(Position {
+            start: index,
+            end: index,
+        });
+
+        match byte {
+            b'+' => {
+                self.instructions.push(Increment {
+                    amount: Wrapping(1),
+                    offset: 0,
+                    position: position,
+                })
+            }
+            b'-' => {
+                self.instructions.push(Increment {
+                    amount: Wrapping(-1),
+                    offset: 0,
+                    position: position,
+                })
+            }
+            b'>' => {
+                self.instructions.push(PointerIncrement {
+                    amount: 1,
+                    position: position,
+                })
+            }
+            b'<' => {
+                self.instructions.push(PointerIncrement {
+                    amount: -1,
+                    position: position,
+                })
+            }
+            b',' => self.instructions.push(Read { position: position }),
+            b'.' => self.instructions.push(Write { position: position }),
+            b'#' if self.recognize_breakpoints => {
+                self.instructions.push(Breakpoint { position: position })
+            }
+            b'[' => {
+                if self.stack.len() == self.max_nesting {
+                    return Err(nesting_error(self.max_nesting, index));
+                }
+                let parent_instrs = mem::take(&mut self.instructions);
+                self.stack.push((parent_instrs, index));
+            }
+            b']' => {
+                if let Some((mut parent_instrs, open_index)) = self.stack.pop() {
+                    let body = mem::take(&mut self.instructions);
+                    parent_instrs.push(Loop {
+                        body: body,
+                        position: Some(Position {
+                            start: open_index,
+                            end: index,
+                        }),
+                    });
+                    self.instructions = parent_instrs;
+                } else {
+                    return Err(ParseError {
+                        kind: ParseErrorKind::UnmatchedClose { index: index },
+                        message: "This ] has no matching [".to_owned(),
+                        position: Position {
+                            start: index,
+                            end: index,
+                        },
+                        location: None,
+                    });
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Return an error if there are unclosed loops.
+    fn check_closed(&self) -> Result<(), ParseError> {
+        if let Some(&(_, pos)) = self.stack.last() {
+            return Err(ParseError {
+                kind: ParseErrorKind::UnmatchedOpen { index: pos },
+                message: "This [ has no matching ]".to_owned(),
+                position: Position {
+                    start: pos,
+                    end: pos,
+                },
+                location: None,
+            });
+        }
+        Ok(())
+    }
+
+    /// Return the parsed instructions, or an error if there are
+    /// unclosed loops.
+    fn finish(self) -> Result<Vec<AstNode>, ParseError> {
+        self.check_closed()?;
+        Ok(self.instructions)
+    }
+}
+
+fn push_repeated(c: char, count: usize, source: &mut String) {
+    for _ in 0..count {
+        source.push(c);
+    }
+}
+
+fn push_pointer_increment(amount: isize, source: &mut String) {
+    if amount < 0 {
+        push_repeated('<', -amount as usize, source);
+    } else {
+        push_repeated('>', amount as usize, source);
+    }
+}
+
+fn push_increment(amount: Cell, source: &mut String) {
+    let amount = amount.0 as isize;
+    if amount < 0 {
+        push_repeated('-', -amount as usize, source);
+    } else {
+        push_repeated('+', amount as usize, source);
+    }
+}
+
+fn push_source(instrs: &[AstNode], source: &mut String) {
+    for instr in instrs {
+        match *instr {
+            Increment { amount, offset, .. } => {
+                push_pointer_increment(offset, source);
+                push_increment(amount, source);
+                push_pointer_increment(-offset, source);
+            }
+            PointerIncrement { amount, .. } => {
+                push_pointer_increment(amount, source);
+            }
+            Read { .. } => source.push(','),
+            Write { .. } => source.push('.'),
+            Breakpoint { .. } => source.push('#'),
+            Loop { ref body, .. } => {
+                source.push('[');
+                push_source(body, source);
+                source.push(']');
+            }
+            Set { amount, offset, .. } => {
+                push_pointer_increment(offset, source);
+                source.push_str("[-]");
+                push_increment(amount, source);
+                push_pointer_increment(-offset, source);
+            }
+            MultiplyMove { ref changes, .. } => {
+                source.push_str("[-");
+
+                let mut current_offset = 0;
+                for (offset, factor) in changes {
+                    push_pointer_increment(offset - current_offset, source);
+                    push_increment(*factor, source);
+                    current_offset = *offset;
+                }
+                push_pointer_increment(-current_offset, source);
+
+                source.push(']');
+            }
+            MultiplyCopy { ref changes, temp, .. } => {
+                // Move to the temporary cell as well, then move it
+                // back.
+                let mut changes = changes.clone();
+                changes.insert(temp, Wrapping(1));
+
+                push_source(&[MultiplyMove {
+                                  changes: changes,
+                                  position: None,
+                              },
+                              PointerIncrement {
+                                  amount: temp,
+                                  position: None,
+                              },
+                              Move {
+                                  offset: -temp,
+                                  position: None,
+                              },
+                              PointerIncrement {
+                                  amount: -temp,
+                                  position: None,
+                              }],
+                            source);
+            }
+            Move { offset, .. } => {
+                source.push_str("[-");
+                push_pointer_increment(offset, source);
+                source.push('+');
+                push_pointer_increment(-offset, source);
+                source.push(']');
+            }
+            ScanZero { step, .. } => {
+                source.push('[');
+                push_pointer_increment(step, source);
+                source.push(']');
+            }
+        }
+    }
+}
+
+/// Convert BF IR back to BF source code. Instructions that we
+/// generate during optimisation are expanded to equivalent BF,
+/// e.g. Set 2 becomes `[-]++`.
+pub fn to_source(instrs: &[AstNode]) -> String {
+    let mut source = String::new();
+    push_source(instrs, &mut source);
+    source
+}
+
+#[test]
+fn parse_increment() {
+    assert_eq!(parse("+").unwrap(),
+               [Increment {
+                    amount: Wrapping(1),
+                    offset: 0,
+                    position: Some(Position { start: 0, end: 0 }),
+                }]);
+    assert_eq!(parse("++").unwrap(),
+               [Increment {
+                    amount: Wrapping(1),
+                    offset: 0,
+                    position: Some(Position { start: 0, end: 0 }),
+                },
+                Increment {
+                    amount: Wrapping(1),
+                    offset: 0,
+                    position: Some(Position { start: 1, end: 1 }),
+                }]);
+}
+
+#[test]
+fn parse_decrement() {
+    assert_eq!(parse("-").unwrap(),
+               [Increment {
+                    amount: Wrapping(-1),
+                    offset: 0,
+                    position: Some(Position { start: 0, end: 0 }),
+                }]);
+}
+
+#[test]
+fn parse_pointer_increment() {
+    assert_eq!(parse(">").unwrap(),
+               [PointerIncrement {
+                    amount: 1,
+                    position: Some(Position { start: 0, end: 0 }),
+                }]);
+}
+
+#[test]
+fn parse_pointer_decrement() {
+    assert_eq!(parse("<").unwrap(),
+               [PointerIncrement {
+                    amount: -1,
+                    position: Some(Position { start: 0, end: 0 }),
+                }]);
+}
+
+#[test]
+fn parse_read() {
+    assert_eq!(parse(",").unwrap(),
+               [Read { position: Some(Position { start: 0, end: 0 }) }]);
+}
+
+#[test]
+fn parse_write() {
+    assert_eq!(parse(".").unwrap(),
+               [Write { position: Some(Position { start: 0, end: 0 }) }]);
+}
+
+#[test]
+fn parse_empty_loop() {
+    let expected = [Loop {
+                        body: vec![],
+                        position: Some(Position { start: 0, end: 1 }),
+                    }];
+    assert_eq!(parse("[]").unwrap(), expected);
+}
+
+#[test]
+fn parse_simple_loop() {
+    let loop_body = vec![Increment {
+                             amount: Wrapping(1),
+                             offset: 0,
+                             position: Some(Position { start: 1, end: 1 }),
+                         }];
+    let expected = [Loop {
+                        body: loop_body,
+                        position: Some(Position { start: 0, end: 2 }),
+                    }];
+    assert_eq!(parse("[+]").unwrap(), expected);
+}
+
+#[test]
+fn parse_complex_loop() {
+    let loop_body = vec![Read { position: Some(Position { start: 2, end: 2 }) },
+                         Increment {
+                             amount: Wrapping(1),
+                             offset: 0,
+                             position: Some(Position { start: 3, end: 3 }),
+                         }];
+    let expected = [Write { position: Some(Position { start: 0, end: 0 }) },
+                    Loop {
+                        body: loop_body,
+                        position: Some(Position { start: 1, end: 4 }),
+                    },
+                    Increment {
+                        amount: Wrapping(-1),
+                        offset: 0,
+                        position: Some(Position { start: 5, end: 5 }),
+                    }];
+    assert_eq!(parse(".[,+]-").unwrap(), expected);
+}
+
+#[test]
+fn parse_nested_loop_positions() {
+    let inner_loop = Loop {
+        body: vec![Write { position: Some(Position { start: 3, end: 3 }) }],
+        position: Some(Position { start: 2, end: 4 }),
+    };
+    let expected = [Loop {
+                        body: vec![PointerIncrement {
+                                       amount: 1,
+                                       position: Some(Position { start: 1, end: 1 }),
+                                   },
+                                   inner_loop],
+                        position: Some(Position { start: 0, end: 5 }),
+                    }];
+    assert_eq!(parse("[>[.]]").unwrap(), expected);
+}
+
+/// The parser makes a single pass with an explicit stack, so large
+/// numbers of loops don't make parsing quadratic.
+#[test]
+fn parse_many_loops() {
+    let source = "[-]".repeat(100000);
+    let instrs = parse(&source).unwrap();
+    assert_eq!(instrs.len(), 100000);
+    assert_eq!(instrs[99999],
+               Loop {
+                   body: vec![Increment {
+                                  amount: Wrapping(-1),
+                                  offset: 0,
+                                  position: Some(Position {
+                                      start: 299998,
+                                      end: 299998,
+                                  }),
+                              }],
+                   position: Some(Position {
+                       start: 299997,
+                       end: 299999,
+                   }),
+               });
+}
+
+#[test]
+fn parse_deeply_nested_loops() {
+    let depth = 1000;
+    let source = format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+    let instrs = parse(&source).unwrap();
+
+    let mut current = &instrs;
+    for i in 0..depth {
+        assert_eq!(current.len(), 1);
+        match current[0] {
+            Loop { ref body, position } => {
+                assert_eq!(position,
+                           Some(Position {
+                               start: i,
+                               end: 2 * depth - 1 - i,
+                           }));
+                current = body;
+            }
+            ref instr => panic!("Expected a loop, got {:?}", instr),
+        }
+    }
+    assert!(current.is_empty());
+}
+
+#[test]
+fn parse_too_deeply_nested_loops() {
+    let depth = DEFAULT_MAX_NESTING + 1;
+    let source = format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+    let result = parse(&source);
+    assert!(result.is_err());
+    assert_eq!(result.unwrap_err().position,
+               Position {
+                   start: DEFAULT_MAX_NESTING,
+                   end: DEFAULT_MAX_NESTING,
+               });
+    assert!(validate(&source).is_err());
+}
+
+#[test]
+fn parse_custom_max_nesting() {
+    let options = ParseOptions { max_nesting: 2, ..ParseOptions::default() };
+    assert!(parse_with_options("[[]][[]]", &options).is_ok());
+
+    let error = parse_with_options("[[[]]]", &options).unwrap_err();
+    assert_eq!(error.kind,
+               ParseErrorKind::NestingTooDeep {
+                   depth: 2,
+                   index: 2,
+               });
+    assert_eq!(error.message, "This [ is nested more than 2 loops deep");
+    assert_eq!(error.position, Position { start: 2, end: 2 });
+}
+
+#[test]
+fn write_run_length_counts_consecutive_writes() {
+    let instrs = parse("...+.").unwrap();
+    assert_eq!(write_run_length(&instrs), 3);
+    assert_eq!(write_run_length(&instrs[3..]), 0);
+    assert_eq!(write_run_length(&instrs[4..]), 1);
+    // Any other instruction ends the run, even one that doesn't
+    // change the current cell.
+    assert_eq!(write_run_length(&parse(".>.").unwrap()), 1);
+}
+
+#[test]
+fn parse_unbalanced_loop() {
+    assert!(parse("[").is_err());
+    assert!(parse("]").is_err());
+    assert!(parse("][").is_err());
+    assert!(parse("[][").is_err());
+}
+
+#[test]
+fn parse_unmatched_open_position() {
+    let error = parse("+[+").unwrap_err();
+    assert_eq!(error.kind, ParseErrorKind::UnmatchedOpen { index: 1 });
+    assert_eq!(error.position, Position { start: 1, end: 1 });
+}
+
+#[test]
+fn parse_unmatched_close_position() {
+    let error = parse("+]").unwrap_err();
+    assert_eq!(error.kind, ParseErrorKind::UnmatchedClose { index: 1 });
+    assert_eq!(error.position, Position { start: 1, end: 1 });
+}
+
+#[test]
+fn parse_error_line_and_column() {
+    let error = parse("+++\n>>\n  -]<\n").unwrap_err();
+    let location = error.location.clone().unwrap();
+    assert_eq!(location.line, 3);
+    assert_eq!(location.column, 4);
+    assert_eq!(format!("{}", error),
+               "line 3, column 4: This ] has no matching [ (found ']')\n  -]<\n   ^");
+}
+
+#[test]
+fn parse_error_column_counts_characters() {
+    let error = parse("é\t[").unwrap_err();
+    assert_eq!(error.location.clone().unwrap().column, 3);
+    assert!(format!("{}", error).ends_with("\n \t^"));
+}
+
+#[test]
+fn parse_reader_error_without_location() {
+    let error = parse_reader(&b"+]"[..]).unwrap_err();
+    assert_eq!(error.location, None);
+    assert_eq!(format!("{}", error), "byte 1: This ] has no matching [");
+}
+
+#[test]
+fn validate_unmatched_open() {
+    let error = validate("[[]").unwrap_err();
+    assert_eq!(error.kind, ParseErrorKind::UnmatchedOpen { index: 0 });
+    assert_eq!(error.position, Position { start: 0, end: 0 });
+}
+
+#[test]
+fn validate_unmatched_close() {
+    let error = validate("]").unwrap_err();
+    assert_eq!(error.kind, ParseErrorKind::UnmatchedClose { index: 0 });
+    assert_eq!(error.position, Position { start: 0, end: 0 });
+}
+
+#[test]
+fn validate_balanced() {
+    assert!(validate(include_str!("../sample_programs/mandelbrot.bf")).is_ok());
+    assert!(validate("").is_ok());
+}
+
+#[test]
+fn validate_matches_parse() {
+    for source in &["[", "][", "[][", "+[[+]", "[[]]]", "[[[", "x]"] {
+        let validate_error = validate(source).unwrap_err();
+        let parse_error = parse(source).unwrap_err();
+        assert_eq!(validate_error.kind, parse_error.kind);
+        assert_eq!(validate_error.message, parse_error.message);
+        assert_eq!(validate_error.position, parse_error.position);
+    }
+}
+
+#[test]
+fn validate_ir_parsed() {
+    let checks = IrChecks {
+        normalized: false,
+        non_empty_loops: true,
+    };
+    assert!(validate_ir(&parse("+[->+<]>.").unwrap(), checks).is_ok());
+    assert!(validate_ir(&parse("[]").unwrap(), IrChecks::default()).is_ok());
+    assert_eq!(validate_ir(&parse("+[[]]").unwrap(), checks),
+               Err(vec![IrError {
+                            message: "Loop has an empty body".to_owned(),
+                            position: Some(Position { start: 2, end: 3 }),
+                        }]));
+}
+
+#[test]
+fn validate_ir_nested_scan_zero() {
+    let instrs = vec![Loop {
+                          body: vec![ScanZero {
+                                         step: 0,
+                                         position: None,
+                                     }],
+                          position: None,
+                      }];
+    let errors = validate_ir(&instrs, IrChecks::default()).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].message, "ScanZero has a step of zero");
+}
+
+#[test]
+fn validate_ir_multiply_move_source() {
+    let mut changes = BTreeMap::new();
+    changes.insert(0, Wrapping(1));
+    let instrs = vec![MultiplyMove {
+                          changes: changes,
+                          position: None,
+                      }];
+    assert!(validate_ir(&instrs, IrChecks::default()).is_err());
+}
+
+#[test]
+fn validate_ir_move_to_itself() {
+    let instrs = vec![Move {
+                          offset: 0,
+                          position: None,
+                      }];
+    let errors = validate_ir(&instrs, IrChecks::default()).unwrap_err();
+    assert_eq!(errors[0].message, "Move moves a cell onto itself");
+}
+
+#[test]
+fn validate_ir_multiply_copy_temp() {
+    let mut changes = BTreeMap::new();
+    changes.insert(1, Wrapping(1));
+    let instrs = vec![MultiplyCopy {
+                          changes: changes,
+                          temp: 1,
+                          position: None,
+                      }];
+    let errors = validate_ir(&instrs, IrChecks::default()).unwrap_err();
+    assert_eq!(errors[0].message, "MultiplyCopy changes its temporary cell");
+}
+
+#[test]
+fn validate_ir_reports_every_error() {
+    let instrs = vec![ScanZero {
+                          step: 0,
+                          position: Some(Position { start: 0, end: 2 }),
+                      },
+                      Loop {
+                          body: vec![ScanZero {
+                                         step: 0,
+                                         position: Some(Position { start: 4, end: 6 }),
+                                     }],
+                          position: Some(Position { start: 3, end: 7 }),
+                      }];
+    let errors = validate_ir(&instrs, IrChecks::default()).unwrap_err();
+    let starts: Vec<_> = errors.iter().map(|e| e.position.unwrap().start).collect();
+    assert_eq!(starts, vec![0, 4]);
+}
+
+#[test]
+fn validate_ir_offset_too_large() {
+    let instrs = vec![Increment {
+                          amount: Wrapping(1),
+                          offset: -(MAX_CELL_INDEX as isize) - 1,
+                          position: None,
+                      },
+                      Set {
+                          amount: Wrapping(1),
+                          offset: MAX_CELL_INDEX as isize,
+                          position: None,
+                      },
+                      PointerIncrement {
+                          amount: isize::MIN,
+                          position: None,
+                      }];
+    let errors = validate_ir(&instrs, IrChecks::default()).unwrap_err();
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].message,
+               format!("Offset of {} is larger than the maximum of {}",
+                       MAX_CELL_INDEX + 1,
+                       MAX_CELL_INDEX));
+}
+
+/// Check that parsing `source` succeeds exactly when its brackets are
+/// balanced, and that any error points at a bracket and displays
+/// without panicking.
+#[cfg(test)]
+fn parse_is_well_formed(source: &str) -> bool {
+    match parse(source) {
+        Ok(_) => validate(source).is_ok(),
+        Err(error) => {
+            let is_bracket = matches!(source.as_bytes().get(error.position.start),
+                                      Some(&b'[') | Some(&b']'));
+            let _ = format!("{}", error);
+            is_bracket && error.location.is_some() && validate(source).is_err()
+        }
+    }
+}
+
+#[test]
+fn quickcheck_parse_arbitrary_strings() {
+    use quickcheck::quickcheck;
+
+    fn parse_arbitrary(source: String) -> bool {
+        parse_is_well_formed(&source)
+    }
+    quickcheck(parse_arbitrary as fn(String) -> bool);
+}
+
+#[test]
+fn quickcheck_parse_bracket_heavy_strings() {
+    use quickcheck::quickcheck;
+
+    // Arbitrary strings rarely contain brackets, so pick characters
+    // from a small alphabet to exercise nesting.
+    fn parse_bracket_heavy(choices: Vec<u8>) -> bool {
+        let alphabet = ['[', ']', '[', ']', '+', '>', '.', '\n', 'é'];
+        let source: String = choices.iter()
+            .map(|&choice| alphabet[choice as usize % alphabet.len()])
+            .collect();
+        parse_is_well_formed(&source)
+    }
+    quickcheck(parse_bracket_heavy as fn(Vec<u8>) -> bool);
+}
+
+#[test]
+fn quickcheck_parse_reader_arbitrary_bytes() {
+    use quickcheck::quickcheck;
+
+    // The input needn't be UTF-8.
+    fn parse_reader_arbitrary(bytes: Vec<u8>) -> bool {
+        match parse_reader(&bytes[..]) {
+            Ok(_) => true,
+            Err(error) => error.position.start < bytes.len(),
+        }
+    }
+    quickcheck(parse_reader_arbitrary as fn(Vec<u8>) -> bool);
+}
+
+/// Inputs that have made the parser panic in the past, or are likely
+/// to.
+#[test]
+fn parse_regression_corpus() {
+    let deeply_nested = "[".repeat(DEFAULT_MAX_NESTING + 1);
+    let corpus = ["", "[", "]", "][", "[[", "[\n", "\n]", "é[", "[é", "\t]", &deeply_nested];
+    for source in &corpus {
+        assert!(parse_is_well_formed(source), "Bad result for {:?}", source);
+    }
+}
+
+#[test]
+fn from_char_commands() {
+    assert_eq!(AstNode::from_char('+'),
+               Some(Increment {
+                   amount: Wrapping(1),
+                   offset: 0,
+                   position: None,
+               }));
+    assert_eq!(AstNode::from_char('-'),
+               Some(Increment {
+                   amount: Wrapping(-1),
+                   offset: 0,
+                   position: None,
+               }));
+    assert_eq!(AstNode::from_char('>'),
+               Some(PointerIncrement {
+                   amount: 1,
+                   position: None,
+               }));
+    assert_eq!(AstNode::from_char('<'),
+               Some(PointerIncrement {
+                   amount: -1,
+                   position: None,
+               }));
+    assert_eq!(AstNode::from_char(','), Some(Read { position: None }));
+    assert_eq!(AstNode::from_char('.'), Some(Write { position: None }));
+    assert_eq!(AstNode::from_char('['), None);
+    assert_eq!(AstNode::from_char(']'), None);
+    assert_eq!(AstNode::from_char('x'), None);
+}
+
+#[test]
+fn iter_flat_depths() {
+    let instrs = parse(".[,+]-").unwrap();
+    let flat: Vec<_> = iter_flat(&instrs)
+        .map(|(depth, instr)| (depth, to_source(::std::slice::from_ref(instr))))
+        .collect();
+    assert_eq!(flat,
+               vec![(0, ".".to_owned()),
+                    (0, "[,+]".to_owned()),
+                    (1, ",".to_owned()),
+                    (1, "+".to_owned()),
+                    (0, "-".to_owned())]);
+}
+
+#[test]
+fn iter_flat_nested() {
+    let instrs = parse("[[>]]<").unwrap();
+    let depths: Vec<_> = iter_flat(&instrs).map(|(depth, _)| depth).collect();
+    assert_eq!(depths, vec![0, 1, 2, 0]);
+    assert_eq!(iter_flat(&[]).count(), 0);
+}
+
+#[test]
+fn node_count_includes_loop_bodies() {
+    // Write, Loop, Read, Increment inside the loop, Increment.
+    let instrs = parse(".[,+]-").unwrap();
+    assert_eq!(node_count(&instrs), 5);
+    assert_eq!(instrs[1].node_count(), 3);
+    assert_eq!(node_count(&[]), 0);
+}
+
+#[test]
+fn concat_runs_programs_in_order() {
+    let programs = [parse(",").unwrap(), vec![], parse("+.").unwrap()];
+    assert_eq!(concat(&programs),
+               vec![Read { position: Some(Position { start: 0, end: 0 }) },
+                    Increment {
+                        amount: Wrapping(1),
+                        offset: 0,
+                        position: Some(Position { start: 0, end: 0 }),
+                    },
+                    Write { position: Some(Position { start: 1, end: 1 }) }]);
+    assert_eq!(concat(&[]), vec![]);
+}
+
+#[test]
+fn parse_comment() {
+    assert_eq!(parse("foo! ").unwrap(), []);
+}
+
+#[test]
+fn parse_ignores_breakpoints_by_default() {
+    assert_eq!(parse("#").unwrap(), []);
+    assert_eq!(parse_with_options("#", &ParseOptions::default()).unwrap().0, []);
+}
+
+#[test]
+fn parse_breakpoints() {
+    let options = ParseOptions { recognize_breakpoints: true, ..ParseOptions::default() };
+    assert_eq!(parse_with_options(".#", &options).unwrap().0,
+               [Write { position: Some(Position { start: 0, end: 0 }) },
+                Breakpoint { position: Some(Position { start: 1, end: 1 }) }]);
+}
+
+#[test]
+fn parse_with_comments_records_comments() {
+    let (instrs, comments) = parse_with_comments("a+ b").unwrap();
+    assert_eq!(instrs, parse("a+ b").unwrap());
+    assert_eq!(comments, vec![(0, 'a'), (2, ' '), (3, 'b')]);
+}
+
+#[test]
+fn parse_with_options_default_discards_comments() {
+    let (instrs, comments) = parse_with_options("a+ b", &ParseOptions::default()).unwrap();
+    assert_eq!(instrs, parse("a+ b").unwrap());
+    assert_eq!(comments, vec![]);
+}
+
+#[test]
+fn parse_with_options_combined() {
+    let options = ParseOptions {
+        recognize_breakpoints: true,
+        collect_comments: true,
+        ..ParseOptions::default()
+    };
+    let (instrs, comments) = parse_with_options("x#", &options).unwrap();
+    assert_eq!(instrs, [Breakpoint { position: Some(Position { start: 1, end: 1 }) }]);
+    assert_eq!(comments, vec![(0, 'x')]);
+}
+
+#[test]
+fn same_kind_ignores_values() {
+    let increment = |amount| {
+        Increment {
+            amount: Wrapping(amount),
+            offset: 0,
+            position: None,
+        }
+    };
+    let set = Set {
+        amount: Wrapping(1),
+        offset: 0,
+        position: None,
+    };
+    assert!(same_kind(&increment(3), &increment(-1)));
+    assert!(!same_kind(&increment(1), &set));
+}
+
+#[test]
+fn has_shape_compares_each_instr() {
+    let instrs = parse("->+<").unwrap();
+    assert!(has_shape(&instrs, &parse("+<->").unwrap()));
+    assert!(!has_shape(&instrs, &parse("+<-").unwrap()));
+    assert!(!has_shape(&instrs, &parse("+<-.").unwrap()));
+
+    // Only the loop itself is compared.
+    assert!(has_shape(&parse("[-]").unwrap(), &parse("[>.<]").unwrap()));
+}
+
+#[test]
+fn parse_program_and_input_splits() {
+    let (instrs, input) = parse_program_and_input(",.!hi", '!').unwrap();
+    assert_eq!(instrs, parse(",.").unwrap());
+    assert_eq!(input, "hi");
+
+    // Only the first separator counts.
+    let (_, input) = parse_program_and_input("+!a!b", '!').unwrap();
+    assert_eq!(input, "a!b");
+}
+
+#[test]
+fn parse_program_and_input_without_separator() {
+    let (instrs, input) = parse_program_and_input(",.", '!').unwrap();
+    assert_eq!(instrs, parse(",.").unwrap());
+    assert_eq!(input, "");
+}
+
+#[test]
+fn parse_program_and_input_errors() {
+    // Brackets in the input don't matter.
+    assert!(parse_program_and_input("+!]", '!').is_ok());
+    assert!(parse_program_and_input("[!]", '!').is_err());
+}
+
+#[test]
+fn snippet_unmatched_open() {
+    let source = "+\n\t[.";
+    let error = parse(source).unwrap_err();
+    assert_eq!(snippet(source, error.position.start..error.position.end + 1),
+               "\t[.\n\t^");
+}
+
+#[test]
+fn snippet_multiline_span() {
+    // We only underline the first line of the span.
+    assert_eq!(snippet("+[.\n]", 1..5), "+[.\n ^^");
+    assert_eq!(snippet("+", 1..1), "+\n ^");
+}
+
+#[test]
+fn source_map_from_unordered_offsets() {
+    let generated = "a\nbc\nd\n";
+    let offsets = vec![(5, 2..3), (0, 0..1), (3, 1..2)];
+    assert_eq!(source_map_from_offsets(generated, offsets),
+               vec![(1, 0..1), (2, 1..2), (3, 2..3)]);
+}
+
+#[test]
+fn parse_modules_splits() {
+    let modules = parse_modules("+.\n---\n,[.,]\n", "---").unwrap();
+    assert_eq!(modules, vec![parse("+.\n").unwrap(), parse(",[.,]\n").unwrap()]);
+
+    // Boundaries are whole lines, so --- elsewhere is code.
+    let modules = parse_modules("---.\n---", "---").unwrap();
+    assert_eq!(modules, vec![parse("---.\n").unwrap(), vec![]]);
+}
+
+#[test]
+fn parse_modules_error_names_module() {
+    let error = parse_modules("+\n===\n[[]\n", "===").unwrap_err();
+    assert_eq!(error.kind, ParseErrorKind::UnmatchedOpen { index: 0 });
+    assert_eq!(error.message, "Module 1: This [ has no matching ]");
+    assert_eq!(error.position, Position { start: 0, end: 0 });
+}
+
+#[test]
+fn parse_iter_matches_parse() {
+    let instrs: Result<Vec<_>, _> = parse_iter(".[,+]-").collect();
+    assert_eq!(instrs.unwrap(), parse(".[,+]-").unwrap());
+}
+
+#[test]
+fn parse_iter_is_lazy() {
+    // We return the first instruction before seeing the error.
+    let mut instrs = parse_iter("+]");
+    assert!(matches!(instrs.next(), Some(Ok(Increment { .. }))));
+    assert!(matches!(instrs.next(), Some(Err(_))));
+    assert!(instrs.next().is_none());
+}
+
+#[test]
+fn parse_iter_unclosed_loop() {
+    let results: Vec<_> = parse_iter("+[").collect();
+    assert_eq!(results.len(), 2);
+    let error = results[1].as_ref().unwrap_err();
+    assert_eq!(error.kind, ParseErrorKind::UnmatchedOpen { index: 1 });
+    assert_eq!(error.position, Position { start: 1, end: 1 });
+}
+
+#[test]
+fn parse_strict_rejects_stray_characters() {
+    let options = ParseOptions { strict: true, ..ParseOptions::default() };
+    let error = parse_with_options("+\n a-", &options).unwrap_err();
+    assert_eq!(error.kind, ParseErrorKind::UnexpectedChar { c: 'a', index: 3 });
+    assert_eq!(error.message, "Unexpected character 'a'");
+    assert_eq!(error.position, Position { start: 3, end: 3 });
+    assert_eq!(error.location.unwrap().line, 2);
+
+    // The position covers the whole of a multibyte character.
+    let error = parse_with_options("+é", &options).unwrap_err();
+    assert_eq!(error.position, Position { start: 1, end: 2 });
+}
+
+#[test]
+fn parse_strict_allows_whitespace_and_comment_chars() {
+    let options = ParseOptions {
+        strict: true,
+        comment_chars: "/",
+        ..ParseOptions::default()
+    };
+    let (instrs, _) = parse_with_options("+ \t-\r\n// .", &options).unwrap();
+    assert_eq!(instrs, parse("+ \t-\r\n// .").unwrap());
+    assert!(parse_with_options("+#", &options).is_err());
+
+    // Breakpoints are commands, not comments, when recognised.
+    let options = ParseOptions { recognize_breakpoints: true, ..options };
+    assert!(parse_with_options("+#", &options).is_ok());
+}
+
+#[test]
+fn parse_char_map_swapped() {
+    let char_map = CharMap {
+        increment: b'-',
+        decrement: b'+',
+        ..CharMap::default()
+    };
+    let options = ParseOptions { char_map: char_map, ..ParseOptions::default() };
+    let (instrs, _) = parse_with_options("+[-]", &options).unwrap();
+    assert_eq!(instrs,
+               vec![Increment {
+                        amount: Wrapping(-1),
+                        offset: 0,
+                        position: Some(Position { start: 0, end: 0 }),
+                    },
+                    Loop {
+                        body: vec![Increment {
+                                       amount: Wrapping(1),
+                                       offset: 0,
+                                       position: Some(Position { start: 2, end: 2 }),
+                                   }],
+                        position: Some(Position { start: 1, end: 3 }),
+                    }]);
+}
+
+#[test]
+fn parse_char_map_unused_commands_are_comments() {
+    let char_map = CharMap {
+        loop_start: b'(',
+        loop_end: b')',
+        ..CharMap::default()
+    };
+    let options = ParseOptions { char_map: char_map, ..ParseOptions::default() };
+    let (instrs, _) = parse_with_options("[+(-)]", &options).unwrap();
+    assert_eq!(instrs, parse(" +[-] ").unwrap());
+}
+
+#[test]
+fn parse_lenient_by_default() {
+    assert!(parse("a").is_ok());
+}
+
+#[test]
+fn parse_with_comments_only_comments() {
+    let (instrs, comments) = parse_with_comments("foo! ").unwrap();
+    assert_eq!(instrs, []);
+    assert_eq!(comments,
+               vec![(0, 'f'), (1, 'o'), (2, 'o'), (3, '!'), (4, ' ')]);
+}
+
+#[test]
+fn parse_with_comments_in_loops() {
+    let (_, comments) = parse_with_comments("[x[y]]z").unwrap();
+    assert_eq!(comments, vec![(1, 'x'), (3, 'y'), (6, 'z')]);
+}
+
+#[test]
+fn parse_with_comments_unbalanced() {
+    assert!(parse_with_comments("foo [").is_err());
+}
+
+#[test]
+fn map_instructions_visits_loop_bodies() {
+    let instrs = parse("+[-]").unwrap();
+    let add_write = |mut instrs: Vec<AstNode>| {
+        instrs.push(Write { position: None });
+        instrs
+    };
+
+    let expected = vec![Increment {
+                            amount: Wrapping(1),
+                            offset: 0,
+                            position: Some(Position { start: 0, end: 0 }),
+                        },
+                        Loop {
+                            body: vec![Increment {
+                                           amount: Wrapping(-1),
+                                           offset: 0,
+                                           position: Some(Position { start: 2, end: 2 }),
+                                       },
+                                       Write { position: None }],
+                            position: Some(Position { start: 1, end: 3 }),
+                        },
+                        Write { position: None }];
+    assert_eq!(map_instructions(instrs, &add_write), expected);
+}
+
+#[test]
+fn map_instructions_is_bottom_up() {
+    // Removing empty loops from the innermost loop first makes the
+    // outer loop empty too.
+    let remove_empty_loops = |instrs: Vec<AstNode>| {
+        instrs.into_iter()
+            .filter(|instr| {
+                match *instr {
+                    Loop { ref body, .. } => !body.is_empty(),
+                    _ => true,
+                }
+            })
+            .collect()
+    };
+    assert_eq!(map_instructions(parse("[[]]").unwrap(), &remove_empty_loops),
+               []);
+}
+
+#[test]
+fn parse_non_ascii_comment() {
+    // Positions are byte offsets, and 'é' is two bytes.
+    assert_eq!(parse("é+").unwrap(),
+               [Increment {
+                    amount: Wrapping(1),
+                    offset: 0,
+                    position: Some(Position { start: 2, end: 2 }),
+                }]);
+
+    let (_, comments) = parse_with_comments("é+ü").unwrap();
+    assert_eq!(comments, vec![(0, 'é'), (3, 'ü')]);
+}
+
+#[test]
+fn parse_reader_matches_parse() {
+    let source = include_str!("../sample_programs/mandelbrot.bf");
+    assert_eq!(parse_reader(source.as_bytes()).unwrap(),
+               parse(source).unwrap());
+}
+
+#[test]
+fn parse_reader_unbalanced() {
+    let error = parse_reader(&b"+[+"[..]).unwrap_err();
+    assert_eq!(error.kind, ParseErrorKind::UnmatchedOpen { index: 1 });
+    assert_eq!(error.position, Position { start: 1, end: 1 });
+
+    let error = parse_reader(&b"+]"[..]).unwrap_err();
+    assert_eq!(error.kind, ParseErrorKind::UnmatchedClose { index: 1 });
+    assert_eq!(error.position, Position { start: 1, end: 1 });
+}
+
+#[test]
+fn parse_reader_io_error() {
+    struct FailingReader;
+    impl io::Read for FailingReader {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::Other, "disk on fire"))
+        }
+    }
+
+    let error = parse_reader(FailingReader).unwrap_err();
+    assert_eq!(error.kind, ParseErrorKind::Io);
+    assert_eq!(error.message, "Could not read source: disk on fire");
+}
+
+#[test]
+fn display_listing() {
+    let listing = Listing(parse(",[>[.]]").unwrap());
+    let expected = "Read { position: Some(0) }
+Loop position: Some(1-6)
+  PointerIncrement { amount: 1, position: Some(2) }
+  Loop position: Some(3-5)
+    Write { position: Some(4) }
+";
+    assert_eq!(format!("{}", listing), expected);
+}
+
+#[test]
+fn display_empty_listing() {
+    assert_eq!(format!("{}", Listing(vec![])), "");
+}
+
+/// A writer that fails once it has received `capacity` bytes.
+#[cfg(test)]
+struct FailingWriter {
+    capacity: usize,
+}
+
+#[cfg(test)]
+impl fmt::Write for FailingWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if s.len() > self.capacity {
+            return Err(fmt::Error);
+        }
+        self.capacity -= s.len();
+        Ok(())
+    }
+}
+
+#[test]
+fn display_propagates_write_errors() {
+    use std::fmt::Write;
+
+    let instrs = parse("[[.]]").unwrap();
+
+    // Fail part way through the nested loop.
+    let mut writer = FailingWriter { capacity: 30 };
+    assert_eq!(write!(writer, "{}", instrs[0]), Err(fmt::Error));
+
+    let mut writer = FailingWriter { capacity: 30 };
+    assert_eq!(write!(writer, "{}", Listing(instrs.clone())), Err(fmt::Error));
+
+    let mut writer = FailingWriter { capacity: 1000 };
+    assert_eq!(write!(writer, "{}", Listing(instrs)), Ok(()));
+}
+
+#[test]
+fn test_combine_pos() {
+    let pos1 = Some(Position { start: 1, end: 2 });
+    let pos2 = Some(Position { start: 3, end: 4 });
+
+    assert_eq!(pos1.combine(pos2), Some(Position { start: 1, end: 4 }));
+}
+
+#[test]
+fn test_combine_order() {
+    let pos1 = Some(Position { start: 3, end: 4 });
+    let pos2 = Some(Position { start: 1, end: 2 });
+
+    assert_eq!(pos1.combine(pos2), Some(Position { start: 1, end: 4 }));
+}
+
+#[test]
+fn test_combine_pos_not_consecutive() {
+    let pos1 = Some(Position { start: 1, end: 2 });
+    let pos2 = Some(Position { start: 4, end: 5 });
+
+    assert_eq!(pos1.combine(pos2), Some(Position { start: 4, end: 5 }));
+}
+
+#[test]
+fn test_combine_pos_overlap() {
+    let pos1 = Some(Position { start: 1, end: 1 });
+    let pos2 = Some(Position { start: 1, end: 3 });
 
     assert_eq!(pos1.combine(pos2), Some(Position { start: 1, end: 3 }));
 }
+
+#[test]
+fn merged_position_spans_gaps() {
+    let pos1 = Some(Position { start: 1, end: 2 });
+    let pos2 = Some(Position { start: 4, end: 5 });
+
+    assert_eq!(merged_position(pos1, pos2), Some(Position { start: 1, end: 5 }));
+    assert_eq!(merged_position(pos2, pos1), Some(Position { start: 1, end: 5 }));
+}
+
+#[test]
+fn merged_position_missing() {
+    let pos = Some(Position { start: 1, end: 2 });
+
+    assert_eq!(merged_position(pos, None), pos);
+    assert_eq!(merged_position(None, pos), pos);
+    assert_eq!(merged_position(None, None), None);
+}
+
+#[test]
+fn to_source_parsed() {
+    let instrs = parse("+[->+<]. comment").unwrap();
+    assert_eq!(to_source(&instrs), "+[->+<].");
+}
+
+#[test]
+fn to_source_breakpoint() {
+    let options = ParseOptions { recognize_breakpoints: true, ..ParseOptions::default() };
+    let (instrs, _) = parse_with_options("+#.", &options).unwrap();
+    assert_eq!(to_source(&instrs), "+#.");
+}
+
+#[test]
+fn to_source_optimised_instrs() {
+    let mut changes = BTreeMap::new();
+    changes.insert(-1, Wrapping(2));
+    changes.insert(2, Wrapping(-1));
+    let instrs = [Set {
+                      amount: Wrapping(-2),
+                      offset: 1,
+                      position: None,
+                  },
+                  Increment {
+                      amount: Wrapping(3),
+                      offset: -2,
+                      position: None,
+                  },
+                  MultiplyMove {
+                      changes: changes,
+                      position: None,
+                  }];
+    assert_eq!(to_source(&instrs), ">[-]--<<<+++>>[-<++>>>-<<]");
+}
+
+#[test]
+fn to_source_multiply_copy() {
+    let mut changes = BTreeMap::new();
+    changes.insert(1, Wrapping(2));
+    let instrs = [MultiplyCopy {
+                      changes: changes,
+                      temp: -1,
+                      position: None,
+                  }];
+    assert_eq!(to_source(&instrs), "[-<+>>++<]<[->+<]>");
+}
+
+#[test]
+fn to_source_move() {
+    let instrs = [Move {
+                      offset: -2,
+                      position: None,
+                  }];
+    assert_eq!(to_source(&instrs), "[-<<+>>]");
+}
+
+#[test]
+fn to_source_is_equivalent_after_optimisation() {
+    use interpreter::interpret;
+    use peephole::optimize;
+    use std::io::Cursor;
+
+    let source = include_str!("../sample_programs/factor.bf");
+    let optimised = optimize(parse(source).unwrap(), &None).0;
+    let regenerated = parse(&to_source(&optimised)).unwrap();
+
+    let mut expected = vec![];
+    interpret(&parse(source).unwrap(),
+              &mut Cursor::new(b"360\n"),
+              &mut expected)
+        .unwrap();
+    let mut output = vec![];
+    interpret(&regenerated, &mut Cursor::new(b"360\n"), &mut output).unwrap();
+
+    assert_eq!(output, expected);
+    assert_eq!(String::from_utf8(output).unwrap(), "360: 2 2 2 3 3 5\n");
+}
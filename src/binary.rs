@@ -0,0 +1,546 @@
+//! Convert BF IR to and from a compact binary format, so tools can
+//! cache parsed or optimised programs and reload them quickly.
+//!
+//! The format starts with the magic bytes `BFIR` and a version
+//! byte. Each instruction is then a tag byte followed by its
+//! fields. Integers are LEB128 varints, with signed integers
+//! zigzag-encoded first so small negative numbers stay small. Cells
+//! are a single byte. Loops are a start tag, the body, and an end
+//! tag, so decoding doesn't need to recurse.
+//!
+//! Positions are a varint that's 0 for no position, or the start plus
+//! one, followed by a varint of the end minus the start.
+//!
+//! Saved bytecode states use the magic bytes `BFST` and their own
+//! version byte, followed by varints of the program counter, the cell
+//! pointer and the tape length. Then there's a varint count of cells
+//! up to the last nonzero cell, and those cells as bytes.
+//!
+//! Loops may be nested at most `bfir::DEFAULT_MAX_NESTING` deep, as
+//! in source code, and tapes may be at most `MAX_CELL_INDEX + 1`
+//! cells long, so untrusted input can't exhaust the stack or memory.
+
+use std::collections::BTreeMap;
+use std::mem;
+use std::num::Wrapping;
+
+use bfir::{AstNode, Cell, Position, DEFAULT_MAX_NESTING};
+use bfir::AstNode::*;
+use bounds::MAX_CELL_INDEX;
+use bytecode::SavedState;
+
+#[cfg(test)]
+use bfir::parse;
+
+const MAGIC: &[u8] = b"BFIR";
+/// Version 2 added Move, and version 3 added MultiplyCopy. Older
+/// files are still valid version 3 files, so we read them all.
+const VERSION: u8 = 3;
+
+const STATE_MAGIC: &[u8] = b"BFST";
+const STATE_VERSION: u8 = 1;
+
+const TAG_INCREMENT: u8 = 0;
+const TAG_POINTER_INCREMENT: u8 = 1;
+const TAG_READ: u8 = 2;
+const TAG_WRITE: u8 = 3;
+const TAG_LOOP_START: u8 = 4;
+const TAG_LOOP_END: u8 = 5;
+const TAG_SET: u8 = 6;
+const TAG_MULTIPLY_MOVE: u8 = 7;
+const TAG_SCAN_ZERO: u8 = 8;
+const TAG_BREAKPOINT: u8 = 9;
+const TAG_MOVE: u8 = 10;
+const TAG_MULTIPLY_COPY: u8 = 11;
+
+fn push_varint(mut value: u64, out: &mut Vec<u8>) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn push_signed(value: isize, out: &mut Vec<u8>) {
+    let value = value as i64;
+    push_varint(((value << 1) ^ (value >> 63)) as u64, out);
+}
+
+fn push_cell(value: Cell, out: &mut Vec<u8>) {
+    out.push(value.0 as u8);
+}
+
+fn push_position(position: Option<Position>, out: &mut Vec<u8>) -> Result<(), String> {
+    match position {
+        Some(Position { start, end }) => {
+            if end < start {
+                return Err(format!("Position {}-{} ends before it starts", start, end));
+            }
+            push_varint(start as u64 + 1, out);
+            push_varint((end - start) as u64, out);
+        }
+        None => push_varint(0, out),
+    }
+    Ok(())
+}
+
+fn push_changes(changes: &BTreeMap<isize, Cell>, out: &mut Vec<u8>) {
+    push_varint(changes.len() as u64, out);
+    for (offset, factor) in changes {
+        push_signed(*offset, out);
+        push_cell(*factor, out);
+    }
+}
+
+fn push_instrs(instrs: &[AstNode], out: &mut Vec<u8>) -> Result<(), String> {
+    for instr in instrs {
+        match *instr {
+            Increment { amount, offset, position } => {
+                out.push(TAG_INCREMENT);
+                push_cell(amount, out);
+                push_signed(offset, out);
+                push_position(position, out)?;
+            }
+            PointerIncrement { amount, position } => {
+                out.push(TAG_POINTER_INCREMENT);
+                push_signed(amount, out);
+                push_position(position, out)?;
+            }
+            Read { position } => {
+                out.push(TAG_READ);
+                push_position(position, out)?;
+            }
+            Write { position } => {
+                out.push(TAG_WRITE);
+                push_position(position, out)?;
+            }
+            Loop { ref body, position } => {
+                out.push(TAG_LOOP_START);
+                push_position(position, out)?;
+                push_instrs(body, out)?;
+                out.push(TAG_LOOP_END);
+            }
+            Set { amount, offset, position } => {
+                out.push(TAG_SET);
+                push_cell(amount, out);
+                push_signed(offset, out);
+                push_position(position, out)?;
+            }
+            MultiplyMove { ref changes, position } => {
+                out.push(TAG_MULTIPLY_MOVE);
+                push_changes(changes, out);
+                push_position(position, out)?;
+            }
+            Move { offset, position } => {
+                out.push(TAG_MOVE);
+                push_signed(offset, out);
+                push_position(position, out)?;
+            }
+            MultiplyCopy { ref changes, temp, position } => {
+                out.push(TAG_MULTIPLY_COPY);
+                push_changes(changes, out);
+                push_signed(temp, out);
+                push_position(position, out)?;
+            }
+            ScanZero { step, position } => {
+                out.push(TAG_SCAN_ZERO);
+                push_signed(step, out);
+                push_position(position, out)?;
+            }
+            Breakpoint { position } => {
+                out.push(TAG_BREAKPOINT);
+                push_position(position, out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Convert the instructions given to our binary format. Returns a
+/// description of the problem if an instruction has a position that
+/// ends before it starts, as we can't encode it.
+pub fn to_bytes(instrs: &[AstNode]) -> Result<Vec<u8>, String> {
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    push_instrs(instrs, &mut out)?;
+    Ok(out)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    index: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, String> {
+        match self.bytes.get(self.index) {
+            Some(&byte) => {
+                self.index += 1;
+                Ok(byte)
+            }
+            None => Err("Unexpected end of input".to_owned()),
+        }
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            // The tenth byte can only hold the top bit of a u64.
+            if shift >= 64 || (shift == 63 && byte & 0x7e != 0) {
+                return Err(format!("Varint too long at byte {}", self.index - 1));
+            }
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    fn usize(&mut self) -> Result<usize, String> {
+        let value = self.varint()?;
+        if value > usize::MAX as u64 {
+            return Err(format!("Value {} is out of range", value));
+        }
+        Ok(value as usize)
+    }
+
+    fn signed(&mut self) -> Result<isize, String> {
+        let value = self.varint()?;
+        let value = ((value >> 1) as i64) ^ -((value & 1) as i64);
+        if value < isize::MIN as i64 || value > isize::MAX as i64 {
+            return Err(format!("Value {} is out of range", value));
+        }
+        Ok(value as isize)
+    }
+
+    fn cell(&mut self) -> Result<Cell, String> {
+        Ok(Wrapping(self.byte()? as i8))
+    }
+
+    fn changes(&mut self) -> Result<BTreeMap<isize, Cell>, String> {
+        let num_changes = self.usize()?;
+        let mut changes = BTreeMap::new();
+        for _ in 0..num_changes {
+            let offset = self.signed()?;
+            changes.insert(offset, self.cell()?);
+        }
+        Ok(changes)
+    }
+
+    fn position(&mut self) -> Result<Option<Position>, String> {
+        let start = self.usize()?;
+        if start == 0 {
+            return Ok(None);
+        }
+        let start = start - 1;
+        match start.checked_add(self.usize()?) {
+            Some(end) => {
+                Ok(Some(Position {
+                    start: start,
+                    end: end,
+                }))
+            }
+            None => Err("Position is out of range".to_owned()),
+        }
+    }
+}
+
+/// Convert our binary format back to instructions. Returns a
+/// description of the problem if `bytes` isn't valid.
+pub fn from_bytes(bytes: &[u8]) -> Result<Vec<AstNode>, String> {
+    if !bytes.starts_with(MAGIC) {
+        return Err("Not a BF IR file".to_owned());
+    }
+    let mut reader = Reader {
+        bytes: bytes,
+        index: MAGIC.len(),
+    };
+    let version = reader.byte()?;
+    if version == 0 || version > VERSION {
+        return Err(format!("Unsupported BF IR version {}", version));
+    }
+
+    let mut instrs = vec![];
+    // The instructions and positions of the loops that we're inside.
+    let mut stack: Vec<(Vec<AstNode>, Option<Position>)> = vec![];
+
+    while reader.index < bytes.len() {
+        let tag_index = reader.index;
+        let instr = match reader.byte()? {
+            TAG_INCREMENT => {
+                Increment {
+                    amount: reader.cell()?,
+                    offset: reader.signed()?,
+                    position: reader.position()?,
+                }
+            }
+            TAG_POINTER_INCREMENT => {
+                PointerIncrement {
+                    amount: reader.signed()?,
+                    position: reader.position()?,
+                }
+            }
+            TAG_READ => Read { position: reader.position()? },
+            TAG_WRITE => Write { position: reader.position()? },
+            TAG_LOOP_START => {
+                // Deeply nested loops would overflow the stack in
+                // every pass that recurses on loop bodies.
+                if stack.len() >= DEFAULT_MAX_NESTING {
+                    return Err(format!("Loop at byte {} is nested more than {} loops deep",
+                                       tag_index,
+                                       DEFAULT_MAX_NESTING));
+                }
+                let position = reader.position()?;
+                let parent_instrs = mem::take(&mut instrs);
+                stack.push((parent_instrs, position));
+                continue;
+            }
+            TAG_LOOP_END => {
+                match stack.pop() {
+                    Some((parent_instrs, position)) => {
+                        let body = mem::replace(&mut instrs, parent_instrs);
+                        Loop {
+                            body: body,
+                            position: position,
+                        }
+                    }
+                    None => return Err(format!("Unmatched loop end at byte {}", tag_index)),
+                }
+            }
+            TAG_SET => {
+                Set {
+                    amount: reader.cell()?,
+                    offset: reader.signed()?,
+                    position: reader.position()?,
+                }
+            }
+            TAG_MULTIPLY_MOVE => {
+                MultiplyMove {
+                    changes: reader.changes()?,
+                    position: reader.position()?,
+                }
+            }
+            TAG_MOVE => {
+                Move {
+                    offset: reader.signed()?,
+                    position: reader.position()?,
+                }
+            }
+            TAG_MULTIPLY_COPY => {
+                MultiplyCopy {
+                    changes: reader.changes()?,
+                    temp: reader.signed()?,
+                    position: reader.position()?,
+                }
+            }
+            TAG_SCAN_ZERO => {
+                ScanZero {
+                    step: reader.signed()?,
+                    position: reader.position()?,
+                }
+            }
+            TAG_BREAKPOINT => Breakpoint { position: reader.position()? },
+            tag => return Err(format!("Unknown tag {} at byte {}", tag, tag_index)),
+        };
+        instrs.push(instr);
+    }
+
+    if !stack.is_empty() {
+        return Err("Unterminated loop at end of input".to_owned());
+    }
+    Ok(instrs)
+}
+
+/// Convert a paused bytecode program's state to bytes, so it can be
+/// stored and resumed later.
+pub fn state_to_bytes(state: &SavedState) -> Vec<u8> {
+    let mut out = STATE_MAGIC.to_vec();
+    out.push(STATE_VERSION);
+    push_varint(state.pc as u64, &mut out);
+    push_varint(state.cell_ptr as u64, &mut out);
+    push_varint(state.cells.len() as u64, &mut out);
+
+    // Most of the tape is usually zero, so leave off the zeroes at the
+    // end.
+    let num_stored = state.cells.iter().rposition(|&cell| cell != 0).map_or(0, |index| index + 1);
+    push_varint(num_stored as u64, &mut out);
+    out.extend_from_slice(&state.cells[..num_stored]);
+    out
+}
+
+/// Convert bytes from `state_to_bytes` back to a saved state. Returns
+/// a description of the problem if `bytes` isn't valid.
+pub fn state_from_bytes(bytes: &[u8]) -> Result<SavedState, String> {
+    if !bytes.starts_with(STATE_MAGIC) {
+        return Err("Not a saved BF state".to_owned());
+    }
+    let mut reader = Reader {
+        bytes: bytes,
+        index: STATE_MAGIC.len(),
+    };
+    let version = reader.byte()?;
+    if version != STATE_VERSION {
+        return Err(format!("Unsupported saved state version {}", version));
+    }
+
+    let pc = reader.usize()?;
+    let cell_ptr = reader.usize()?;
+    let num_cells = reader.usize()?;
+    if num_cells > MAX_CELL_INDEX + 1 {
+        return Err(format!("A tape of {} cells is longer than the maximum of {}",
+                           num_cells,
+                           MAX_CELL_INDEX + 1));
+    }
+    let num_stored = reader.usize()?;
+    if num_stored > num_cells || num_stored != bytes.len() - reader.index {
+        return Err("Saved cells don't match the tape length".to_owned());
+    }
+
+    let mut cells = bytes[reader.index..].to_vec();
+    cells.resize(num_cells, 0);
+    Ok(SavedState {
+        cells: cells,
+        cell_ptr: cell_ptr,
+        pc: pc,
+    })
+}
+
+#[test]
+fn binary_roundtrip() {
+    let instrs = parse("+[->+<]>.,<#").unwrap();
+    assert_eq!(from_bytes(&to_bytes(&instrs).unwrap()), Ok(instrs));
+}
+
+#[test]
+fn binary_roundtrip_multiply_copy() {
+    use peephole::optimize;
+
+    let instrs = optimize(parse(",[->+>+<<]>>[-<<+>>]<<.>.").unwrap(), &None).0;
+    assert!(instrs.iter().any(|instr| matches!(*instr, MultiplyCopy { .. })));
+    assert_eq!(from_bytes(&to_bytes(&instrs).unwrap()), Ok(instrs));
+}
+
+#[test]
+fn binary_roundtrip_move() {
+    let instrs = vec![Move {
+                          offset: -3,
+                          position: Some(Position { start: 0, end: 7 }),
+                      }];
+    assert_eq!(from_bytes(&to_bytes(&instrs).unwrap()), Ok(instrs));
+}
+
+#[test]
+fn binary_roundtrip_optimised() {
+    use peephole::optimize;
+
+    let source = include_str!("../sample_programs/mandelbrot.bf");
+    let instrs = optimize(parse(source).unwrap(), &None).0;
+    let bytes = to_bytes(&instrs).unwrap();
+    assert_eq!(from_bytes(&bytes), Ok(instrs.clone()));
+
+    // At the time of writing, optimised mandelbrot is 126 KB as JSON,
+    // but only 10 KB in this format.
+    #[cfg(feature = "json")]
+    assert!(bytes.len() * 10 < ::json::to_json(&instrs).len());
+}
+
+#[test]
+fn binary_roundtrip_extremes() {
+    let instrs = vec![PointerIncrement {
+                          amount: isize::MIN,
+                          position: Some(Position {
+                              start: 0,
+                              end: usize::MAX - 1,
+                          }),
+                      },
+                      Set {
+                          amount: Wrapping(-128),
+                          offset: isize::MAX,
+                          position: None,
+                      },
+                      Loop {
+                          body: vec![],
+                          position: None,
+                      }];
+    assert_eq!(from_bytes(&to_bytes(&instrs).unwrap()), Ok(instrs));
+}
+
+#[test]
+fn from_bytes_errors() {
+    assert!(from_bytes(b"").is_err());
+    assert!(from_bytes(b"BFIR").is_err());
+    assert!(from_bytes(b"BFIR\x00").is_err());
+    assert!(from_bytes(b"BFIR\x04").is_err());
+    assert_eq!(from_bytes(b"BFIR\x01"), Ok(vec![]));
+    assert_eq!(from_bytes(b"BFIR\x02"), Ok(vec![]));
+    assert_eq!(from_bytes(b"BFIR\x03"), Ok(vec![]));
+    // Unknown tag.
+    assert!(from_bytes(b"BFIR\x03\x63").is_err());
+    // Truncated increment.
+    assert!(from_bytes(b"BFIR\x03\x00\x01").is_err());
+    // Unbalanced loops.
+    assert!(from_bytes(b"BFIR\x03\x04\x00").is_err());
+    assert!(from_bytes(b"BFIR\x03\x05").is_err());
+    // A pointer increment by a varint with more than 64 bits.
+    assert!(from_bytes(b"BFIR\x03\x01\xff\xff\xff\xff\xff\xff\xff\xff\xff\x03\x00").is_err());
+}
+
+#[test]
+fn from_bytes_deep_nesting() {
+    let nested = |depth| {
+        let mut bytes = b"BFIR\x01".to_vec();
+        for _ in 0..depth {
+            bytes.extend_from_slice(&[TAG_LOOP_START, 0]);
+        }
+        bytes.resize(bytes.len() + depth, TAG_LOOP_END);
+        bytes
+    };
+
+    assert!(from_bytes(&nested(DEFAULT_MAX_NESTING)).is_ok());
+    assert!(from_bytes(&nested(DEFAULT_MAX_NESTING + 1)).is_err());
+    // This used to overflow the stack when dropping the loops.
+    assert!(from_bytes(&nested(1_000_000)).is_err());
+}
+
+#[test]
+fn to_bytes_backwards_position() {
+    let instrs = vec![Write { position: Some(Position { start: 2, end: 1 }) }];
+    assert!(to_bytes(&instrs).is_err());
+}
+
+#[test]
+fn saved_state_roundtrip() {
+    use bytecode::{compile_to_bytecode, run_steps};
+
+    let bytecode = compile_to_bytecode(&parse("++>+++[-]<").unwrap());
+    let mut state = SavedState::default();
+    run_steps(&bytecode, &mut state, &mut &b""[..], &mut vec![], 6).unwrap();
+
+    let bytes = state_to_bytes(&state);
+    // Only the first two cells are stored.
+    assert_eq!(&bytes[bytes.len() - 3..], [2, 2, 3]);
+    assert_eq!(state_from_bytes(&bytes), Ok(state));
+}
+
+#[test]
+fn state_from_bytes_errors() {
+    assert!(state_from_bytes(b"BFIR\x01").is_err());
+    assert!(state_from_bytes(b"BFST\x02\x00\x00\x00\x00").is_err());
+    assert!(state_from_bytes(b"BFST\x01\x00\x00").is_err());
+    assert_eq!(state_from_bytes(b"BFST\x01\x00\x00\x02\x00"),
+               Ok(SavedState {
+                   cells: vec![0, 0],
+                   cell_ptr: 0,
+                   pc: 0,
+               }));
+    // More cells stored than the tape holds.
+    assert!(state_from_bytes(b"BFST\x01\x00\x00\x01\x02\x01\x01").is_err());
+    // Fewer cells than we said we'd store.
+    assert!(state_from_bytes(b"BFST\x01\x00\x00\x02\x02\x01").is_err());
+    // A tape of 2^63 - 1 cells, which we can't allocate.
+    assert!(state_from_bytes(b"BFST\x01\x00\x00\xff\xff\xff\xff\xff\xff\xff\xff\x7f\x00")
+        .is_err());
+}
@@ -5,12 +5,12 @@
 #[cfg(test)]
 use quickcheck::quickcheck;
 #[cfg(test)]
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 #[cfg(test)]
 use std::num::Wrapping;
 
-use std::ops::Add;
-use std::cmp::{Ord, Ordering, max};
+use core::ops::Add;
+use core::cmp::{Ord, Ordering, max};
 
 use bfir::AstNode;
 use bfir::AstNode::*;
@@ -24,18 +24,28 @@ pub const MAX_CELL_INDEX: usize = 99999;
 /// Return the highest cell index that can be reached during program
 /// execution. Zero-indexed.
 pub fn highest_cell_index(instrs: &[AstNode]) -> usize {
+    match max_pointer_offset(instrs) {
+        Some(x) if x <= MAX_CELL_INDEX => x,
+        // TODO: generate a warning when we exceed MAX_CELL_INDEX.
+        _ => MAX_CELL_INDEX,
+    }
+}
+
+/// Return the highest offset from the initial cell that the program
+/// can reach, or None if we can't bound it.
+///
+/// This is conservative: a loop with net positive pointer movement
+/// (e.g. `[>]`) could run any number of times, so we return None. A
+/// loop with net negative movement may run zero times, so we assume
+/// it doesn't move the pointer at all.
+pub fn max_pointer_offset(instrs: &[AstNode]) -> Option<usize> {
     let (highest_index, _) = overall_movement(instrs);
 
     match highest_index {
-        SaturatingInt::Number(x) => {
-            if x > MAX_CELL_INDEX as i64 {
-                // TODO: generate a warning here.
-                MAX_CELL_INDEX
-            } else {
-                x as usize
-            }
-        }
-        SaturatingInt::Max => MAX_CELL_INDEX,
+        // We start at cell zero, so the highest index is never
+        // negative.
+        SaturatingInt::Number(x) => Some(x as usize),
+        SaturatingInt::Max => None,
     }
 }
 
@@ -111,6 +121,14 @@ fn movement(instr: &AstNode) -> (SaturatingInt, SaturatingInt) {
             }
             (SaturatingInt::Number(highest_affected as i64), SaturatingInt::Number(0))
         }
+        Move { offset, .. } => {
+            (SaturatingInt::Number(max(offset, 0) as i64), SaturatingInt::Number(0))
+        }
+        MultiplyCopy { ref changes, temp, .. } => {
+            let highest_affected = changes.keys()
+                .fold(max(temp, 0), |highest, &cell| max(highest, cell));
+            (SaturatingInt::Number(highest_affected as i64), SaturatingInt::Number(0))
+        }
         Loop { ref body, .. } => {
             let (max_in_body, net_in_body) = overall_movement(body);
 
@@ -135,7 +153,19 @@ fn movement(instr: &AstNode) -> (SaturatingInt, SaturatingInt) {
                 }
             }
         }
-        Read { .. } | Write { .. } => (SaturatingInt::Number(0), SaturatingInt::Number(0)),
+        ScanZero { step, .. } => {
+            if step < 0 {
+                // As with loops, assume a scan leftwards may not
+                // move at all.
+                (SaturatingInt::Number(0), SaturatingInt::Number(0))
+            } else {
+                // We can't know how far right a scan goes.
+                (SaturatingInt::Max, SaturatingInt::Max)
+            }
+        }
+        Read { .. } | Write { .. } | Breakpoint { .. } => {
+            (SaturatingInt::Number(0), SaturatingInt::Number(0))
+        }
     }
 }
 
@@ -171,7 +201,7 @@ fn multiple_ptr_increment_bounds() {
 
 #[test]
 fn multiply_move_bounds() {
-    let mut dest_cells = HashMap::new();
+    let mut dest_cells = BTreeMap::new();
     dest_cells.insert(1, Wrapping(3));
     dest_cells.insert(4, Wrapping(1));
     let instrs = vec![MultiplyMove {
@@ -193,7 +223,7 @@ fn multiply_move_bounds() {
 /// Verify we add to the current pointer value.
 #[test]
 fn multiply_move_bounds_are_relative() {
-    let mut dest_cells = HashMap::new();
+    let mut dest_cells = BTreeMap::new();
     dest_cells.insert(1, Wrapping(5));
     let instrs = vec![// Move to cell #2.
                       PointerIncrement {
@@ -211,7 +241,7 @@ fn multiply_move_bounds_are_relative() {
 
 #[test]
 fn multiply_move_backwards_bounds() {
-    let mut dest_cells = HashMap::new();
+    let mut dest_cells = BTreeMap::new();
     dest_cells.insert(-1, Wrapping(2));
     let instrs = vec![PointerIncrement {
                           amount: 1,
@@ -292,3 +322,40 @@ fn set_offset_bounds() {
                   }];
     assert_eq!(highest_cell_index(&instrs), 11);
 }
+
+#[test]
+fn max_pointer_offset_straight_line() {
+    let instrs = parse(">>><").unwrap();
+    assert_eq!(max_pointer_offset(&instrs), Some(3));
+}
+
+#[test]
+fn max_pointer_offset_balanced_loop() {
+    let instrs = parse(">[>>+<<-]").unwrap();
+    assert_eq!(max_pointer_offset(&instrs), Some(3));
+}
+
+#[test]
+fn max_pointer_offset_unbounded_loop() {
+    let instrs = parse("[>]").unwrap();
+    assert_eq!(max_pointer_offset(&instrs), None);
+}
+
+#[test]
+fn max_pointer_offset_negative_loop() {
+    let instrs = parse(">[<]").unwrap();
+    assert_eq!(max_pointer_offset(&instrs), Some(1));
+}
+
+#[test]
+fn highest_cell_index_is_capped() {
+    let instrs = parse("[>]").unwrap();
+    assert_eq!(highest_cell_index(&instrs), MAX_CELL_INDEX);
+
+    let instrs = vec![PointerIncrement {
+                          amount: MAX_CELL_INDEX as isize + 10,
+                          position: None,
+                      }];
+    assert_eq!(max_pointer_offset(&instrs), Some(MAX_CELL_INDEX + 10));
+    assert_eq!(highest_cell_index(&instrs), MAX_CELL_INDEX);
+}
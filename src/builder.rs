@@ -0,0 +1,180 @@
+//! Build BF IR programmatically, for code generators and tests.
+//!
+//! Writing out `AstNode` values by hand is verbose and easy to get
+//! subtly wrong, so `ProgramBuilder` offers one method per BF
+//! command, and checks the result when building.
+//!
+//! For example, to add two cells and print the result:
+//!
+//! ```
+//! use bfc::builder::ProgramBuilder;
+//! use bfc::interpreter::interpret;
+//!
+//! let instrs = ProgramBuilder::new()
+//!     .increment(2)
+//!     .pointer(1)
+//!     .increment(3)
+//!     .loop_(|b| b.increment(-1).pointer(-1).increment(1).pointer(1))
+//!     .pointer(-1)
+//!     .write()
+//!     .build()
+//!     .unwrap();
+//!
+//! let mut output = vec![];
+//! interpret(&instrs, &mut &b""[..], &mut output).unwrap();
+//! assert_eq!(output, vec![5]);
+//! ```
+
+use std::fmt;
+use std::num::Wrapping;
+
+use bfir::{AstNode, DEFAULT_MAX_NESTING};
+use bfir::AstNode::*;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildError {
+    pub message: String,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Accumulates instructions, in the order the methods are called.
+#[derive(Debug, Clone, Default)]
+pub struct ProgramBuilder {
+    instrs: Vec<AstNode>,
+    depth: usize,
+    error: Option<BuildError>,
+}
+
+impl ProgramBuilder {
+    pub fn new() -> Self {
+        ProgramBuilder::default()
+    }
+
+    fn push(mut self, instr: AstNode) -> Self {
+        self.instrs.push(instr);
+        self
+    }
+
+    /// Add `amount` to the current cell, like a run of `+` or `-`.
+    pub fn increment(self, amount: i8) -> Self {
+        self.push(Increment {
+            amount: Wrapping(amount),
+            offset: 0,
+            position: None,
+        })
+    }
+
+    /// Move the cell pointer by `amount`, like a run of `>` or `<`.
+    pub fn pointer(self, amount: isize) -> Self {
+        self.push(PointerIncrement {
+            amount: amount,
+            position: None,
+        })
+    }
+
+    pub fn read(self) -> Self {
+        self.push(Read { position: None })
+    }
+
+    pub fn write(self) -> Self {
+        self.push(Write { position: None })
+    }
+
+    /// Add a loop, whose body is built by `f`.
+    pub fn loop_<F>(mut self, f: F) -> Self
+        where F: FnOnce(ProgramBuilder) -> ProgramBuilder
+    {
+        if self.error.is_some() {
+            return self;
+        }
+
+        let body_builder = f(ProgramBuilder {
+            instrs: vec![],
+            depth: self.depth + 1,
+            error: None,
+        });
+
+        if body_builder.error.is_some() {
+            self.error = body_builder.error;
+        } else if body_builder.depth > DEFAULT_MAX_NESTING {
+            self.error = Some(BuildError {
+                message: format!("Loops are nested more than {} deep.", DEFAULT_MAX_NESTING),
+            });
+        } else if body_builder.instrs.is_empty() {
+            // `[]` is a valid program, but it never terminates when
+            // entered, which is rarely what a caller meant.
+            self.error = Some(BuildError {
+                message: "Loop has an empty body, so it never terminates if entered \
+                          (use `increment(-1)` in the body to clear a cell)."
+                    .to_owned(),
+            });
+        } else {
+            self.instrs.push(Loop {
+                body: body_builder.instrs,
+                position: None,
+            });
+        }
+        self
+    }
+
+    /// Return the instructions built, or the first problem found.
+    pub fn build(self) -> Result<Vec<AstNode>, BuildError> {
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(self.instrs),
+        }
+    }
+}
+
+#[cfg(test)]
+use bfir::to_source;
+
+#[test]
+fn build_matches_parse() {
+    let instrs = ProgramBuilder::new()
+        .read()
+        .loop_(|b| b.increment(-1).pointer(1).increment(1).pointer(-1))
+        .pointer(1)
+        .write()
+        .build()
+        .unwrap();
+    assert_eq!(to_source(&instrs), ",[->+<]>.");
+}
+
+#[test]
+fn build_nested_loops() {
+    let instrs = ProgramBuilder::new()
+        .loop_(|b| b.loop_(|b| b.increment(-1)).pointer(1))
+        .build()
+        .unwrap();
+    assert_eq!(to_source(&instrs), "[[-]>]");
+}
+
+#[test]
+fn build_rejects_empty_loop() {
+    let result = ProgramBuilder::new()
+        .increment(1)
+        .loop_(|b| b.loop_(|b| b))
+        .write()
+        .build();
+    assert!(result.is_err());
+}
+
+#[test]
+fn build_rejects_deep_nesting() {
+    fn nest(b: ProgramBuilder, depth: usize) -> ProgramBuilder {
+        if depth == 0 {
+            b.increment(1)
+        } else {
+            b.loop_(|b| nest(b, depth - 1))
+        }
+    }
+
+    assert!(nest(ProgramBuilder::new(), DEFAULT_MAX_NESTING).build().is_ok());
+    assert!(nest(ProgramBuilder::new(), DEFAULT_MAX_NESTING + 1).build().is_err());
+}
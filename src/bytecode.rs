@@ -0,0 +1,504 @@
+//! Flatten a BF AST into bytecode with precomputed jump targets, and
+//! execute it.
+//!
+//! This is much faster than `interpreter` on loop-heavy programs, as
+//! we don't recurse into loop bodies or look up matching brackets at
+//! runtime. It only supports the default interpreter behaviour: a
+//! fixed tape of 8-bit cells that wrap, with 0 on EOF. Use
+//! `interpreter` for anything else, or for debugging.
+//!
+//! Since the program counter is a single index, it's also easy to
+//! pause: `run_steps` stops after a given number of ops, and `resume`
+//! continues from the `SavedState` it left.
+//!
+//! Without the `std` feature, `run_on_tape` runs bytecode on a tape
+//! provided by the caller.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
+use std::io;
+
+use bfir::{AstNode, Cell, Position, get_position};
+use bfir::AstNode::*;
+
+use bounds::MAX_CELL_INDEX;
+use interpreter::ExecutionError;
+
+#[cfg(test)]
+use bfir::parse;
+
+/// A single bytecode operation. Cell amounts are unsigned, as cells
+/// are bytes that wrap around.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Add { amount: u8, offset: isize },
+    Set { amount: u8, offset: isize },
+    MovePointer(isize),
+    /// Add the current cell times each factor to the cell at each
+    /// offset, then zero the current cell. The changes are sorted by
+    /// offset.
+    MultiplyMove(Vec<(isize, u8)>),
+    /// Add the current cell to the cell at this offset, then zero the
+    /// current cell.
+    Move(isize),
+    /// As `MultiplyMove`, but leave the current cell unchanged.
+    MultiplyCopy(Vec<(isize, u8)>),
+    Read,
+    Write,
+    ScanZero(isize),
+    /// Jump to the op at this index if the current cell is zero.
+    JumpIfZero(usize),
+    /// Jump to the op at this index if the current cell is nonzero.
+    JumpIfNonZero(usize),
+}
+
+/// A flat program, ready for `run_bytecode`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bytecode {
+    pub ops: Vec<Op>,
+    /// The source position of each op, for error messages.
+    pub positions: Vec<Option<Position>>,
+}
+
+fn cell_value(amount: Cell) -> u8 {
+    amount.0 as u8
+}
+
+/// The changes of a multiply instruction as bytes, in offset order.
+fn byte_changes(changes: &BTreeMap<isize, Cell>) -> Vec<(isize, u8)> {
+    changes.iter()
+        .map(|(offset, factor)| (*offset, cell_value(*factor)))
+        .collect()
+}
+
+fn compile_instrs(instrs: &[AstNode], bytecode: &mut Bytecode) {
+    for instr in instrs {
+        let position = get_position(instr);
+        let op = match *instr {
+            Increment { amount, offset, .. } => {
+                Op::Add {
+                    amount: cell_value(amount),
+                    offset: offset,
+                }
+            }
+            Set { amount, offset, .. } => {
+                Op::Set {
+                    amount: cell_value(amount),
+                    offset: offset,
+                }
+            }
+            PointerIncrement { amount, .. } => Op::MovePointer(amount),
+            MultiplyMove { ref changes, .. } => Op::MultiplyMove(byte_changes(changes)),
+            Move { offset, .. } => Op::Move(offset),
+            MultiplyCopy { ref changes, .. } => Op::MultiplyCopy(byte_changes(changes)),
+            Read { .. } => Op::Read,
+            Write { .. } => Op::Write,
+            ScanZero { step, .. } => Op::ScanZero(step),
+            // There's no debugger to stop for.
+            Breakpoint { .. } => continue,
+            Loop { ref body, .. } => {
+                let start = bytecode.ops.len();
+                // We fill in the target once we know where the loop
+                // ends.
+                bytecode.ops.push(Op::JumpIfZero(0));
+                bytecode.positions.push(position);
+
+                compile_instrs(body, bytecode);
+
+                let end = bytecode.ops.len();
+                bytecode.ops[start] = Op::JumpIfZero(end + 1);
+                Op::JumpIfNonZero(start + 1)
+            }
+        };
+        bytecode.ops.push(op);
+        bytecode.positions.push(position);
+    }
+}
+
+/// Convert the instructions given to bytecode. Loops become a
+/// `JumpIfZero` past the end of the loop, the body, then a
+/// `JumpIfNonZero` back to the start of the body.
+pub fn compile_to_bytecode(instrs: &[AstNode]) -> Bytecode {
+    let mut bytecode = Bytecode {
+        ops: vec![],
+        positions: vec![],
+    };
+    compile_instrs(instrs, &mut bytecode);
+    bytecode
+}
+
+/// Return the index of the cell at `offset` from `cell_ptr`, or an
+/// error if it's not on a tape of `num_cells` cells.
+fn cell_index(cell_ptr: usize,
+              offset: isize,
+              num_cells: usize,
+              position: Option<Position>)
+              -> Result<usize, ExecutionError> {
+    let index = cell_ptr as isize + offset;
+    if index < 0 || index >= num_cells as isize {
+        return Err(ExecutionError::PointerOutOfBounds {
+            cell_index: index,
+            position: position,
+        });
+    }
+    Ok(index as usize)
+}
+
+/// Everything needed to continue running bytecode: the tape, the cell
+/// pointer, and the index of the next op. As bytecode is flat, the
+/// position in nested loops is just `pc`. The fields are plain data,
+/// so debuggers can inspect, store or edit a state between runs, and
+/// `binary::state_to_bytes` can save it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SavedState {
+    /// The tape. This may be any length, but the pointer can't leave
+    /// it.
+    pub cells: Vec<u8>,
+    pub cell_ptr: usize,
+    pub pc: usize,
+}
+
+impl Default for SavedState {
+    /// The state before running any ops: a zeroed tape with the
+    /// pointer on the first cell.
+    fn default() -> Self {
+        SavedState {
+            cells: vec![0; MAX_CELL_INDEX + 1],
+            cell_ptr: 0,
+            pc: 0,
+        }
+    }
+}
+
+impl SavedState {
+    /// Have we run every op in `bytecode`?
+    pub fn is_finished(&self, bytecode: &Bytecode) -> bool {
+        self.pc >= bytecode.ops.len()
+    }
+}
+
+/// Run `bytecode`, reading from `input` and writing to `output`. This
+/// behaves exactly like `interpreter::interpret` on the original
+/// instructions, except that breakpoints are ignored.
+#[cfg(feature = "std")]
+pub fn run_bytecode<R: io::Read, W: io::Write>(bytecode: &Bytecode,
+                                               input: &mut R,
+                                               output: &mut W)
+                                               -> Result<(), ExecutionError> {
+    resume(bytecode, &mut SavedState::default(), input, output)
+}
+
+/// Run `bytecode` from `state` until the program finishes, updating
+/// `state` as we go.
+#[cfg(feature = "std")]
+pub fn resume<R: io::Read, W: io::Write>(bytecode: &Bytecode,
+                                         state: &mut SavedState,
+                                         input: &mut R,
+                                         output: &mut W)
+                                         -> Result<(), ExecutionError> {
+    execute_io(bytecode, state, input, output, None)
+}
+
+/// Run at most `max_steps` ops of `bytecode` from `state`, updating
+/// `state` so a later `run_steps` or `resume` carries on from there.
+/// Each op, including each jump, is one step.
+#[cfg(feature = "std")]
+pub fn run_steps<R: io::Read, W: io::Write>(bytecode: &Bytecode,
+                                            state: &mut SavedState,
+                                            input: &mut R,
+                                            output: &mut W,
+                                            max_steps: u64)
+                                            -> Result<(), ExecutionError> {
+    execute_io(bytecode, state, input, output, Some(max_steps))
+}
+
+/// Run `bytecode` on `cells`, starting at the first cell, reading
+/// from `input` and passing each byte written to `output`. We read 0
+/// once `input` is exhausted. This doesn't need std, so embedded
+/// targets can provide a tape of whatever size they can spare.
+pub fn run_on_tape<F>(bytecode: &Bytecode,
+                      cells: &mut [u8],
+                      input: &[u8],
+                      mut output: F)
+                      -> Result<(), ExecutionError>
+    where F: FnMut(u8)
+{
+    let mut input = input.iter();
+    execute(bytecode,
+            cells,
+            &mut 0,
+            &mut 0,
+            || Ok(input.next().cloned().unwrap_or(0)),
+            |value| {
+                output(value);
+                Ok(())
+            },
+            None)
+}
+
+/// Run ops from `state` as `execute`, performing I/O on `input` and
+/// `output`.
+#[cfg(feature = "std")]
+fn execute_io<R: io::Read, W: io::Write>(bytecode: &Bytecode,
+                                         state: &mut SavedState,
+                                         input: &mut R,
+                                         output: &mut W,
+                                         max_steps: Option<u64>)
+                                         -> Result<(), ExecutionError> {
+    let read = || {
+        let mut buf = [0];
+        match input.read(&mut buf) {
+            Ok(0) => Ok(0),
+            Ok(_) => Ok(buf[0]),
+            Err(e) => Err(ExecutionError::Io(format!("{}", e))),
+        }
+    };
+    let write = |value| {
+        output.write_all(&[value]).map_err(|e| ExecutionError::Io(format!("{}", e)))
+    };
+    execute(bytecode,
+            &mut state.cells,
+            &mut state.cell_ptr,
+            &mut state.pc,
+            read,
+            write,
+            max_steps)
+}
+
+/// Run ops from `pc` until we finish, or we've run `max_steps`
+/// ops. If an op fails, `pc` is left at that op.
+fn execute<R, W>(bytecode: &Bytecode,
+                 cells: &mut [u8],
+                 cell_ptr: &mut usize,
+                 pc: &mut usize,
+                 mut read: R,
+                 mut write: W,
+                 max_steps: Option<u64>)
+                 -> Result<(), ExecutionError>
+    where R: FnMut() -> Result<u8, ExecutionError>,
+          W: FnMut(u8) -> Result<(), ExecutionError>
+{
+    let ops = &bytecode.ops;
+    let num_cells = cells.len();
+    let mut steps = 0;
+
+    // The state may have been edited, or saved from other bytecode.
+    if *pc > ops.len() {
+        return Err(ExecutionError::InvalidState(format!("There is no op {} to resume from.",
+                                                        *pc)));
+    }
+    if *cell_ptr >= num_cells {
+        return Err(ExecutionError::PointerOutOfBounds {
+            cell_index: *cell_ptr as isize,
+            position: None,
+        });
+    }
+
+    while *pc < ops.len() {
+        if Some(steps) == max_steps {
+            break;
+        }
+        steps += 1;
+
+        let current = *cell_ptr;
+        let position = bytecode.positions.get(*pc).cloned().unwrap_or(None);
+        match ops[*pc] {
+            Op::Add { amount, offset } => {
+                let index = cell_index(current, offset, num_cells, position)?;
+                cells[index] = cells[index].wrapping_add(amount);
+            }
+            Op::Set { amount, offset } => {
+                let index = cell_index(current, offset, num_cells, position)?;
+                cells[index] = amount;
+            }
+            Op::MovePointer(amount) => {
+                *cell_ptr = cell_index(current, amount, num_cells, position)?;
+            }
+            Op::MultiplyMove(ref changes) |
+            Op::MultiplyCopy(ref changes) => {
+                let cell_value = cells[current];
+                if cell_value != 0 {
+                    for &(offset, factor) in changes {
+                        let index = cell_index(current, offset, num_cells, position)?;
+                        cells[index] = cells[index].wrapping_add(cell_value.wrapping_mul(factor));
+                    }
+                    if let Op::MultiplyMove(_) = ops[*pc] {
+                        cells[current] = 0;
+                    }
+                }
+            }
+            Op::Move(offset) => {
+                let cell_value = cells[current];
+                if cell_value != 0 {
+                    let index = cell_index(current, offset, num_cells, position)?;
+                    cells[index] = cells[index].wrapping_add(cell_value);
+                    cells[current] = 0;
+                }
+            }
+            Op::Read => {
+                cells[current] = read()?;
+            }
+            Op::Write => {
+                write(cells[current])?;
+            }
+            Op::ScanZero(step) => {
+                while cells[*cell_ptr] != 0 {
+                    *cell_ptr = cell_index(*cell_ptr, step, num_cells, position)?;
+                }
+            }
+            Op::JumpIfZero(target) => {
+                if cells[current] == 0 {
+                    *pc = target;
+                    continue;
+                }
+            }
+            Op::JumpIfNonZero(target) => {
+                if cells[current] != 0 {
+                    *pc = target;
+                    continue;
+                }
+            }
+        }
+        *pc += 1;
+    }
+    Ok(())
+}
+
+#[test]
+fn compile_to_bytecode_resolves_jumps() {
+    let bytecode = compile_to_bytecode(&parse("+[-[>]]").unwrap());
+    assert_eq!(bytecode.ops,
+               vec![Op::Add {
+                        amount: 1,
+                        offset: 0,
+                    },
+                    Op::JumpIfZero(7),
+                    Op::Add {
+                        amount: 255,
+                        offset: 0,
+                    },
+                    Op::JumpIfZero(6),
+                    Op::MovePointer(1),
+                    Op::JumpIfNonZero(4),
+                    Op::JumpIfNonZero(2)]);
+    assert_eq!(bytecode.positions.len(), bytecode.ops.len());
+}
+
+#[test]
+fn run_bytecode_matches_interpreter() {
+    use interpreter::interpret;
+    use peephole::optimize;
+    use testing::FIXTURES;
+
+    for &(name, source, input) in FIXTURES {
+        let unoptimized = parse(source).unwrap();
+        let optimized = optimize(unoptimized.clone(), &None).0;
+        for instrs in &[unoptimized, optimized] {
+            let mut expected = vec![];
+            interpret(instrs, &mut &input[..], &mut expected).unwrap();
+
+            let mut output = vec![];
+            run_bytecode(&compile_to_bytecode(instrs), &mut &input[..], &mut output).unwrap();
+            assert_eq!(output, expected, "{} output differs", name);
+        }
+    }
+}
+
+#[test]
+fn run_bytecode_eof_is_zero() {
+    let bytecode = compile_to_bytecode(&parse("+,.").unwrap());
+    let mut output = vec![];
+    run_bytecode(&bytecode, &mut &b""[..], &mut output).unwrap();
+    assert_eq!(output, [0]);
+}
+
+#[test]
+fn run_on_tape_cat() {
+    let bytecode = compile_to_bytecode(&parse(",[.,]").unwrap());
+    let mut cells = [0; 4];
+    let mut output = vec![];
+    run_on_tape(&bytecode, &mut cells, b"abc", |value| output.push(value)).unwrap();
+    assert_eq!(output, b"abc");
+}
+
+#[test]
+fn run_on_tape_small_tape() {
+    let bytecode = compile_to_bytecode(&parse(">>").unwrap());
+    let mut cells = [0; 2];
+    let result = run_on_tape(&bytecode, &mut cells, b"", |_| {});
+    assert_eq!(result,
+               Err(ExecutionError::PointerOutOfBounds {
+                   cell_index: 2,
+                   position: Some(Position { start: 1, end: 1 }),
+               }));
+}
+
+#[test]
+fn run_bytecode_out_of_bounds() {
+    let bytecode = compile_to_bytecode(&parse("+[<]").unwrap());
+    let result = run_bytecode(&bytecode, &mut &b""[..], &mut vec![]);
+    assert_eq!(result,
+               Err(ExecutionError::PointerOutOfBounds {
+                   cell_index: -1,
+                   position: Some(Position { start: 2, end: 2 }),
+               }));
+}
+
+#[test]
+fn run_steps_then_resume() {
+    let bytecode = compile_to_bytecode(&parse(include_str!("../sample_programs/hello_world.bf"))
+        .unwrap());
+    let mut expected = vec![];
+    run_bytecode(&bytecode, &mut &b""[..], &mut expected).unwrap();
+
+    let mut state = SavedState::default();
+    let mut output = vec![];
+    run_steps(&bytecode, &mut state, &mut &b""[..], &mut output, 5).unwrap();
+    assert_eq!(state.pc, 5);
+    assert!(!state.is_finished(&bytecode));
+
+    let mut snapshot = state.clone();
+    resume(&bytecode, &mut snapshot, &mut &b""[..], &mut output).unwrap();
+    assert!(snapshot.is_finished(&bytecode));
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn resume_small_tape() {
+    let bytecode = compile_to_bytecode(&parse("+>+>+").unwrap());
+    let mut state = SavedState { cells: vec![0; 2], ..SavedState::default() };
+    assert_eq!(resume(&bytecode, &mut state, &mut &b""[..], &mut vec![]),
+               Err(ExecutionError::PointerOutOfBounds {
+                   cell_index: 2,
+                   position: Some(Position { start: 3, end: 3 }),
+               }));
+    assert_eq!(state.cells, [1, 1]);
+}
+
+#[test]
+fn resume_invalid_state() {
+    let bytecode = compile_to_bytecode(&parse("+").unwrap());
+
+    let mut state = SavedState { cells: vec![], ..SavedState::default() };
+    assert!(matches!(resume(&bytecode, &mut state, &mut &b""[..], &mut vec![]),
+                     Err(ExecutionError::PointerOutOfBounds { cell_index: 0, .. })));
+
+    let mut state = SavedState { pc: 2, ..SavedState::default() };
+    assert!(matches!(resume(&bytecode, &mut state, &mut &b""[..], &mut vec![]),
+                     Err(ExecutionError::InvalidState(_))));
+}
+
+#[test]
+fn run_steps_counts_jumps() {
+    // Two adds, the jump into the loop, then the body and the jump
+    // back for each iteration.
+    let bytecode = compile_to_bytecode(&parse("++[-]").unwrap());
+    let mut state = SavedState::default();
+    run_steps(&bytecode, &mut state, &mut &b""[..], &mut vec![], 6).unwrap();
+    // We've zeroed the cell, but not yet run the final jump.
+    assert_eq!((state.cells[0], state.pc), (0, 4));
+    run_steps(&bytecode, &mut state, &mut &b""[..], &mut vec![], 1).unwrap();
+    assert!(state.is_finished(&bytecode));
+}
@@ -0,0 +1,396 @@
+//! The C module converts a BF AST to C source code, so programs can
+//! be compiled with any C compiler.
+
+use std::cmp::max;
+use std::ops::Range;
+
+use bfir::{AstNode, Cell, SourceMap, source_map_from_offsets, source_span, write_run_length};
+use bfir::AstNode::*;
+
+use bounds::highest_cell_index;
+
+#[cfg(test)]
+use bfir::parse;
+
+fn push_indent(indent: usize, out: &mut String) {
+    for _ in 0..indent {
+        out.push_str("    ");
+    }
+}
+
+/// The C expression for the cell at `offset` from the cell pointer.
+fn cell_at(offset: isize) -> String {
+    if offset == 0 {
+        "*p".to_owned()
+    } else {
+        format!("p[{}]", offset)
+    }
+}
+
+/// A C statement that adds `amount` to `target`, using -= for
+/// negative amounts so the output is easier to read.
+fn add_statement(target: &str, amount: isize) -> String {
+    if amount < 0 {
+        format!("{} -= {};", target, -amount)
+    } else {
+        format!("{} += {};", target, amount)
+    }
+}
+
+/// The C type of each cell. Cells are unsigned, so arithmetic always
+/// wraps around.
+///
+/// Note that the optimiser assumes 8-bit cells, so wider cells
+/// should only be used with unoptimised instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellType {
+    UnsignedChar,
+    UnsignedShort,
+    /// We assume that an unsigned int is 32 bits.
+    UnsignedInt,
+}
+
+impl CellType {
+    fn c_name(self) -> &'static str {
+        match self {
+            CellType::UnsignedChar => "unsigned char",
+            CellType::UnsignedShort => "unsigned short",
+            CellType::UnsignedInt => "unsigned int",
+        }
+    }
+
+    /// Convert an IR cell value to a constant of this type, treating
+    /// it as signed so negative amounts wrap correctly.
+    fn value(self, amount: Cell) -> u32 {
+        let value = amount.0 as i32 as u32;
+        match self {
+            CellType::UnsignedChar => value & 0xFF,
+            CellType::UnsignedShort => value & 0xFFFF,
+            CellType::UnsignedInt => value,
+        }
+    }
+}
+
+/// Settings for the generated C program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodegenOptions {
+    /// The number of cells on the tape. If None, we only allocate as
+    /// many cells as the program needs, just like the LLVM backend. We
+    /// never allocate fewer cells than the program needs, so a smaller
+    /// size is ignored.
+    pub tape_size: Option<usize>,
+    pub cell_type: CellType,
+}
+
+impl Default for CodegenOptions {
+    fn default() -> Self {
+        CodegenOptions {
+            tape_size: None,
+            cell_type: CellType::UnsignedChar,
+        }
+    }
+}
+
+/// Where each instruction's code starts in the output, and the source
+/// it came from, if we're building a source map.
+type Offsets<'a> = Option<&'a mut Vec<(usize, Range<usize>)>>;
+
+fn compile_instr(instr: &AstNode,
+                 indent: usize,
+                 cell_type: CellType,
+                 out: &mut String,
+                 offsets: &mut Offsets) {
+    push_indent(indent, out);
+
+    match *instr {
+        Increment { amount, offset, .. } => {
+            out.push_str(&add_statement(&cell_at(offset), amount.0 as isize));
+        }
+        PointerIncrement { amount, .. } => {
+            out.push_str(&add_statement("p", amount));
+        }
+        Set { amount, offset, .. } => {
+            out.push_str(&format!("{} = {};", cell_at(offset), cell_type.value(amount)));
+        }
+        MultiplyMove { ref changes, .. } |
+        MultiplyCopy { ref changes, .. } => {
+            out.push_str("if (*p) {\n");
+            for (offset, factor) in changes {
+                push_indent(indent + 1, out);
+                // Moving a value, as in [->+<], needs no multiplication.
+                let change = if factor.0 == 1 {
+                    "*p".to_owned()
+                } else {
+                    format!("*p * {}", cell_type.value(*factor))
+                };
+                out.push_str(&format!("{} += {};\n", cell_at(*offset), change));
+            }
+            if let MultiplyMove { .. } = *instr {
+                push_indent(indent + 1, out);
+                out.push_str("*p = 0;\n");
+            }
+            push_indent(indent, out);
+            out.push('}');
+        }
+        Move { offset, .. } => {
+            out.push_str("if (*p) {\n");
+            push_indent(indent + 1, out);
+            out.push_str(&format!("{} += *p;\n", cell_at(offset)));
+            push_indent(indent + 1, out);
+            out.push_str("*p = 0;\n");
+            push_indent(indent, out);
+            out.push('}');
+        }
+        Read { .. } => {
+            out.push_str("*p = getchar();");
+        }
+        Write { .. } => {
+            out.push_str("putchar(*p);");
+        }
+        ScanZero { step, .. } => {
+            out.push_str(&format!("while (*p) {}", add_statement("p", step)));
+        }
+        Breakpoint { .. } => {
+            out.push_str("/* Breakpoint */");
+        }
+        Loop { ref body, .. } => {
+            out.push_str("while (*p) {\n");
+            compile_instrs(body, indent + 1, cell_type, out, offsets);
+            push_indent(indent, out);
+            out.push('}');
+        }
+    }
+
+    out.push('\n');
+}
+
+/// Write the current cell `count` times with a single call, rather
+/// than calling putchar for each byte.
+fn compile_write_run(count: usize, indent: usize, out: &mut String) {
+    push_indent(indent, out);
+    out.push_str("{\n");
+    for line in &[format!("unsigned char run[{}];", count),
+                  format!("memset(run, *p, {});", count),
+                  format!("fwrite(run, 1, {}, stdout);", count)] {
+        push_indent(indent + 1, out);
+        out.push_str(line);
+        out.push('\n');
+    }
+    push_indent(indent, out);
+    out.push_str("}\n");
+}
+
+fn compile_instrs(instrs: &[AstNode],
+                  indent: usize,
+                  cell_type: CellType,
+                  out: &mut String,
+                  offsets: &mut Offsets) {
+    let mut i = 0;
+    while i < instrs.len() {
+        let run_length = write_run_length(&instrs[i..]);
+        let consumed = if run_length > 1 { run_length } else { 1 };
+
+        if let Some(ref mut offsets) = *offsets {
+            if let Some(span) = source_span(&instrs[i..i + consumed]) {
+                offsets.push((out.len(), span));
+            }
+        }
+
+        if run_length > 1 {
+            compile_write_run(run_length, indent, out);
+        } else {
+            compile_instr(&instrs[i], indent, cell_type, out, offsets);
+        }
+        i += consumed;
+    }
+}
+
+/// Convert the instructions given to a complete C program. We only
+/// allocate as many cells as the program needs, just like the LLVM
+/// backend.
+pub fn codegen_c(instrs: &[AstNode]) -> String {
+    codegen_c_with_options(instrs, &CodegenOptions::default())
+}
+
+/// Convert the instructions given to a complete C program, as
+/// `codegen_c`, with the tape specified in `options`.
+pub fn codegen_c_with_options(instrs: &[AstNode], options: &CodegenOptions) -> String {
+    codegen_c_inner(instrs, options, &mut None)
+}
+
+/// Convert the instructions given to a complete C program, as
+/// `codegen_c_with_options`, and also return which source each line
+/// came from.
+pub fn codegen_c_with_source_map(instrs: &[AstNode],
+                                 options: &CodegenOptions)
+                                 -> (String, SourceMap) {
+    let mut offsets = vec![];
+    let out = codegen_c_inner(instrs, options, &mut Some(&mut offsets));
+    let source_map = source_map_from_offsets(&out, offsets);
+    (out, source_map)
+}
+
+fn codegen_c_inner(instrs: &[AstNode], options: &CodegenOptions, offsets: &mut Offsets) -> String {
+    let needed_cells = highest_cell_index(instrs) + 1;
+    let num_cells = match options.tape_size {
+        Some(tape_size) => max(tape_size, needed_cells),
+        None => needed_cells,
+    };
+    let cell_type = options.cell_type;
+
+    let mut out = String::new();
+    out.push_str("#include <stdio.h>\n");
+    out.push_str("#include <string.h>\n\n");
+    out.push_str(&format!("{} tape[{}];\n\n", cell_type.c_name(), num_cells));
+    out.push_str("int main(void) {\n");
+    out.push_str(&format!("    {} *p = tape;\n", cell_type.c_name()));
+
+    compile_instrs(instrs, 1, cell_type, &mut out, offsets);
+
+    out.push_str("    return 0;\n");
+    out.push_str("}\n");
+    out
+}
+
+#[test]
+fn codegen_c_complex_loop() {
+    let instrs = parse(".[,+]-").unwrap();
+    let expected = "#include <stdio.h>
+#include <string.h>
+
+unsigned char tape[1];
+
+int main(void) {
+    unsigned char *p = tape;
+    putchar(*p);
+    while (*p) {
+        *p = getchar();
+        *p += 1;
+    }
+    *p -= 1;
+    return 0;
+}
+";
+    assert_eq!(codegen_c(&instrs), expected);
+}
+
+#[test]
+fn codegen_c_source_map() {
+    let instrs = parse("+\n[-]..").unwrap();
+    let (c_src, source_map) = codegen_c_with_source_map(&instrs, &CodegenOptions::default());
+    let lines: Vec<_> = c_src.lines().collect();
+
+    assert_eq!(source_map,
+               vec![(8, 0..1), (9, 2..5), (10, 3..4), (12, 5..7)]);
+    assert_eq!(lines[9 - 1], "    while (*p) {");
+    assert_eq!(lines[12 - 1], "    {");
+}
+
+#[test]
+fn codegen_c_pointer_increment() {
+    let instrs = parse("><<").unwrap();
+    let c_src = codegen_c(&instrs);
+    assert!(c_src.contains("    p += 1;\n    p -= 1;\n    p -= 1;\n"));
+}
+
+#[test]
+fn codegen_c_scan_zero_stride() {
+    let instrs = [ScanZero {
+                      step: 1,
+                      position: None,
+                  },
+                  ScanZero {
+                      step: -2,
+                      position: None,
+                  }];
+    let c_src = codegen_c(&instrs);
+    assert!(c_src.contains("    while (*p) p += 1;\n    while (*p) p -= 2;\n"));
+}
+
+#[test]
+fn codegen_c_optimised_instrs() {
+    use std::collections::BTreeMap;
+    use std::num::Wrapping;
+
+    let mut changes = BTreeMap::new();
+    changes.insert(2, Wrapping(-1));
+    changes.insert(1, Wrapping(3));
+    let instrs = [Set {
+                      amount: Wrapping(-1),
+                      offset: 1,
+                      position: None,
+                  },
+                  MultiplyMove {
+                      changes: changes,
+                      position: None,
+                  }];
+
+    let expected_body = "    p[1] = 255;
+    if (*p) {
+        p[1] += *p * 3;
+        p[2] += *p * 255;
+        *p = 0;
+    }
+";
+    assert!(codegen_c(&instrs).contains(expected_body));
+}
+
+#[test]
+fn codegen_c_move() {
+    use peephole::extract_multiply;
+
+    let instrs = extract_multiply(parse("[->+<]").unwrap());
+    assert!(codegen_c(&instrs).contains("        p[1] += *p;\n        *p = 0;\n"));
+}
+
+#[test]
+fn codegen_c_write_runs() {
+    let instrs = parse("...>.[..]").unwrap();
+    let expected_body = "    {
+        unsigned char run[3];
+        memset(run, *p, 3);
+        fwrite(run, 1, 3, stdout);
+    }
+    p += 1;
+    putchar(*p);
+    while (*p) {
+        {
+            unsigned char run[2];
+            memset(run, *p, 2);
+            fwrite(run, 1, 2, stdout);
+        }
+    }
+";
+    assert!(codegen_c(&instrs).contains(expected_body));
+}
+
+#[test]
+fn codegen_c_tape_options() {
+    use std::num::Wrapping;
+
+    let instrs = [Set {
+                      amount: Wrapping(-1),
+                      offset: 0,
+                      position: None,
+                  }];
+    let options = CodegenOptions {
+        tape_size: Some(100_000),
+        cell_type: CellType::UnsignedShort,
+    };
+    let c_src = codegen_c_with_options(&instrs, &options);
+    assert!(c_src.contains("\nunsigned short tape[100000];\n"));
+    assert!(c_src.contains("    unsigned short *p = tape;\n"));
+    assert!(c_src.contains("    *p = 65535;\n"));
+
+    let options = CodegenOptions { cell_type: CellType::UnsignedInt, ..CodegenOptions::default() };
+    let c_src = codegen_c_with_options(&instrs, &options);
+    assert!(c_src.contains("\nunsigned int tape[1];\n"));
+    assert!(c_src.contains("    *p = 4294967295;\n"));
+}
+
+#[test]
+fn codegen_c_tape_too_small() {
+    let instrs = parse(">>+").unwrap();
+    let options = CodegenOptions { tape_size: Some(1), ..CodegenOptions::default() };
+    assert!(codegen_c_with_options(&instrs, &options).contains("\nunsigned char tape[3];\n"));
+}
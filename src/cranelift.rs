@@ -0,0 +1,546 @@
+//! The cranelift module compiles a BF AST to machine code in memory,
+//! so programs run at near native speed without an external
+//! toolchain. This needs the `cranelift` feature.
+//!
+//! Compiled programs behave like `interpreter::interpret` with the
+//! default options, except that the caller provides the tape. Every
+//! cell access is checked against the length of the tape, so
+//! programs can't touch memory outside it.
+
+use std::io;
+use std::mem;
+use std::num::Wrapping;
+
+use cranelift_codegen::ir::{types, AbiParam, Block, BlockArg, FuncRef, InstBuilder, MemFlags,
+                            Type, UserFuncName, Value};
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage, Module};
+
+use bfir::{AstNode, Cell, Position, get_position};
+use bfir::AstNode::*;
+
+use interpreter::ExecutionError;
+
+#[cfg(test)]
+use bfir::parse;
+
+/// Everything the functions called by compiled code need.
+struct Context<'a, 'p> {
+    input: &'a mut dyn io::Read,
+    output: &'a mut dyn io::Write,
+    /// The position of each instruction that checks the cell pointer,
+    /// so we can say where a program left the tape.
+    positions: &'p [Option<Position>],
+    /// Why the program stopped, if it didn't finish.
+    error: Option<ExecutionError>,
+}
+
+/// Read a byte into the current cell for compiled code. We return 0
+/// at EOF, or -1 if reading failed.
+extern "C" fn jit_read(context: *mut u8) -> i32 {
+    let context = unsafe { &mut *(context as *mut Context) };
+    let mut buf = [0];
+    match context.input.read(&mut buf) {
+        Ok(0) => 0,
+        Ok(_) => i32::from(buf[0]),
+        Err(e) => {
+            context.error = Some(ExecutionError::Io(format!("{}", e)));
+            -1
+        }
+    }
+}
+
+/// Write the byte `value` for compiled code. We return 0 on success,
+/// or 1 if writing failed.
+extern "C" fn jit_write(context: *mut u8, value: i32) -> i32 {
+    let context = unsafe { &mut *(context as *mut Context) };
+    match context.output.write_all(&[value as u8]) {
+        Ok(()) => 0,
+        Err(e) => {
+            context.error = Some(ExecutionError::Io(format!("{}", e)));
+            1
+        }
+    }
+}
+
+/// Record that the instruction at `site` in `Context::positions`
+/// tried to access `cell_index`, which isn't on the tape.
+extern "C" fn jit_out_of_bounds(context: *mut u8, cell_index: i64, site: i64) {
+    let context = unsafe { &mut *(context as *mut Context) };
+    context.error = Some(ExecutionError::PointerOutOfBounds {
+        cell_index: cell_index as isize,
+        position: context.positions[site as usize],
+    });
+}
+
+/// Compiled code takes the tape, its length and a `Context`, and
+/// returns 0 if the program finished or 1 if it stopped with an
+/// error.
+type CompiledFn = extern "C" fn(*mut u8, usize, *mut u8) -> i32;
+
+/// A compiled program and the memory that holds its code.
+struct Compiled {
+    module: Option<JITModule>,
+    function: CompiledFn,
+    positions: Vec<Option<Position>>,
+}
+
+impl Drop for Compiled {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            // We own the only pointer to the code, and we're done
+            // with it.
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+/// Cells are bytes, so we only need the unsigned value of each
+/// amount. Cranelift arithmetic on bytes wraps around.
+fn cell_value(amount: Cell) -> i64 {
+    i64::from(amount.0 as u8)
+}
+
+struct Compiler<'a> {
+    builder: FunctionBuilder<'a>,
+    pointer_type: Type,
+    cells: Value,
+    num_cells: Value,
+    context: Value,
+    /// The index of the current cell.
+    cell_ptr: Variable,
+    read: FuncRef,
+    write: FuncRef,
+    /// Jumped to with the cell index and the site when we leave the
+    /// tape.
+    out_of_bounds: Block,
+    /// Jumped to when I/O fails.
+    fail: Block,
+    positions: Vec<Option<Position>>,
+}
+
+impl<'a> Compiler<'a> {
+    /// Return the index of the cell at `offset` from the cell
+    /// pointer, checking that it's on the tape. The cell pointer is
+    /// always on the tape, so we don't check an offset of 0.
+    fn checked_index(&mut self, offset: isize, position: Option<Position>) -> Value {
+        let cell_ptr = self.builder.use_var(self.cell_ptr);
+        if offset == 0 {
+            return cell_ptr;
+        }
+
+        let index = self.builder.ins().iadd_imm(cell_ptr, offset as i64);
+        let site = self.builder.ins().iconst(self.pointer_type, self.positions.len() as i64);
+        self.positions.push(position);
+
+        // Negative indices are huge as unsigned integers, so this
+        // checks both ends of the tape.
+        let in_bounds = self.builder.ins().icmp(IntCC::UnsignedLessThan, index, self.num_cells);
+        let next = self.builder.create_block();
+        self.builder.ins().brif(in_bounds,
+                                next,
+                                &[],
+                                self.out_of_bounds,
+                                &[BlockArg::Value(index), BlockArg::Value(site)]);
+        self.builder.switch_to_block(next);
+        index
+    }
+
+    fn load(&mut self, index: Value) -> Value {
+        let address = self.builder.ins().iadd(self.cells, index);
+        self.builder.ins().load(types::I8, MemFlags::trusted(), address, 0)
+    }
+
+    fn store(&mut self, index: Value, value: Value) {
+        let address = self.builder.ins().iadd(self.cells, index);
+        self.builder.ins().store(MemFlags::trusted(), value, address, 0);
+    }
+
+    fn current_cell(&mut self) -> Value {
+        let cell_ptr = self.builder.use_var(self.cell_ptr);
+        self.load(cell_ptr)
+    }
+
+    /// Emit a loop that runs the code emitted by `body` until the
+    /// current cell is zero.
+    fn while_nonzero<F>(&mut self, body: F)
+        where F: FnOnce(&mut Self)
+    {
+        let header = self.builder.create_block();
+        let body_block = self.builder.create_block();
+        let after = self.builder.create_block();
+
+        self.builder.ins().jump(header, &[]);
+        self.builder.switch_to_block(header);
+        let value = self.current_cell();
+        self.builder.ins().brif(value, body_block, &[], after, &[]);
+
+        self.builder.switch_to_block(body_block);
+        body(self);
+        self.builder.ins().jump(header, &[]);
+
+        self.builder.switch_to_block(after);
+    }
+
+    /// Add the current cell times each factor in `changes` to the
+    /// cell at each offset, then zero the current cell if `zero` is
+    /// set. We do nothing if the current cell is zero, so we only
+    /// check offsets that we actually access.
+    fn multiply<I>(&mut self, changes: I, zero: bool, position: Option<Position>)
+        where I: Iterator<Item = (isize, Cell)>
+    {
+        let multiply_block = self.builder.create_block();
+        let after = self.builder.create_block();
+
+        let value = self.current_cell();
+        self.builder.ins().brif(value, multiply_block, &[], after, &[]);
+
+        self.builder.switch_to_block(multiply_block);
+        for (offset, factor) in changes {
+            let index = self.checked_index(offset, position);
+            let factor = self.builder.ins().iconst(types::I8, cell_value(factor));
+            let product = self.builder.ins().imul(value, factor);
+            let old_value = self.load(index);
+            let new_value = self.builder.ins().iadd(old_value, product);
+            self.store(index, new_value);
+        }
+        if zero {
+            let cell_ptr = self.builder.use_var(self.cell_ptr);
+            let zero = self.builder.ins().iconst(types::I8, 0);
+            self.store(cell_ptr, zero);
+        }
+        self.builder.ins().jump(after, &[]);
+
+        self.builder.switch_to_block(after);
+    }
+
+    /// Continue if `failed` is zero, otherwise stop the program.
+    fn fail_if(&mut self, failed: Value) {
+        let next = self.builder.create_block();
+        self.builder.ins().brif(failed, self.fail, &[], next, &[]);
+        self.builder.switch_to_block(next);
+    }
+
+    fn compile_instr(&mut self, instr: &AstNode) {
+        let position = get_position(instr);
+        match *instr {
+            Increment { amount, offset, .. } => {
+                let index = self.checked_index(offset, position);
+                let old_value = self.load(index);
+                let amount = self.builder.ins().iconst(types::I8, cell_value(amount));
+                let new_value = self.builder.ins().iadd(old_value, amount);
+                self.store(index, new_value);
+            }
+            Set { amount, offset, .. } => {
+                let index = self.checked_index(offset, position);
+                let amount = self.builder.ins().iconst(types::I8, cell_value(amount));
+                self.store(index, amount);
+            }
+            PointerIncrement { amount, .. } => {
+                let index = self.checked_index(amount, position);
+                self.builder.def_var(self.cell_ptr, index);
+            }
+            MultiplyMove { ref changes, .. } => {
+                self.multiply(changes.iter().map(|(&offset, &factor)| (offset, factor)),
+                              true,
+                              position);
+            }
+            Move { offset, .. } => {
+                self.multiply(Some((offset, Wrapping(1))).into_iter(), true, position);
+            }
+            MultiplyCopy { ref changes, .. } => {
+                self.multiply(changes.iter().map(|(&offset, &factor)| (offset, factor)),
+                              false,
+                              position);
+            }
+            Read { .. } => {
+                let call = self.builder.ins().call(self.read, &[self.context]);
+                let value = self.builder.inst_results(call)[0];
+                let failed = self.builder.ins().icmp_imm(IntCC::SignedLessThan, value, 0);
+                self.fail_if(failed);
+
+                let value = self.builder.ins().ireduce(types::I8, value);
+                let cell_ptr = self.builder.use_var(self.cell_ptr);
+                self.store(cell_ptr, value);
+            }
+            Write { .. } => {
+                let value = self.current_cell();
+                let value = self.builder.ins().uextend(types::I32, value);
+                let call = self.builder.ins().call(self.write, &[self.context, value]);
+                let failed = self.builder.inst_results(call)[0];
+                self.fail_if(failed);
+            }
+            ScanZero { step, .. } => {
+                self.while_nonzero(|compiler| {
+                    let index = compiler.checked_index(step, position);
+                    compiler.builder.def_var(compiler.cell_ptr, index);
+                });
+            }
+            Loop { ref body, .. } => {
+                self.while_nonzero(|compiler| {
+                    for instr in body {
+                        compiler.compile_instr(instr);
+                    }
+                });
+            }
+            // There's no debugger to stop for.
+            Breakpoint { .. } => {}
+        }
+    }
+}
+
+/// Compile the function that runs `instrs`.
+fn compile(instrs: &[AstNode]) -> Result<Compiled, String> {
+    let mut flags = settings::builder();
+    flags.set("use_colocated_libcalls", "false").map_err(|e| format!("{}", e))?;
+    flags.set("is_pic", "false").map_err(|e| format!("{}", e))?;
+    flags.set("opt_level", "speed").map_err(|e| format!("{}", e))?;
+    let isa = cranelift_native::builder()
+        .map_err(|e| format!("This machine is not supported: {}", e))?
+        .finish(settings::Flags::new(flags))
+        .map_err(|e| format!("{}", e))?;
+
+    let mut jit_builder = JITBuilder::with_isa(isa, default_libcall_names());
+    jit_builder.symbol("bfc_read", jit_read as *const u8);
+    jit_builder.symbol("bfc_write", jit_write as *const u8);
+    jit_builder.symbol("bfc_out_of_bounds", jit_out_of_bounds as *const u8);
+    let mut module = JITModule::new(jit_builder);
+    let pointer_type = module.target_config().pointer_type();
+
+    let mut read_sig = module.make_signature();
+    read_sig.params.push(AbiParam::new(pointer_type));
+    read_sig.returns.push(AbiParam::new(types::I32));
+    let read_id = module.declare_function("bfc_read", Linkage::Import, &read_sig)
+        .map_err(|e| format!("{}", e))?;
+
+    let mut write_sig = module.make_signature();
+    write_sig.params.push(AbiParam::new(pointer_type));
+    write_sig.params.push(AbiParam::new(types::I32));
+    write_sig.returns.push(AbiParam::new(types::I32));
+    let write_id = module.declare_function("bfc_write", Linkage::Import, &write_sig)
+        .map_err(|e| format!("{}", e))?;
+
+    let mut out_of_bounds_sig = module.make_signature();
+    for _ in 0..3 {
+        out_of_bounds_sig.params.push(AbiParam::new(pointer_type));
+    }
+    let out_of_bounds_id =
+        module.declare_function("bfc_out_of_bounds", Linkage::Import, &out_of_bounds_sig)
+            .map_err(|e| format!("{}", e))?;
+
+    let mut run_sig = module.make_signature();
+    for _ in 0..3 {
+        run_sig.params.push(AbiParam::new(pointer_type));
+    }
+    run_sig.returns.push(AbiParam::new(types::I32));
+    let run_id = module.declare_function("run", Linkage::Export, &run_sig)
+        .map_err(|e| format!("{}", e))?;
+
+    let mut ctx = module.make_context();
+    ctx.func.signature = run_sig;
+    ctx.func.name = UserFuncName::user(0, run_id.as_u32());
+
+    let mut builder_context = FunctionBuilderContext::new();
+    let positions = {
+        let mut builder = FunctionBuilder::new(&mut ctx.func, &mut builder_context);
+        let read = module.declare_func_in_func(read_id, builder.func);
+        let write = module.declare_func_in_func(write_id, builder.func);
+        let report_out_of_bounds = module.declare_func_in_func(out_of_bounds_id, builder.func);
+
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        let params = builder.block_params(entry).to_vec();
+
+        let cell_ptr = builder.declare_var(pointer_type);
+        let zero = builder.ins().iconst(pointer_type, 0);
+        builder.def_var(cell_ptr, zero);
+
+        let out_of_bounds = builder.create_block();
+        builder.append_block_param(out_of_bounds, pointer_type);
+        builder.append_block_param(out_of_bounds, pointer_type);
+        let fail = builder.create_block();
+
+        let mut compiler = Compiler {
+            builder: builder,
+            pointer_type: pointer_type,
+            cells: params[0],
+            num_cells: params[1],
+            context: params[2],
+            cell_ptr: cell_ptr,
+            read: read,
+            write: write,
+            out_of_bounds: out_of_bounds,
+            fail: fail,
+            positions: vec![],
+        };
+        for instr in instrs {
+            compiler.compile_instr(instr);
+        }
+        let finished = compiler.builder.ins().iconst(types::I32, 0);
+        compiler.builder.ins().return_(&[finished]);
+
+        let mut builder = compiler.builder;
+        builder.switch_to_block(out_of_bounds);
+        let args = builder.block_params(out_of_bounds).to_vec();
+        builder.ins().call(report_out_of_bounds, &[params[2], args[0], args[1]]);
+        builder.ins().jump(fail, &[]);
+
+        builder.switch_to_block(fail);
+        let failed = builder.ins().iconst(types::I32, 1);
+        builder.ins().return_(&[failed]);
+
+        builder.seal_all_blocks();
+        builder.finalize();
+        compiler.positions
+    };
+
+    module.define_function(run_id, &mut ctx).map_err(|e| format!("{:?}", e))?;
+    module.clear_context(&mut ctx);
+    module.finalize_definitions().map_err(|e| format!("{}", e))?;
+
+    let code = module.get_finalized_function(run_id);
+    Ok(Compiled {
+        module: Some(module),
+        function: unsafe { mem::transmute::<*const u8, CompiledFn>(code) },
+        positions: positions,
+    })
+}
+
+/// Compile `instrs` to native code, and return a function that runs
+/// them on a tape, reading from `input` and writing to `output`. The
+/// tape may be any length, but programs that leave it stop with
+/// `ExecutionError::PointerOutOfBounds`. The function can be called
+/// many times, each on a new tape.
+#[allow(clippy::type_complexity)]
+pub fn jit_compile(instrs: &[AstNode])
+                   -> Result<impl FnMut(&mut [u8], &mut dyn io::Read, &mut dyn io::Write)
+                                        -> Result<(), ExecutionError>,
+                             String> {
+    let compiled = compile(instrs)?;
+    Ok(move |cells: &mut [u8], input: &mut dyn io::Read, output: &mut dyn io::Write| {
+        if cells.is_empty() {
+            return Err(ExecutionError::PointerOutOfBounds {
+                cell_index: 0,
+                position: None,
+            });
+        }
+
+        let mut context = Context {
+            input: input,
+            output: output,
+            positions: &compiled.positions,
+            error: None,
+        };
+        let status = (compiled.function)(cells.as_mut_ptr(),
+                                         cells.len(),
+                                         &mut context as *mut Context as *mut u8);
+        match context.error {
+            Some(error) => Err(error),
+            None => {
+                debug_assert_eq!(status, 0);
+                Ok(())
+            }
+        }
+    })
+}
+
+#[test]
+fn jit_hello_world_matches_interpreter() {
+    use interpreter::interpret;
+
+    let instrs = parse(include_str!("../sample_programs/hello_world.bf")).unwrap();
+    let mut expected = vec![];
+    interpret(&instrs, &mut &b""[..], &mut expected).unwrap();
+
+    let mut run = jit_compile(&instrs).unwrap();
+    let mut output = vec![];
+    run(&mut [0; 100], &mut &b""[..], &mut output).unwrap();
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn jit_fixtures_match_interpreter() {
+    use interpreter::interpret;
+    use peephole::optimize;
+    use testing::FIXTURES;
+
+    for &(name, source, input) in FIXTURES {
+        let unoptimized = parse(source).unwrap();
+        let optimized = optimize(unoptimized.clone(), &None).0;
+        for instrs in &[unoptimized, optimized] {
+            let mut expected = vec![];
+            interpret(instrs, &mut &input[..], &mut expected).unwrap();
+
+            let mut output = vec![];
+            let mut run = jit_compile(instrs).unwrap();
+            run(&mut [0; 100], &mut &input[..], &mut output).unwrap();
+            assert_eq!(output, expected, "{} output differs", name);
+        }
+    }
+}
+
+#[test]
+fn jit_multiply_and_scan() {
+    use interpreter::interpret;
+    use peephole::optimize;
+
+    // A multiply move, a move, a multiply copy and a scan.
+    let source = "++++[->+++>+<<]>[->>+<<]>[->+>+<<]>>[-<<+>>]<<[<]>>>>.";
+    let instrs = parse(source).unwrap();
+    let optimized = optimize(instrs.clone(), &None).0;
+
+    let mut expected = vec![];
+    interpret(&instrs, &mut &b""[..], &mut expected).unwrap();
+    let mut output = vec![];
+    jit_compile(&optimized).unwrap()(&mut [0; 10], &mut &b""[..], &mut output).unwrap();
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn jit_runs_more_than_once() {
+    let mut run = jit_compile(&parse(",.").unwrap()).unwrap();
+    for &byte in b"ab" {
+        let mut output = vec![];
+        run(&mut [0; 1], &mut &[byte][..], &mut output).unwrap();
+        assert_eq!(output, vec![byte]);
+    }
+}
+
+#[test]
+fn jit_eof_is_zero() {
+    let mut output = vec![];
+    jit_compile(&parse("+,.").unwrap()).unwrap()(&mut [0; 1], &mut &b""[..], &mut output)
+        .unwrap();
+    assert_eq!(output, vec![0]);
+}
+
+#[test]
+fn jit_pointer_out_of_bounds() {
+    let mut run = jit_compile(&parse("+[<]").unwrap()).unwrap();
+    assert_eq!(run(&mut [0; 10], &mut &b""[..], &mut vec![]),
+               Err(ExecutionError::PointerOutOfBounds {
+                   cell_index: -1,
+                   position: Some(Position { start: 2, end: 2 }),
+               }));
+
+    let mut run = jit_compile(&parse(">>").unwrap()).unwrap();
+    assert_eq!(run(&mut [0; 2], &mut &b""[..], &mut vec![]),
+               Err(ExecutionError::PointerOutOfBounds {
+                   cell_index: 2,
+                   position: Some(Position { start: 1, end: 1 }),
+               }));
+}
+
+#[test]
+fn jit_empty_tape() {
+    let mut run = jit_compile(&parse("+").unwrap()).unwrap();
+    assert_eq!(run(&mut [], &mut &b""[..], &mut vec![]),
+               Err(ExecutionError::PointerOutOfBounds {
+                   cell_index: 0,
+                   position: None,
+               }));
+}
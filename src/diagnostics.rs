@@ -1,8 +1,17 @@
 //! Human-readable warnings and errors for the CLI.
+//!
+//! Displaying an `Info` needs the `std` feature, as we colour it for
+//! the terminal.
 
+use alloc::string::String;
+
+#[cfg(feature = "std")]
 use std::fmt;
+#[cfg(feature = "std")]
 use ansi_term::Colour::{Red, Purple};
+#[cfg(feature = "std")]
 use ansi_term::Style;
+#[cfg(feature = "std")]
 use ansi_term::ANSIStrings;
 
 use bfir::Position;
@@ -34,6 +43,7 @@ pub struct Info {
 
 // Given an index into a string, return the line number and column
 // count (both zero-indexed).
+#[cfg(feature = "std")]
 fn position(s: &str, i: usize) -> (usize, usize) {
     let mut char_count = 0;
     for (line_idx, line) in s.split('\n').enumerate() {
@@ -48,6 +58,7 @@ fn position(s: &str, i: usize) -> (usize, usize) {
     unreachable!()
 }
 
+#[cfg(feature = "std")]
 impl fmt::Display for Info {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         let mut file_text = self.filename.to_owned();
@@ -0,0 +1,139 @@
+//! Compare two BF programs instruction by instruction, so pass
+//! authors can see exactly where a transformation changed a program.
+
+use std::fmt;
+
+use bfir::AstNode;
+use bfir::AstNode::*;
+
+#[cfg(test)]
+use bfir::parse;
+
+/// An instruction that differs between two programs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Difference {
+    /// The index of each loop that we're inside, followed by the
+    /// index of the instruction in the innermost loop body.
+    pub path: Vec<usize>,
+    /// The instruction in the first program, or None if the first
+    /// program has fewer instructions here.
+    pub left: Option<AstNode>,
+    /// The instruction in the second program, or None if the second
+    /// program has fewer instructions here.
+    pub right: Option<AstNode>,
+}
+
+impl fmt::Display for Difference {
+    /// Show the path, followed by the two instructions in the style
+    /// of a unified diff. For example:
+    ///
+    /// ```text
+    /// loop 0, index 2:
+    /// - Write { position: Some(3) }
+    /// + Read { position: Some(3) }
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (index, loops) = self.path.split_last().expect("A difference has a non-empty path");
+        for loop_index in loops {
+            write!(f, "loop {}, ", loop_index)?;
+        }
+        write!(f, "index {}:", index)?;
+
+        if let Some(ref left) = self.left {
+            write!(f, "\n- {}", left)?;
+        }
+        if let Some(ref right) = self.right {
+            write!(f, "\n+ {}", right)?;
+        }
+        Ok(())
+    }
+}
+
+fn diff_inner(left: &[AstNode],
+              right: &[AstNode],
+              path: &mut Vec<usize>,
+              differences: &mut Vec<Difference>) {
+    for index in 0..left.len().max(right.len()) {
+        path.push(index);
+        match (left.get(index), right.get(index)) {
+            // Compare loops by their bodies, so we report the
+            // instruction that differs rather than the whole loop.
+            (Some(&Loop { body: ref left_body, position: left_position }),
+             Some(&Loop { body: ref right_body, position: right_position }))
+                if left_position == right_position => {
+                diff_inner(left_body, right_body, path, differences);
+            }
+            (left_instr, right_instr) => {
+                if left_instr != right_instr {
+                    differences.push(Difference {
+                        path: path.clone(),
+                        left: left_instr.cloned(),
+                        right: right_instr.cloned(),
+                    });
+                }
+            }
+        }
+        path.pop();
+    }
+}
+
+/// Return every instruction that differs between `left` and `right`,
+/// comparing instructions at the same index and recursing into loops
+/// at the same position. Positions count, so instructions only match
+/// if they came from the same source.
+///
+/// After an instruction is inserted or removed, every later
+/// instruction in that sequence differs, so the first difference is
+/// usually the interesting one.
+pub fn diff(left: &[AstNode], right: &[AstNode]) -> Vec<Difference> {
+    let mut differences = vec![];
+    diff_inner(left, right, &mut vec![], &mut differences);
+    differences
+}
+
+#[test]
+fn diff_identical() {
+    let instrs = parse("+[->+<].").unwrap();
+    assert_eq!(diff(&instrs, &instrs), vec![]);
+}
+
+#[test]
+fn diff_zeroing_loop() {
+    use peephole::zeroing_loops;
+
+    let left = parse("[-]").unwrap();
+    let right = zeroing_loops(left.clone());
+    assert_eq!(diff(&left, &right),
+               vec![Difference {
+                        path: vec![0],
+                        left: Some(left[0].clone()),
+                        right: Some(right[0].clone()),
+                    }]);
+}
+
+#[test]
+fn diff_inside_loop() {
+    let left = parse("+[->.<]").unwrap();
+    let right = parse("+[->,<]").unwrap();
+    let differences = diff(&left, &right);
+    assert_eq!(differences.len(), 1);
+    assert_eq!(format!("{}", differences[0]),
+               "loop 1, index 2:
+- Write { position: Some(4) }
++ Read { position: Some(4) }");
+}
+
+#[test]
+fn diff_different_lengths() {
+    let left = parse("+.").unwrap();
+    let right = parse("+").unwrap();
+    let differences = diff(&left, &right);
+    assert_eq!(differences,
+               vec![Difference {
+                        path: vec![1],
+                        left: Some(left[1].clone()),
+                        right: None,
+                    }]);
+    assert_eq!(format!("{}", differences[0]),
+               "index 1:\n- Write { position: Some(1) }");
+}
@@ -0,0 +1,161 @@
+//! Render BF IR as a Graphviz DOT graph, to visualise control flow.
+//!
+//! Each instruction is a node, and sequential instructions are joined
+//! by edges. Loops become clusters: a header node checks the current
+//! cell, the body follows it, and a back edge returns to the header.
+
+use std::collections::BTreeMap;
+
+use bfir::{AstNode, Cell};
+use bfir::AstNode::*;
+
+#[cfg(test)]
+use bfir::parse;
+
+/// Show the changes of a multiply instruction, sorted by offset.
+fn changes_label(changes: &BTreeMap<isize, Cell>) -> String {
+    let changes: Vec<_> = changes.iter()
+        .map(|(offset, factor)| format!("{}: {}", offset, factor.0))
+        .collect();
+    changes.join(", ")
+}
+
+fn label(instr: &AstNode) -> String {
+    let with_offset = |name: &str, amount: i8, offset: isize| {
+        if offset == 0 {
+            format!("{} {}", name, amount)
+        } else {
+            format!("{} {} (offset {})", name, amount, offset)
+        }
+    };
+
+    match *instr {
+        Increment { amount, offset, .. } => with_offset("Increment", amount.0, offset),
+        Set { amount, offset, .. } => with_offset("Set", amount.0, offset),
+        PointerIncrement { amount, .. } => format!("PointerIncrement {}", amount),
+        Read { .. } => "Read".to_owned(),
+        Write { .. } => "Write".to_owned(),
+        ScanZero { step, .. } => format!("ScanZero {}", step),
+        Breakpoint { .. } => "Breakpoint".to_owned(),
+        Loop { .. } => "Loop".to_owned(),
+        MultiplyMove { ref changes, .. } => format!("MultiplyMove {{{}}}", changes_label(changes)),
+        Move { offset, .. } => format!("Move {}", offset),
+        MultiplyCopy { ref changes, temp, .. } => {
+            format!("MultiplyCopy {{{}}} via {}", changes_label(changes), temp)
+        }
+    }
+}
+
+struct DotWriter {
+    out: String,
+    next_id: usize,
+}
+
+impl DotWriter {
+    fn line(&mut self, indent: usize, line: &str) {
+        for _ in 0..indent {
+            self.out.push_str("    ");
+        }
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+
+    /// Add a node for every instruction in `instrs`, chained after
+    /// the node `prev_id` if given. Returns the last node in the
+    /// sequence.
+    fn add_instrs(&mut self,
+                  instrs: &[AstNode],
+                  mut prev_id: Option<usize>,
+                  indent: usize)
+                  -> Option<usize> {
+        for instr in instrs {
+            let id = self.next_id;
+            self.next_id += 1;
+
+            if let Loop { ref body, .. } = *instr {
+                self.line(indent, &format!("subgraph cluster_{} {{", id));
+                self.line(indent + 1, "label=\"Loop\";");
+                self.line(indent + 1, &format!("n{} [label=\"Loop\", shape=diamond];", id));
+                if let Some(last_id) = self.add_instrs(body, Some(id), indent + 1) {
+                    self.line(indent + 1, &format!("n{} -> n{} [style=dashed];", last_id, id));
+                }
+                self.line(indent, "}");
+            } else {
+                self.line(indent, &format!("n{} [label=\"{}\"];", id, label(instr)));
+            }
+
+            if let Some(prev_id) = prev_id {
+                self.line(indent, &format!("n{} -> n{};", prev_id, id));
+            }
+            // Execution continues after a loop from its header.
+            prev_id = Some(id);
+        }
+        prev_id
+    }
+}
+
+/// Convert the instructions given to a DOT graph.
+pub fn to_dot(instrs: &[AstNode]) -> String {
+    let mut writer = DotWriter {
+        out: String::new(),
+        next_id: 0,
+    };
+    writer.line(0, "digraph bfir {");
+    writer.line(1, "node [shape=box];");
+    writer.add_instrs(instrs, None, 1);
+    writer.line(0, "}");
+    writer.out
+}
+
+#[test]
+fn to_dot_one_loop() {
+    let instrs = parse(".[,+]-").unwrap();
+    let expected = "digraph bfir {
+    node [shape=box];
+    n0 [label=\"Write\"];
+    subgraph cluster_1 {
+        label=\"Loop\";
+        n1 [label=\"Loop\", shape=diamond];
+        n2 [label=\"Read\"];
+        n1 -> n2;
+        n3 [label=\"Increment 1\"];
+        n2 -> n3;
+        n3 -> n1 [style=dashed];
+    }
+    n0 -> n1;
+    n4 [label=\"Increment -1\"];
+    n1 -> n4;
+}
+";
+    let dot = to_dot(&instrs);
+    assert_eq!(dot.matches("subgraph cluster_").count(), 1);
+    assert_eq!(dot, expected);
+}
+
+#[test]
+fn to_dot_nested_loops() {
+    let instrs = parse("[[]>[]]").unwrap();
+    assert_eq!(to_dot(&instrs).matches("subgraph cluster_").count(), 3);
+}
+
+#[test]
+fn to_dot_labels() {
+    use std::collections::BTreeMap;
+    use std::num::Wrapping;
+
+    let mut changes = BTreeMap::new();
+    changes.insert(2, Wrapping(3));
+    changes.insert(-1, Wrapping(1));
+    let instrs = [Set {
+                      amount: Wrapping(5),
+                      offset: 2,
+                      position: None,
+                  },
+                  MultiplyMove {
+                      changes: changes,
+                      position: None,
+                  }];
+    let dot = to_dot(&instrs);
+    assert!(dot.contains("n0 [label=\"Set 5 (offset 2)\"];"));
+    assert!(dot.contains("n1 [label=\"MultiplyMove {-1: 1, 2: 3}\"];"));
+}
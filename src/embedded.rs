@@ -0,0 +1,81 @@
+//! The simplest way to run BF on embedded targets or in constrained
+//! WebAssembly, where we only have `core` and `alloc`.
+//!
+//! ```
+//! use bfc::embedded::{parse, run};
+//!
+//! let bytecode = parse(",[.,]").unwrap();
+//! let mut cells = [0; 100];
+//! let mut output = vec![];
+//! run(&bytecode, &mut cells, b"hi", |byte| output.push(byte)).unwrap();
+//! assert_eq!(output, b"hi");
+//! ```
+//!
+//! This uses the same parser and bytecode as the rest of bfc, so
+//! programs behave as they do in `interpreter` with the default
+//! options, except that the caller chooses how many cells there are.
+//! We don't optimise, to keep memory use down. For faster programs,
+//! pass the output of `bfir::parse` through `peephole::optimize`
+//! before `bytecode::compile_to_bytecode`.
+
+use bfir::{self, ParseError};
+use bytecode::{self, Bytecode};
+use interpreter::ExecutionError;
+
+#[cfg(test)]
+use bfir::ParseErrorKind;
+
+/// Parse BF source code into bytecode, ready for `run`.
+pub fn parse(source: &str) -> Result<Bytecode, ParseError> {
+    let instrs = bfir::parse(source)?;
+    Ok(bytecode::compile_to_bytecode(&instrs))
+}
+
+/// Run `bytecode` on the tape `cells`, which should start as zeroes.
+/// We read bytes from `input`, then 0 once it's exhausted, and call
+/// `output` with every byte written.
+pub fn run<F: FnMut(u8)>(bytecode: &Bytecode,
+                         cells: &mut [u8],
+                         input: &[u8],
+                         output: F)
+                         -> Result<(), ExecutionError> {
+    bytecode::run_on_tape(bytecode, cells, input, output)
+}
+
+#[test]
+fn embedded_parse_unbalanced() {
+    assert_eq!(parse("+]").unwrap_err().kind,
+               ParseErrorKind::UnmatchedClose { index: 1 });
+    assert_eq!(parse("[[]").unwrap_err().kind,
+               ParseErrorKind::UnmatchedOpen { index: 0 });
+}
+
+#[test]
+fn embedded_run_hello_world() {
+    let source = include_str!("../sample_programs/hello_world.bf");
+    let mut cells = [0; 100];
+    let mut output = vec![];
+    run(&parse(source).unwrap(), &mut cells, b"", |byte| output.push(byte)).unwrap();
+    assert_eq!(output, b"Hello World!\n");
+}
+
+#[test]
+fn embedded_run_out_of_bounds() {
+    let mut cells = [0; 2];
+    let result = run(&parse("<<").unwrap(), &mut cells, b"", |_| {});
+    assert!(matches!(result,
+                     Err(ExecutionError::PointerOutOfBounds { cell_index: -1, .. })));
+    let result = run(&parse(">>>").unwrap(), &mut cells, b"", |_| {});
+    assert!(matches!(result,
+                     Err(ExecutionError::PointerOutOfBounds { cell_index: 2, .. })));
+}
+
+#[test]
+fn embedded_run_empty_tape() {
+    let mut cells = [];
+    assert_eq!(run(&parse("+").unwrap(), &mut cells, b"", |_| {}),
+               Err(ExecutionError::PointerOutOfBounds {
+                   cell_index: 0,
+                   position: None,
+               }));
+}
@@ -3,7 +3,7 @@
 //! Compile time execution of BF programs.
 
 #[cfg(test)]
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::num::Wrapping;
 
 #[cfg(test)]
@@ -133,7 +133,8 @@ pub fn execute_with_state<'a>(instrs: &'a [AstNode],
                     instr_idx += 1;
                 }
             }
-            MultiplyMove { ref changes, position, .. } => {
+            MultiplyMove { ref changes, position, .. } |
+            MultiplyCopy { ref changes, position, .. } => {
                 let cell_value = state.cells[cell_ptr];
 
                 if cell_value.0 != 0 {
@@ -173,7 +174,33 @@ pub fn execute_with_state<'a>(instrs: &'a [AstNode],
                         state.cells[dest_ptr as usize] = current_val + cell_value * (*factor);
                     }
 
-                    // Finally, zero the cell we used.
+                    // Finally, zero the cell we used, unless we're
+                    // copying.
+                    if let MultiplyMove { .. } = instrs[instr_idx] {
+                        state.cells[cell_ptr] = Wrapping(0);
+                    }
+                }
+
+                instr_idx += 1;
+            }
+            Move { offset, position } => {
+                let cell_value = state.cells[cell_ptr];
+
+                if cell_value.0 != 0 {
+                    let dest_ptr = cell_ptr as isize + offset;
+                    if dest_ptr < 0 || dest_ptr as usize >= state.cells.len() {
+                        state.start_instr = Some(&instrs[instr_idx]);
+                        return Outcome::RuntimeError(Warning {
+                            message: format!("This move loop tried to access cell {} (offset {} \
+                                              from current cell {})",
+                                             dest_ptr,
+                                             offset,
+                                             cell_ptr),
+                            position: position,
+                        });
+                    }
+
+                    state.cells[dest_ptr as usize] += cell_value;
                     state.cells[cell_ptr] = Wrapping(0);
                 }
 
@@ -184,6 +211,31 @@ pub fn execute_with_state<'a>(instrs: &'a [AstNode],
                 state.outputs.push(cell_value.0);
                 instr_idx += 1;
             }
+            ScanZero { step, position } => {
+                if state.cells[cell_ptr].0 == 0 {
+                    instr_idx += 1;
+                } else {
+                    // Move one step per step, so we stop in the
+                    // middle of long scans. Runtime execution can
+                    // carry on scanning from the current cell.
+                    let new_cell_ptr = state.cell_ptr + step;
+                    if new_cell_ptr < 0 || new_cell_ptr >= state.cells.len() as isize {
+                        state.start_instr = Some(&instrs[instr_idx]);
+                        return Outcome::RuntimeError(Warning {
+                            message: format!("This loop moves the pointer to cell {}, but \
+                                              cells are numbered 0 to {}.",
+                                             new_cell_ptr,
+                                             state.cells.len() - 1),
+                            position: position,
+                        });
+                    }
+                    state.cell_ptr = new_cell_ptr;
+                }
+            }
+            // Breakpoints only matter when interpreting.
+            Breakpoint { .. } => {
+                instr_idx += 1;
+            }
             Read { .. } => {
                 if let Some(read_value) = dummy_read_value {
                     // If we're given a dummy value to use for the
@@ -280,7 +332,7 @@ fn increment_executed() {
 
 #[test]
 fn multiply_move_executed() {
-    let mut changes = HashMap::new();
+    let mut changes = BTreeMap::new();
     changes.insert(1, Wrapping(2));
     changes.insert(3, Wrapping(3));
 
@@ -324,7 +376,7 @@ fn multiply_move_executed() {
 /// undefined behaviour when we have a multiply move instruction.
 #[test]
 fn multiply_move_when_current_cell_is_zero() {
-    let mut changes = HashMap::new();
+    let mut changes = BTreeMap::new();
     changes.insert(-1, Wrapping(2));
 
     let instrs = [MultiplyMove {
@@ -345,7 +397,7 @@ fn multiply_move_when_current_cell_is_zero() {
 
 #[test]
 fn multiply_move_wrapping() {
-    let mut changes = HashMap::new();
+    let mut changes = BTreeMap::new();
     changes.insert(1, Wrapping(3));
     let instrs = [Increment {
                       amount: Wrapping(100),
@@ -370,7 +422,7 @@ fn multiply_move_wrapping() {
 
 #[test]
 fn multiply_move_offset_too_high() {
-    let mut changes: HashMap<isize, Cell> = HashMap::new();
+    let mut changes: BTreeMap<isize, Cell> = BTreeMap::new();
     changes.insert(MAX_CELL_INDEX as isize + 1, Wrapping(1));
     let instrs = [Increment {
                       amount: Wrapping(1),
@@ -396,7 +448,7 @@ fn multiply_move_offset_too_high() {
 
 #[test]
 fn multiply_move_offset_too_low() {
-    let mut changes = HashMap::new();
+    let mut changes = BTreeMap::new();
     changes.insert(-1, Wrapping(1));
     let instrs = [Increment {
                       amount: Wrapping(1),
@@ -716,6 +768,43 @@ fn up_to_nonempty_infinite_loop() {
                });
 }
 
+#[test]
+fn execute_scan_zero() {
+    let instrs = vec![Increment {
+                          amount: Wrapping(1),
+                          offset: 0,
+                          position: None,
+                      },
+                      Increment {
+                          amount: Wrapping(1),
+                          offset: 1,
+                          position: None,
+                      },
+                      ScanZero {
+                          step: 1,
+                          position: None,
+                      }];
+    let final_state = execute(&instrs, MAX_STEPS).0;
+    assert_eq!(final_state.start_instr, None);
+    assert_eq!(final_state.cell_ptr, 2);
+}
+
+#[test]
+fn execute_scan_zero_out_of_bounds() {
+    let instrs = vec![Increment {
+                          amount: Wrapping(1),
+                          offset: 0,
+                          position: None,
+                      },
+                      ScanZero {
+                          step: -1,
+                          position: Some(Position { start: 1, end: 3 }),
+                      }];
+    let (final_state, warning) = execute(&instrs, MAX_STEPS);
+    assert_eq!(final_state.start_instr, Some(&instrs[1]));
+    assert_eq!(warning.unwrap().position, Some(Position { start: 1, end: 3 }));
+}
+
 #[test]
 fn quickcheck_cell_ptr_in_bounds() {
     fn cell_ptr_in_bounds(instrs: Vec<AstNode>) -> bool {
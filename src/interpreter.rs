@@ -0,0 +1,1336 @@
+//! A straightforward interpreter for BF IR, so we can run programs
+//! without compiling them.
+//!
+//! Unlike compile time execution, the interpreter performs real I/O
+//! and runs until the program terminates.
+//!
+//! Without the `std` feature, only the error and option types are
+//! available. `bytecode` runs programs without std.
+
+use core::fmt;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
+use std::cmp::{max, min};
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+#[cfg(feature = "std")]
+use std::io;
+
+#[cfg(test)]
+use std::io::Cursor;
+#[cfg(test)]
+use std::num::Wrapping;
+
+use bfir::{AstNode, Position};
+#[cfg(feature = "std")]
+use bfir::{Cell, get_position};
+#[cfg(feature = "std")]
+use bfir::AstNode::*;
+
+use bounds::MAX_CELL_INDEX;
+
+#[cfg(test)]
+use bfir::{parse, parse_with_options, ParseOptions};
+
+/// Reasons that interpreting a BF program can fail.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ExecutionError {
+    /// The program tried to access a cell outside of the tape.
+    PointerOutOfBounds {
+        cell_index: isize,
+        position: Option<Position>,
+    },
+    /// Reading input or writing output failed.
+    Io(String),
+    /// The program ran for more steps than it was allowed.
+    FuelExhausted,
+    /// The program tried to write more bytes than it was allowed.
+    OutputLimitExceeded,
+    /// An increment took a cell outside the values it can hold, and
+    /// we're trapping overflow.
+    CellOverflow { position: Option<Position> },
+    /// The `Options` given can't be used, such as a circular tape with
+    /// no cells.
+    InvalidOptions(String),
+    /// A `bytecode::SavedState` can't be resumed, such as one whose
+    /// program counter is past the end of the bytecode.
+    InvalidState(String),
+}
+
+impl fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExecutionError::PointerOutOfBounds { cell_index, .. } if cell_index < 0 => {
+                write!(f,
+                       "Tried to access cell {}, but there are no cells before cell 0.",
+                       cell_index)
+            }
+            ExecutionError::PointerOutOfBounds { cell_index, .. } => {
+                write!(f,
+                       "Tried to access cell {}, but cells are numbered 0 to {}.",
+                       cell_index,
+                       MAX_CELL_INDEX)
+            }
+            ExecutionError::Io(ref message) => write!(f, "I/O error: {}", message),
+            ExecutionError::FuelExhausted => {
+                write!(f, "The program did not terminate within the step limit.")
+            }
+            ExecutionError::OutputLimitExceeded => {
+                write!(f, "The program wrote more output than the limit allows.")
+            }
+            ExecutionError::CellOverflow { .. } => {
+                write!(f, "This increment overflowed the cell.")
+            }
+            ExecutionError::InvalidOptions(ref message) => {
+                write!(f, "Invalid interpreter options: {}", message)
+            }
+            ExecutionError::InvalidState(ref message) => {
+                write!(f, "Invalid saved state: {}", message)
+            }
+        }
+    }
+}
+
+/// The size of each cell on the tape.
+///
+/// Note that the optimiser assumes 8-bit cells (e.g. it combines 256
+/// increments into nothing), so wider cells should only be used with
+/// unoptimised instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellWidth {
+    Eight,
+    Sixteen,
+    ThirtyTwo,
+}
+
+impl CellWidth {
+    /// A mask of the bits that a cell of this width can hold.
+    #[cfg(feature = "std")]
+    fn mask(self) -> u32 {
+        match self {
+            CellWidth::Eight => 0xFF,
+            CellWidth::Sixteen => 0xFFFF,
+            CellWidth::ThirtyTwo => 0xFFFF_FFFF,
+        }
+    }
+}
+
+/// What `Read` does to the current cell when there's no more input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EofMode {
+    /// Set the cell to 0.
+    Zero,
+    /// Set the cell to -1, i.e. the largest value the cell can hold.
+    /// Compiled programs behave like this, as they store the EOF value
+    /// from getchar.
+    MinusOne,
+    /// Leave the cell as it was.
+    Unchanged,
+}
+
+/// What happens when an increment takes a cell past the largest or
+/// smallest value it can hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowMode {
+    /// Wrap around, as compiled programs do.
+    Wrap,
+    /// Stop with `ExecutionError::CellOverflow`. This only finds
+    /// every overflow in unoptimised instructions: the optimiser
+    /// combines increments with wrapping arithmetic, so 200 `+` become
+    /// an increment of -56, and multiply moves still wrap.
+    Error,
+}
+
+/// Which cells exist either side of the starting cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapeKind {
+    /// Cells start at the starting cell, as in classic BF.
+    RightOnly,
+    /// There are also cells to the left of the starting cell. We add
+    /// them as the pointer reaches them.
+    Bidirectional,
+}
+
+/// What happens when the pointer moves off either end of the tape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PointerMode {
+    /// Stop with `ExecutionError::PointerOutOfBounds`, unless the
+    /// tape can grow.
+    Error,
+    /// Use a circular tape with this many cells, so moving past the
+    /// last cell reaches the first cell and vice versa. This ignores
+    /// `growable_tape` and `tape_kind`. There must be at least one
+    /// cell.
+    Wrap(usize),
+}
+
+/// When we flush the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushMode {
+    /// Flush after every byte written, so interactive programs show
+    /// their output before they wait for input.
+    EveryWrite,
+    /// Only flush when the program finishes, which is faster.
+    OnExit,
+}
+
+/// Settings that control how the interpreter behaves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    pub cell_width: CellWidth,
+    pub eof_mode: EofMode,
+    pub overflow_mode: OverflowMode,
+    /// Extend the tape whenever the pointer moves past the end, rather
+    /// than providing a fixed number of cells.
+    pub growable_tape: bool,
+    pub tape_kind: TapeKind,
+    pub pointer_mode: PointerMode,
+    /// The maximum number of steps the program may run for, or None
+    /// for no limit. Every instruction is a step, as is every loop
+    /// iteration.
+    pub max_steps: Option<u64>,
+    /// The maximum number of bytes the program may write, or None for
+    /// no limit.
+    pub max_output: Option<usize>,
+    /// Whether to flush the output after every write, or only at exit.
+    pub flush_mode: FlushMode,
+    /// The values of the first cells when the program starts, so
+    /// tests can set up memory without a BF preamble. The rest of the
+    /// tape is zero. This can't be longer than the tape, unless the
+    /// tape is growable.
+    pub initial_tape: Vec<u8>,
+}
+
+impl Default for Options {
+    /// We default to 8-bit cells that wrap and a fixed tape with no
+    /// cells left of the start, as in compiled programs, use 0 for
+    /// EOF, don't limit the number of steps or the output, only flush
+    /// at exit, and start with every cell zero.
+    fn default() -> Self {
+        Options {
+            cell_width: CellWidth::Eight,
+            eof_mode: EofMode::Zero,
+            overflow_mode: OverflowMode::Wrap,
+            growable_tape: false,
+            tape_kind: TapeKind::RightOnly,
+            pointer_mode: PointerMode::Error,
+            max_steps: None,
+            max_output: None,
+            flush_mode: FlushMode::OnExit,
+            initial_tape: vec![],
+        }
+    }
+}
+
+/// How many cells a growable tape starts with. This is the
+/// traditional size of a BF tape.
+pub const INITIAL_GROWABLE_CELLS: usize = 30_000;
+
+/// Widen an IR cell value, treating it as signed so negative amounts
+/// wrap correctly at any cell width.
+#[cfg(feature = "std")]
+fn widen(value: Cell) -> u32 {
+    value.0 as i32 as u32
+}
+
+/// A function called with the tape and the cell pointer at every
+/// Breakpoint.
+#[cfg(feature = "std")]
+type BreakpointHandler<'a> = dyn FnMut(&[u32], usize) + 'a;
+
+/// What the interpreter is about to do, as shown to observers.
+#[derive(Debug)]
+pub struct ExecState<'s> {
+    /// The whole tape. This may grow during execution.
+    pub cells: &'s [u32],
+    /// The index of the current cell in `cells`.
+    pub cell_ptr: usize,
+    /// The instruction that we're about to execute.
+    pub instr: &'s AstNode,
+}
+
+/// The state of the tape when a program finishes, as returned by
+/// `interpret_full`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecOutcome {
+    /// Every cell from the lowest to the highest that the program
+    /// accessed, including the starting cell.
+    pub tape: Vec<u32>,
+    /// The index of the current cell in `tape`.
+    pub pointer: usize,
+    /// The index of the starting cell in `tape`. This is only nonzero
+    /// for bidirectional tapes.
+    pub start: usize,
+}
+
+/// A function called with the machine state before each instruction.
+#[cfg(feature = "std")]
+type Observer<'a> = dyn FnMut(&ExecState) + 'a;
+
+#[cfg(feature = "std")]
+struct Machine<'a, R: io::Read + 'a, W: io::Write + 'a> {
+    cells: Vec<u32>,
+    mask: u32,
+    eof_mode: EofMode,
+    overflow_mode: OverflowMode,
+    growable_tape: bool,
+    tape_kind: TapeKind,
+    pointer_mode: PointerMode,
+    /// The index in `cells` of the starting cell. This is only
+    /// nonzero for bidirectional tapes that have grown leftwards.
+    origin: usize,
+    steps_remaining: Option<u64>,
+    output_remaining: Option<usize>,
+    flush_mode: FlushMode,
+    cell_ptr: usize,
+    /// The lowest and highest indices in `cells` that we've accessed.
+    lowest_touched: usize,
+    highest_touched: usize,
+    input: &'a mut R,
+    output: &'a mut W,
+    on_breakpoint: Option<&'a mut BreakpointHandler<'a>>,
+    observer: Option<&'a mut Observer<'a>>,
+    counts: Option<HashMap<Position, u64>>,
+}
+
+#[cfg(feature = "std")]
+impl<'a, R: io::Read, W: io::Write> Machine<'a, R, W> {
+    fn new(input: &'a mut R,
+           output: &'a mut W,
+           options: &Options)
+           -> Result<Self, ExecutionError> {
+        let num_cells = match options.pointer_mode {
+            PointerMode::Wrap(0) => {
+                let message = "A circular tape needs at least one cell.".to_owned();
+                return Err(ExecutionError::InvalidOptions(message));
+            }
+            PointerMode::Wrap(num_cells) => num_cells,
+            PointerMode::Error if options.growable_tape => INITIAL_GROWABLE_CELLS,
+            PointerMode::Error => MAX_CELL_INDEX + 1,
+        };
+
+        let mut cells = vec![0; num_cells];
+        if options.growable_tape && options.initial_tape.len() > num_cells {
+            cells.resize(options.initial_tape.len(), 0);
+        }
+        if options.initial_tape.len() > cells.len() {
+            let message = format!("The initial tape has {} cells, but the tape only has {}.",
+                                  options.initial_tape.len(),
+                                  cells.len());
+            return Err(ExecutionError::InvalidOptions(message));
+        }
+        for (cell, &value) in cells.iter_mut().zip(&options.initial_tape) {
+            *cell = u32::from(value);
+        }
+
+        Ok(Machine {
+            cells: cells,
+            mask: options.cell_width.mask(),
+            eof_mode: options.eof_mode,
+            overflow_mode: options.overflow_mode,
+            growable_tape: options.growable_tape,
+            tape_kind: options.tape_kind,
+            pointer_mode: options.pointer_mode,
+            origin: 0,
+            steps_remaining: options.max_steps,
+            output_remaining: options.max_output,
+            flush_mode: options.flush_mode,
+            cell_ptr: 0,
+            lowest_touched: 0,
+            // Preset cells are part of the tape we report, even if
+            // the program never reaches them.
+            highest_touched: options.initial_tape.len().saturating_sub(1),
+            input: input,
+            output: output,
+            on_breakpoint: None,
+            observer: None,
+            counts: None,
+        })
+    }
+
+    /// Return the index of the cell at `offset` from the cell pointer,
+    /// growing the tape if allowed.
+    fn cell_index(&mut self,
+                  offset: isize,
+                  position: Option<Position>)
+                  -> Result<usize, ExecutionError> {
+        if let PointerMode::Wrap(num_cells) = self.pointer_mode {
+            let cell_index = (self.cell_ptr as isize + offset).rem_euclid(num_cells as isize);
+            return Ok(self.touch(cell_index as usize));
+        }
+
+        let mut cell_index = self.cell_ptr as isize + offset;
+        if self.tape_kind == TapeKind::Bidirectional && cell_index < 0 {
+            // Add cells at the start, doubling the tape as when we
+            // grow it to the right.
+            let extra = max(-cell_index as usize, self.cells.len());
+            let mut cells = vec![0; extra];
+            cells.extend_from_slice(&self.cells);
+            self.cells = cells;
+
+            self.cell_ptr += extra;
+            self.origin += extra;
+            self.lowest_touched += extra;
+            self.highest_touched += extra;
+            cell_index += extra as isize;
+        }
+        if self.growable_tape && cell_index >= self.cells.len() as isize {
+            // Double the tape, so growing is amortised constant time.
+            let new_len = max(cell_index as usize + 1, self.cells.len() * 2);
+            self.cells.resize(new_len, 0);
+        }
+        if cell_index < 0 || cell_index >= self.cells.len() as isize {
+            return Err(ExecutionError::PointerOutOfBounds {
+                cell_index: cell_index - self.origin as isize,
+                position: position,
+            });
+        }
+        Ok(self.touch(cell_index as usize))
+    }
+
+    /// Record that we've accessed the cell at `index`, and return it.
+    fn touch(&mut self, index: usize) -> usize {
+        self.lowest_touched = min(self.lowest_touched, index);
+        self.highest_touched = max(self.highest_touched, index);
+        index
+    }
+
+    /// The state of the tape, once we've finished running.
+    fn outcome(self) -> ExecOutcome {
+        ExecOutcome {
+            tape: self.cells[self.lowest_touched..self.highest_touched + 1].to_vec(),
+            pointer: self.cell_ptr - self.lowest_touched,
+            start: self.origin - self.lowest_touched,
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>, ExecutionError> {
+        let mut buf = [0];
+        match self.input.read(&mut buf) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(buf[0])),
+            Err(e) => Err(ExecutionError::Io(format!("{}", e))),
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), ExecutionError> {
+        match self.output_remaining {
+            Some(0) => return Err(ExecutionError::OutputLimitExceeded),
+            Some(ref mut remaining) => *remaining -= 1,
+            None => {}
+        }
+        if let Err(e) = self.output.write_all(&[byte]) {
+            return Err(ExecutionError::Io(format!("{}", e)));
+        }
+        if self.flush_mode == FlushMode::EveryWrite {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), ExecutionError> {
+        match self.output.flush() {
+            Ok(_) => Ok(()),
+            Err(e) => Err(ExecutionError::Io(format!("{}", e))),
+        }
+    }
+
+    /// Run `instrs` as a whole program, flushing at the end if we
+    /// haven't been flushing as we go. We flush even if the program
+    /// fails, so users see the output from before the error.
+    fn run_program(&mut self, instrs: &[AstNode]) -> Result<(), ExecutionError> {
+        let result = self.run(instrs);
+        if self.flush_mode == FlushMode::OnExit {
+            let flushed = self.flush();
+            return result.and(flushed);
+        }
+        result
+    }
+
+    /// Use up one step for the instruction at `position`, returning
+    /// an error if there are none left.
+    fn step(&mut self, position: Option<Position>) -> Result<(), ExecutionError> {
+        if let (Some(ref mut counts), Some(position)) = (self.counts.as_mut(), position) {
+            *counts.entry(position).or_insert(0) += 1;
+        }
+
+        match self.steps_remaining {
+            Some(0) => Err(ExecutionError::FuelExhausted),
+            Some(ref mut steps) => {
+                *steps -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn run(&mut self, instrs: &[AstNode]) -> Result<(), ExecutionError> {
+        for instr in instrs {
+            // Loops use a step for each iteration instead.
+            if !matches!(*instr, Loop { .. }) {
+                self.step(get_position(instr))?;
+
+                if let Some(ref mut observer) = self.observer {
+                    observer(&ExecState {
+                        cells: &self.cells,
+                        cell_ptr: self.cell_ptr,
+                        instr: instr,
+                    });
+                }
+            }
+
+            match *instr {
+                Increment { amount, offset, position } => {
+                    let index = self.cell_index(offset, position)?;
+                    if self.overflow_mode == OverflowMode::Error {
+                        let result = i64::from(self.cells[index]) + i64::from(amount.0);
+                        if result < 0 || result > i64::from(self.mask) {
+                            return Err(ExecutionError::CellOverflow { position: position });
+                        }
+                    }
+                    self.cells[index] = self.cells[index].wrapping_add(widen(amount)) & self.mask;
+                }
+                Set { amount, offset, position } => {
+                    let index = self.cell_index(offset, position)?;
+                    self.cells[index] = widen(amount) & self.mask;
+                }
+                PointerIncrement { amount, position } => {
+                    self.cell_ptr = self.cell_index(amount, position)?;
+                }
+                MultiplyMove { ref changes, position } |
+                MultiplyCopy { ref changes, position, .. } => {
+                    let cell_value = self.cells[self.cell_ptr];
+                    // Like the loop it replaced, a multiply move does
+                    // nothing when the current cell is zero.
+                    if cell_value != 0 {
+                        for (offset, factor) in changes {
+                            let index = self.cell_index(*offset, position)?;
+                            let change = cell_value.wrapping_mul(widen(*factor));
+                            self.cells[index] = self.cells[index].wrapping_add(change) & self.mask;
+                        }
+                        if let MultiplyMove { .. } = *instr {
+                            self.cells[self.cell_ptr] = 0;
+                        }
+                    }
+                }
+                Move { offset, position } => {
+                    let cell_value = self.cells[self.cell_ptr];
+                    if cell_value != 0 {
+                        let index = self.cell_index(offset, position)?;
+                        self.cells[index] = self.cells[index].wrapping_add(cell_value) & self.mask;
+                        self.cells[self.cell_ptr] = 0;
+                    }
+                }
+                Read { .. } => {
+                    match (self.read_byte()?, self.eof_mode) {
+                        (Some(byte), _) => self.cells[self.cell_ptr] = u32::from(byte),
+                        (None, EofMode::Zero) => self.cells[self.cell_ptr] = 0,
+                        (None, EofMode::MinusOne) => self.cells[self.cell_ptr] = self.mask,
+                        (None, EofMode::Unchanged) => {}
+                    }
+                }
+                Write { .. } => {
+                    // Wider cells are truncated to their lowest byte.
+                    let byte = self.cells[self.cell_ptr] as u8;
+                    self.write_byte(byte)?;
+                }
+                ScanZero { step, position } => {
+                    // The first check is part of this instruction's
+                    // step, and each move uses another.
+                    while self.cells[self.cell_ptr] != 0 {
+                        self.step(position)?;
+                        self.cell_ptr = self.cell_index(step, position)?;
+                    }
+                }
+                Breakpoint { .. } => {
+                    if let Some(ref mut on_breakpoint) = self.on_breakpoint {
+                        on_breakpoint(&self.cells, self.cell_ptr);
+                    }
+                }
+                Loop { ref body, position } => {
+                    while self.cells[self.cell_ptr] != 0 {
+                        self.step(position)?;
+                        self.run(body)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run the instructions given, reading from `input` and writing to
+/// `output`. We provide the same 100,000 cells as compiled programs.
+#[cfg(feature = "std")]
+pub fn interpret<R: io::Read, W: io::Write>(instrs: &[AstNode],
+                                            input: &mut R,
+                                            output: &mut W)
+                                            -> Result<(), ExecutionError> {
+    interpret_with_options(instrs, input, output, &Options::default())
+}
+
+/// Run the instructions given, as `interpret`, but with the behaviour
+/// specified in `options`.
+#[cfg(feature = "std")]
+pub fn interpret_with_options<R: io::Read, W: io::Write>(instrs: &[AstNode],
+                                                         input: &mut R,
+                                                         output: &mut W,
+                                                         options: &Options)
+                                                         -> Result<(), ExecutionError> {
+    Machine::new(input, output, options)?.run_program(instrs)
+}
+
+/// Run the instructions given, as `interpret_with_options`, but call
+/// `on_breakpoint` with the tape and the cell pointer whenever we
+/// reach a Breakpoint. This is the building block for BF debuggers.
+#[cfg(feature = "std")]
+pub fn interpret_with_breakpoints<R, W, F>(instrs: &[AstNode],
+                                           input: &mut R,
+                                           output: &mut W,
+                                           options: &Options,
+                                           mut on_breakpoint: F)
+                                           -> Result<(), ExecutionError>
+    where R: io::Read,
+          W: io::Write,
+          F: FnMut(&[u32], usize)
+{
+    let mut machine = Machine::new(input, output, options)?;
+    machine.on_breakpoint = Some(&mut on_breakpoint);
+    machine.run_program(instrs)
+}
+
+/// Run the instructions given, as `interpret_with_options`, but call
+/// `observer` before executing each instruction other than a
+/// loop. Loop bodies are observed as they run. This allows debuggers
+/// to single-step, or to implement watchpoints.
+#[cfg(feature = "std")]
+pub fn interpret_with_observer<R, W, F>(instrs: &[AstNode],
+                                        input: &mut R,
+                                        output: &mut W,
+                                        options: &Options,
+                                        mut observer: F)
+                                        -> Result<(), ExecutionError>
+    where R: io::Read,
+          W: io::Write,
+          F: FnMut(&ExecState)
+{
+    let mut machine = Machine::new(input, output, options)?;
+    machine.observer = Some(&mut observer);
+    machine.run_program(instrs)
+}
+
+/// How many times each instruction ran, keyed by source position.
+/// Loops count each iteration.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg(feature = "std")]
+pub struct Profile {
+    pub counts: HashMap<Position, u64>,
+}
+
+#[cfg(feature = "std")]
+impl Profile {
+    /// Return every position and its count, with the hottest
+    /// positions first.
+    pub fn by_count(&self) -> Vec<(Position, u64)> {
+        let mut counts: Vec<_> = self.counts.iter().map(|(&pos, &count)| (pos, count)).collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.start.cmp(&b.0.start)));
+        counts
+    }
+}
+
+/// Run the instructions given, as `interpret_with_options`, and
+/// count how many times each instruction ran. This shows users where
+/// their program spends its time.
+#[cfg(feature = "std")]
+pub fn profile<R: io::Read, W: io::Write>(instrs: &[AstNode],
+                                          input: &mut R,
+                                          output: &mut W,
+                                          options: &Options)
+                                          -> Result<Profile, ExecutionError> {
+    let mut machine = Machine::new(input, output, options)?;
+    machine.counts = Some(HashMap::new());
+    machine.run_program(instrs)?;
+    Ok(Profile { counts: machine.counts.unwrap_or_default() })
+}
+
+/// Run the instructions given, as `interpret`, but stop with
+/// `ExecutionError::FuelExhausted` after `max_steps` steps. This
+/// ensures untrusted programs can't run forever.
+#[cfg(feature = "std")]
+pub fn interpret_with_limit<R: io::Read, W: io::Write>(instrs: &[AstNode],
+                                                       input: &mut R,
+                                                       output: &mut W,
+                                                       max_steps: u64)
+                                                       -> Result<(), ExecutionError> {
+    let options = Options { max_steps: Some(max_steps), ..Options::default() };
+    interpret_with_options(instrs, input, output, &options)
+}
+
+/// Run the instructions given, as `interpret`, but stop with
+/// `ExecutionError::OutputLimitExceeded` rather than write more than
+/// `max_bytes` bytes. This stops untrusted programs from filling the
+/// host's memory or disk. To limit steps as well, set both limits in
+/// `Options`.
+#[cfg(feature = "std")]
+pub fn interpret_with_output_limit<R: io::Read, W: io::Write>(instrs: &[AstNode],
+                                                              input: &mut R,
+                                                              output: &mut W,
+                                                              max_bytes: usize)
+                                                              -> Result<(), ExecutionError> {
+    let options = Options { max_output: Some(max_bytes), ..Options::default() };
+    interpret_with_options(instrs, input, output, &options)
+}
+
+/// Run the instructions given, as `interpret_with_options`, and
+/// return the cells that the program left behind, so users can see
+/// what a program did to memory.
+#[cfg(feature = "std")]
+pub fn interpret_full<R: io::Read, W: io::Write>(instrs: &[AstNode],
+                                                 input: &mut R,
+                                                 output: &mut W,
+                                                 options: &Options)
+                                                 -> Result<ExecOutcome, ExecutionError> {
+    let mut machine = Machine::new(input, output, options)?;
+    machine.run_program(instrs)?;
+    Ok(machine.outcome())
+}
+
+/// Run the instructions given, as `interpret`, with the bytes of
+/// `input` as input. Returns everything written, decoded as UTF-8, so
+/// programs can write multi-byte characters one byte at a time.
+/// Invalid UTF-8 is replaced with U+FFFD.
+#[cfg(feature = "std")]
+pub fn interpret_to_string(instrs: &[AstNode], input: &str) -> Result<String, ExecutionError> {
+    let output = interpret_bytes(instrs, input.as_bytes())?;
+    Ok(String::from_utf8_lossy(&output).into_owned())
+}
+
+/// Run the instructions given, as `interpret`, reading from `input`
+/// and returning every byte written.
+#[cfg(feature = "std")]
+pub fn interpret_bytes(instrs: &[AstNode], mut input: &[u8]) -> Result<Vec<u8>, ExecutionError> {
+    let mut output = vec![];
+    interpret(instrs, &mut input, &mut output)?;
+    Ok(output)
+}
+
+#[test]
+fn interpret_initial_tape() {
+    // Print the second cell, then the first cell plus one.
+    let instrs = parse(">.<+.").unwrap();
+    let options = Options { initial_tape: vec![b'a', b'b'], ..Options::default() };
+    let mut output = vec![];
+    let outcome = interpret_full(&instrs, &mut &b""[..], &mut output, &options).unwrap();
+    assert_eq!(output, b"bb");
+    assert_eq!(outcome.tape, vec![u32::from(b'b'), u32::from(b'b')]);
+}
+
+#[test]
+fn interpret_initial_tape_too_long() {
+    let options = Options {
+        pointer_mode: PointerMode::Wrap(2),
+        initial_tape: vec![1, 2, 3],
+        ..Options::default()
+    };
+    assert!(matches!(interpret_source_with_options(".", &[], &options),
+                     Err(ExecutionError::InvalidOptions(_))));
+}
+
+#[test]
+fn interpret_bytes_cat() {
+    let instrs = parse(",[.,]").unwrap();
+    assert_eq!(interpret_bytes(&instrs, &[104, 105]), Ok(vec![104, 105]));
+}
+
+#[test]
+fn interpret_full_final_tape() {
+    let instrs = parse("+++>++").unwrap();
+    let outcome = interpret_full(&instrs, &mut &b""[..], &mut vec![], &Options::default());
+    assert_eq!(outcome,
+               Ok(ExecOutcome {
+                   tape: vec![3, 2],
+                   pointer: 1,
+                   start: 0,
+               }));
+}
+
+#[test]
+fn interpret_full_includes_zero_cells() {
+    // Cells we visit count, even if they end up zero.
+    let instrs = parse(">>+-<").unwrap();
+    let outcome = interpret_full(&instrs, &mut &b""[..], &mut vec![], &Options::default());
+    assert_eq!(outcome.unwrap().tape, vec![0, 0, 0]);
+}
+
+#[test]
+fn interpret_full_bidirectional() {
+    let options = Options { tape_kind: TapeKind::Bidirectional, ..Options::default() };
+    let instrs = parse("+<<++").unwrap();
+    let outcome = interpret_full(&instrs, &mut &b""[..], &mut vec![], &options);
+    assert_eq!(outcome,
+               Ok(ExecOutcome {
+                   tape: vec![2, 0, 1],
+                   pointer: 0,
+                   start: 2,
+               }));
+}
+
+#[test]
+fn interpret_to_string_hello_world() {
+    let instrs = parse(include_str!("../sample_programs/hello_world.bf")).unwrap();
+    assert_eq!(interpret_to_string(&instrs, ""), Ok("Hello World!\n".to_owned()));
+}
+
+#[test]
+fn interpret_to_string_utf8() {
+    // Write 'é' as two bytes, then an invalid byte.
+    let source = "+".repeat(0xC3) + ".>" + &"+".repeat(0xA9) + ".>-.";
+    let instrs = parse(&source).unwrap();
+    assert_eq!(interpret_to_string(&instrs, ""), Ok("é\u{FFFD}".to_owned()));
+}
+
+#[test]
+fn interpret_to_string_reads_input() {
+    let instrs = parse(",[.,]").unwrap();
+    assert_eq!(interpret_to_string(&instrs, "hé"), Ok("hé".to_owned()));
+}
+
+#[cfg(test)]
+fn interpret_source(source: &str, input: &[u8]) -> Result<Vec<u8>, ExecutionError> {
+    interpret_source_with_options(source, input, &Options::default())
+}
+
+#[cfg(test)]
+fn interpret_source_with_options(source: &str,
+                                 input: &[u8],
+                                 options: &Options)
+                                 -> Result<Vec<u8>, ExecutionError> {
+    let instrs = parse(source).unwrap();
+    let mut output = vec![];
+    interpret_with_options(&instrs, &mut Cursor::new(input), &mut output, options)?;
+    Ok(output)
+}
+
+#[test]
+fn interpret_hello_world() {
+    let source = include_str!("../sample_programs/hello_world.bf");
+    assert_eq!(interpret_source(source, b""), Ok(b"Hello World!\n".to_vec()));
+}
+
+#[test]
+fn interpret_cat() {
+    assert_eq!(interpret_source(",[.,]", b"hello"), Ok(b"hello".to_vec()));
+}
+
+#[test]
+fn interpret_read_eof_is_zero() {
+    assert_eq!(interpret_source("+,.", b""), Ok(vec![0]));
+}
+
+#[test]
+fn interpret_cells_wrap() {
+    assert_eq!(interpret_source("-.", b""), Ok(vec![255]));
+}
+
+#[test]
+fn interpret_pointer_out_of_bounds() {
+    assert_eq!(interpret_source("+<", b""),
+               Err(ExecutionError::PointerOutOfBounds {
+                   cell_index: -1,
+                   position: Some(Position { start: 1, end: 1 }),
+               }));
+}
+
+#[test]
+fn interpret_optimized_multiply() {
+    use peephole::optimize;
+
+    let instrs = optimize(parse("++++++[->++++++++<]>.").unwrap(), &None).0;
+    let mut output = vec![];
+    interpret(&instrs, &mut Cursor::new(vec![]), &mut output).unwrap();
+    assert_eq!(output, vec![48]);
+}
+
+/// Increment the first cell `count` times, then write 1 if it's
+/// nonzero.
+#[cfg(test)]
+fn is_nonzero_after_increments(count: usize, cell_width: CellWidth) -> Vec<u8> {
+    let source = format!("{}[>+<[-]]>.", "+".repeat(count));
+    let options = Options { cell_width: cell_width, ..Options::default() };
+    interpret_source_with_options(&source, b"", &options).unwrap()
+}
+
+#[test]
+fn interpret_8_bit_cells_wrap_at_256() {
+    assert_eq!(is_nonzero_after_increments(256, CellWidth::Eight), vec![0]);
+}
+
+#[test]
+fn interpret_16_bit_cells_hold_256() {
+    assert_eq!(is_nonzero_after_increments(256, CellWidth::Sixteen), vec![1]);
+}
+
+#[test]
+fn interpret_16_bit_cells_wrap_at_65536() {
+    assert_eq!(is_nonzero_after_increments(65536, CellWidth::Sixteen), vec![0]);
+    assert_eq!(is_nonzero_after_increments(65536, CellWidth::ThirtyTwo), vec![1]);
+}
+
+#[test]
+fn interpret_16_bit_decrement_wraps() {
+    // Decrementing zero gives 0xFFFF, so the cell is still nonzero
+    // after 256 increments.
+    let options = Options { cell_width: CellWidth::Sixteen, ..Options::default() };
+    let source = format!("-{}[>+<[-]]>.", "+".repeat(256));
+    assert_eq!(interpret_source_with_options(&source, b"", &options),
+               Ok(vec![1]));
+}
+
+#[test]
+fn interpret_default_cell_width() {
+    assert_eq!(Options::default().cell_width, CellWidth::Eight);
+}
+
+#[cfg(test)]
+fn read_at_eof(eof_mode: EofMode) -> Vec<u8> {
+    let options = Options { eof_mode: eof_mode, ..Options::default() };
+    interpret_source_with_options("+,.", b"", &options).unwrap()
+}
+
+#[test]
+fn interpret_eof_modes() {
+    assert_eq!(Options::default().eof_mode, EofMode::Zero);
+    assert_eq!(read_at_eof(EofMode::Zero), vec![0]);
+    assert_eq!(read_at_eof(EofMode::MinusOne), vec![255]);
+    assert_eq!(read_at_eof(EofMode::Unchanged), vec![1]);
+}
+
+#[test]
+fn interpret_eof_minus_one_with_wide_cells() {
+    // With 16-bit cells, -1 is 0xFFFF so the cell is still nonzero
+    // after 255 increments.
+    let options = Options {
+        cell_width: CellWidth::Sixteen,
+        eof_mode: EofMode::MinusOne,
+        ..Options::default()
+    };
+    let source = format!(",{}[>+<[-]]>.", "+".repeat(255));
+    assert_eq!(interpret_source_with_options(&source, b"", &options),
+               Ok(vec![1]));
+}
+
+#[test]
+fn interpret_infinite_loop_exhausts_fuel() {
+    let instrs = parse("+[]").unwrap();
+    let mut output = vec![];
+    assert_eq!(interpret_with_limit(&instrs, &mut Cursor::new(vec![]), &mut output, 1000),
+               Err(ExecutionError::FuelExhausted));
+}
+
+#[test]
+fn interpret_within_fuel_limit() {
+    // Two increments, then one write.
+    let instrs = parse("++.").unwrap();
+    let mut output = vec![];
+    assert_eq!(interpret_with_limit(&instrs, &mut Cursor::new(vec![]), &mut output, 3),
+               Ok(()));
+    assert_eq!(output, vec![2]);
+
+    let mut output = vec![];
+    assert_eq!(interpret_with_limit(&instrs, &mut Cursor::new(vec![]), &mut output, 2),
+               Err(ExecutionError::FuelExhausted));
+    assert!(output.is_empty());
+}
+
+#[test]
+fn interpret_loop_iterations_use_fuel() {
+    // One increment, then one step for the single iteration, then
+    // one decrement inside the loop.
+    let instrs = parse("+[-]").unwrap();
+    let mut output = vec![];
+    assert_eq!(interpret_with_limit(&instrs, &mut Cursor::new(vec![]), &mut output, 3),
+               Ok(()));
+    assert_eq!(interpret_with_limit(&instrs, &mut Cursor::new(vec![]), &mut output, 2),
+               Err(ExecutionError::FuelExhausted));
+}
+
+#[test]
+fn interpret_output_limit() {
+    let instrs = parse("+[.]").unwrap();
+    let mut output = vec![];
+    assert_eq!(interpret_with_output_limit(&instrs, &mut Cursor::new(vec![]), &mut output, 5),
+               Err(ExecutionError::OutputLimitExceeded));
+    assert_eq!(output, vec![1; 5]);
+
+    // Writing exactly the limit is fine.
+    let instrs = parse("+..").unwrap();
+    let mut output = vec![];
+    assert_eq!(interpret_with_output_limit(&instrs, &mut Cursor::new(vec![]), &mut output, 2),
+               Ok(()));
+}
+
+#[test]
+fn interpret_output_and_fuel_limits() {
+    let instrs = parse("+[.]").unwrap();
+    let options = Options {
+        max_steps: Some(100),
+        max_output: Some(1000),
+        ..Options::default()
+    };
+    let mut output = vec![];
+    assert_eq!(interpret_with_options(&instrs, &mut Cursor::new(vec![]), &mut output, &options),
+               Err(ExecutionError::FuelExhausted));
+}
+
+#[test]
+fn interpret_breakpoint_sees_tape() {
+    let options = ParseOptions { recognize_breakpoints: true, ..ParseOptions::default() };
+    let (instrs, _) = parse_with_options("++>+++#<#", &options).unwrap();
+
+    let mut seen = vec![];
+    interpret_with_breakpoints(&instrs,
+                               &mut Cursor::new(vec![]),
+                               &mut vec![],
+                               &Options::default(),
+                               |cells: &[u32], cell_ptr| seen.push((cells[..2].to_vec(), cell_ptr)))
+        .unwrap();
+
+    assert_eq!(seen, vec![(vec![2, 3], 1), (vec![2, 3], 0)]);
+}
+
+#[test]
+fn interpret_ignores_breakpoints_without_callback() {
+    let options = ParseOptions { recognize_breakpoints: true, ..ParseOptions::default() };
+    let (instrs, _) = parse_with_options("+#.", &options).unwrap();
+    let mut output = vec![];
+    interpret(&instrs, &mut Cursor::new(vec![]), &mut output).unwrap();
+    assert_eq!(output, vec![1]);
+}
+
+#[test]
+fn interpret_observer_sees_each_instruction() {
+    let instrs = parse("++.").unwrap();
+
+    let mut seen = vec![];
+    interpret_with_observer(&instrs,
+                            &mut Cursor::new(vec![]),
+                            &mut vec![],
+                            &Options::default(),
+                            |state: &ExecState| seen.push((state.cells[0], state.instr.clone())))
+        .unwrap();
+
+    assert_eq!(seen,
+               vec![(0, instrs[0].clone()), (1, instrs[1].clone()), (2, instrs[2].clone())]);
+}
+
+#[test]
+fn interpret_observer_in_loops() {
+    let instrs = parse("++[>+<-]").unwrap();
+
+    let mut cell_ptrs = vec![];
+    interpret_with_observer(&instrs,
+                            &mut Cursor::new(vec![]),
+                            &mut vec![],
+                            &Options::default(),
+                            |state: &ExecState| cell_ptrs.push(state.cell_ptr))
+        .unwrap();
+
+    // Two increments, then two iterations of four instructions.
+    assert_eq!(cell_ptrs, vec![0, 0, 0, 1, 1, 0, 0, 1, 1, 0]);
+}
+
+#[test]
+fn profile_counted_loop() {
+    let instrs = parse("+++[->+<]").unwrap();
+    let mut output = vec![];
+    let profile = profile(&instrs, &mut Cursor::new(vec![]), &mut output, &Options::default())
+        .unwrap();
+
+    let single = |index| Position {
+        start: index,
+        end: index,
+    };
+    assert_eq!(profile.counts.get(&single(0)), Some(&1));
+    assert_eq!(profile.counts.get(&single(4)), Some(&3));
+    assert_eq!(profile.counts.get(&Position { start: 3, end: 8 }), Some(&3));
+
+    let by_count: Vec<_> = profile.by_count()
+        .into_iter()
+        .map(|(pos, count)| (pos.start, count))
+        .collect();
+    assert_eq!(by_count,
+               vec![(3, 3), (4, 3), (5, 3), (6, 3), (7, 3), (0, 1), (1, 1), (2, 1)]);
+}
+
+#[test]
+fn profile_skips_unentered_loops() {
+    let instrs = parse("[+]").unwrap();
+    let mut output = vec![];
+    let profile = profile(&instrs, &mut Cursor::new(vec![]), &mut output, &Options::default())
+        .unwrap();
+    assert!(profile.counts.is_empty());
+}
+
+#[test]
+fn interpret_overflow_wraps_by_default() {
+    let source = "+".repeat(256) + ".";
+    assert_eq!(interpret_source(&source, &[]), Ok(vec![0]));
+}
+
+#[test]
+fn interpret_overflow_error() {
+    let options = Options { overflow_mode: OverflowMode::Error, ..Options::default() };
+    let source = "+".repeat(255) + ".";
+    assert_eq!(interpret_source_with_options(&source, &[], &options), Ok(vec![255]));
+
+    let source = "+".repeat(256);
+    let position = Some(Position {
+        start: 255,
+        end: 255,
+    });
+    assert_eq!(interpret_source_with_options(&source, &[], &options),
+               Err(ExecutionError::CellOverflow { position: position }));
+}
+
+#[test]
+fn interpret_underflow_error() {
+    let options = Options { overflow_mode: OverflowMode::Error, ..Options::default() };
+    assert_eq!(interpret_source_with_options(">-", &[], &options),
+               Err(ExecutionError::CellOverflow { position: Some(Position { start: 1, end: 1 }) }));
+}
+
+#[test]
+fn interpret_overflow_error_wide_cells() {
+    let options = Options {
+        cell_width: CellWidth::Sixteen,
+        overflow_mode: OverflowMode::Error,
+        ..Options::default()
+    };
+    let source = "+".repeat(256) + ".";
+    assert_eq!(interpret_source_with_options(&source, &[], &options), Ok(vec![0]));
+}
+
+#[test]
+fn interpret_growable_tape() {
+    let options = Options { growable_tape: true, ..Options::default() };
+
+    // Walk past the initial allocation, and beyond the fixed tape
+    // size too.
+    let source = ">".repeat(MAX_CELL_INDEX + 1) + "+.";
+    assert_eq!(interpret_source_with_options(&source, &[], &options), Ok(vec![1]));
+    assert!(interpret_source(&source, &[]).is_err());
+}
+
+#[test]
+fn interpret_growable_tape_negative_pointer() {
+    let options = Options { growable_tape: true, ..Options::default() };
+    assert_eq!(interpret_source_with_options(">+<<", &[], &options),
+               Err(ExecutionError::PointerOutOfBounds {
+                   cell_index: -1,
+                   position: Some(Position { start: 3, end: 3 }),
+               }));
+}
+
+#[test]
+fn display_negative_pointer() {
+    let error = ExecutionError::PointerOutOfBounds {
+        cell_index: -2,
+        position: None,
+    };
+    assert_eq!(format!("{}", error),
+               "Tried to access cell -2, but there are no cells before cell 0.");
+}
+
+#[test]
+fn interpret_bidirectional_tape() {
+    let options = Options { tape_kind: TapeKind::Bidirectional, ..Options::default() };
+    assert_eq!(interpret_source_with_options("<+.>.", &[], &options), Ok(vec![1, 0]));
+
+    // Cells left of the start keep their values as the tape grows
+    // further left.
+    assert_eq!(interpret_source_with_options("<++<<<+>>>.", &[], &options),
+               Ok(vec![2]));
+}
+
+#[test]
+fn interpret_bidirectional_tape_far_left() {
+    let options = Options { tape_kind: TapeKind::Bidirectional, ..Options::default() };
+    let source = "<".repeat(MAX_CELL_INDEX * 3) + "+." + &">".repeat(MAX_CELL_INDEX * 3) + ".";
+    assert_eq!(interpret_source_with_options(&source, &[], &options), Ok(vec![1, 0]));
+}
+
+#[test]
+fn interpret_bidirectional_fixed_right_side() {
+    // Growing leftwards doesn't give us more cells on the right.
+    let options = Options { tape_kind: TapeKind::Bidirectional, ..Options::default() };
+    let source = "<".to_owned() + &">".repeat(MAX_CELL_INDEX + 2);
+    assert_eq!(interpret_source_with_options(&source, &[], &options),
+               Err(ExecutionError::PointerOutOfBounds {
+                   cell_index: MAX_CELL_INDEX as isize + 1,
+                   position: Some(Position {
+                       start: MAX_CELL_INDEX + 2,
+                       end: MAX_CELL_INDEX + 2,
+                   }),
+               }));
+}
+
+#[test]
+fn interpret_wrapping_pointer() {
+    let options = Options { pointer_mode: PointerMode::Wrap(3), ..Options::default() };
+    // < at cell 0 reaches the last cell.
+    assert_eq!(interpret_source_with_options("<+>>>.", &[], &options), Ok(vec![1]));
+    // > at the last cell reaches cell 0.
+    assert_eq!(interpret_source_with_options("+>>>.", &[], &options), Ok(vec![1]));
+}
+
+#[test]
+fn interpret_wrapping_pointer_no_cells() {
+    let options = Options { pointer_mode: PointerMode::Wrap(0), ..Options::default() };
+    assert!(matches!(interpret_source_with_options("+", &[], &options),
+                     Err(ExecutionError::InvalidOptions(_))));
+}
+
+#[test]
+fn interpret_wrapping_pointer_large_moves() {
+    use std::num::Wrapping;
+
+    let options = Options { pointer_mode: PointerMode::Wrap(3), ..Options::default() };
+    let instrs = [Set {
+                      amount: Wrapping(1),
+                      offset: -7,
+                      position: None,
+                  },
+                  PointerIncrement {
+                      amount: 8,
+                      position: None,
+                  },
+                  Write { position: None }];
+    let mut output = vec![];
+    interpret_with_options(&instrs, &mut &b""[..], &mut output, &options).unwrap();
+    assert_eq!(output, [1]);
+}
+
+#[test]
+fn interpret_scan_zero() {
+    let instrs = vec![Increment {
+                          amount: Wrapping(1),
+                          offset: 1,
+                          position: None,
+                      },
+                      Increment {
+                          amount: Wrapping(1),
+                          offset: 2,
+                          position: None,
+                      },
+                      PointerIncrement {
+                          amount: 1,
+                          position: None,
+                      },
+                      ScanZero {
+                          step: 1,
+                          position: None,
+                      },
+                      Increment {
+                          amount: Wrapping(5),
+                          offset: 0,
+                          position: None,
+                      },
+                      Write { position: None }];
+    let mut output = vec![];
+    interpret(&instrs, &mut Cursor::new(vec![]), &mut output).unwrap();
+    assert_eq!(output, vec![5]);
+
+    // Scanning left from the second cell stops at the first.
+    let instrs = vec![PointerIncrement {
+                          amount: 1,
+                          position: None,
+                      },
+                      Increment {
+                          amount: Wrapping(1),
+                          offset: 0,
+                          position: None,
+                      },
+                      ScanZero {
+                          step: -1,
+                          position: None,
+                      },
+                      PointerIncrement {
+                          amount: 1,
+                          position: None,
+                      },
+                      Write { position: None }];
+    let mut output = vec![];
+    interpret(&instrs, &mut Cursor::new(vec![]), &mut output).unwrap();
+    assert_eq!(output, vec![1]);
+}
+
+#[test]
+fn interpret_scan_zero_stride() {
+    // The first and third cells are nonzero, so [>>] skips over the
+    // zero second cell and stops at the fifth.
+    let instrs = vec![Increment {
+                          amount: Wrapping(1),
+                          offset: 0,
+                          position: None,
+                      },
+                      Increment {
+                          amount: Wrapping(1),
+                          offset: 2,
+                          position: None,
+                      },
+                      ScanZero {
+                          step: 2,
+                          position: None,
+                      }];
+    let outcome = interpret_full(&instrs, &mut &b""[..], &mut vec![], &Options::default());
+    assert_eq!(outcome.unwrap().pointer, 4);
+}
+
+/// A writer that counts how many times it's flushed.
+#[cfg(test)]
+#[derive(Default)]
+struct FlushCounter {
+    written: Vec<u8>,
+    flushes: usize,
+}
+
+#[cfg(test)]
+impl io::Write for FlushCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.written.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flushes += 1;
+        Ok(())
+    }
+}
+
+#[test]
+fn interpret_flush_every_write() {
+    let instrs = parse(",[.,]").unwrap();
+    let options = Options { flush_mode: FlushMode::EveryWrite, ..Options::default() };
+    let mut output = FlushCounter::default();
+    interpret_with_options(&instrs, &mut &b"abc"[..], &mut output, &options).unwrap();
+    assert_eq!(output.written, b"abc");
+    assert_eq!(output.flushes, 3);
+}
+
+#[test]
+fn interpret_flush_on_exit() {
+    assert_eq!(Options::default().flush_mode, FlushMode::OnExit);
+
+    let instrs = parse(",[.,]").unwrap();
+    let mut output = FlushCounter::default();
+    interpret(&instrs, &mut &b"abc"[..], &mut output).unwrap();
+    assert_eq!(output.flushes, 1);
+
+    // We still flush if the program fails.
+    let instrs = parse(".<").unwrap();
+    let mut output = FlushCounter::default();
+    assert!(interpret(&instrs, &mut &b""[..], &mut output).is_err());
+    assert_eq!(output.flushes, 1);
+}
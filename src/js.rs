@@ -0,0 +1,255 @@
+//! The JS module converts a BF AST to JavaScript, so programs can be
+//! embedded in web pages without a WebAssembly toolchain.
+//!
+//! The generated code is a single function `run(read, write)`. `read`
+//! is called with no arguments and should return the next byte of
+//! input. Cells are stored in a `Uint8Array`, so returning -1 at EOF
+//! behaves like compiled programs. `write` is called with each output
+//! byte.
+
+use std::num::Wrapping;
+use std::ops::Range;
+
+use bfir::{AstNode, SourceMap, source_map_from_offsets, source_span, write_run_length};
+use bfir::AstNode::*;
+
+#[cfg(test)]
+use bfir::parse;
+
+fn push_line(line: &str, indent: usize, out: &mut String) {
+    for _ in 0..indent {
+        out.push_str("    ");
+    }
+    out.push_str(line);
+    out.push('\n');
+}
+
+/// The JS expression for the cell at `offset` from the cell pointer.
+fn cell_at(offset: isize) -> String {
+    if offset == 0 {
+        "tape[ptr]".to_owned()
+    } else if offset < 0 {
+        format!("tape[ptr - {}]", -offset)
+    } else {
+        format!("tape[ptr + {}]", offset)
+    }
+}
+
+/// A JS statement that adds `amount` to `target`, using -= for
+/// negative amounts so the output is easier to read.
+fn add_statement(target: &str, amount: isize) -> String {
+    if amount < 0 {
+        format!("{} -= {};", target, -amount)
+    } else {
+        format!("{} += {};", target, amount)
+    }
+}
+
+/// The output offset and source span of each statement we emit, when
+/// we're asked for a source map.
+type Offsets<'a> = Option<&'a mut Vec<(usize, Range<usize>)>>;
+
+fn compile_instrs(instrs: &[AstNode], indent: usize, out: &mut String, offsets: &mut Offsets) {
+    let mut i = 0;
+    while i < instrs.len() {
+        let start = i;
+        let start_offset = out.len();
+
+        match instrs[i] {
+            Increment { amount, offset, .. } => {
+                // Collapse consecutive increments of the same cell
+                // into a single statement.
+                let mut total = amount;
+                while let Some(&Increment { amount, offset: next_offset, .. }) = instrs.get(i + 1) {
+                    if next_offset != offset {
+                        break;
+                    }
+                    total += amount;
+                    i += 1;
+                }
+                if total != Wrapping(0) {
+                    push_line(&add_statement(&cell_at(offset), total.0 as isize), indent, out);
+                }
+            }
+            PointerIncrement { amount, .. } => {
+                let mut total = amount;
+                while let Some(&PointerIncrement { amount, .. }) = instrs.get(i + 1) {
+                    total += amount;
+                    i += 1;
+                }
+                if total != 0 {
+                    push_line(&add_statement("ptr", total), indent, out);
+                }
+            }
+            Set { amount, offset, .. } => {
+                push_line(&format!("{} = {};", cell_at(offset), amount.0 as u8), indent, out);
+            }
+            MultiplyMove { ref changes, .. } |
+            MultiplyCopy { ref changes, .. } => {
+                push_line("if (tape[ptr]) {", indent, out);
+
+                for (offset, factor) in changes {
+                    let line = if factor.0 == 1 {
+                        format!("{} += tape[ptr];", cell_at(*offset))
+                    } else {
+                        format!("{} += tape[ptr] * {};", cell_at(*offset), factor.0 as u8)
+                    };
+                    push_line(&line, indent + 1, out);
+                }
+
+                if let MultiplyMove { .. } = instrs[i] {
+                    push_line("tape[ptr] = 0;", indent + 1, out);
+                }
+                push_line("}", indent, out);
+            }
+            Move { offset, .. } => {
+                push_line("if (tape[ptr]) {", indent, out);
+                push_line(&format!("{} += tape[ptr];", cell_at(offset)), indent + 1, out);
+                push_line("tape[ptr] = 0;", indent + 1, out);
+                push_line("}", indent, out);
+            }
+            Read { .. } => {
+                push_line("tape[ptr] = read();", indent, out);
+            }
+            Write { .. } => {
+                // `write` takes one byte at a time, but we can still
+                // avoid re-reading the tape for a run of writes.
+                let run_length = write_run_length(&instrs[i..]);
+                if run_length > 1 {
+                    let line = format!("for (var i = 0, byte = tape[ptr]; i < {}; i++) \
+                                        write(byte);",
+                                       run_length);
+                    push_line(&line, indent, out);
+                    i += run_length - 1;
+                } else {
+                    push_line("write(tape[ptr]);", indent, out);
+                }
+            }
+            ScanZero { step, .. } => {
+                push_line(&format!("while (tape[ptr]) {}", add_statement("ptr", step)),
+                          indent,
+                          out);
+            }
+            Breakpoint { .. } => {
+                push_line("debugger;", indent, out);
+            }
+            Loop { ref body, .. } => {
+                push_line("while (tape[ptr]) {", indent, out);
+                compile_instrs(body, indent + 1, out, offsets);
+                push_line("}", indent, out);
+            }
+        }
+
+        // Instructions we merged share a line, and instructions that
+        // cancelled out don't have one.
+        if let Some(ref mut offsets) = *offsets {
+            if out.len() > start_offset {
+                if let Some(span) = source_span(&instrs[start..i + 1]) {
+                    offsets.push((start_offset, span));
+                }
+            }
+        }
+        i += 1;
+    }
+}
+
+/// Convert the instructions given to a JS function that runs the
+/// program with a tape of `tape_length` cells.
+pub fn codegen_js(instrs: &[AstNode], tape_length: usize) -> String {
+    codegen_js_inner(instrs, tape_length, &mut None)
+}
+
+/// Convert the instructions given to a JS function, as `codegen_js`,
+/// and also return which source each line came from.
+pub fn codegen_js_with_source_map(instrs: &[AstNode], tape_length: usize) -> (String, SourceMap) {
+    let mut offsets = vec![];
+    let out = codegen_js_inner(instrs, tape_length, &mut Some(&mut offsets));
+    let source_map = source_map_from_offsets(&out, offsets);
+    (out, source_map)
+}
+
+fn codegen_js_inner(instrs: &[AstNode], tape_length: usize, offsets: &mut Offsets) -> String {
+    let mut out = String::new();
+    push_line("function run(read, write) {", 0, &mut out);
+    push_line(&format!("var tape = new Uint8Array({});", tape_length), 1, &mut out);
+    push_line("var ptr = 0;", 1, &mut out);
+    compile_instrs(instrs, 1, &mut out, offsets);
+    push_line("}", 0, &mut out);
+    out
+}
+
+#[test]
+fn codegen_js_complex_loop() {
+    let instrs = parse(".[,+]-").unwrap();
+    let expected = "function run(read, write) {
+    var tape = new Uint8Array(30000);
+    var ptr = 0;
+    write(tape[ptr]);
+    while (tape[ptr]) {
+        tape[ptr] = read();
+        tape[ptr] += 1;
+    }
+    tape[ptr] -= 1;
+}
+";
+    assert_eq!(codegen_js(&instrs, 30000), expected);
+}
+
+#[test]
+fn codegen_js_source_map() {
+    let instrs = parse("++-\n[,]+-").unwrap();
+    let (js_src, source_map) = codegen_js_with_source_map(&instrs, 10);
+    let lines: Vec<_> = js_src.lines().collect();
+
+    // The increments that cancel out at the end have no line.
+    assert_eq!(source_map, vec![(4, 0..3), (5, 4..7), (6, 5..6)]);
+    assert_eq!(lines[5 - 1], "    while (tape[ptr]) {");
+}
+
+#[test]
+fn codegen_js_collapses_increments() {
+    let instrs = parse("+++>>-<<<+-").unwrap();
+    let expected = "function run(read, write) {
+    var tape = new Uint8Array(10);
+    var ptr = 0;
+    tape[ptr] += 3;
+    ptr += 2;
+    tape[ptr] -= 1;
+    ptr -= 3;
+}
+";
+    assert_eq!(codegen_js(&instrs, 10), expected);
+}
+
+#[test]
+fn codegen_js_offsets() {
+    let instrs = [Set {
+                      amount: Wrapping(-1),
+                      offset: -2,
+                      position: None,
+                  },
+                  Increment {
+                      amount: Wrapping(5),
+                      offset: 1,
+                      position: None,
+                  }];
+    let js_src = codegen_js(&instrs, 10);
+    assert!(js_src.contains("    tape[ptr - 2] = 255;\n    tape[ptr + 1] += 5;\n"));
+}
+
+#[test]
+fn codegen_js_move() {
+    use peephole::extract_multiply;
+
+    let instrs = extract_multiply(parse("[-<<+>>]").unwrap());
+    let js_src = codegen_js(&instrs, 10);
+    assert!(js_src.contains("        tape[ptr - 2] += tape[ptr];\n"));
+}
+
+#[test]
+fn codegen_js_write_runs() {
+    let instrs = parse("...>.").unwrap();
+    let js_src = codegen_js(&instrs, 10);
+    assert!(js_src.contains("    for (var i = 0, byte = tape[ptr]; i < 3; i++) write(byte);\n    \
+                             ptr += 1;\n    write(tape[ptr]);\n"));
+}
@@ -0,0 +1,569 @@
+//! Convert BF IR to and from JSON, so other tools can consume parsed
+//! or optimised programs.
+//!
+//! Each instruction is an object with a single key naming the
+//! variant, and loops nest their bodies as arrays:
+//!
+//! ```text
+//! [{"Loop":{"body":[{"Increment":{"amount":-1,"offset":0,"position":{"start":1,"end":1}}}],
+//!           "position":{"start":0,"end":2}}}]
+//! ```
+//!
+//! This is the same layout that serde uses for enums, but we don't
+//! need the extra dependency for such a small format.
+//!
+//! This module is only available with the `json` feature, which is
+//! enabled by default.
+
+use std::collections::BTreeMap;
+use std::num::Wrapping;
+use std::str;
+
+use bfir::{AstNode, Cell, Position, DEFAULT_MAX_NESTING};
+use bfir::AstNode::*;
+
+#[cfg(test)]
+use bfir::parse;
+
+fn position_to_json(position: Option<Position>) -> String {
+    match position {
+        Some(Position { start, end }) => format!("{{\"start\":{},\"end\":{}}}", start, end),
+        None => "null".to_owned(),
+    }
+}
+
+fn changes_to_json(changes: &BTreeMap<isize, Cell>) -> String {
+    // JSON keys must be strings.
+    let changes: Vec<_> = changes.iter()
+        .map(|(offset, factor)| format!("\"{}\":{}", offset, factor.0))
+        .collect();
+    format!("{{{}}}", changes.join(","))
+}
+
+fn instr_to_json(instr: &AstNode, out: &mut String) {
+    match *instr {
+        Increment { amount, offset, position } => {
+            out.push_str(&format!("{{\"Increment\":{{\"amount\":{},\"offset\":{},\"position\":{}}}}}",
+                                  amount.0,
+                                  offset,
+                                  position_to_json(position)));
+        }
+        PointerIncrement { amount, position } => {
+            out.push_str(&format!("{{\"PointerIncrement\":{{\"amount\":{},\"position\":{}}}}}",
+                                  amount,
+                                  position_to_json(position)));
+        }
+        Read { position } => {
+            out.push_str(&format!("{{\"Read\":{{\"position\":{}}}}}", position_to_json(position)));
+        }
+        Write { position } => {
+            out.push_str(&format!("{{\"Write\":{{\"position\":{}}}}}", position_to_json(position)));
+        }
+        ScanZero { step, position } => {
+            out.push_str(&format!("{{\"ScanZero\":{{\"step\":{},\"position\":{}}}}}",
+                                  step,
+                                  position_to_json(position)));
+        }
+        Breakpoint { position } => {
+            out.push_str(&format!("{{\"Breakpoint\":{{\"position\":{}}}}}",
+                                  position_to_json(position)));
+        }
+        Loop { ref body, position } => {
+            out.push_str("{\"Loop\":{\"body\":");
+            instrs_to_json(body, out);
+            out.push_str(&format!(",\"position\":{}}}}}", position_to_json(position)));
+        }
+        Set { amount, offset, position } => {
+            out.push_str(&format!("{{\"Set\":{{\"amount\":{},\"offset\":{},\"position\":{}}}}}",
+                                  amount.0,
+                                  offset,
+                                  position_to_json(position)));
+        }
+        MultiplyMove { ref changes, position } => {
+            out.push_str(&format!("{{\"MultiplyMove\":{{\"changes\":{},\"position\":{}}}}}",
+                                  changes_to_json(changes),
+                                  position_to_json(position)));
+        }
+        Move { offset, position } => {
+            out.push_str(&format!("{{\"Move\":{{\"offset\":{},\"position\":{}}}}}",
+                                  offset,
+                                  position_to_json(position)));
+        }
+        MultiplyCopy { ref changes, temp, position } => {
+            out.push_str(&format!("{{\"MultiplyCopy\":{{\"changes\":{},\"temp\":{},\
+                                   \"position\":{}}}}}",
+                                  changes_to_json(changes),
+                                  temp,
+                                  position_to_json(position)));
+        }
+    }
+}
+
+fn instrs_to_json(instrs: &[AstNode], out: &mut String) {
+    out.push('[');
+    for (i, instr) in instrs.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        instr_to_json(instr, out);
+    }
+    out.push(']');
+}
+
+/// Serialise the instructions given as a JSON array.
+pub fn to_json(instrs: &[AstNode]) -> String {
+    let mut out = String::new();
+    instrs_to_json(instrs, &mut out);
+    out
+}
+
+/// How deeply arrays and objects may nest, so deeply nested input is
+/// an error rather than a stack overflow. Each loop is three levels
+/// deep (the instruction, its fields and its body), so this allows
+/// fewer nested loops than `bfir::parse` does.
+const MAX_DEPTH: usize = DEFAULT_MAX_NESTING;
+
+/// The subset of JSON that we need to represent BF IR.
+#[derive(Debug)]
+enum Value {
+    Null,
+    Number(i64),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    index: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(&self, message: &str) -> Result<T, String> {
+        Err(format!("{} at byte {}", message, self.index))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(&b) = self.bytes.get(self.index) {
+            if b == b' ' || b == b'\n' || b == b'\r' || b == b'\t' {
+                self.index += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.bytes.get(self.index).cloned()
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<(), String> {
+        if self.peek() == Some(expected) {
+            self.index += 1;
+            Ok(())
+        } else {
+            self.error(&format!("Expected '{}'", expected as char))
+        }
+    }
+
+    /// Parse a value, which is nested inside `depth` arrays or
+    /// objects.
+    fn parse_value(&mut self, depth: usize) -> Result<Value, String> {
+        if depth == MAX_DEPTH {
+            return self.error(&format!("Nested more than {} deep", MAX_DEPTH));
+        }
+        match self.peek() {
+            Some(b'n') => {
+                if self.bytes[self.index..].starts_with(b"null") {
+                    self.index += 4;
+                    Ok(Value::Null)
+                } else {
+                    self.error("Invalid literal")
+                }
+            }
+            Some(b'"') => self.error("Strings are only supported as keys"),
+            Some(b'[') => self.parse_array(depth),
+            Some(b'{') => self.parse_object(depth),
+            Some(b'-') | Some(b'0'..=b'9') => self.parse_number(),
+            Some(_) => self.error("Unexpected character"),
+            None => self.error("Unexpected end of input"),
+        }
+    }
+
+    fn parse_array(&mut self, depth: usize) -> Result<Value, String> {
+        self.expect(b'[')?;
+        let mut items = vec![];
+        if self.peek() == Some(b']') {
+            self.index += 1;
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.parse_value(depth + 1)?);
+            match self.peek() {
+                Some(b',') => self.index += 1,
+                Some(b']') => {
+                    self.index += 1;
+                    return Ok(Value::Array(items));
+                }
+                _ => return self.error("Expected ',' or ']'"),
+            }
+        }
+    }
+
+    fn parse_object(&mut self, depth: usize) -> Result<Value, String> {
+        self.expect(b'{')?;
+        let mut fields = vec![];
+        if self.peek() == Some(b'}') {
+            self.index += 1;
+            return Ok(Value::Object(fields));
+        }
+        loop {
+            if self.peek() != Some(b'"') {
+                return self.error("Expected a string key");
+            }
+            let key = self.parse_string()?;
+            self.expect(b':')?;
+            fields.push((key, self.parse_value(depth + 1)?));
+            match self.peek() {
+                Some(b',') => self.index += 1,
+                Some(b'}') => {
+                    self.index += 1;
+                    return Ok(Value::Object(fields));
+                }
+                _ => return self.error("Expected ',' or '}'"),
+            }
+        }
+    }
+
+    /// Parse a string. None of our strings need escapes, so we only
+    /// support \" and \\.
+    fn parse_string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut bytes = vec![];
+        loop {
+            match self.bytes.get(self.index) {
+                Some(&b'"') => {
+                    self.index += 1;
+                    break;
+                }
+                Some(&b'\\') => {
+                    match self.bytes.get(self.index + 1) {
+                        Some(&b) if b == b'"' || b == b'\\' => bytes.push(b),
+                        _ => return self.error("Unsupported escape"),
+                    }
+                    self.index += 2;
+                }
+                Some(&b) => {
+                    bytes.push(b);
+                    self.index += 1;
+                }
+                None => return self.error("Unterminated string"),
+            }
+        }
+        match String::from_utf8(bytes) {
+            Ok(s) => Ok(s),
+            Err(_) => self.error("Invalid UTF-8 in string"),
+        }
+    }
+
+    /// Parse an integer. BF IR never contains fractional numbers.
+    fn parse_number(&mut self) -> Result<Value, String> {
+        let start = self.index;
+        if self.bytes[self.index] == b'-' {
+            self.index += 1;
+        }
+        while let Some(&(b'0'..=b'9')) = self.bytes.get(self.index) {
+            self.index += 1;
+        }
+        // We only ever consume ASCII, so this is valid UTF-8.
+        let digits = str::from_utf8(&self.bytes[start..self.index]).unwrap();
+        match digits.parse() {
+            Ok(n) => Ok(Value::Number(n)),
+            Err(_) => self.error("Invalid number"),
+        }
+    }
+}
+
+fn get_field<'a>(fields: &'a [(String, Value)], name: &str) -> Result<&'a Value, String> {
+    match fields.iter().find(|field| field.0 == name) {
+        Some(field) => Ok(&field.1),
+        None => Err(format!("Missing field `{}`", name)),
+    }
+}
+
+fn to_isize(value: &Value) -> Result<isize, String> {
+    match *value {
+        Value::Number(n) if n >= isize::MIN as i64 && n <= isize::MAX as i64 => {
+            Ok(n as isize)
+        }
+        _ => Err(format!("Expected an integer, got {:?}", value)),
+    }
+}
+
+fn to_usize(value: &Value) -> Result<usize, String> {
+    match *value {
+        Value::Number(n) if n >= 0 => Ok(n as usize),
+        _ => Err(format!("Expected a non-negative integer, got {:?}", value)),
+    }
+}
+
+fn to_cell(value: &Value) -> Result<Cell, String> {
+    match *value {
+        Value::Number(n) if n >= i64::from(i8::MIN) && n <= i64::from(i8::MAX) => {
+            Ok(Wrapping(n as i8))
+        }
+        _ => Err(format!("Expected a cell value between -128 and 127, got {:?}", value)),
+    }
+}
+
+fn to_position(value: &Value) -> Result<Option<Position>, String> {
+    match *value {
+        Value::Null => Ok(None),
+        Value::Object(ref fields) => {
+            Ok(Some(Position {
+                start: to_usize(get_field(fields, "start")?)?,
+                end: to_usize(get_field(fields, "end")?)?,
+            }))
+        }
+        _ => Err(format!("Expected a position or null, got {:?}", value)),
+    }
+}
+
+fn to_changes(value: &Value) -> Result<BTreeMap<isize, Cell>, String> {
+    let mut changes = BTreeMap::new();
+    match *value {
+        Value::Object(ref change_fields) => {
+            for (offset, factor) in change_fields {
+                let offset = match offset.parse() {
+                    Ok(offset) => offset,
+                    Err(_) => return Err(format!("Invalid offset `{}`", offset)),
+                };
+                changes.insert(offset, to_cell(factor)?);
+            }
+        }
+        ref other => return Err(format!("Expected an object of changes, got {:?}", other)),
+    }
+    Ok(changes)
+}
+
+fn to_instrs(value: &Value) -> Result<Vec<AstNode>, String> {
+    match *value {
+        Value::Array(ref items) => items.iter().map(to_instr).collect(),
+        _ => Err(format!("Expected an array of instructions, got {:?}", value)),
+    }
+}
+
+fn to_instr(value: &Value) -> Result<AstNode, String> {
+    let (name, fields) = match *value {
+        Value::Object(ref variants) if variants.len() == 1 => {
+            match variants[0] {
+                (ref name, Value::Object(ref fields)) => (name, fields),
+                _ => return Err(format!("Expected an object for {}", variants[0].0)),
+            }
+        }
+        _ => return Err(format!("Expected an instruction, got {:?}", value)),
+    };
+
+    let position = to_position(get_field(fields, "position")?)?;
+    if name == "Loop" {
+        return Ok(Loop {
+            body: to_instrs(get_field(fields, "body")?)?,
+            position: position,
+        });
+    }
+    to_simple_instr(name, fields, position)
+}
+
+/// Convert an instruction other than a loop. This is separate from
+/// `to_instr` so the recursion through loop bodies uses as little
+/// stack as possible.
+fn to_simple_instr(name: &str,
+                   fields: &[(String, Value)],
+                   position: Option<Position>)
+                   -> Result<AstNode, String> {
+    match name {
+        "Increment" => {
+            Ok(Increment {
+                amount: to_cell(get_field(fields, "amount")?)?,
+                offset: to_isize(get_field(fields, "offset")?)?,
+                position: position,
+            })
+        }
+        "PointerIncrement" => {
+            Ok(PointerIncrement {
+                amount: to_isize(get_field(fields, "amount")?)?,
+                position: position,
+            })
+        }
+        "Read" => Ok(Read { position: position }),
+        "Write" => Ok(Write { position: position }),
+        "ScanZero" => {
+            Ok(ScanZero {
+                step: to_isize(get_field(fields, "step")?)?,
+                position: position,
+            })
+        }
+        "Breakpoint" => Ok(Breakpoint { position: position }),
+        "Set" => {
+            Ok(Set {
+                amount: to_cell(get_field(fields, "amount")?)?,
+                offset: to_isize(get_field(fields, "offset")?)?,
+                position: position,
+            })
+        }
+        "MultiplyMove" => {
+            Ok(MultiplyMove {
+                changes: to_changes(get_field(fields, "changes")?)?,
+                position: position,
+            })
+        }
+        "Move" => {
+            Ok(Move {
+                offset: to_isize(get_field(fields, "offset")?)?,
+                position: position,
+            })
+        }
+        "MultiplyCopy" => {
+            Ok(MultiplyCopy {
+                changes: to_changes(get_field(fields, "changes")?)?,
+                temp: to_isize(get_field(fields, "temp")?)?,
+                position: position,
+            })
+        }
+        _ => Err(format!("Unknown instruction `{}`", name)),
+    }
+}
+
+/// Deserialise instructions from JSON produced by `to_json`.
+pub fn from_json(source: &str) -> Result<Vec<AstNode>, String> {
+    let mut parser = Parser {
+        bytes: source.as_bytes(),
+        index: 0,
+    };
+    let value = parser.parse_value(0)?;
+    if parser.peek().is_some() {
+        return parser.error("Unexpected trailing characters");
+    }
+    to_instrs(&value)
+}
+
+#[test]
+fn to_json_nested_loop() {
+    let instrs = parse("[-]").unwrap();
+    assert_eq!(to_json(&instrs),
+               "[{\"Loop\":{\"body\":[{\"Increment\":{\"amount\":-1,\"offset\":0,\
+                \"position\":{\"start\":1,\"end\":1}}}],\"position\":{\"start\":0,\"end\":2}}}]");
+}
+
+#[test]
+fn json_roundtrip() {
+    let instrs = parse(include_str!("../sample_programs/hello_world.bf")).unwrap();
+    assert_eq!(from_json(&to_json(&instrs)), Ok(instrs));
+}
+
+#[test]
+fn json_roundtrip_optimised() {
+    use peephole::optimize;
+
+    let instrs = optimize(parse("+>-<[->++>+++<<],.").unwrap(), &None).0;
+    assert_eq!(from_json(&to_json(&instrs)), Ok(instrs));
+
+    let mut changes = BTreeMap::new();
+    changes.insert(-3, Wrapping(-128));
+    changes.insert(2, Wrapping(127));
+    let instrs = vec![MultiplyMove {
+                          changes: changes,
+                          position: None,
+                      },
+                      Set {
+                          amount: Wrapping(0),
+                          offset: -1,
+                          position: None,
+                      }];
+    assert_eq!(from_json(&to_json(&instrs)), Ok(instrs));
+}
+
+#[test]
+fn json_multiply_copy() {
+    let mut changes = BTreeMap::new();
+    changes.insert(1, Wrapping(1));
+    let instrs = vec![MultiplyCopy {
+                          changes: changes,
+                          temp: 2,
+                          position: None,
+                      }];
+    let json = "[{\"MultiplyCopy\":{\"changes\":{\"1\":1},\"temp\":2,\"position\":null}}]";
+    assert_eq!(to_json(&instrs), json);
+    assert_eq!(from_json(json), Ok(instrs));
+}
+
+#[test]
+fn json_move() {
+    let instrs = vec![Move {
+                          offset: -2,
+                          position: None,
+                      }];
+    let json = "[{\"Move\":{\"offset\":-2,\"position\":null}}]";
+    assert_eq!(to_json(&instrs), json);
+    assert_eq!(from_json(json), Ok(instrs));
+}
+
+#[test]
+fn json_round_trip_breakpoint() {
+    let instrs = vec![Breakpoint { position: Some(Position { start: 3, end: 3 }) }];
+    assert_eq!(to_json(&instrs),
+               "[{\"Breakpoint\":{\"position\":{\"start\":3,\"end\":3}}}]");
+    assert_eq!(from_json(&to_json(&instrs)), Ok(instrs));
+}
+
+#[test]
+fn from_json_whitespace() {
+    let json = " [ { \"Write\" : { \"position\" : null } } ] ";
+    assert_eq!(from_json(json), Ok(vec![Write { position: None }]));
+}
+
+#[test]
+fn from_json_errors() {
+    assert!(from_json("").is_err());
+    assert!(from_json("[").is_err());
+    assert!(from_json("[] []").is_err());
+    assert!(from_json("[{\"Jump\":{\"position\":null}}]").is_err());
+    assert!(from_json("[{\"Set\":{\"amount\":128,\"offset\":0,\"position\":null}}]").is_err());
+    assert!(from_json("[{\"Set\":{\"offset\":0,\"position\":null}}]").is_err());
+}
+
+#[test]
+fn from_json_deep_nesting() {
+    let nested = |depth| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+    let nested_loops = |depth| {
+        let mut json = "[]".to_owned();
+        for _ in 0..depth {
+            json = format!("[{{\"Loop\":{{\"body\":{},\"position\":null}}}}]", json);
+        }
+        json
+    };
+
+    let too_deep = from_json(&nested(MAX_DEPTH + 1)).unwrap_err();
+    assert!(too_deep.starts_with("Nested more than"));
+
+    assert!(from_json(&nested_loops(MAX_DEPTH / 3)).is_ok());
+    assert!(from_json(&nested_loops(MAX_DEPTH / 3 + 1)).is_err());
+    // This used to overflow the stack.
+    assert!(from_json(&"[".repeat(200000)).is_err());
+}
+
+#[test]
+fn from_json_zero_increment_strict() {
+    use bfir::{validate_ir, IrChecks};
+
+    let json = "[{\"Increment\":{\"amount\":0,\"offset\":0,\"position\":null}}]";
+    let instrs = from_json(json).unwrap();
+    assert!(validate_ir(&instrs, IrChecks::default()).is_ok());
+
+    let strict = IrChecks {
+        normalized: true,
+        non_empty_loops: false,
+    };
+    let errors = validate_ir(&instrs, strict).unwrap_err();
+    assert_eq!(errors[0].message, "Increment by zero in normalized IR");
+}
@@ -0,0 +1,196 @@
+#![warn(trivial_numeric_casts)]
+
+// The parser, optimiser and bytecode interpreter are available
+// without std.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+// option_unwrap_used is specific to clippy. However, we don't want to
+// add clippy to the build requirements, so we build without it and
+// ignore any warnings about rustc not recognising clippy's lints.
+#![allow(unknown_lints)]
+
+// TODO: enable this warning and cleanup.
+#![allow(option_unwrap_used)]
+
+//! bfc is a highly optimising compiler for BF.
+//!
+//! This library contains everything except the command line
+//! interface, so other tools can parse, optimise, interpret or
+//! compile BF programs.
+//!
+//! `program::Program` is the simplest way to get started, and `run`
+//! executes a program in a single call.
+//!
+//! Most of the library needs the `std` feature, which is enabled by
+//! default. `bfir`, `bounds`, `peephole`, `bytecode` and `embedded`
+//! only need `core` and `alloc`, for running BF on embedded targets.
+//! The `cranelift` feature adds a JIT compiler.
+
+#[cfg(feature = "std")]
+extern crate core;
+#[cfg_attr(not(feature = "std"), macro_use)]
+extern crate alloc;
+
+#[cfg(feature = "std")]
+extern crate llvm_sys;
+#[cfg(feature = "std")]
+extern crate itertools;
+#[cfg(feature = "std")]
+extern crate quickcheck;
+#[cfg(feature = "std")]
+extern crate rand;
+#[cfg(feature = "std")]
+extern crate ansi_term;
+
+#[cfg(feature = "std")]
+#[macro_use]
+extern crate matches;
+
+#[cfg(feature = "cranelift")]
+extern crate cranelift_codegen;
+#[cfg(feature = "cranelift")]
+extern crate cranelift_frontend;
+#[cfg(feature = "cranelift")]
+extern crate cranelift_jit;
+#[cfg(feature = "cranelift")]
+extern crate cranelift_module;
+#[cfg(feature = "cranelift")]
+extern crate cranelift_native;
+
+pub mod embedded;
+pub mod bfir;
+#[cfg(feature = "std")]
+pub mod llvm;
+pub mod peephole;
+pub mod bounds;
+#[cfg(feature = "std")]
+pub mod execution;
+pub mod interpreter;
+pub mod bytecode;
+#[cfg(feature = "std")]
+pub mod c;
+#[cfg(feature = "std")]
+pub mod wat;
+#[cfg(feature = "std")]
+pub mod llvm_text;
+#[cfg(feature = "std")]
+pub mod js;
+#[cfg(feature = "json")]
+pub mod json;
+#[cfg(feature = "std")]
+pub mod binary;
+#[cfg(feature = "std")]
+pub mod stats;
+#[cfg(feature = "std")]
+pub mod analysis;
+#[cfg(feature = "std")]
+pub mod dot;
+#[cfg(feature = "std")]
+pub mod minify;
+#[cfg(feature = "std")]
+pub mod diff;
+#[cfg(feature = "std")]
+pub mod x86_64;
+#[cfg(feature = "cranelift")]
+pub mod cranelift;
+pub mod diagnostics;
+#[cfg(feature = "std")]
+pub mod builder;
+#[cfg(feature = "std")]
+pub mod program;
+#[cfg(all(feature = "std", any(test, feature = "testing")))]
+pub mod testing;
+
+#[cfg(test)]
+mod peephole_tests;
+#[cfg(test)]
+mod soundness_tests;
+#[cfg(test)]
+mod llvm_tests;
+
+#[cfg(feature = "std")]
+use std::fmt;
+#[cfg(feature = "std")]
+use std::io::{Read, Write};
+
+#[cfg(feature = "std")]
+use bfir::ParseError;
+#[cfg(feature = "std")]
+use interpreter::ExecutionError;
+#[cfg(feature = "std")]
+use peephole::OptLevel;
+
+/// Reasons that `run` can fail.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub enum BfError {
+    Parse(ParseError),
+    Execution(ExecutionError),
+}
+
+#[cfg(feature = "std")]
+impl From<ParseError> for BfError {
+    fn from(error: ParseError) -> Self {
+        BfError::Parse(error)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<ExecutionError> for BfError {
+    fn from(error: ExecutionError) -> Self {
+        BfError::Execution(error)
+    }
+}
+
+#[cfg(feature = "std")]
+impl fmt::Display for BfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BfError::Parse(ref error) => write!(f, "{}", error),
+            BfError::Execution(ref error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// Parse, optimise and interpret the BF program in `source`, reading
+/// from `input` and writing to `output`.
+///
+/// ```
+/// use bfc::peephole::OptLevel;
+///
+/// let source = include_str!("../sample_programs/hello_world.bf");
+/// let mut output = vec![];
+/// bfc::run(source, &mut &b""[..], &mut output, OptLevel::Basic).unwrap();
+/// assert_eq!(String::from_utf8(output).unwrap(), "Hello World!\n");
+/// ```
+#[cfg(feature = "std")]
+pub fn run(source: &str,
+           mut input: &mut dyn Read,
+           mut output: &mut dyn Write,
+           opt: OptLevel)
+           -> Result<(), BfError> {
+    let instrs = bfir::parse(source)?;
+    let (instrs, _) = peephole::optimize_for_level(instrs, opt, &None);
+    interpreter::interpret(&instrs, &mut input, &mut output)?;
+    Ok(())
+}
+
+#[test]
+fn run_reports_parse_errors() {
+    let result = run("[", &mut &b""[..], &mut vec![], OptLevel::Basic);
+    assert!(matches!(result, Err(BfError::Parse(_))));
+}
+
+#[test]
+fn run_reports_execution_errors() {
+    let result = run("<+", &mut &b""[..], &mut vec![], OptLevel::None);
+    assert!(matches!(result,
+                     Err(BfError::Execution(ExecutionError::PointerOutOfBounds { .. }))));
+}
+
+#[test]
+fn run_reads_input() {
+    let mut output = vec![];
+    run(",[.,]", &mut &b"abc"[..], &mut output, OptLevel::Aggressive).unwrap();
+    assert_eq!(output, b"abc");
+}
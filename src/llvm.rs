@@ -13,7 +13,7 @@ use std::ffi::{CString, CStr};
 use std::ptr::null_mut;
 use std::str;
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::num::Wrapping;
 
 use bfir::{AstNode, Cell};
@@ -440,7 +440,11 @@ unsafe fn compile_set(amount: Cell,
     bb
 }
 
-unsafe fn compile_multiply_move(changes: &HashMap<isize, Cell>,
+/// Add the current cell times each factor to the cells in `changes`.
+/// We then zero the current cell if `zero_source` is set, as for a
+/// MultiplyMove rather than a MultiplyCopy.
+unsafe fn compile_multiply_move(changes: &BTreeMap<isize, Cell>,
+                                zero_source: bool,
                                 module: &mut Module,
                                 bb: LLVMBasicBlockRef,
                                 ctx: CompileContext)
@@ -471,8 +475,10 @@ unsafe fn compile_multiply_move(changes: &HashMap<isize, Cell>,
     // In the multiply body, do the mulitply
     builder.position_at_end(multiply_body);
 
-    // Zero the current cell.
-    LLVMBuildStore(builder.builder, int8(0), cell_val_ptr);
+    // Zero the current cell. We've already loaded its value.
+    if zero_source {
+        LLVMBuildStore(builder.builder, int8(0), cell_val_ptr);
+    }
 
     let mut targets: Vec<_> = changes.keys().collect();
     targets.sort();
@@ -654,11 +660,32 @@ unsafe fn compile_instr(instr: &AstNode,
     match *instr {
         Increment { amount, offset, .. } => compile_increment(amount, offset, module, bb, ctx),
         Set { amount, offset, .. } => compile_set(amount, offset, module, bb, ctx),
-        MultiplyMove { ref changes, .. } => compile_multiply_move(changes, module, bb, ctx),
+        MultiplyMove { ref changes, .. } => {
+            compile_multiply_move(changes, true, module, bb, ctx)
+        }
+        Move { offset, .. } => {
+            // LLVM removes the multiplication by one.
+            let mut changes = BTreeMap::new();
+            changes.insert(offset, Wrapping(1));
+            compile_multiply_move(&changes, true, module, bb, ctx)
+        }
+        MultiplyCopy { ref changes, .. } => {
+            compile_multiply_move(changes, false, module, bb, ctx)
+        }
         PointerIncrement { amount, .. } => compile_ptr_increment(amount, module, bb, ctx),
         Read { .. } => compile_read(module, bb, ctx),
         Write { .. } => compile_write(module, bb, ctx),
         Loop { ref body, .. } => compile_loop(body, start_instr, module, main_fn, bb, ctx),
+        ScanZero { step, .. } => {
+            // A scan is just a loop that moves the pointer.
+            let body = [PointerIncrement {
+                            amount: step,
+                            position: None,
+                        }];
+            compile_loop(&body, start_instr, module, main_fn, bb, ctx)
+        }
+        // Compiled programs have no debugger to stop in.
+        Breakpoint { .. } => bb,
     }
 }
 
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::ffi::CString;
 use std::num::Wrapping;
 
@@ -388,7 +388,7 @@ attributes #0 = { argmemonly nounwind }
 
 #[test]
 fn compile_multiply_move() {
-    let mut changes = HashMap::new();
+    let mut changes = BTreeMap::new();
     changes.insert(1, Wrapping(2));
     changes.insert(2, Wrapping(3));
     let instrs = vec![MultiplyMove {
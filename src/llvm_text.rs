@@ -0,0 +1,297 @@
+//! The LLVM text module converts a BF AST to textual LLVM IR, so
+//! programs can be optimised with `opt` and compiled with `llc`
+//! without linking against LLVM.
+//!
+//! The IR uses opaque pointers, so it needs LLVM 15 or later (or
+//! `-opaque-pointers` with LLVM 14). The cell pointer lives in an
+//! `alloca`, so loops are plain branches between basic blocks and we
+//! never need phi nodes. `opt -passes=mem2reg` will turn it into SSA
+//! registers.
+//!
+//! The generated `main` calls `getchar` and `putchar` from the C
+//! library for I/O, and allocates the cells with `calloc`.
+
+use bfir::{AstNode, Cell};
+use bfir::AstNode::*;
+
+use bounds::highest_cell_index;
+
+#[cfg(test)]
+use bfir::parse;
+
+/// Cells are bytes, so we only need the unsigned value of each
+/// amount. i8 arithmetic wraps around.
+fn cell_value(amount: Cell) -> u8 {
+    amount.0 as u8
+}
+
+/// Builds up the body of `main`, naming registers and basic blocks
+/// uniquely as we go.
+struct Codegen {
+    out: String,
+    next_value: usize,
+    next_label: usize,
+}
+
+impl Codegen {
+    fn push_line(&mut self, line: &str) {
+        self.out.push_str("  ");
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+
+    fn push_label(&mut self, label: &str) {
+        self.out.push_str(label);
+        self.out.push_str(":\n");
+    }
+
+    /// A register name that hasn't been used yet.
+    fn value(&mut self) -> String {
+        let name = format!("%v{}", self.next_value);
+        self.next_value += 1;
+        name
+    }
+
+    /// A number for a new group of basic blocks.
+    fn label(&mut self) -> usize {
+        let label = self.next_label;
+        self.next_label += 1;
+        label
+    }
+
+    /// Load the cell pointer and return the address of the cell at
+    /// `offset` from it.
+    fn address(&mut self, offset: isize) -> String {
+        let ptr = self.value();
+        self.push_line(&format!("{} = load ptr, ptr %p", ptr));
+        if offset == 0 {
+            return ptr;
+        }
+        let address = self.value();
+        self.push_line(&format!("{} = getelementptr i8, ptr {}, i64 {}", address, ptr, offset));
+        address
+    }
+
+    fn load(&mut self, address: &str) -> String {
+        let value = self.value();
+        self.push_line(&format!("{} = load i8, ptr {}", value, address));
+        value
+    }
+
+    fn move_pointer(&mut self, amount: isize) {
+        let address = self.address(amount);
+        self.push_line(&format!("store ptr {}, ptr %p", address));
+    }
+
+    /// Branch to `after` if the current cell is zero, otherwise fall
+    /// through to `body`.
+    fn branch_if_zero(&mut self, after: &str, body: &str) {
+        let address = self.address(0);
+        let cell = self.load(&address);
+        let is_zero = self.value();
+        self.push_line(&format!("{} = icmp eq i8 {}, 0", is_zero, cell));
+        self.push_line(&format!("br i1 {}, label %{}, label %{}", is_zero, after, body));
+        self.push_label(body);
+    }
+
+    fn compile_instr(&mut self, instr: &AstNode) {
+        match *instr {
+            Increment { amount, offset, .. } => {
+                let address = self.address(offset);
+                let cell = self.load(&address);
+                let sum = self.value();
+                self.push_line(&format!("{} = add i8 {}, {}", sum, cell, cell_value(amount)));
+                self.push_line(&format!("store i8 {}, ptr {}", sum, address));
+            }
+            PointerIncrement { amount, .. } => {
+                self.move_pointer(amount);
+            }
+            Set { amount, offset, .. } => {
+                let address = self.address(offset);
+                self.push_line(&format!("store i8 {}, ptr {}", cell_value(amount), address));
+            }
+            MultiplyMove { ref changes, .. } |
+            MultiplyCopy { ref changes, .. } => {
+                // Skip the multiply when the cell is zero, as the
+                // offsets may be out of bounds.
+                let label = self.label();
+                let after = format!("multiply_after{}", label);
+                self.branch_if_zero(&after, &format!("multiply{}", label));
+
+                let address = self.address(0);
+                let factor = self.load(&address);
+
+                for (offset, amount) in changes {
+                    let target = self.address(*offset);
+                    let cell = self.load(&target);
+                    let product = self.value();
+                    self.push_line(&format!("{} = mul i8 {}, {}",
+                                            product,
+                                            factor,
+                                            cell_value(*amount)));
+                    let sum = self.value();
+                    self.push_line(&format!("{} = add i8 {}, {}", sum, cell, product));
+                    self.push_line(&format!("store i8 {}, ptr {}", sum, target));
+                }
+                if let MultiplyMove { .. } = *instr {
+                    self.push_line(&format!("store i8 0, ptr {}", address));
+                }
+                self.push_line(&format!("br label %{}", after));
+                self.push_label(&after);
+            }
+            Move { offset, .. } => {
+                // As for a multiply, the offset may be out of bounds
+                // when the cell is zero.
+                let label = self.label();
+                let after = format!("move_after{}", label);
+                self.branch_if_zero(&after, &format!("move{}", label));
+
+                let address = self.address(0);
+                let value = self.load(&address);
+                let target = self.address(offset);
+                let cell = self.load(&target);
+                let sum = self.value();
+                self.push_line(&format!("{} = add i8 {}, {}", sum, cell, value));
+                self.push_line(&format!("store i8 {}, ptr {}", sum, target));
+                self.push_line(&format!("store i8 0, ptr {}", address));
+                self.push_line(&format!("br label %{}", after));
+                self.push_label(&after);
+            }
+            Read { .. } => {
+                let input = self.value();
+                self.push_line(&format!("{} = call i32 @getchar()", input));
+                let byte = self.value();
+                self.push_line(&format!("{} = trunc i32 {} to i8", byte, input));
+                let address = self.address(0);
+                self.push_line(&format!("store i8 {}, ptr {}", byte, address));
+            }
+            Write { .. } => {
+                let address = self.address(0);
+                let cell = self.load(&address);
+                let output = self.value();
+                self.push_line(&format!("{} = zext i8 {} to i32", output, cell));
+                self.push_line(&format!("call i32 @putchar(i32 {})", output));
+            }
+            ScanZero { step, .. } => {
+                let label = self.label();
+                let head = format!("scan{}", label);
+                let after = format!("scan_after{}", label);
+                self.push_line(&format!("br label %{}", head));
+                self.push_label(&head);
+                self.branch_if_zero(&after, &format!("scan_body{}", label));
+                self.move_pointer(step);
+                self.push_line(&format!("br label %{}", head));
+                self.push_label(&after);
+            }
+            Breakpoint { .. } => {
+                self.push_line("; Breakpoint");
+            }
+            Loop { ref body, .. } => {
+                let label = self.label();
+                let head = format!("loop{}", label);
+                let after = format!("after{}", label);
+                self.push_line(&format!("br label %{}", head));
+                self.push_label(&head);
+                self.branch_if_zero(&after, &format!("body{}", label));
+                for loop_instr in body {
+                    self.compile_instr(loop_instr);
+                }
+                self.push_line(&format!("br label %{}", head));
+                self.push_label(&after);
+            }
+        }
+    }
+}
+
+/// Convert the instructions given to a textual LLVM IR module with a
+/// `main` function. We allocate as many cells as the program needs,
+/// just like the LLVM backend.
+pub fn codegen_llvm(instrs: &[AstNode]) -> String {
+    let num_cells = highest_cell_index(instrs) + 1;
+
+    let mut codegen = Codegen {
+        out: String::new(),
+        next_value: 0,
+        next_label: 0,
+    };
+    codegen.out.push_str("declare i32 @getchar()\n");
+    codegen.out.push_str("declare i32 @putchar(i32)\n");
+    codegen.out.push_str("declare ptr @calloc(i64, i64)\n");
+    codegen.out.push_str("declare void @free(ptr)\n\n");
+    codegen.out.push_str("define i32 @main() {\n");
+    codegen.push_label("entry");
+    codegen.push_line(&format!("%cells = call ptr @calloc(i64 {}, i64 1)", num_cells));
+    codegen.push_line("%p = alloca ptr");
+    codegen.push_line("store ptr %cells, ptr %p");
+
+    for instr in instrs {
+        codegen.compile_instr(instr);
+    }
+
+    codegen.push_line("call void @free(ptr %cells)");
+    codegen.push_line("ret i32 0");
+    codegen.out.push_str("}\n");
+    codegen.out
+}
+
+#[test]
+fn codegen_llvm_loop() {
+    let instrs = parse("[-]").unwrap();
+    let expected = "declare i32 @getchar()
+declare i32 @putchar(i32)
+declare ptr @calloc(i64, i64)
+declare void @free(ptr)
+
+define i32 @main() {
+entry:
+  %cells = call ptr @calloc(i64 1, i64 1)
+  %p = alloca ptr
+  store ptr %cells, ptr %p
+  br label %loop0
+loop0:
+  %v0 = load ptr, ptr %p
+  %v1 = load i8, ptr %v0
+  %v2 = icmp eq i8 %v1, 0
+  br i1 %v2, label %after0, label %body0
+body0:
+  %v3 = load ptr, ptr %p
+  %v4 = load i8, ptr %v3
+  %v5 = add i8 %v4, 255
+  store i8 %v5, ptr %v3
+  br label %loop0
+after0:
+  call void @free(ptr %cells)
+  ret i32 0
+}
+";
+    assert_eq!(codegen_llvm(&instrs), expected);
+}
+
+#[test]
+fn codegen_llvm_unique_labels() {
+    let instrs = parse("[>[<]][.]").unwrap();
+    let ir = codegen_llvm(&instrs);
+    for label in &["loop0:", "after0:", "loop1:", "after1:", "loop2:", "after2:"] {
+        assert_eq!(ir.matches(label).count(), 1, "{} should appear once", label);
+    }
+}
+
+#[test]
+fn codegen_llvm_io() {
+    let ir = codegen_llvm(&parse(",.").unwrap());
+    assert!(ir.contains("%v0 = call i32 @getchar()\n  %v1 = trunc i32 %v0 to i8\n"));
+    assert!(ir.contains("%v5 = zext i8 %v4 to i32\n  call i32 @putchar(i32 %v5)\n"));
+}
+
+#[test]
+fn codegen_llvm_offsets() {
+    use std::num::Wrapping;
+
+    let instrs = [Set {
+                      amount: Wrapping(2),
+                      offset: -1,
+                      position: None,
+                  }];
+    assert!(codegen_llvm(&instrs).contains("  %v1 = getelementptr i8, ptr %v0, i64 -1\n  \
+                                            store i8 2, ptr %v1\n"));
+}
@@ -8,18 +8,11 @@
 // TODO: enable this warning and cleanup.
 #![allow(option_unwrap_used)]
 
-//! bfc is a highly optimising compiler for BF.
+//! The bfc command line interface.
 
-extern crate llvm_sys;
-extern crate itertools;
-extern crate quickcheck;
-extern crate rand;
+extern crate bfc;
 extern crate tempfile;
 extern crate getopts;
-extern crate ansi_term;
-
-#[macro_use]
-extern crate matches;
 
 use std::env;
 use std::fs::File;
@@ -27,22 +20,12 @@ use std::io::prelude::Read;
 use std::path::Path;
 use getopts::{Options, Matches};
 use tempfile::NamedTempFile;
-use diagnostics::{Info, Level};
-
-mod bfir;
-mod llvm;
-mod peephole;
-mod bounds;
-mod execution;
-mod diagnostics;
-mod shell;
 
-#[cfg(test)]
-mod peephole_tests;
-#[cfg(test)]
-mod soundness_tests;
-#[cfg(test)]
-mod llvm_tests;
+use bfc::{analysis, bfir, llvm, peephole, execution};
+use bfc::diagnostics::{Info, Level};
+use bfc::peephole::OptLevel;
+
+mod shell;
 
 /// Read the contents of the file at path, and return a string of its
 /// contents. Return a diagnostic if we can't open or read the file.
@@ -142,32 +125,45 @@ fn compile_file(matches: &Matches) -> Result<(), String> {
         }
     };
 
-    let opt_level = matches.opt_str("opt").unwrap_or_else(|| String::from("2"));
-    if opt_level != "0" {
-        let pass_specification = matches.opt_str("passes");
-        let (opt_instrs, warnings) = peephole::optimize(instrs, &pass_specification);
-        instrs = opt_instrs;
+    let opt_arg = matches.opt_str("opt").unwrap_or_else(|| String::from("2"));
+    let opt_level = match OptLevel::from_arg(&opt_arg) {
+        Some(opt_level) => opt_level,
+        None => return Err(format!("Invalid optimization level: {}", opt_arg)),
+    };
 
-        for warning in warnings {
-            let info = Info {
-                level: Level::Warning,
-                filename: path.to_owned(),
-                message: warning.message,
-                position: warning.position,
-                source: Some(src.clone()),
-            };
-            println!("{}", info);
-        }
+    let pass_specification = matches.opt_str("passes");
+    let (opt_instrs, warnings) =
+        peephole::optimize_for_level(instrs, opt_level, &pass_specification);
+    instrs = opt_instrs;
+
+    for warning in warnings {
+        let info = Info {
+            level: Level::Warning,
+            filename: path.to_owned(),
+            message: warning.message,
+            position: warning.position,
+            source: Some(src.clone()),
+        };
+        println!("{}", info);
+    }
+
+    for position in analysis::detect_infinite_loops(&instrs) {
+        let info = Info {
+            level: Level::Warning,
+            filename: path.to_owned(),
+            message: "This loop never terminates once entered".to_owned(),
+            position: Some(position),
+            source: Some(src.clone()),
+        };
+        println!("{}", info);
     }
 
     if matches.opt_present("dump-ir") {
-        for instr in &instrs {
-            println!("{}", instr);
-        }
+        print!("{}", bfir::Listing(instrs));
         return Ok(());
     }
 
-    let (state, execution_warning) = if opt_level == "2" {
+    let (state, execution_warning) = if opt_level == OptLevel::Aggressive {
         execution::execute(&instrs, execution::MAX_STEPS)
     } else {
         let mut init_state = execution::ExecutionState::initial(&instrs[..]);
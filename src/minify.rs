@@ -0,0 +1,159 @@
+//! Shrink BF source code, for code golf or for shipping compact
+//! programs.
+//!
+//! We work on the commands themselves, rather than our IR, because
+//! the IR assumes that cells wrap around. For example, 255 `+`
+//! commands are a single increment of -1 in the IR, but writing that
+//! as `-` would change the behaviour on interpreters that don't wrap.
+//! Instead, we only cancel adjacent commands that undo each other, and
+//! remove loops that can never be entered, so the result behaves the
+//! same on any interpreter.
+
+use bfir::{ParseError, validate};
+
+/// A run of BF commands, where runs of `+`/`-` and `>`/`<` have been
+/// added up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    Add(isize),
+    Move(isize),
+    Read,
+    Write,
+    LoopStart,
+    LoopEnd,
+}
+
+/// Add one `+`/`-` or `>`/`<` to `tokens`, cancelling it against the
+/// previous token if that's the same kind.
+fn push_change(tokens: &mut Vec<Token>, token: Token) {
+    let merged = match (tokens.last(), token) {
+        (Some(&Token::Add(prev)), Token::Add(amount)) => Token::Add(prev + amount),
+        (Some(&Token::Move(prev)), Token::Move(amount)) => Token::Move(prev + amount),
+        _ => {
+            tokens.push(token);
+            return;
+        }
+    };
+
+    tokens.pop();
+    if merged != Token::Add(0) && merged != Token::Move(0) {
+        tokens.push(merged);
+    }
+}
+
+fn push_repeated(c: char, count: isize, out: &mut String) {
+    for _ in 0..count {
+        out.push(c);
+    }
+}
+
+/// Return the shortest equivalent BF source we can find for
+/// `source`. Comments are removed, adjacent commands that cancel out
+/// (such as `+-` or `><`) are removed, and so are loops that can never
+/// be entered, because they're at the start of the program or
+/// straight after another loop.
+pub fn minify(source: &str) -> Result<String, ParseError> {
+    validate(source)?;
+
+    let mut tokens = vec![];
+    // How many loops deep we are in a loop that we're removing.
+    let mut dead_depth = 0;
+
+    for c in source.chars() {
+        if dead_depth > 0 {
+            match c {
+                '[' => dead_depth += 1,
+                ']' => dead_depth -= 1,
+                _ => {}
+            }
+            continue;
+        }
+
+        match c {
+            '+' => push_change(&mut tokens, Token::Add(1)),
+            '-' => push_change(&mut tokens, Token::Add(-1)),
+            '>' => push_change(&mut tokens, Token::Move(1)),
+            '<' => push_change(&mut tokens, Token::Move(-1)),
+            ',' => tokens.push(Token::Read),
+            '.' => tokens.push(Token::Write),
+            '[' => {
+                // Every cell is zero at the start, and the current
+                // cell is zero after a loop, so the loop is dead.
+                match tokens.last() {
+                    None | Some(&Token::LoopEnd) => dead_depth = 1,
+                    _ => tokens.push(Token::LoopStart),
+                }
+            }
+            ']' => tokens.push(Token::LoopEnd),
+            _ => {}
+        }
+    }
+
+    let mut out = String::new();
+    for token in tokens {
+        match token {
+            Token::Add(amount) if amount > 0 => push_repeated('+', amount, &mut out),
+            Token::Add(amount) => push_repeated('-', -amount, &mut out),
+            Token::Move(amount) if amount > 0 => push_repeated('>', amount, &mut out),
+            Token::Move(amount) => push_repeated('<', -amount, &mut out),
+            Token::Read => out.push(','),
+            Token::Write => out.push('.'),
+            Token::LoopStart => out.push('['),
+            Token::LoopEnd => out.push(']'),
+        }
+    }
+    Ok(out)
+}
+
+#[test]
+fn minify_cancels_everything() {
+    assert_eq!(minify("++-- >< foo").unwrap(), "");
+}
+
+#[test]
+fn minify_cancels_nested_runs() {
+    // Removing >< lets the + and - cancel.
+    assert_eq!(minify("+><-.").unwrap(), ".");
+    assert_eq!(minify(">+++--").unwrap(), ">+");
+}
+
+#[test]
+fn minify_keeps_clear_loops() {
+    assert_eq!(minify("+[-]").unwrap(), "+[-]");
+    assert_eq!(minify("+[+]").unwrap(), "+[+]");
+}
+
+#[test]
+fn minify_keeps_large_increments() {
+    // Writing this as - would only work with wrapping cells.
+    let source = "+".repeat(255) + ".";
+    assert_eq!(minify(&source).unwrap(), source);
+}
+
+#[test]
+fn minify_removes_dead_loops() {
+    assert_eq!(minify("[.[-]]+[-][.[]]>[-]").unwrap(), "+[-]>[-]");
+    assert_eq!(minify("+-[.]").unwrap(), "");
+}
+
+#[test]
+fn minify_rejects_unbalanced() {
+    assert!(minify("[").is_err());
+    assert!(minify("]").is_err());
+}
+
+#[test]
+fn minify_preserves_behaviour() {
+    use bfir::parse;
+    use testing::{FIXTURES, unoptimized_output};
+
+    for &(name, source, input) in FIXTURES {
+        let minified = minify(source).unwrap();
+        assert!(minified.len() <= source.len());
+        assert!(parse(&minified).is_ok());
+        assert_eq!(unoptimized_output(&minified, input),
+                   unoptimized_output(source, input),
+                   "{} behaves differently",
+                   name);
+    }
+}
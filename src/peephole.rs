@@ -1,24 +1,136 @@
 //! Optimisations that replace parts of the BF AST with faster
 //! equivalents.
 
-use std::hash::Hash;
-use std::collections::{HashMap, HashSet};
-use std::num::Wrapping;
+use core::num::Wrapping;
+use core::time::Duration;
+use alloc::borrow::ToOwned;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::vec::Vec;
 
-use itertools::Itertools;
+#[cfg(feature = "std")]
+use std::time::Instant;
 
+use bounds::MAX_CELL_INDEX;
 use diagnostics::Warning;
+use interpreter::OverflowMode;
 
-use bfir::{AstNode, Position, Combine, Cell, get_position};
+use bfir::{AstNode, Position, Combine, Cell, get_position, map_instructions, merged_position,
+           node_count, concat, same_kind, has_shape};
 use bfir::AstNode::*;
 
 const MAX_OPT_ITERATIONS: u64 = 40;
 
+/// Instructions to compare against with `same_kind` and `has_shape`.
+const INCREMENT: AstNode = Increment {
+    amount: Wrapping(0),
+    offset: 0,
+    position: None,
+};
+const POINTER_INCREMENT: AstNode = PointerIncrement {
+    amount: 0,
+    position: None,
+};
+
+/// How aggressively we optimise a BF program, as set by `--opt`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptLevel {
+    /// No bfc optimisations at all (`--opt=0`).
+    None,
+    /// All peephole optimisations, run to a fixed point (`--opt=1`).
+    Basic,
+    /// All peephole optimisations, followed by speculative execution
+    /// at compile time (`--opt=2`, the default).
+    Aggressive,
+}
+
+impl OptLevel {
+    /// Convert a `--opt` argument to an `OptLevel`.
+    pub fn from_arg(arg: &str) -> Option<OptLevel> {
+        match arg {
+            "0" => Some(OptLevel::None),
+            "1" => Some(OptLevel::Basic),
+            "2" => Some(OptLevel::Aggressive),
+            _ => None,
+        }
+    }
+}
+
+/// Apply the peephole optimisations appropriate for `level`. Speculative
+/// execution is not a peephole optimisation, so `Basic` and
+/// `Aggressive` produce the same instructions here.
+pub fn optimize_for_level(instrs: Vec<AstNode>,
+                          level: OptLevel,
+                          pass_specification: &Option<String>)
+                          -> (Vec<AstNode>, Vec<Warning>) {
+    match level {
+        OptLevel::None => (instrs, vec![]),
+        OptLevel::Basic | OptLevel::Aggressive => optimize(instrs, pass_specification),
+    }
+}
+
+/// What a single run of a peephole pass did, as recorded by
+/// `optimize_with_trace`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PassResult {
+    /// The pass name, as used in pass specifications.
+    pub name: &'static str,
+    /// The number of instructions before and after the pass,
+    /// including instructions inside loops.
+    pub nodes_before: usize,
+    pub nodes_after: usize,
+    /// Whether the pass changed the instructions at all. Passes can
+    /// fire without changing the number of instructions.
+    pub changed: bool,
+    /// How long the pass took, so slow passes stand out on large
+    /// programs. This is always zero without std, as we have no
+    /// clock.
+    pub duration: Duration,
+}
+
+/// Apply the peephole optimisations appropriate for `level`, as
+/// `optimize_for_level` does, and record every pass that we ran and
+/// how long it took. Passes run repeatedly until we reach a fixed
+/// point, so the same pass may appear many times. As in
+/// `optimize_with_mode`, we only make changes that are correct when
+/// cells overflow as `overflow_mode` says.
+pub fn optimize_with_trace(instrs: Vec<AstNode>,
+                           level: OptLevel,
+                           overflow_mode: OverflowMode)
+                           -> (Vec<AstNode>, Vec<PassResult>) {
+    let mut trace = vec![];
+    let instrs = match level {
+        OptLevel::None => instrs,
+        OptLevel::Basic | OptLevel::Aggressive => {
+            optimize_traced(instrs, &None, overflow_mode, &mut Some(&mut trace)).0
+        }
+    };
+    (instrs, trace)
+}
+
 /// Given a sequence of BF instructions, apply peephole optimisations
 /// (repeatedly if necessary).
 pub fn optimize(instrs: Vec<AstNode>,
                 pass_specification: &Option<String>)
                 -> (Vec<AstNode>, Vec<Warning>) {
+    optimize_with_mode(instrs, pass_specification, OverflowMode::Wrap)
+}
+
+/// Apply peephole optimisations as `optimize`, but only make changes
+/// that are correct when cells overflow as `overflow_mode` says. For
+/// example, `[+]` only reaches zero if cells wrap.
+pub fn optimize_with_mode(instrs: Vec<AstNode>,
+                          pass_specification: &Option<String>,
+                          overflow_mode: OverflowMode)
+                          -> (Vec<AstNode>, Vec<Warning>) {
+    optimize_traced(instrs, pass_specification, overflow_mode, &mut None)
+}
+
+fn optimize_traced(instrs: Vec<AstNode>,
+                   pass_specification: &Option<String>,
+                   overflow_mode: OverflowMode,
+                   trace: &mut Option<&mut Vec<PassResult>>)
+                   -> (Vec<AstNode>, Vec<Warning>) {
     // Many of our individual peephole optimisations remove
     // instructions, creating new opportunities to combine. We run
     // until we've found a fixed-point where no further optimisations
@@ -26,7 +138,7 @@ pub fn optimize(instrs: Vec<AstNode>,
     let mut prev = instrs.clone();
     let mut warnings = vec![];
 
-    let (mut result, warning) = optimize_once(instrs, pass_specification);
+    let (mut result, warning) = optimize_once(instrs, pass_specification, overflow_mode, trace);
 
     if let Some(warning) = warning {
         warnings.push(warning);
@@ -38,7 +150,8 @@ pub fn optimize(instrs: Vec<AstNode>,
         } else {
             prev = result.clone();
 
-            let (new_result, new_warning) = optimize_once(result, pass_specification);
+            let (new_result, new_warning) =
+                optimize_once(result, pass_specification, overflow_mode, trace);
 
             if let Some(warning) = new_warning {
                 warnings.push(warning);
@@ -48,63 +161,161 @@ pub fn optimize(instrs: Vec<AstNode>,
     }
 
     // TODO: use proper Info here.
+    #[cfg(feature = "std")]
     println!("Warning: ran peephole optimisations {} times but did not reach a fixed point!",
              MAX_OPT_ITERATIONS);
 
     (result, warnings)
 }
 
+/// Reduce instructions to a canonical form, so tests can compare
+/// programs that only differ trivially. We guarantee exactly these
+/// transformations, applied until nothing changes:
+///
+/// * consecutive increments of the same cell are merged,
+/// * consecutive pointer increments are merged,
+/// * increments and pointer increments of zero are removed, and
+/// * loops straight after a `Set` of zero (so they can never be
+///   entered) are removed.
+///
+/// Unlike `optimize`, this never introduces new kinds of instruction.
+pub fn normalize(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let mut instrs = instrs;
+    loop {
+        let normalized = remove_dead_loops(combine_ptr_increments(combine_increments(instrs.clone())));
+        if normalized == instrs {
+            return normalized;
+        }
+        instrs = normalized;
+    }
+}
+
+/// Join `programs` as `bfir::concat`, then `normalize` the result, so
+/// instructions either side of a join are merged. For example, a
+/// program ending in `++` followed by one starting with `+++` gives a
+/// single increment of 5.
+///
+/// Each program's positions still refer to its own source, so merged
+/// instructions may have positions that span unrelated source.
+pub fn concat_normalized(programs: &[Vec<AstNode>]) -> Vec<AstNode> {
+    normalize(concat(programs))
+}
+
+/// Run the pass `f`, recording what it did in `trace` if we're
+/// tracing.
+fn run_pass<F>(name: &'static str,
+               instrs: Vec<AstNode>,
+               trace: &mut Option<&mut Vec<PassResult>>,
+               f: F)
+               -> Vec<AstNode>
+    where F: FnOnce(Vec<AstNode>) -> Vec<AstNode>
+{
+    match *trace {
+        Some(ref mut trace) => {
+            let before = instrs.clone();
+            let (after, duration) = timed(|| f(instrs));
+            trace.push(PassResult {
+                name: name,
+                nodes_before: node_count(&before),
+                nodes_after: node_count(&after),
+                changed: before != after,
+                duration: duration,
+            });
+            after
+        }
+        None => f(instrs),
+    }
+}
+
+/// Call `f`, and return its result with how long it took.
+#[cfg(feature = "std")]
+fn timed<T, F: FnOnce() -> T>(f: F) -> (T, Duration) {
+    let start = Instant::now();
+    let result = f();
+    (result, start.elapsed())
+}
+
+/// Call `f`. Without std we have no clock, so we always say it took
+/// no time.
+#[cfg(not(feature = "std"))]
+fn timed<T, F: FnOnce() -> T>(f: F) -> (T, Duration) {
+    (f(), Duration::from_secs(0))
+}
+
 /// Apply all our peephole optimisations once and return the result.
 fn optimize_once(instrs: Vec<AstNode>,
-                 pass_specification: &Option<String>)
+                 pass_specification: &Option<String>,
+                 overflow_mode: OverflowMode,
+                 trace: &mut Option<&mut Vec<PassResult>>)
                  -> (Vec<AstNode>, Option<Warning>) {
     let pass_specification = pass_specification.clone()
                                                .unwrap_or("combine_inc,combine_ptr,known_zero,\
-                                                           multiply,zeroing_loop,combine_set,\
-                                                           dead_loop,redundant_set,read_clobber,\
-                                                           pure_removal,offset_sort"
+                                                           multiply,copy_temp,zeroing_loop,\
+                                                           scan_zero,combine_set,const_prefix,\
+                                                           dead_loop,known_guard,redundant_set,\
+                                                           read_clobber,pure_removal,offset_sort,\
+                                                           prune_noops"
                                                               .to_owned());
     let passes: Vec<_> = pass_specification.split(',').collect();
 
     let mut instrs = instrs;
 
     if passes.contains(&"combine_inc") {
-        instrs = combine_increments(instrs);
+        instrs = run_pass("combine_inc", instrs, trace, combine_increments);
     }
     if passes.contains(&"combine_ptr") {
-        instrs = combine_ptr_increments(instrs);
+        instrs = run_pass("combine_ptr", instrs, trace, combine_ptr_increments);
     }
     if passes.contains(&"known_zero") {
-        instrs = annotate_known_zero(instrs);
+        instrs = run_pass("known_zero", instrs, trace, annotate_known_zero);
     }
     if passes.contains(&"multiply") {
-        instrs = extract_multiply(instrs);
+        instrs = run_pass("multiply", instrs, trace, extract_multiply);
+    }
+    if passes.contains(&"copy_temp") {
+        instrs = run_pass("copy_temp", instrs, trace, fuse_copy_via_temp);
     }
     if passes.contains(&"zeroing_loop") {
-        instrs = zeroing_loops(instrs);
+        instrs = run_pass("zeroing_loop",
+                          instrs,
+                          trace,
+                          |instrs| zeroing_loops_for_mode(instrs, overflow_mode));
+    }
+    if passes.contains(&"scan_zero") {
+        instrs = run_pass("scan_zero", instrs, trace, scan_zero_loops);
     }
     if passes.contains(&"combine_set") {
-        instrs = combine_set_and_increments(instrs);
+        instrs = run_pass("combine_set", instrs, trace, combine_set_and_increments);
+    }
+    if passes.contains(&"const_prefix") {
+        instrs = run_pass("const_prefix", instrs, trace, eval_constant_prefix);
     }
     if passes.contains(&"dead_loop") {
-        instrs = remove_dead_loops(instrs);
+        instrs = run_pass("dead_loop", instrs, trace, remove_dead_loops);
+    }
+    if passes.contains(&"known_guard") {
+        instrs = run_pass("known_guard", instrs, trace, specialize_known_guards);
     }
     if passes.contains(&"redundant_set") {
-        instrs = remove_redundant_sets(instrs);
+        instrs = run_pass("redundant_set", instrs, trace, remove_redundant_sets);
     }
     if passes.contains(&"read_clobber") {
-        instrs = remove_read_clobber(instrs);
+        instrs = run_pass("read_clobber", instrs, trace, remove_read_clobber);
+    }
+    let mut warning = None;
+    if passes.contains(&"pure_removal") {
+        instrs = run_pass("pure_removal", instrs, trace, |instrs| {
+            let (removed, pure_warning) = remove_pure_code(instrs);
+            warning = pure_warning;
+            removed
+        });
     }
-    let warning = if passes.contains(&"pure_removal") {
-        let (removed, pure_warning) = remove_pure_code(instrs);
-        instrs = removed;
-        pure_warning
-    } else {
-        None
-    };
 
     if passes.contains(&"offset_sort") {
-        instrs = sort_by_offset(instrs);
+        instrs = run_pass("offset_sort", instrs, trace, sort_by_offset);
+    }
+    if passes.contains(&"prune_noops") {
+        instrs = run_pass("prune_noops", instrs, trace, prune_noops);
     }
 
     (instrs, warning)
@@ -132,6 +343,56 @@ trait MapLoopsExt: Iterator<Item = AstNode> {
 
 impl<I> MapLoopsExt for I where I: Iterator<Item = AstNode> {}
 
+/// Defines a method on iterators to merge adjacent instructions. `f`
+/// returns the merged instruction, or both instructions if they can't
+/// be merged. This is itertools' `coalesce`, which we can't use
+/// without std.
+trait CoalesceExt: Iterator<Item = AstNode> + Sized {
+    fn coalesce<F>(self, f: F) -> Coalesce<Self, F>
+        where F: FnMut(AstNode, AstNode) -> Result<AstNode, (AstNode, AstNode)>
+    {
+        Coalesce {
+            iter: self,
+            last: None,
+            f: f,
+        }
+    }
+}
+
+impl<I> CoalesceExt for I where I: Iterator<Item = AstNode> {}
+
+struct Coalesce<I, F> {
+    iter: I,
+    /// The instruction we couldn't merge with its predecessor, which
+    /// we may merge with its successors.
+    last: Option<AstNode>,
+    f: F,
+}
+
+impl<I, F> Iterator for Coalesce<I, F>
+    where I: Iterator<Item = AstNode>,
+          F: FnMut(AstNode, AstNode) -> Result<AstNode, (AstNode, AstNode)>
+{
+    type Item = AstNode;
+
+    fn next(&mut self) -> Option<AstNode> {
+        let mut last = match self.last.take() {
+            Some(instr) => instr,
+            None => self.iter.next()?,
+        };
+        for instr in &mut self.iter {
+            match (self.f)(last, instr) {
+                Ok(merged) => last = merged,
+                Err((prev_instr, instr)) => {
+                    self.last = Some(instr);
+                    return Some(prev_instr);
+                }
+            }
+        }
+        Some(last)
+    }
+}
+
 /// Given an index into a vector of instructions, find the index of
 /// the previous instruction that modified the current cell. If we're
 /// unsure, or there isn't one, return None.
@@ -168,11 +429,28 @@ pub fn previous_cell_change(instrs: &[AstNode], index: usize) -> Option<usize> {
                     return Some(i);
                 }
             }
+            Move { offset, .. } => {
+                // This cell is written to, and this cell is zeroed.
+                if needed_offset == offset || needed_offset == 0 {
+                    return Some(i);
+                }
+            }
+            MultiplyCopy { ref changes, temp, .. } => {
+                // This cell is read, and the temporary cell is used,
+                // even though they end up unchanged.
+                if needed_offset == 0 || needed_offset == temp ||
+                   changes.contains_key(&needed_offset) {
+                    return Some(i);
+                }
+            }
             // No cells changed, so just keep working backwards.
             Write { .. } => {}
             // These instructions may have modified the cell, so
             // we return None for "I don't know".
-            Read { .. } | Loop { .. } => return None,
+            Read { .. } | Loop { .. } | ScanZero { .. } => return None,
+            // A debugger may inspect any cell here, so we can't look
+            // past it.
+            Breakpoint { .. } => return None,
         }
     }
     None
@@ -215,56 +493,88 @@ pub fn next_cell_change(instrs: &[AstNode], index: usize) -> Option<usize> {
                     return Some(i);
                 }
             }
+            Move { offset, .. } => {
+                // This cell is written to, and this cell is zeroed.
+                if needed_offset == offset || needed_offset == 0 {
+                    return Some(i);
+                }
+            }
+            MultiplyCopy { ref changes, temp, .. } => {
+                // This cell is read, and the temporary cell is used,
+                // even though they end up unchanged.
+                if needed_offset == 0 || needed_offset == temp ||
+                   changes.contains_key(&needed_offset) {
+                    return Some(i);
+                }
+            }
             // No cells changed, so just keep working backwards.
             Write { .. } => {}
             // These instructions may have modified the cell, so
             // we return None for "I don't know".
-            Read { .. } | Loop { .. } => return None,
+            Read { .. } | Loop { .. } | ScanZero { .. } => return None,
+            // A debugger may inspect any cell here, so we can't look
+            // past it.
+            Breakpoint { .. } => return None,
         }
     }
     None
 }
 
 /// Combine consecutive increments into a single increment
-/// instruction.
+/// instruction. The result spans all the increments, see
+/// `merged_position`.
+///
+/// Cells are 8 bits and wrap around, so amounts are added modulo
+/// 256. However many increments there are, this can't overflow.
 pub fn combine_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
-    instrs.into_iter()
-        .coalesce(|prev_instr, instr| {
-            // Collapse consecutive increments.
-            if let Increment { amount: prev_amount, offset: prev_offset, position: prev_pos } =
-                   prev_instr {
-                if let Increment { amount, offset, position } = instr {
-                    if prev_offset == offset {
-                        return Ok(Increment {
-                            amount: amount + prev_amount,
-                            offset: offset,
-                            position: prev_pos.combine(position),
-                        });
+    map_instructions(instrs, &|instrs| {
+        instrs.into_iter()
+            .coalesce(|prev_instr, instr| {
+                // Collapse consecutive increments.
+                if let Increment { amount: prev_amount, offset: prev_offset, position: prev_pos } =
+                       prev_instr {
+                    if let Increment { amount, offset, position } = instr {
+                        if prev_offset == offset {
+                            return Ok(Increment {
+                                amount: amount + prev_amount,
+                                offset: offset,
+                                position: merged_position(prev_pos, position),
+                            });
+                        }
                     }
                 }
-            }
-            Err((prev_instr, instr))
-        })
-        .filter(|instr| {
-            // Remove any increments of 0.
-            if let Increment { amount: Wrapping(0), .. } = *instr {
-                return false;
-            }
-            true
-        })
-        .map_loops(combine_increments)
+                Err((prev_instr, instr))
+            })
+            .filter(|instr| {
+                // Remove any increments of 0.
+                if let Increment { amount: Wrapping(0), .. } = *instr {
+                    return false;
+                }
+                true
+            })
+            .collect()
+    })
 }
 
+/// Combine consecutive pointer increments into a single instruction,
+/// which spans them all.
+///
+/// If the total movement doesn't fit in an isize, we leave the
+/// pointer increments separate. The pointer would leave the tape
+/// either way.
 pub fn combine_ptr_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
     instrs.into_iter()
         .coalesce(|prev_instr, instr| {
             // Collapse consecutive increments.
             if let PointerIncrement { amount: prev_amount, position: prev_pos } = prev_instr {
                 if let PointerIncrement { amount, position } = instr {
-                    return Ok(PointerIncrement {
-                        amount: amount + prev_amount,
-                        position: prev_pos.combine(position),
-                    });
+                    if let Some(total) = prev_amount.checked_add(amount) {
+                        return Ok(PointerIncrement {
+                            amount: total,
+                            position: merged_position(prev_pos, position),
+                        });
+                    }
+                    return Err((prev_instr, instr));
                 }
             }
             Err((prev_instr, instr))
@@ -279,11 +589,82 @@ pub fn combine_ptr_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
         .map_loops(combine_ptr_increments)
 }
 
+/// Return true if the current cell is certainly zero just before the
+/// instruction at `index`.
+fn known_zero_before(instrs: &[AstNode], index: usize) -> bool {
+    if index == 0 {
+        // We don't know if we're at the start of the program or a
+        // loop body.
+        return false;
+    }
+
+    match instrs[index - 1] {
+        // We only leave a loop when the current cell is zero.
+        Loop { .. } | ScanZero { .. } | MultiplyMove { .. } | Move { .. } => true,
+        _ => {
+            match previous_cell_change(instrs, index) {
+                Some(prev_index) => {
+                    matches!(instrs[prev_index], Set { amount: Wrapping(0), offset: 0, .. })
+                }
+                None => false,
+            }
+        }
+    }
+}
+
+/// Remove instructions that have no effect: increments and pointer
+/// increments of zero, a set that is immediately followed by a set of
+/// the same cell, and empty loops where the current cell is zero.
+///
+/// An empty loop never terminates if it's entered, so we keep it
+/// unless we know it's dead. `detect_infinite_loops` reports the
+/// empty loops that we keep.
+///
+/// This is a cheap cleanup for instructions left behind by other
+/// passes, so we run it last.
+pub fn prune_noops(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    map_instructions(instrs, &|instrs| {
+        let instrs: Vec<_> = instrs.into_iter()
+            .filter(|instr| {
+                !matches!(*instr,
+                          Increment { amount: Wrapping(0), .. } |
+                          PointerIncrement { amount: 0, .. })
+            })
+            .coalesce(|prev_instr, instr| {
+                // Only the last set takes effect.
+                if let Set { offset: prev_offset, .. } = prev_instr {
+                    if let Set { offset, .. } = instr {
+                        if prev_offset == offset {
+                            return Ok(instr);
+                        }
+                    }
+                }
+                Err((prev_instr, instr))
+            })
+            .collect();
+
+        let dead_indices: BTreeSet<_> = instrs.iter()
+            .enumerate()
+            .filter(|&(index, instr)| {
+                matches!(*instr, Loop { ref body, .. } if body.is_empty()) &&
+                known_zero_before(&instrs, index)
+            })
+            .map(|(index, _)| index)
+            .collect();
+
+        instrs.into_iter()
+            .enumerate()
+            .filter(|&(index, _)| !dead_indices.contains(&index))
+            .map(|(_, instr)| instr)
+            .collect()
+    })
+}
+
 /// Don't bother updating cells if they're immediately overwritten
 /// by a value from stdin.
 // TODO: this should generate a warning too.
 pub fn remove_read_clobber(instrs: Vec<AstNode>) -> Vec<AstNode> {
-    let mut redundant_instr_positions = HashSet::new();
+    let mut redundant_instr_positions = BTreeSet::new();
     let mut last_write_index = None;
 
     for (index, instr) in instrs.iter().enumerate() {
@@ -300,16 +681,18 @@ pub fn remove_read_clobber(instrs: Vec<AstNode>) -> Vec<AstNode> {
                         }
                     }
 
-                    // MultiplyMove instructions are not redundant,
-                    // because they affect other cells too.
-                    if matches!(instrs[prev_modify_index], MultiplyMove { ..}) {
+                    // MultiplyMove, Move and MultiplyCopy instructions
+                    // are not redundant, because they affect other
+                    // cells too.
+                    if matches!(instrs[prev_modify_index],
+                                MultiplyMove { .. } | Move { .. } | MultiplyCopy { .. }) {
                         continue;
                     }
 
                     redundant_instr_positions.insert(prev_modify_index);
                 }
             }
-            Write { .. } => {
+            Write { .. } | Breakpoint { .. } => {
                 last_write_index = Some(index);
             }
             _ => {}
@@ -323,25 +706,65 @@ pub fn remove_read_clobber(instrs: Vec<AstNode>) -> Vec<AstNode> {
         .map_loops(remove_read_clobber)
 }
 
-/// Convert [-] to Set 0.
+/// Convert loops that only move the pointer, such as `[>]` or
+/// `[<<]`, to a ScanZero. Backends can implement these as a tight
+/// scan, but must move by the whole step each time: `[>>]` never
+/// checks the cells it skips over, so it can't use `memchr`.
+pub fn scan_zero_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    map_instructions(instrs, &|instrs| {
+        instrs.into_iter()
+            .map(|instr| {
+                if let Loop { ref body, position } = instr {
+                    if let [PointerIncrement { amount, .. }] = body[..] {
+                        // [] with a zero pointer increment never
+                        // terminates, so leave it alone.
+                        if amount != 0 {
+                            return ScanZero {
+                                step: amount,
+                                position: position,
+                            };
+                        }
+                    }
+                }
+                instr
+            })
+            .collect()
+    })
+}
+
+/// Replace `[-]` and `[+]` with a Set instruction. Like the other
+/// passes that simplify loops, the result keeps the position of the
+/// whole loop.
 pub fn zeroing_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    zeroing_loops_for_mode(instrs, OverflowMode::Wrap)
+}
+
+/// Replace zeroing loops with a Set instruction, as `zeroing_loops`,
+/// but only where that's correct for `overflow_mode`. Without
+/// wrapping, `[+]` overflows rather than reaching zero, so we only
+/// replace `[-]`.
+pub fn zeroing_loops_for_mode(instrs: Vec<AstNode>, overflow_mode: OverflowMode) -> Vec<AstNode> {
     instrs.into_iter()
         .map(|instr| {
             if let Loop { ref body, position } = instr {
-                // If the loop is [-]
-                if body.len() == 1 {
-                    if let Increment { amount: Wrapping(-1), offset: 0, .. } = body[0] {
-                        return Set {
-                            amount: Wrapping(0),
-                            offset: 0,
-                            position: position,
-                        };
-                    }
+                // [-] counts down to zero, and [+] counts up until
+                // the cell wraps around to zero.
+                let is_zeroing = has_shape(body, &[INCREMENT]) &&
+                                 (loop_is_counting(body).is_some() ||
+                                  (overflow_mode == OverflowMode::Wrap &&
+                                   matches!(body[0],
+                                            Increment { amount: Wrapping(1), offset: 0, .. })));
+                if is_zeroing {
+                    return Set {
+                        amount: Wrapping(0),
+                        offset: 0,
+                        position: position,
+                    };
                 }
             }
             instr
         })
-        .map_loops(zeroing_loops)
+        .map_loops(|body| zeroing_loops_for_mode(body, overflow_mode))
 }
 
 /// Remove any loops where we know the current cell is zero.
@@ -351,7 +774,7 @@ pub fn remove_dead_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
         .enumerate()
         .filter(|&(index, ref instr)| {
             match *instr {
-                Loop { .. } => {}
+                Loop { .. } | ScanZero { .. } => {}
                 // Keep all instructions that aren't loops.
                 _ => {
                     return true;
@@ -373,6 +796,137 @@ pub fn remove_dead_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
         .map_loops(remove_dead_loops)
 }
 
+/// Find the value of the current cell just before the instruction at
+/// `index`, by working backwards to the instruction that last set it,
+/// and adding up the increments since. Returns None if we can't tell.
+fn known_cell_value(instrs: &[AstNode], index: usize) -> Option<Cell> {
+    known_cell_value_at(instrs, index, 0, false)
+}
+
+/// Find the value of the cell at `offset` from the current cell just
+/// before the instruction at `index`, as `known_cell_value`. If
+/// `start_is_zero`, every cell is zero before the first instruction,
+/// as at the start of a program, rather than the start of a loop body.
+fn known_cell_value_at(instrs: &[AstNode],
+                       index: usize,
+                       offset: isize,
+                       start_is_zero: bool)
+                       -> Option<Cell> {
+    let mut needed_offset = offset;
+    let mut total_increment = Wrapping(0);
+
+    for i in (0..index).rev() {
+        match instrs[i] {
+            Increment { amount, offset, .. } => {
+                if offset == needed_offset {
+                    total_increment += amount;
+                }
+            }
+            Set { amount, offset, .. } => {
+                if offset == needed_offset {
+                    return Some(amount + total_increment);
+                }
+            }
+            PointerIncrement { amount, .. } => {
+                needed_offset += amount;
+            }
+            MultiplyMove { ref changes, .. } => {
+                if needed_offset == 0 {
+                    return Some(total_increment);
+                }
+                if changes.contains_key(&needed_offset) {
+                    return None;
+                }
+            }
+            Move { offset, .. } => {
+                if needed_offset == 0 {
+                    return Some(total_increment);
+                }
+                if needed_offset == offset {
+                    return None;
+                }
+            }
+            // The current cell and the temporary cell are unchanged.
+            MultiplyCopy { ref changes, .. } => {
+                if changes.contains_key(&needed_offset) {
+                    return None;
+                }
+            }
+            // We only leave a loop or a scan when the cell under the
+            // pointer is zero, but we don't know about other cells.
+            Loop { .. } | ScanZero { .. } => {
+                if needed_offset == 0 {
+                    return Some(total_increment);
+                }
+                return None;
+            }
+            Read { .. } => {
+                if needed_offset == 0 {
+                    return None;
+                }
+            }
+            Write { .. } => {}
+            Breakpoint { .. } => return None,
+        }
+    }
+    if start_is_zero {
+        Some(total_increment)
+    } else {
+        None
+    }
+}
+
+/// Remove loops and scans whose guard cell has a value we can work
+/// out at compile time, and that value is zero. This is more thorough
+/// than `remove_dead_loops`, as it looks through increments, pointer
+/// movement and earlier loops, so it catches cases like "[-]+-[.]"
+/// before the other passes have simplified them.
+pub fn specialize_known_guards(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    instrs.clone()
+        .into_iter()
+        .enumerate()
+        .filter(|&(index, ref instr)| {
+            match *instr {
+                Loop { .. } | ScanZero { .. } => {
+                    known_cell_value(&instrs, index) != Some(Wrapping(0))
+                }
+                _ => true,
+            }
+        })
+        .map(|(_, instr)| instr)
+        .map_loops(specialize_known_guards)
+}
+
+/// Replace counting loops, such as `[->+<]`, with copies of their
+/// body when we know the guard cell's value and it's at most
+/// `max_iterations`. A counting loop decrements its guard by one each
+/// time, so it runs exactly as many times as the guard's value, and
+/// the copies leave the guard at zero.
+///
+/// This removes the loop's branches, and `combine_increments` and
+/// `sort_by_offset` can then merge the copies.
+pub fn unroll_small_loops(instrs: Vec<AstNode>, max_iterations: usize) -> Vec<AstNode> {
+    let mut result = vec![];
+    for (index, instr) in instrs.iter().enumerate() {
+        if let Loop { ref body, .. } = *instr {
+            if loop_is_counting(body).is_some() {
+                if let Some(value) = known_cell_value(&instrs, index) {
+                    let iterations = value.0 as u8 as usize;
+                    if iterations <= max_iterations {
+                        for _ in 0..iterations {
+                            result.extend(body.iter().cloned());
+                        }
+                        continue;
+                    }
+                }
+            }
+        }
+        result.push(instr.clone());
+    }
+
+    result.into_iter().map_loops(|body| unroll_small_loops(body, max_iterations))
+}
+
 /// Reorder flat sequences of instructions so we use offsets and only
 /// have one pointer increment at the end. For example, given "+>+>+<"
 /// we return:
@@ -380,6 +934,10 @@ pub fn remove_dead_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
 /// Increment { amount: 1, offset: 1 }
 /// Increment { amount: 2, offset: 2 }
 /// PointerIncrement(1)
+///
+/// A sequence is a maximal run of Increment, Set and
+/// PointerIncrement instructions. If the pointer movement in the run
+/// is balanced, such as ">++<", there's no PointerIncrement at all.
 pub fn sort_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
     let mut sequence = vec![];
     let mut result = vec![];
@@ -415,19 +973,10 @@ pub fn sort_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
     result
 }
 
-/// Given a `HashMap` with orderable keys, return the values according to
-/// the key order.
-/// {2: 'foo': 1: 'bar'} => vec!['bar', 'foo']
-fn ordered_values<K: Ord + Hash + Eq, V>(map: HashMap<K, V>) -> Vec<V> {
-    let mut items: Vec<_> = map.into_iter().collect();
-    items.sort_by(|a, b| a.0.cmp(&b.0));
-    items.into_iter().map(|(_, v)| v).collect()
-}
-
 /// Given a BF program, combine sets/increments using offsets so we
 /// have single `PointerIncrement` at the end.
 fn sort_sequence_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
-    let mut instrs_by_offset: HashMap<isize, Vec<AstNode>> = HashMap::new();
+    let mut instrs_by_offset: BTreeMap<isize, Vec<AstNode>> = BTreeMap::new();
     let mut current_offset = 0;
     let mut last_ptr_inc_pos = None;
 
@@ -467,7 +1016,7 @@ fn sort_sequence_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
 
     // Append the increment/set instructions, in offset order.
     let mut results: Vec<AstNode> = vec![];
-    for same_offset_instrs in ordered_values(instrs_by_offset) {
+    for same_offset_instrs in instrs_by_offset.into_values() {
         results.extend(same_offset_instrs.into_iter());
     }
 
@@ -555,12 +1104,14 @@ pub fn remove_redundant_sets(instrs: Vec<AstNode>) -> Vec<AstNode> {
 }
 
 fn remove_redundant_sets_inner(instrs: Vec<AstNode>) -> Vec<AstNode> {
-    let mut redundant_instr_positions = HashSet::new();
+    let mut redundant_instr_positions = BTreeSet::new();
 
     for (index, instr) in instrs.iter().enumerate() {
         match *instr {
             Loop { .. } |
-            MultiplyMove { .. } => {
+            ScanZero { .. } |
+            MultiplyMove { .. } |
+            Move { .. } => {
                 // There's no point setting to zero after a loop, as
                 // the cell is already zero.
                 if let Some(next_index) = next_cell_change(&instrs, index) {
@@ -610,6 +1161,80 @@ pub fn annotate_known_zero(instrs: Vec<AstNode>) -> Vec<AstNode> {
     result
 }
 
+/// Every cell is zero at program start, so any straight-line code at
+/// the beginning of the program has a known effect. Replace it with a
+/// Set for every cell that ends up nonzero, followed by a pointer
+/// increment to where the pointer ends up.
+///
+/// We stop at the first instruction that isn't an increment, pointer
+/// increment or set. If the pointer leaves the tape, we leave the
+/// instructions alone, so the program still reports the error.
+pub fn eval_constant_prefix(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let prefix_len = instrs.iter()
+        .take_while(|instr| {
+            matches!(**instr, Increment { .. } | PointerIncrement { .. } | Set { .. })
+        })
+        .count();
+    if prefix_len == 0 {
+        return instrs;
+    }
+
+    // The value of each cell we've modified, and the positions of
+    // the instructions that modified it.
+    let mut cells: BTreeMap<isize, (Cell, Option<Position>)> = BTreeMap::new();
+    let mut cell_ptr: isize = 0;
+    let mut ptr_position = None;
+    let mut ptr_moved = false;
+
+    for instr in &instrs[..prefix_len] {
+        match *instr {
+            Increment { amount, offset, position } => {
+                let cell = cells.entry(cell_ptr + offset).or_insert((Wrapping(0), position));
+                cell.0 += amount;
+                cell.1 = cell.1.combine(position);
+            }
+            Set { amount, offset, position } => {
+                let cell = cells.entry(cell_ptr + offset).or_insert((Wrapping(0), position));
+                cell.0 = amount;
+                cell.1 = cell.1.combine(position);
+            }
+            PointerIncrement { amount, position } => {
+                cell_ptr += amount;
+                ptr_position = if ptr_moved {
+                    ptr_position.combine(position)
+                } else {
+                    position
+                };
+                ptr_moved = true;
+                if cell_ptr < 0 || cell_ptr > MAX_CELL_INDEX as isize {
+                    return instrs;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    let mut result: Vec<_> = cells.into_iter()
+        .filter(|&(_, (amount, _))| amount.0 != 0)
+        .map(|(cell_index, (amount, position))| {
+            Set {
+                amount: amount,
+                offset: cell_index,
+                position: position,
+            }
+        })
+        .collect();
+    if cell_ptr != 0 {
+        result.push(PointerIncrement {
+            amount: cell_ptr,
+            position: ptr_position,
+        });
+    }
+
+    result.extend(instrs.into_iter().skip(prefix_len));
+    result
+}
+
 fn annotate_known_zero_inner(instrs: Vec<AstNode>) -> Vec<AstNode> {
     let mut result = vec![];
 
@@ -640,6 +1265,25 @@ fn annotate_known_zero_inner(instrs: Vec<AstNode>) -> Vec<AstNode> {
                     result.push(set_instr.clone());
                 }
             }
+            // Likewise after a scan.
+            ScanZero { position, .. } => {
+                result.push(instr);
+                let set_pos = position.map(|scan_pos| {
+                    Position {
+                        start: scan_pos.end,
+                        end: scan_pos.end,
+                    }
+                });
+
+                let set_instr = Set {
+                    amount: Wrapping(0),
+                    offset: 0,
+                    position: set_pos,
+                };
+                if instrs.get(i + 1) != Some(&set_instr) {
+                    result.push(set_instr);
+                }
+            }
             _ => {
                 result.push(instr);
             }
@@ -658,7 +1302,7 @@ pub fn remove_pure_code(mut instrs: Vec<AstNode>) -> (Vec<AstNode>, Option<Warni
         let last_instr = instrs.pop().unwrap();
 
         match last_instr {
-            Read { .. } | Write { .. } | Loop { .. } => {
+            Read { .. } | Write { .. } | Loop { .. } | ScanZero { .. } | Breakpoint { .. } => {
                 instrs.push(last_instr);
                 break;
             }
@@ -674,7 +1318,7 @@ pub fn remove_pure_code(mut instrs: Vec<AstNode>) -> (Vec<AstNode>, Option<Warni
         let position = pure_instrs.into_iter()
             .map(|instr| get_position(&instr))
             .filter(|pos| pos.is_some())
-            .fold1(|pos1, pos2| pos1.combine(pos2))
+            .reduce(|pos1, pos2| pos1.combine(pos2))
             .map(|pos| pos.unwrap());
         Some(Warning {
             message: "These instructions have no effect.".to_owned(),
@@ -688,17 +1332,27 @@ pub fn remove_pure_code(mut instrs: Vec<AstNode>) -> (Vec<AstNode>, Option<Warni
 /// Does this loop body represent a multiplication operation?
 /// E.g. "[->>>++<<<]" sets cell #3 to 2*cell #0.
 fn is_multiply_loop_body(body: &[AstNode]) -> bool {
-    // A multiply loop may only contain increments and pointer increments.
-    for body_instr in body {
-        match *body_instr {
-            Increment { .. } |
-            PointerIncrement { .. } => {}
-            _ => return false,
-        }
+    // A multiply loop must also change at least one other cell.
+    loop_is_counting(body).is_some() && cell_changes(body).len() >= 2
+}
+
+/// Return Some if a loop with this body runs exactly as many times as
+/// the value of the current cell when we enter it, such as `[-]` or
+/// `[->+<]`. This is what makes it safe to replace a loop with a Set
+/// or a MultiplyMove.
+///
+/// The body may only contain increments and pointer increments, with
+/// no net pointer movement, and must decrement the current cell by
+/// exactly one. Loops like `[+>]`, whose guard may increase or move,
+/// return None.
+pub fn loop_is_counting(body: &[AstNode]) -> Option<()> {
+    let only_increments = body.iter().all(|instr| {
+        same_kind(instr, &INCREMENT) || same_kind(instr, &POINTER_INCREMENT)
+    });
+    if !only_increments {
+        return None;
     }
 
-    // A multiply loop must have a net pointer movement of
-    // zero.
     let mut net_movement = 0;
     for body_instr in body {
         if let PointerIncrement { amount, .. } = *body_instr {
@@ -706,36 +1360,34 @@ fn is_multiply_loop_body(body: &[AstNode]) -> bool {
         }
     }
     if net_movement != 0 {
-        return false;
+        return None;
     }
 
-    let changes = cell_changes(body);
-    // A multiply loop must decrement cell #0.
-    if let Some(&Wrapping(-1)) = changes.get(&0) {
-    } else {
-        return false;
+    match cell_changes(body).get(&0) {
+        Some(&Wrapping(-1)) => Some(()),
+        _ => None,
     }
-
-    changes.len() >= 2
 }
 
 /// Return a hashmap of all the cells that are affected by this
 /// sequence of instructions, and how much they change.
 /// E.g. "->>+++>+" -> {0: -1, 2: 3, 3: 1}
-fn cell_changes(instrs: &[AstNode]) -> HashMap<isize, Cell> {
-    let mut changes = HashMap::new();
+fn cell_changes(instrs: &[AstNode]) -> BTreeMap<isize, Cell> {
+    let mut changes = BTreeMap::new();
     let mut cell_index: isize = 0;
 
     for instr in instrs {
         match *instr {
             Increment { amount, offset, .. } => {
-                let current_amount = *changes.get(&(cell_index + offset)).unwrap_or(&Wrapping(0));
-                changes.insert(cell_index, current_amount + amount);
+                let index = cell_index + offset;
+                let current_amount = *changes.get(&index).unwrap_or(&Wrapping(0));
+                changes.insert(index, current_amount + amount);
             }
             PointerIncrement { amount, .. } => {
                 cell_index += amount;
             }
-            // We assume this is only called from is_multiply_loop.
+            // We assume the body has been checked by
+            // loop_is_counting.
             _ => unreachable!(),
         }
     }
@@ -743,6 +1395,9 @@ fn cell_changes(instrs: &[AstNode]) -> HashMap<isize, Cell> {
     changes
 }
 
+/// Replace multiply loops with a MultiplyMove. A loop that adds the
+/// current cell to exactly one other cell, such as `[->+<]`, becomes
+/// a Move instead.
 pub fn extract_multiply(instrs: Vec<AstNode>) -> Vec<AstNode> {
     instrs.into_iter()
         .map(|instr| {
@@ -754,6 +1409,12 @@ pub fn extract_multiply(instrs: Vec<AstNode>) -> Vec<AstNode> {
                         // the cell we're moving from.
                         changes.remove(&0);
 
+                        if let Some(offset) = single_move_offset(&changes) {
+                            return Move {
+                                offset: offset,
+                                position: position,
+                            };
+                        }
                         MultiplyMove {
                             changes: changes,
                             position: position,
@@ -770,3 +1431,113 @@ pub fn extract_multiply(instrs: Vec<AstNode>) -> Vec<AstNode> {
         })
         .collect()
 }
+
+/// If `changes` adds the current cell to exactly one other cell,
+/// return that cell's offset.
+fn single_move_offset(changes: &BTreeMap<isize, Cell>) -> Option<isize> {
+    if changes.len() != 1 {
+        return None;
+    }
+    match changes.iter().next() {
+        Some((&offset, &Wrapping(1))) => Some(offset),
+        _ => None,
+    }
+}
+
+/// The changes that a MultiplyMove or Move makes to other cells.
+fn move_changes(instr: &AstNode) -> Option<BTreeMap<isize, Cell>> {
+    match *instr {
+        MultiplyMove { ref changes, .. } => Some(changes.clone()),
+        Move { offset, .. } => {
+            let mut changes = BTreeMap::new();
+            changes.insert(offset, Wrapping(1));
+            Some(changes)
+        }
+        _ => None,
+    }
+}
+
+/// Fuse a multiply move that includes a temporary cell with the
+/// move that moves the temporary cell back, as in
+/// `[->+>+<<]>>[-<<+>>]<<`. BF code generators use this idiom to copy
+/// a cell without losing it. If the temporary cell is zero to start
+/// with, the pair leaves the current cell unchanged, so we replace it
+/// with a single MultiplyCopy.
+///
+/// We only fuse the two moves when they're separated by nothing but
+/// the pointer increment to the temporary cell, and we can show that
+/// the temporary cell starts at zero.
+pub fn fuse_copy_via_temp(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    fuse_copy_via_temp_inner(instrs, true)
+}
+
+fn fuse_copy_via_temp_inner(instrs: Vec<AstNode>, is_toplevel: bool) -> Vec<AstNode> {
+    let mut result = vec![];
+    let mut index = 0;
+
+    while index < instrs.len() {
+        if let Some((copy, ptr_increment)) = copy_via_temp(&instrs[index..], &result, is_toplevel) {
+            result.extend(copy);
+            result.extend(ptr_increment);
+            index += 4;
+            continue;
+        }
+
+        result.push(match instrs[index].clone() {
+            Loop { body, position } => {
+                Loop {
+                    body: fuse_copy_via_temp_inner(body, false),
+                    position: position,
+                }
+            }
+            instr => instr,
+        });
+        index += 1;
+    }
+    result
+}
+
+/// If `instrs` starts with a copy via a temporary cell, return the
+/// MultiplyCopy that replaces it, if it still changes any cells, and
+/// the pointer increment that follows. `before` is the instructions
+/// that run before `instrs`.
+fn copy_via_temp(instrs: &[AstNode],
+                 before: &[AstNode],
+                 is_toplevel: bool)
+                 -> Option<(Option<AstNode>, Option<AstNode>)> {
+    if let [ref first,
+            PointerIncrement { amount: temp, .. },
+            Move { offset: back_offset, position: back_position },
+            PointerIncrement { amount, position: ptr_position }, ..] = *instrs {
+        let first_changes = move_changes(first)?;
+        if temp == 0 || first_changes.get(&temp) != Some(&Wrapping(1)) || back_offset != -temp {
+            return None;
+        }
+        if known_cell_value_at(before, before.len(), temp, is_toplevel) != Some(Wrapping(0)) {
+            return None;
+        }
+
+        let mut changes = first_changes;
+        changes.remove(&temp);
+        let copy = if changes.is_empty() {
+            // We moved the cell to the temporary cell and back again.
+            None
+        } else {
+            Some(MultiplyCopy {
+                changes: changes,
+                temp: temp,
+                position: merged_position(get_position(first), back_position),
+            })
+        };
+        let ptr_increment = if amount + temp == 0 {
+            None
+        } else {
+            Some(PointerIncrement {
+                amount: amount + temp,
+                position: ptr_position,
+            })
+        };
+        return Some((copy, ptr_increment));
+    }
+    None
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::num::Wrapping;
 
 use quickcheck::quickcheck;
@@ -6,6 +6,7 @@ use quickcheck::quickcheck;
 use bfir::{AstNode, Position};
 use bfir::AstNode::*;
 use diagnostics::Warning;
+use interpreter::OverflowMode;
 
 use peephole::*;
 use bfir::parse;
@@ -21,9 +22,9 @@ impl Arbitrary for AstNode {
 // See https://github.com/BurntSushi/quickcheck/issues/23
 fn arbitrary_instr<G: Gen>(g: &mut G, max_depth: usize) -> AstNode {
     let modulus = if max_depth == 0 {
-        8
-    } else {
         9
+    } else {
+        10
     };
 
     // If max_depth is zero, don't create loops.
@@ -53,7 +54,7 @@ fn arbitrary_instr<G: Gen>(g: &mut G, max_depth: usize) -> AstNode {
         3 => Read { position: Some(Position { start: 0, end: 0 }) },
         4 => Write { position: Some(Position { start: 0, end: 0 }) },
         5 => {
-            let mut changes = HashMap::new();
+            let mut changes = BTreeMap::new();
             changes.insert(1, Wrapping(-1));
             MultiplyMove {
                 changes: changes,
@@ -61,7 +62,7 @@ fn arbitrary_instr<G: Gen>(g: &mut G, max_depth: usize) -> AstNode {
             }
         }
         6 => {
-            let mut changes = HashMap::new();
+            let mut changes = BTreeMap::new();
             changes.insert(1, Wrapping(2));
             changes.insert(4, Wrapping(10));
             MultiplyMove {
@@ -85,6 +86,12 @@ fn arbitrary_instr<G: Gen>(g: &mut G, max_depth: usize) -> AstNode {
             }
         }
         8 => {
+            Move {
+                offset: 1,
+                position: Some(Position { start: 0, end: 0 }),
+            }
+        }
+        9 => {
             assert!(max_depth > 0);
             let loop_length = g.next_u32() % 10;
             let mut body: Vec<_> = vec![];
@@ -111,6 +118,29 @@ fn combine_increments_flat() {
     assert_eq!(combine_increments(initial), expected);
 }
 
+#[test]
+fn combine_increments_run() {
+    let initial = parse("+++").unwrap();
+    let expected = vec![Increment {
+                            amount: Wrapping(3),
+                            offset: 0,
+                            position: Some(Position { start: 0, end: 2 }),
+                        }];
+    assert_eq!(combine_increments(initial), expected);
+}
+
+#[test]
+fn combine_increments_across_comments() {
+    // The merged increment spans the comment too.
+    let initial = parse("+ x\n+").unwrap();
+    let expected = vec![Increment {
+                            amount: Wrapping(2),
+                            offset: 0,
+                            position: Some(Position { start: 0, end: 4 }),
+                        }];
+    assert_eq!(combine_increments(initial), expected);
+}
+
 #[test]
 fn combine_increments_unrelated() {
     let initial = parse("+>+.").unwrap();
@@ -166,6 +196,89 @@ fn combine_increment_sum_to_zero() {
     assert_eq!(combine_increments(initial), vec![]);
 }
 
+#[test]
+fn combine_increments_wraps_long_runs() {
+    // 300 is 44 modulo 256.
+    let initial = parse(&"+".repeat(300)).unwrap();
+    let expected = vec![Increment {
+                            amount: Wrapping(44),
+                            offset: 0,
+                            position: Some(Position { start: 0, end: 299 }),
+                        }];
+    assert_eq!(combine_increments(initial), expected);
+}
+
+#[test]
+fn combine_increments_wraps_extremes() {
+    let initial = vec![Increment {
+                           amount: Wrapping(127),
+                           offset: 0,
+                           position: None,
+                       },
+                       Increment {
+                           amount: Wrapping(127),
+                           offset: 0,
+                           position: None,
+                       }];
+    let expected = vec![Increment {
+                            amount: Wrapping(-2),
+                            offset: 0,
+                            position: None,
+                        }];
+    assert_eq!(combine_increments(initial), expected);
+}
+
+#[test]
+fn combine_ptr_increments_overflow() {
+    let initial = vec![PointerIncrement {
+                           amount: isize::MAX,
+                           position: None,
+                       },
+                       PointerIncrement {
+                           amount: 1,
+                           position: None,
+                       },
+                       PointerIncrement {
+                           amount: -2,
+                           position: None,
+                       }];
+    // The first increment can't absorb the second, but the second
+    // and third still combine.
+    let expected = vec![PointerIncrement {
+                            amount: isize::MAX,
+                            position: None,
+                        },
+                        PointerIncrement {
+                            amount: -1,
+                            position: None,
+                        }];
+    assert_eq!(combine_ptr_increments(initial), expected);
+}
+
+#[test]
+fn combine_ptr_increments_long_runs() {
+    let source = ">".repeat(100_000) + "+" + &"<".repeat(150_000) + ".";
+    let initial = parse(&source).unwrap();
+    let expected = vec![PointerIncrement {
+                            amount: 100_000,
+                            position: Some(Position { start: 0, end: 99_999 }),
+                        },
+                        Increment {
+                            amount: Wrapping(1),
+                            offset: 0,
+                            position: Some(Position { start: 100_000, end: 100_000 }),
+                        },
+                        PointerIncrement {
+                            amount: -150_000,
+                            position: Some(Position { start: 100_001, end: 250_000 }),
+                        },
+                        Write { position: Some(Position { start: 250_001, end: 250_001 }) }];
+    assert_eq!(combine_ptr_increments(initial.clone()), expected);
+
+    // The whole pipeline copes with motion far beyond the tape.
+    optimize(initial, &None);
+}
+
 #[test]
 fn should_combine_ptr_increments() {
     let initial = parse(">>").unwrap();
@@ -176,6 +289,167 @@ fn should_combine_ptr_increments() {
     assert_eq!(combine_ptr_increments(initial), expected);
 }
 
+#[test]
+fn combine_ptr_increments_partly_cancel() {
+    let initial = parse(">><").unwrap();
+    let expected = vec![PointerIncrement {
+                            amount: 1,
+                            position: Some(Position { start: 0, end: 2 }),
+                        }];
+    assert_eq!(combine_ptr_increments(initial), expected);
+}
+
+#[test]
+fn combine_ptr_increments_across_comments() {
+    let initial = parse(">>  <").unwrap();
+    let expected = vec![PointerIncrement {
+                            amount: 1,
+                            position: Some(Position { start: 0, end: 4 }),
+                        }];
+    assert_eq!(combine_ptr_increments(initial), expected);
+}
+
+#[test]
+fn combine_ptr_increments_remove_redundant() {
+    let initial = parse("><").unwrap();
+    assert_eq!(combine_ptr_increments(initial), vec![]);
+}
+
+#[test]
+fn combine_ptr_increments_nested() {
+    let initial = parse("[<<]").unwrap();
+    let expected = vec![Loop {
+                            body: vec![PointerIncrement {
+                                           amount: -2,
+                                           position: Some(Position { start: 1, end: 2 }),
+                                       }],
+                            position: Some(Position { start: 0, end: 3 }),
+                        }];
+    assert_eq!(combine_ptr_increments(initial), expected);
+}
+
+#[test]
+fn eval_constant_prefix_two_cells() {
+    let initial = parse("+++>++").unwrap();
+    let expected = vec![Set {
+                            amount: Wrapping(3),
+                            offset: 0,
+                            position: Some(Position { start: 0, end: 2 }),
+                        },
+                        Set {
+                            amount: Wrapping(2),
+                            offset: 1,
+                            position: Some(Position { start: 4, end: 5 }),
+                        },
+                        PointerIncrement {
+                            amount: 1,
+                            position: Some(Position { start: 3, end: 3 }),
+                        }];
+    assert_eq!(eval_constant_prefix(initial), expected);
+}
+
+#[test]
+fn eval_constant_prefix_stops_at_io() {
+    let initial = parse("+-<.+").unwrap();
+    // The pointer moves before the first cell, so we leave it alone.
+    assert_eq!(eval_constant_prefix(initial.clone()), initial);
+
+    let initial = parse("+>-<[.]").unwrap();
+    let result = eval_constant_prefix(initial);
+    assert_eq!(result.len(), 3);
+    assert!(matches!(result[2], Loop { .. }));
+}
+
+#[test]
+fn prune_noops_removes_zero_increments() {
+    let initial = vec![Increment {
+                           amount: Wrapping(0),
+                           offset: 1,
+                           position: Some(Position { start: 0, end: 1 }),
+                       },
+                       Loop {
+                           body: vec![PointerIncrement {
+                                          amount: 0,
+                                          position: Some(Position { start: 3, end: 4 }),
+                                      }],
+                           position: Some(Position { start: 2, end: 5 }),
+                       }];
+    let expected = vec![Loop {
+                            body: vec![],
+                            position: Some(Position { start: 2, end: 5 }),
+                        }];
+    assert_eq!(prune_noops(initial), expected);
+}
+
+#[test]
+fn prune_noops_keeps_last_set() {
+    let initial = vec![Set {
+                           amount: Wrapping(3),
+                           offset: 0,
+                           position: Some(Position { start: 0, end: 0 }),
+                       },
+                       Set {
+                           amount: Wrapping(5),
+                           offset: 0,
+                           position: Some(Position { start: 1, end: 1 }),
+                       }];
+    let expected = vec![Set {
+                            amount: Wrapping(5),
+                            offset: 0,
+                            position: Some(Position { start: 1, end: 1 }),
+                        }];
+    assert_eq!(prune_noops(initial), expected);
+}
+
+#[test]
+fn prune_noops_keeps_sets_of_different_cells() {
+    let initial = vec![Set {
+                           amount: Wrapping(3),
+                           offset: 0,
+                           position: None,
+                       },
+                       Set {
+                           amount: Wrapping(5),
+                           offset: 1,
+                           position: None,
+                       }];
+    assert_eq!(prune_noops(initial.clone()), initial);
+}
+
+#[test]
+fn prune_noops_removes_empty_loop_after_loop() {
+    // We only leave [-] when the cell is zero, so [] is dead.
+    let initial = parse("[-][]").unwrap();
+    let expected = vec![initial[0].clone()];
+    assert_eq!(prune_noops(initial), expected);
+}
+
+#[test]
+fn prune_noops_removes_empty_loop_after_zero_set() {
+    let initial = vec![Set {
+                           amount: Wrapping(0),
+                           offset: 0,
+                           position: None,
+                       },
+                       Write { position: None },
+                       Loop {
+                           body: vec![],
+                           position: None,
+                       }];
+    let expected = initial[..2].to_vec();
+    assert_eq!(prune_noops(initial), expected);
+}
+
+#[test]
+fn prune_noops_keeps_empty_loop_on_unknown_cell() {
+    let initial = parse(",[]").unwrap();
+    assert_eq!(prune_noops(initial.clone()), initial);
+
+    // The cell inside a loop body is nonzero.
+    let initial = parse("[[]-]").unwrap();
+    assert_eq!(prune_noops(initial.clone()), initial);
+}
+
 #[test]
 fn combine_set_sum_to_zero() {
     let initial = vec![Set {
@@ -270,7 +544,7 @@ fn no_combine_before_read_after_write() {
 
 #[test]
 fn no_combine_before_read_after_multiply() {
-    let mut changes = HashMap::new();
+    let mut changes = BTreeMap::new();
     changes.insert(1, Wrapping(-1));
     let initial = vec![MultiplyMove { changes: changes, position: None}, Read { position: None }];
     let expected = initial.clone();
@@ -288,6 +562,108 @@ fn simplify_zeroing_loop() {
     assert_eq!(zeroing_loops(initial), expected);
 }
 
+#[test]
+fn simplify_incrementing_zeroing_loop() {
+    let initial = parse("[+]").unwrap();
+    let expected = vec![Set {
+                            amount: Wrapping(0),
+                            offset: 0,
+                            position: Some(Position { start: 0, end: 2 }),
+                        }];
+    assert_eq!(zeroing_loops(initial), expected);
+}
+
+#[test]
+fn zeroing_loops_without_wrapping() {
+    let initial = parse("[-]").unwrap();
+    let expected = vec![Set {
+                            amount: Wrapping(0),
+                            offset: 0,
+                            position: Some(Position { start: 0, end: 2 }),
+                        }];
+    assert_eq!(zeroing_loops_for_mode(initial, OverflowMode::Error),
+               expected);
+
+    // [+] only reaches zero by wrapping around, so it must stay a
+    // loop, including inside other loops.
+    let initial = parse("[+]+[[+]]").unwrap();
+    assert_eq!(zeroing_loops_for_mode(initial.clone(), OverflowMode::Error),
+               initial);
+}
+
+#[test]
+fn optimize_without_wrapping_keeps_counting_up() {
+    let initial = parse(",[+].").unwrap();
+    let (result, _) = optimize_with_mode(initial.clone(), &None, OverflowMode::Error);
+    assert_eq!(result, initial);
+
+    let (result, _) = optimize_with_mode(initial, &None, OverflowMode::Wrap);
+    assert!(!result.iter().any(|instr| matches!(*instr, Loop { .. })));
+}
+
+#[test]
+fn scan_zero_right() {
+    let initial = parse("[>]").unwrap();
+    let expected = vec![ScanZero {
+                            step: 1,
+                            position: Some(Position { start: 0, end: 2 }),
+                        }];
+    assert_eq!(scan_zero_loops(initial), expected);
+}
+
+#[test]
+fn scan_zero_left_nested() {
+    let initial = combine_ptr_increments(parse("[[<<]]").unwrap());
+    let expected = vec![Loop {
+                            body: vec![ScanZero {
+                                           step: -2,
+                                           position: Some(Position { start: 1, end: 4 }),
+                                       }],
+                            position: Some(Position { start: 0, end: 5 }),
+                        }];
+    assert_eq!(scan_zero_loops(initial), expected);
+}
+
+#[test]
+fn scan_zero_step_two() {
+    let initial = combine_ptr_increments(parse("[>>]").unwrap());
+    let expected = vec![ScanZero {
+                            step: 2,
+                            position: Some(Position { start: 0, end: 3 }),
+                        }];
+    assert_eq!(scan_zero_loops(initial), expected);
+}
+
+#[test]
+fn dont_scan_zero_zero_step() {
+    // A zero step never reaches another cell, so this never
+    // terminates if it's entered.
+    let initial = vec![Loop {
+                           body: vec![PointerIncrement {
+                                          amount: 0,
+                                          position: None,
+                                      }],
+                           position: None,
+                       }];
+    assert_eq!(scan_zero_loops(initial.clone()), initial);
+}
+
+#[test]
+fn dont_scan_zero_other_loops() {
+    let initial = parse("[>+][][>.]").unwrap();
+    assert_eq!(scan_zero_loops(initial.clone()), initial);
+}
+
+#[test]
+fn remove_dead_scan_zero() {
+    let initial = optimize(parse("[>][<]").unwrap(), &None).0;
+    assert_eq!(initial,
+               vec![ScanZero {
+                        step: 1,
+                        position: Some(Position { start: 0, end: 2 }),
+                    }]);
+}
+
 #[test]
 fn simplify_nested_zeroing_loop() {
     let initial = parse("[[-]]").unwrap();
@@ -357,6 +733,35 @@ fn remove_dead_loops_after_set() {
     assert_eq!(remove_dead_loops(initial), expected);
 }
 
+#[test]
+fn remove_dead_loops_at_program_start() {
+    // Cells start at zero, so a leading loop never executes. The
+    // annotation after the loop is left for combine_set to clean up.
+    let initial = annotate_known_zero(parse("[.>]+").unwrap());
+    let expected = vec![Set {
+                            amount: Wrapping(0),
+                            offset: 0,
+                            position: Some(Position { start: 0, end: 0 }),
+                        },
+                        Set {
+                            amount: Wrapping(0),
+                            offset: 0,
+                            position: Some(Position { start: 3, end: 3 }),
+                        },
+                        Increment {
+                            amount: Wrapping(1),
+                            offset: 0,
+                            position: Some(Position { start: 4, end: 4 }),
+                        }];
+    assert_eq!(remove_dead_loops(initial), expected);
+}
+
+#[test]
+fn keep_loops_after_start_increment() {
+    let initial = annotate_known_zero(parse("+[.>]").unwrap());
+    assert_eq!(remove_dead_loops(initial.clone()), initial);
+}
+
 #[test]
 fn remove_dead_loops_nested() {
     let initial = vec![Loop {
@@ -411,6 +816,111 @@ fn remove_dead_loops_not_adjacent() {
     assert_eq!(remove_dead_loops(initial), expected);
 }
 
+#[test]
+fn specialize_known_guards_after_set() {
+    let initial = vec![Set {
+                           amount: Wrapping(0),
+                           offset: 0,
+                           position: None,
+                       },
+                       Loop {
+                           body: vec![Write { position: None }],
+                           position: None,
+                       }];
+    let expected = vec![Set {
+                            amount: Wrapping(0),
+                            offset: 0,
+                            position: None,
+                        }];
+    assert_eq!(specialize_known_guards(initial), expected);
+}
+
+#[test]
+fn specialize_known_guards_through_increments() {
+    // The cell is zero after the first loop, and still zero after +-,
+    // so the second loop never runs.
+    let initial = parse("[-]+-[.]").unwrap();
+    let expected = parse("[-]+-").unwrap();
+    assert_eq!(specialize_known_guards(initial), expected);
+
+    // We also track the cell across pointer movement.
+    let initial = annotate_known_zero(parse(">+<[.]").unwrap());
+    let mut expected = initial.clone();
+    expected.remove(4);
+    assert_eq!(specialize_known_guards(initial), expected);
+}
+
+#[test]
+fn specialize_known_guards_keeps_live_loops() {
+    let initial = annotate_known_zero(parse("+[-]").unwrap());
+    assert_eq!(specialize_known_guards(initial.clone()), initial);
+
+    let initial = annotate_known_zero(parse(",[.]").unwrap());
+    assert_eq!(specialize_known_guards(initial.clone()), initial);
+
+    // We don't know the value of other cells after a loop.
+    let initial = parse("[-]>[.]").unwrap();
+    assert_eq!(specialize_known_guards(initial.clone()), initial);
+}
+
+#[test]
+fn unroll_small_counting_loop() {
+    let mut initial = vec![Set {
+                               amount: Wrapping(3),
+                               offset: 0,
+                               position: None,
+                           }];
+    initial.extend(parse("[->+<]").unwrap());
+
+    let mut expected = initial.clone();
+    if let Some(Loop { body, .. }) = expected.pop() {
+        for _ in 0..3 {
+            expected.extend(body.iter().cloned());
+        }
+    }
+    assert_eq!(unroll_small_loops(initial, 3), expected);
+}
+
+#[test]
+fn unroll_small_loops_after_increments() {
+    // Increments of a known cell are also a known guard.
+    let mut initial = vec![Set {
+                               amount: Wrapping(0),
+                               offset: 0,
+                               position: None,
+                           }];
+    initial.extend(parse("++[-]").unwrap());
+    let mut expected = initial.clone();
+    if let Some(Loop { body, .. }) = expected.pop() {
+        expected.extend(body.iter().cloned());
+        expected.extend(body.iter().cloned());
+    }
+    assert_eq!(unroll_small_loops(initial, 2), expected);
+}
+
+#[test]
+fn dont_unroll_large_or_unknown_loops() {
+    let initial = annotate_known_zero(parse("++++[->+<]").unwrap());
+    assert_eq!(unroll_small_loops(initial.clone(), 3), initial);
+
+    let initial = annotate_known_zero(parse(",[->+<]").unwrap());
+    assert_eq!(unroll_small_loops(initial.clone(), 3), initial);
+
+    // [->+] moves the pointer, so the guard isn't a counter.
+    let initial = annotate_known_zero(parse("+[->+]").unwrap());
+    assert_eq!(unroll_small_loops(initial.clone(), 3), initial);
+}
+
+#[test]
+fn specialize_known_guards_nested() {
+    let initial = parse("+[[-]-+[.]-]").unwrap();
+    let mut expected = initial.clone();
+    if let Loop { ref mut body, .. } = expected[1] {
+        body.remove(3);
+    }
+    assert_eq!(specialize_known_guards(initial), expected);
+}
+
 #[test]
 fn quickcheck_should_combine_set_and_increment() {
     fn should_combine_set_and_increment(offset: isize,
@@ -440,6 +950,40 @@ fn quickcheck_should_combine_set_and_increment() {
     quickcheck(should_combine_set_and_increment as fn(isize, i8, i8) -> bool);
 }
 
+#[test]
+fn combine_set_after_zeroing_loop() {
+    // Clearing a cell then loading a constant is a common idiom.
+    let mut source = "[-]".to_owned();
+    for _ in 0..65 {
+        source.push('+');
+    }
+    let initial = combine_increments(zeroing_loops(parse(&source).unwrap()));
+
+    let expected = vec![Set {
+                            amount: Wrapping(65),
+                            offset: 0,
+                            position: Some(Position { start: 0, end: 67 }),
+                        }];
+    assert_eq!(combine_set_and_increments(initial), expected);
+}
+
+#[test]
+fn dont_combine_set_and_increment_across_breakpoint() {
+    // A debugger may look at the cell at the breakpoint.
+    let initial = vec![Set {
+                           amount: Wrapping(0),
+                           offset: 0,
+                           position: None,
+                       },
+                       Breakpoint { position: None },
+                       Increment {
+                           amount: Wrapping(1),
+                           offset: 0,
+                           position: None,
+                       }];
+    assert_eq!(combine_set_and_increments(initial.clone()), initial);
+}
+
 // TODO: rename our quickcheck property functions to something shorter.
 #[test]
 fn quickcheck_combine_set_and_increment_different_offsets() {
@@ -627,7 +1171,7 @@ fn should_remove_redundant_set() {
 
 #[test]
 fn should_remove_redundant_set_multiply() {
-    let mut changes = HashMap::new();
+    let mut changes = BTreeMap::new();
     changes.insert(1, Wrapping(1));
 
     let initial = vec![MultiplyMove {
@@ -646,6 +1190,22 @@ fn should_remove_redundant_set_multiply() {
     assert_eq!(remove_redundant_sets(initial), expected);
 }
 
+#[test]
+fn optimize_removes_set_after_multiply() {
+    // Cells are zero after the loop, so annotate_known_zero adds a
+    // Set(0), which the MultiplyMove makes redundant.
+    let instrs = optimize(parse(",[->++<]>.").unwrap(), &None).0;
+    assert!(matches!(instrs[1], MultiplyMove { .. }));
+    assert!(!matches!(instrs[2], Set { .. }));
+}
+
+#[test]
+fn optimize_removes_set_after_move() {
+    let instrs = optimize(parse(",[->+<]>.").unwrap(), &None).0;
+    assert!(matches!(instrs[1], Move { offset: 1, .. }));
+    assert!(!matches!(instrs[2], Set { .. }));
+}
+
 /// After a loop, if we set to a value other than zero, we shouldn't
 /// remove it.
 #[test]
@@ -900,7 +1460,7 @@ fn quickcheck_optimize_should_decrease_size() {
 fn should_extract_multiply_simple() {
     let instrs = parse("[->+++<]").unwrap();
 
-    let mut dest_cells = HashMap::new();
+    let mut dest_cells = BTreeMap::new();
     dest_cells.insert(1, Wrapping(3));
     let expected = vec![MultiplyMove {
                             changes: dest_cells,
@@ -910,15 +1470,105 @@ fn should_extract_multiply_simple() {
     assert_eq!(extract_multiply(instrs), expected);
 }
 
+#[test]
+fn should_extract_move() {
+    let instrs = parse("[->+<]").unwrap();
+    let expected = vec![Move {
+                            offset: 1,
+                            position: Some(Position { start: 0, end: 5 }),
+                        }];
+    assert_eq!(extract_multiply(instrs), expected);
+
+    let instrs = parse("[-<<+>>]").unwrap();
+    assert!(matches!(extract_multiply(instrs)[..], [Move { offset: -2, .. }]));
+}
+
+#[test]
+fn should_extract_multiply_not_move() {
+    // Copying to two cells, or adding twice the cell, isn't a move.
+    for source in &["[->+>+<<]", "[->++<]"] {
+        let instrs = extract_multiply(parse(source).unwrap());
+        assert!(matches!(instrs[..], [MultiplyMove { .. }]));
+    }
+}
+
+#[test]
+fn should_extract_multiply_by_two() {
+    let instrs = parse("[->++<]").unwrap();
+
+    let mut dest_cells = BTreeMap::new();
+    dest_cells.insert(1, Wrapping(2));
+    let expected = vec![MultiplyMove {
+                            changes: dest_cells,
+                            position: Some(Position { start: 0, end: 6 }),
+                        }];
+
+    assert_eq!(extract_multiply(instrs), expected);
+}
+
+/// After sorting by offset, loop bodies may contain increments with
+/// offsets, so we must take the offset into account.
+#[test]
+fn should_extract_multiply_with_offsets() {
+    let instrs = vec![Loop {
+                          body: vec![Increment {
+                                         amount: Wrapping(-1),
+                                         offset: 0,
+                                         position: Some(Position { start: 1, end: 1 }),
+                                     },
+                                     Increment {
+                                         amount: Wrapping(2),
+                                         offset: 1,
+                                         position: Some(Position { start: 3, end: 4 }),
+                                     }],
+                          position: Some(Position { start: 0, end: 6 }),
+                      }];
+
+    let mut dest_cells = BTreeMap::new();
+    dest_cells.insert(1, Wrapping(2));
+    let expected = vec![MultiplyMove {
+                            changes: dest_cells,
+                            position: Some(Position { start: 0, end: 6 }),
+                        }];
+
+    assert_eq!(extract_multiply(instrs), expected);
+}
+
+/// This loop increments cell #0 by 2 and decrements cell #1 by 1,
+/// so it's not a multiply loop.
+#[test]
+fn should_not_extract_multiply_with_misleading_offsets() {
+    let instrs = vec![Loop {
+                          body: vec![Increment {
+                                         amount: Wrapping(-1),
+                                         offset: 1,
+                                         position: None,
+                                     },
+                                     PointerIncrement {
+                                         amount: 1,
+                                         position: None,
+                                     },
+                                     Increment {
+                                         amount: Wrapping(2),
+                                         offset: -1,
+                                         position: None,
+                                     },
+                                     PointerIncrement {
+                                         amount: -1,
+                                         position: None,
+                                     }],
+                          position: None,
+                      }];
+    assert_eq!(extract_multiply(instrs.clone()), instrs);
+}
+
 #[test]
 fn should_extract_multiply_nested() {
     let instrs = parse("[[->+<]]").unwrap();
 
-    let mut dest_cells = HashMap::new();
-    dest_cells.insert(1, Wrapping(1));
     let expected = vec![Loop {
-                            body: vec![MultiplyMove {
-                                           changes: dest_cells,
+                            body: vec![Move {
+                                           offset: 1,
                                            position: Some(Position { start: 1, end: 6 }),
                                        }],
                             position: Some(Position { start: 0, end: 7 }),
@@ -931,7 +1581,7 @@ fn should_extract_multiply_nested() {
 fn should_extract_multiply_negative_number() {
     let instrs = parse("[->--<]").unwrap();
 
-    let mut dest_cells = HashMap::new();
+    let mut dest_cells = BTreeMap::new();
     dest_cells.insert(1, Wrapping(-2));
     let expected = vec![MultiplyMove {
                             changes: dest_cells,
@@ -945,7 +1595,7 @@ fn should_extract_multiply_negative_number() {
 fn should_extract_multiply_multiple_cells() {
     let instrs = parse("[->+++>>>+<<<<]").unwrap();
 
-    let mut dest_cells = HashMap::new();
+    let mut dest_cells = BTreeMap::new();
     dest_cells.insert(1, Wrapping(3));
     dest_cells.insert(4, Wrapping(1));
     let expected = vec![MultiplyMove {
@@ -959,6 +1609,27 @@ fn should_extract_multiply_multiple_cells() {
     assert_eq!(extract_multiply(instrs), expected);
 }
 
+#[test]
+fn optimize_multiply_to_several_cells_is_one_node() {
+    // Read first and write after, so the loop isn't dead.
+    let (instrs, _) = optimize(parse(",[->+>++<<]>.").unwrap(), &None);
+
+    let mut dest_cells = BTreeMap::new();
+    dest_cells.insert(1, Wrapping(1));
+    dest_cells.insert(2, Wrapping(2));
+    let expected = vec![Read { position: Some(Position { start: 0, end: 0 }) },
+                        MultiplyMove {
+                            changes: dest_cells,
+                            position: Some(Position { start: 1, end: 10 }),
+                        },
+                        PointerIncrement {
+                            amount: 1,
+                            position: Some(Position { start: 11, end: 11 }),
+                        },
+                        Write { position: Some(Position { start: 12, end: 12 }) }];
+    assert_eq!(instrs, expected);
+}
+
 #[test]
 fn should_not_extract_multiply_net_movement() {
     let instrs = parse("[->+++<<]").unwrap();
@@ -997,6 +1668,108 @@ fn should_not_extract_multiply_with_write() {
     assert_eq!(extract_multiply(instrs.clone()), instrs);
 }
 
+#[test]
+fn should_not_extract_multiply_copy_with_io() {
+    let instrs = parse("[->+<.]").unwrap();
+    assert_eq!(extract_multiply(instrs.clone()), instrs);
+
+    let instrs = parse("[,->+<]").unwrap();
+    assert_eq!(extract_multiply(instrs.clone()), instrs);
+}
+
+/// Parse `source` and extract its multiply loops, as the multiply
+/// pass does, so we can look for copies.
+fn with_multiply_moves(source: &str) -> Vec<AstNode> {
+    extract_multiply(combine_ptr_increments(parse(source).unwrap()))
+}
+
+#[test]
+fn should_fuse_copy_via_temp() {
+    let instrs = with_multiply_moves(",[->+>+<<]>>[-<<+>>]<<.");
+
+    let mut changes = BTreeMap::new();
+    changes.insert(1, Wrapping(1));
+    let expected = vec![Read { position: Some(Position { start: 0, end: 0 }) },
+                        MultiplyCopy {
+                            changes: changes,
+                            temp: 2,
+                            position: Some(Position { start: 1, end: 19 }),
+                        },
+                        Write { position: Some(Position { start: 22, end: 22 }) }];
+    assert_eq!(fuse_copy_via_temp(instrs), expected);
+}
+
+#[test]
+fn should_fuse_move_to_temp_and_back() {
+    // Moving a cell away and straight back does nothing, apart from
+    // the pointer movement afterwards.
+    let instrs = with_multiply_moves(",[->+<]>[-<+>]<<");
+    let expected = vec![Read { position: Some(Position { start: 0, end: 0 }) },
+                        PointerIncrement {
+                            amount: -1,
+                            position: Some(Position { start: 14, end: 15 }),
+                        }];
+    assert_eq!(fuse_copy_via_temp(instrs), expected);
+}
+
+#[test]
+fn should_not_fuse_copy_via_nonzero_temp() {
+    let instrs = with_multiply_moves(",>>,<<[->+>+<<]>>[-<<+>>]<<");
+    assert_eq!(fuse_copy_via_temp(instrs.clone()), instrs);
+
+    // We don't know what's in the temporary cell at the start of a
+    // loop body.
+    let instrs = with_multiply_moves(",[[->+>+<<]>>[-<<+>>]<<]");
+    assert_eq!(fuse_copy_via_temp(instrs.clone()), instrs);
+}
+
+#[test]
+fn should_not_fuse_copy_with_intervening_instructions() {
+    let instrs = with_multiply_moves(",[->+>+<<]>>.[-<<+>>]<<");
+    assert_eq!(fuse_copy_via_temp(instrs.clone()), instrs);
+
+    // The second loop moves the temporary cell somewhere else.
+    let instrs = with_multiply_moves(",[->+>+<<]>>[-<+>]<<");
+    assert_eq!(fuse_copy_via_temp(instrs.clone()), instrs);
+}
+
+#[test]
+fn optimize_copy_via_temp_preserves_behaviour() {
+    use interpreter::{interpret_full, Options};
+
+    let source = ",[->+>+<<]>>[-<<+>>]<<.>.>.";
+    let unoptimized = parse(source).unwrap();
+    let (optimized, _) = optimize(unoptimized.clone(), &None);
+    assert!(optimized.iter().any(|instr| matches!(*instr, MultiplyCopy { .. })));
+
+    for instrs in &[unoptimized, optimized] {
+        let mut output = vec![];
+        let outcome = interpret_full(instrs, &mut &b"A"[..], &mut output, &Options::default())
+            .unwrap();
+        assert_eq!(output, b"AA\0");
+        assert_eq!(outcome.tape, vec![65, 65, 0]);
+    }
+}
+
+#[test]
+fn loop_is_counting_countdown_loops() {
+    assert_eq!(loop_is_counting(&parse("-").unwrap()), Some(()));
+    assert_eq!(loop_is_counting(&parse("->+<").unwrap()), Some(()));
+    assert_eq!(loop_is_counting(&parse(">++<-").unwrap()), Some(()));
+}
+
+#[test]
+fn loop_is_counting_other_loops() {
+    // The guard moves.
+    assert_eq!(loop_is_counting(&parse("+>").unwrap()), None);
+    // The guard changes by more than one, or increases.
+    assert_eq!(loop_is_counting(&parse("--").unwrap()), None);
+    assert_eq!(loop_is_counting(&parse("+").unwrap()), None);
+    // We can't tell how many times I/O or inner loops run.
+    assert_eq!(loop_is_counting(&parse("-.").unwrap()), None);
+    assert_eq!(loop_is_counting(&parse("-[>]").unwrap()), None);
+}
+
 #[test]
 fn sort_by_offset_increment() {
     let instrs = parse("+>+>").unwrap();
@@ -1040,12 +1813,51 @@ fn sort_by_offset_increment_nested() {
     assert_eq!(sort_by_offset(instrs), expected);
 }
 
+#[test]
+fn sort_by_offset_balanced_movement() {
+    let instrs = combine_increments(sort_by_offset(parse(">++<").unwrap()));
+    let expected = vec![Increment {
+                            amount: Wrapping(2),
+                            offset: 1,
+                            position: Some(Position { start: 1, end: 2 }),
+                        }];
+    assert_eq!(instrs, expected);
+}
+
 #[test]
 fn sort_by_offset_remove_redundant() {
     let initial = parse("><").unwrap();
     assert_eq!(sort_by_offset(initial), vec![]);
 }
 
+#[test]
+fn sort_by_offset_orders_offset_instrs() {
+    // Backends emit cell operations in ascending offset order, which
+    // is kinder to the cache.
+    let instrs = vec![Set {
+                          amount: Wrapping(3),
+                          offset: 2,
+                          position: None,
+                      },
+                      Increment {
+                          amount: Wrapping(1),
+                          offset: -1,
+                          position: None,
+                      },
+                      Set {
+                          amount: Wrapping(5),
+                          offset: 0,
+                          position: None,
+                      },
+                      Increment {
+                          amount: Wrapping(2),
+                          offset: 2,
+                          position: None,
+                      }];
+    let expected = vec![instrs[1].clone(), instrs[2].clone(), instrs[0].clone(), instrs[3].clone()];
+    assert_eq!(sort_by_offset(instrs), expected);
+}
+
 // If there's a read instruction, we should only combine before and
 // after.
 #[test]
@@ -1131,8 +1943,8 @@ fn quickcheck_sort_by_offset_pointer_increments() {
     quickcheck(sort_by_offset_pointer_increments as fn(isize, isize) -> TestResult);
 }
 
-// Don't combine instruction positions when they weren't originally
-// adjacent.
+// Merged increments span all the source they came from, even when
+// it wasn't originally adjacent.
 #[test]
 fn combine_increments_non_adjacent_instrs() {
     let instrs = vec![Increment {
@@ -1148,7 +1960,7 @@ fn combine_increments_non_adjacent_instrs() {
     let expected = vec![Increment {
                             amount: Wrapping(2),
                             offset: 0,
-                            position: Some(Position { start: 2, end: 2 }),
+                            position: Some(Position { start: 0, end: 2 }),
                         }];
     assert_eq!(combine_increments(instrs), expected);
 }
@@ -1184,7 +1996,7 @@ fn combine_increments_after_sort() {
                         Increment {
                             amount: Wrapping(2),
                             offset: 0,
-                            position: Some(Position { start: 5, end: 5 }),
+                            position: Some(Position { start: 1, end: 5 }),
                         },
                         Increment {
                             amount: Wrapping(1),
@@ -1236,7 +2048,7 @@ fn prev_mutate_ignores_offset_at_index() {
 
 #[test]
 fn prev_mutate_multiply_offset_matches() {
-    let mut changes = HashMap::new();
+    let mut changes = BTreeMap::new();
     changes.insert(-1, Wrapping(-1));
 
     let instrs = vec![MultiplyMove {
@@ -1253,7 +2065,7 @@ fn prev_mutate_multiply_offset_matches() {
 
 #[test]
 fn prev_mutate_multiply_offset_doesnt_match() {
-    let mut changes = HashMap::new();
+    let mut changes = BTreeMap::new();
     changes.insert(1, Wrapping(2));
 
     let instrs = vec![MultiplyMove {
@@ -1272,7 +2084,7 @@ fn prev_mutate_multiply_offset_doesnt_match() {
 /// of the current value.
 #[test]
 fn prev_mutate_multiply_ignore_offset() {
-    let mut changes = HashMap::new();
+    let mut changes = BTreeMap::new();
     changes.insert(1, Wrapping(-1));
 
     let instrs = vec![MultiplyMove {
@@ -1391,3 +2203,136 @@ fn next_mutate_consider_pointer_increment() {
 
     assert_eq!(next_cell_change(&instrs, 0), Some(3));
 }
+
+#[test]
+fn opt_level_from_arg() {
+    assert_eq!(OptLevel::from_arg("0"), Some(OptLevel::None));
+    assert_eq!(OptLevel::from_arg("1"), Some(OptLevel::Basic));
+    assert_eq!(OptLevel::from_arg("2"), Some(OptLevel::Aggressive));
+    assert_eq!(OptLevel::from_arg("3"), None);
+}
+
+#[test]
+fn optimize_for_level_none_is_unchanged() {
+    let initial = parse("+-").unwrap();
+    let (result, warnings) = optimize_for_level(initial.clone(), OptLevel::None, &None);
+    assert_eq!(result, initial);
+    assert_eq!(warnings, vec![]);
+}
+
+#[test]
+fn optimize_for_level_basic() {
+    let initial = parse("+-.").unwrap();
+    let expected = vec![Write { position: Some(Position { start: 2, end: 2 }) }];
+    assert_eq!(optimize_for_level(initial, OptLevel::Basic, &None).0, expected);
+}
+
+#[test]
+fn normalize_cancelling_pointer_increments() {
+    let instrs = parse("++>><<").unwrap();
+    let expected = vec![Increment {
+                            amount: Wrapping(2),
+                            offset: 0,
+                            position: Some(Position { start: 0, end: 1 }),
+                        }];
+    assert_eq!(normalize(instrs), expected);
+}
+
+/// Removing `><` makes the increments adjacent, so they merge too.
+#[test]
+fn normalize_to_fixed_point() {
+    assert_eq!(normalize(parse("+><+").unwrap()),
+               vec![Increment {
+                        amount: Wrapping(2),
+                        offset: 0,
+                        position: Some(Position { start: 0, end: 3 }),
+                    }]);
+    assert_eq!(normalize(parse("[+-]").unwrap()),
+               vec![Loop {
+                        body: vec![],
+                        position: Some(Position { start: 0, end: 3 }),
+                    }]);
+}
+
+#[test]
+fn normalize_is_idempotent() {
+    fn is_idempotent(instrs: Vec<AstNode>) -> bool {
+        let normalized = normalize(instrs);
+        normalize(normalized.clone()) == normalized
+    }
+    quickcheck(is_idempotent as fn(Vec<AstNode>) -> bool);
+}
+
+#[test]
+fn concat_normalized_merges_joins() {
+    use bfir::concat;
+    use interpreter::interpret;
+
+    let programs = [parse("[+]++").unwrap(), parse("+++").unwrap(), parse(".").unwrap()];
+    let instrs = concat_normalized(&programs);
+    assert_eq!(instrs.len(), 3);
+    assert!(matches!(instrs[1], Increment { amount: Wrapping(5), offset: 0, .. }));
+
+    for instrs in &[concat(&programs), instrs] {
+        let mut output = vec![];
+        interpret(instrs, &mut &b""[..], &mut output).unwrap();
+        assert_eq!(output, vec![5]);
+    }
+}
+
+#[test]
+fn optimize_with_trace_records_passes() {
+    let (instrs, trace) = optimize_with_trace(parse("[-]+++").unwrap(),
+                                              OptLevel::Basic,
+                                              OverflowMode::Wrap);
+    assert_eq!(instrs, optimize(parse("[-]+++").unwrap(), &None).0);
+
+    let fired: Vec<_> = trace.iter()
+        .filter(|result| result.changed)
+        .map(|result| result.name)
+        .collect();
+    assert!(fired.contains(&"zeroing_loop"));
+    assert!(fired.contains(&"combine_set"));
+
+    // [-] is a loop and an increment, and becomes a single Set.
+    let zeroing = trace.iter().find(|result| result.name == "zeroing_loop").unwrap();
+    assert_eq!(zeroing.nodes_before - zeroing.nodes_after, 1);
+}
+
+#[test]
+fn optimize_with_trace_durations() {
+    use std::time::{Duration, Instant};
+
+    let instrs = parse(include_str!("../sample_programs/mandelbrot.bf")).unwrap();
+    let start = Instant::now();
+    let (_, trace) = optimize_with_trace(instrs, OptLevel::Basic, OverflowMode::Wrap);
+    let elapsed = start.elapsed();
+
+    assert!(!trace.is_empty());
+    let total = trace.iter().fold(Duration::new(0, 0), |total, result| total + result.duration);
+    assert!(total <= elapsed);
+}
+
+#[test]
+fn optimize_with_trace_no_optimisation() {
+    let initial = parse("[-]+++").unwrap();
+    let (instrs, trace) = optimize_with_trace(initial.clone(), OptLevel::None, OverflowMode::Wrap);
+    assert_eq!(instrs, initial);
+    assert_eq!(trace, vec![]);
+}
+
+#[test]
+fn optimize_produces_valid_ir() {
+    use bfir::{validate_ir, IrChecks};
+
+    let checks = IrChecks {
+        normalized: true,
+        non_empty_loops: false,
+    };
+    for source in &[include_str!("../sample_programs/mandelbrot.bf"),
+                    include_str!("../sample_programs/life.bf"),
+                    include_str!("../sample_programs/factor.bf")] {
+        let (instrs, _) = optimize(parse(source).unwrap(), &None);
+        assert_eq!(validate_ir(&instrs, checks), Ok(()));
+    }
+}
@@ -0,0 +1,151 @@
+//! A high-level interface to bfc, keeping a program's source together
+//! with its instructions, so errors and warnings can always be shown
+//! in context.
+//!
+//! ```
+//! use bfc::program::Program;
+//!
+//! let source = include_str!("../sample_programs/hello_world.bf");
+//! let mut program = Program::parse(source).unwrap();
+//! program.optimize();
+//!
+//! let mut output = vec![];
+//! program.interpret(&mut &b""[..], &mut output).unwrap();
+//! assert_eq!(output, b"Hello World!\n");
+//! ```
+//!
+//! `Program` also implements `FromStr` and `TryFrom<&str>`, so
+//! `source.parse::<Program>()` works too.
+//!
+//! The functions in the other modules offer more control, such as
+//! choosing optimisation passes or interpreter options.
+
+use std::convert::TryFrom;
+use std::io;
+use std::mem;
+use std::str::FromStr;
+
+use bfir::{AstNode, ParseError, Position, SourceLocation, parse};
+use c::codegen_c;
+use diagnostics::Warning;
+use interpreter::{ExecutionError, interpret};
+use peephole::optimize;
+
+#[cfg(test)]
+use bfir::ParseErrorKind;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    /// The BF source code that we parsed.
+    pub source: String,
+    /// The instructions for `source`. Their positions refer to
+    /// `source`, even after optimisation.
+    pub instructions: Vec<AstNode>,
+}
+
+impl Program {
+    /// Parse `source`, keeping a copy of it.
+    pub fn parse(source: &str) -> Result<Self, ParseError> {
+        Ok(Program {
+            source: source.to_owned(),
+            instructions: parse(source)?,
+        })
+    }
+
+    /// Apply all our peephole optimisations, returning any warnings
+    /// about the program that they found.
+    pub fn optimize(&mut self) -> Vec<Warning> {
+        let instrs = mem::take(&mut self.instructions);
+        let (instrs, warnings) = optimize(instrs, &None);
+        self.instructions = instrs;
+        warnings
+    }
+
+    /// Run the program, as `interpreter::interpret`.
+    pub fn interpret<R: io::Read, W: io::Write>(&self,
+                                                input: &mut R,
+                                                output: &mut W)
+                                                -> Result<(), ExecutionError> {
+        interpret(&self.instructions, input, output)
+    }
+
+    /// Convert the program to C source code, as `c::codegen_c`.
+    pub fn codegen_c(&self) -> String {
+        codegen_c(&self.instructions)
+    }
+
+    /// Where `position` is in the source, so callers can show errors
+    /// and warnings in context.
+    pub fn location(&self, position: Position) -> SourceLocation {
+        SourceLocation::new(&self.source, position.start)
+    }
+}
+
+impl<'a> TryFrom<&'a str> for Program {
+    type Error = ParseError;
+
+    /// Parse `source`, as `Program::parse`.
+    fn try_from(source: &'a str) -> Result<Self, ParseError> {
+        Program::parse(source)
+    }
+}
+
+impl FromStr for Program {
+    type Err = ParseError;
+
+    /// Parse `source`, as `Program::parse`, so `source.parse()`
+    /// works.
+    fn from_str(source: &str) -> Result<Self, ParseError> {
+        Program::parse(source)
+    }
+}
+
+#[test]
+fn program_parse_error() {
+    let error = Program::parse("+\n[").unwrap_err();
+    assert_eq!(error.location.unwrap().line, 2);
+}
+
+#[test]
+fn program_optimize_preserves_output() {
+    let source = ",[.,]";
+    let unoptimized = Program::parse(source).unwrap();
+    let mut optimized = unoptimized.clone();
+    assert_eq!(optimized.optimize(), vec![]);
+    assert_eq!(optimized.source, source);
+
+    for program in &[unoptimized, optimized] {
+        let mut output = vec![];
+        program.interpret(&mut &b"abc"[..], &mut output).unwrap();
+        assert_eq!(output, b"abc");
+    }
+}
+
+#[test]
+fn program_location() {
+    let program = Program::parse("+\n  -").unwrap();
+    let location = program.location(Position { start: 4, end: 4 });
+    assert_eq!((location.line, location.column), (2, 3));
+    assert_eq!(location.line_text, "  -");
+}
+
+#[test]
+fn program_codegen_c() {
+    let program = Program::parse("+.").unwrap();
+    assert_eq!(program.codegen_c(), codegen_c(&program.instructions));
+}
+
+#[test]
+fn program_from_str() {
+    use std::convert::TryInto;
+
+    let program: Program = "+.".try_into().unwrap();
+    assert_eq!(program, Program::parse("+.").unwrap());
+    assert_eq!("+.".parse::<Program>().unwrap(), program);
+
+    let error = Program::try_from("[").unwrap_err();
+    assert_eq!(error.kind, ParseErrorKind::UnmatchedOpen { index: 0 });
+    let error = "+]".parse::<Program>().unwrap_err();
+    assert_eq!(error.kind, ParseErrorKind::UnmatchedClose { index: 1 });
+    assert_eq!(error.location.unwrap().column, 2);
+}
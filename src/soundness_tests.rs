@@ -1,8 +1,10 @@
 use quickcheck::{quickcheck, TestResult};
 
-use bfir::AstNode;
+use bfir::{AstNode, parse};
+use bfir::AstNode::Breakpoint;
 use execution::{execute_with_state, ExecutionState};
 use execution::Outcome::*;
+use interpreter::{interpret_with_breakpoints, ExecutionError, Options};
 use peephole::*;
 
 
@@ -105,6 +107,17 @@ fn extract_multiply_is_sound() {
     quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
 }
 
+#[test]
+fn fuse_copy_via_temp_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+        transform_is_sound(instrs,
+                           |instrs| fuse_copy_via_temp(extract_multiply(instrs)),
+                           true,
+                           None)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+}
+
 #[test]
 fn simplify_loops_is_sound() {
     fn is_sound(instrs: Vec<AstNode>) -> TestResult {
@@ -129,6 +142,22 @@ fn remove_dead_loops_is_sound() {
     quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
 }
 
+#[test]
+fn specialize_known_guards_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+        transform_is_sound(instrs, specialize_known_guards, true, None)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+}
+
+#[test]
+fn unroll_small_loops_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+        transform_is_sound(instrs, |instrs| unroll_small_loops(instrs, 10), true, None)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+}
+
 #[test]
 fn remove_redundant_sets_is_sound() {
     fn is_sound(instrs: Vec<AstNode>) -> TestResult {
@@ -169,6 +198,30 @@ fn sort_by_offset_is_sound() {
     quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
 }
 
+#[test]
+fn scan_zero_loops_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+        transform_is_sound(instrs, scan_zero_loops, true, None)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+}
+
+#[test]
+fn eval_constant_prefix_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+        transform_is_sound(instrs, eval_constant_prefix, true, None)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+}
+
+#[test]
+fn prune_noops_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+        transform_is_sound(instrs, prune_noops, true, None)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+}
+
 #[test]
 fn test_overall_optimize_is_sound() {
     fn optimize_ignore_warnings(instrs: Vec<AstNode>) -> Vec<AstNode> {
@@ -184,3 +237,90 @@ fn test_overall_optimize_is_sound() {
 
     quickcheck(optimizations_sound_together as fn(Vec<AstNode>, Option<i8>) -> TestResult);
 }
+
+/// Build a BF program with balanced brackets from arbitrary choices.
+fn program_from_choices(choices: &[u8]) -> String {
+    let mut source = String::new();
+    let mut depth = 0;
+    for &choice in choices {
+        match choice % 10 {
+            0 | 1 => source.push('+'),
+            2 => source.push('-'),
+            3 => source.push('>'),
+            4 => source.push('<'),
+            5 => source.push('.'),
+            6 => source.push(','),
+            7 => {
+                source.push('[');
+                depth += 1;
+            }
+            _ => {
+                if depth > 0 {
+                    source.push(']');
+                    depth -= 1;
+                } else {
+                    source.push('-');
+                }
+            }
+        }
+    }
+    for _ in 0..depth {
+        source.push(']');
+    }
+    source
+}
+
+/// Run `instrs` with the interpreter, returning the output and the
+/// tape and cell pointer at the last breakpoint.
+fn interpret_with_final_state(instrs: &[AstNode],
+                              input: &[u8],
+                              max_steps: u64)
+                              -> Result<(Vec<u8>, Vec<u32>, usize), ExecutionError> {
+    let options = Options { max_steps: Some(max_steps), ..Options::default() };
+    let mut output = vec![];
+    let mut final_state = None;
+    interpret_with_breakpoints(instrs,
+                               &mut &input[..],
+                               &mut output,
+                               &options,
+                               |cells: &[u32], cell_ptr| {
+                                   final_state = Some((cells.to_vec(), cell_ptr))
+                               })?;
+
+    let (cells, cell_ptr) = final_state.unwrap();
+    Ok((output, cells, cell_ptr))
+}
+
+#[test]
+fn optimize_preserves_interpreter_semantics() {
+    fn is_sound(choices: Vec<u8>, input: Vec<u8>) -> TestResult {
+        // Optimisations must preserve the tape at breakpoints, so one
+        // at the end shows us the final state.
+        let mut instrs = parse(&program_from_choices(&choices)).unwrap();
+        instrs.push(Breakpoint { position: None });
+
+        // Skip programs that don't terminate quickly, or that leave
+        // the tape, as optimisation can legitimately fix them.
+        let expected = match interpret_with_final_state(&instrs, &input, 10_000) {
+            Ok(expected) => expected,
+            Err(_) => return TestResult::discard(),
+        };
+
+        // Optimised programs can take more steps, e.g. a ScanZero
+        // step for each cell.
+        let optimised = optimize(instrs, &None).0;
+        match interpret_with_final_state(&optimised, &input, 100_000) {
+            Ok(actual) => {
+                if actual != expected {
+                    println!("Optimised program {:?} gave a different result", optimised);
+                }
+                TestResult::from_bool(actual == expected)
+            }
+            Err(e) => {
+                println!("Optimised program {:?} failed: {}", optimised, e);
+                TestResult::failed()
+            }
+        }
+    }
+    quickcheck(is_sound as fn(Vec<u8>, Vec<u8>) -> TestResult);
+}
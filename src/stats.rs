@@ -0,0 +1,157 @@
+//! Static metrics about BF programs, useful for seeing how much the
+//! optimiser has simplified a program.
+
+use std::cmp::max;
+use std::fmt;
+
+use bfir::{AstNode, node_count};
+use bfir::AstNode::*;
+
+#[cfg(test)]
+use bfir::parse;
+
+/// Counts of each kind of instruction in a program, including the
+/// instructions inside loops.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ProgramStats {
+    pub increments: usize,
+    pub pointer_increments: usize,
+    pub reads: usize,
+    pub writes: usize,
+    pub loops: usize,
+    pub sets: usize,
+    pub multiply_moves: usize,
+    pub moves: usize,
+    pub multiply_copies: usize,
+    pub scan_zeros: usize,
+    pub breakpoints: usize,
+    /// The deepest loop nesting, where 0 means there are no loops.
+    pub max_loop_depth: usize,
+    /// The total number of instructions.
+    pub total: usize,
+}
+
+fn count_instrs(instrs: &[AstNode], depth: usize, stats: &mut ProgramStats) {
+    for instr in instrs {
+        stats.total += 1;
+        match *instr {
+            Increment { .. } => stats.increments += 1,
+            PointerIncrement { .. } => stats.pointer_increments += 1,
+            Read { .. } => stats.reads += 1,
+            Write { .. } => stats.writes += 1,
+            Set { .. } => stats.sets += 1,
+            MultiplyMove { .. } => stats.multiply_moves += 1,
+            Move { .. } => stats.moves += 1,
+            MultiplyCopy { .. } => stats.multiply_copies += 1,
+            ScanZero { .. } => stats.scan_zeros += 1,
+            Breakpoint { .. } => stats.breakpoints += 1,
+            Loop { ref body, .. } => {
+                stats.loops += 1;
+                stats.max_loop_depth = max(stats.max_loop_depth, depth + 1);
+                count_instrs(body, depth + 1, stats);
+            }
+        }
+    }
+}
+
+/// Count the instructions in `instrs`.
+pub fn stats(instrs: &[AstNode]) -> ProgramStats {
+    let mut stats = ProgramStats::default();
+    count_instrs(instrs, 0, &mut stats);
+    stats
+}
+
+/// How many times smaller `after` is than `before`, counting the
+/// instructions inside loops. For example, 3.0 means that `after` has
+/// a third as many instructions.
+///
+/// If `after` is empty, the ratio is infinite, unless `before` is
+/// empty too.
+pub fn reduction_ratio(before: &[AstNode], after: &[AstNode]) -> f64 {
+    let before_total = node_count(before);
+    let after_total = node_count(after);
+    if before_total == after_total {
+        return 1.0;
+    }
+    before_total as f64 / after_total as f64
+}
+
+impl fmt::Display for ProgramStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Increment: {}", self.increments)?;
+        writeln!(f, "PointerIncrement: {}", self.pointer_increments)?;
+        writeln!(f, "Read: {}", self.reads)?;
+        writeln!(f, "Write: {}", self.writes)?;
+        writeln!(f, "Loop: {}", self.loops)?;
+        writeln!(f, "Set: {}", self.sets)?;
+        writeln!(f, "MultiplyMove: {}", self.multiply_moves)?;
+        writeln!(f, "Move: {}", self.moves)?;
+        writeln!(f, "MultiplyCopy: {}", self.multiply_copies)?;
+        writeln!(f, "ScanZero: {}", self.scan_zeros)?;
+        writeln!(f, "Breakpoint: {}", self.breakpoints)?;
+        writeln!(f, "Total instructions: {}", self.total)?;
+        write!(f, "Maximum loop depth: {}", self.max_loop_depth)
+    }
+}
+
+#[test]
+fn stats_complex_loop() {
+    let instrs = parse(".[,+]-").unwrap();
+    let expected = ProgramStats {
+        increments: 2,
+        pointer_increments: 0,
+        reads: 1,
+        writes: 1,
+        loops: 1,
+        sets: 0,
+        multiply_moves: 0,
+        moves: 0,
+        multiply_copies: 0,
+        scan_zeros: 0,
+        breakpoints: 0,
+        max_loop_depth: 1,
+        total: 5,
+    };
+    assert_eq!(stats(&instrs), expected);
+}
+
+#[test]
+fn stats_nested_loops() {
+    let instrs = parse("[>[[]]][]").unwrap();
+    let program_stats = stats(&instrs);
+    assert_eq!(program_stats.loops, 4);
+    assert_eq!(program_stats.max_loop_depth, 3);
+    assert_eq!(program_stats.total, 5);
+}
+
+#[test]
+fn stats_empty() {
+    assert_eq!(stats(&[]), ProgramStats::default());
+}
+
+#[test]
+fn stats_display() {
+    let instrs = parse("+>").unwrap();
+    assert_eq!(format!("{}", stats(&instrs)),
+               "Increment: 1\nPointerIncrement: 1\nRead: 0\nWrite: 0\nLoop: 0\nSet: 0\n\
+                MultiplyMove: 0\nMove: 0\nMultiplyCopy: 0\nScanZero: 0\nBreakpoint: 0\n\
+                Total instructions: 2\nMaximum loop depth: 0");
+}
+
+#[test]
+fn reduction_ratio_combined_increments() {
+    use peephole::combine_increments;
+
+    let before = parse("+++").unwrap();
+    let after = combine_increments(before.clone());
+    assert_eq!(reduction_ratio(&before, &after), 3.0);
+}
+
+#[test]
+fn reduction_ratio_edge_cases() {
+    let instrs = parse("[-]>").unwrap();
+    assert_eq!(reduction_ratio(&instrs, &instrs), 1.0);
+    assert_eq!(reduction_ratio(&[], &[]), 1.0);
+    assert_eq!(reduction_ratio(&instrs, &[]), f64::INFINITY);
+    assert_eq!(reduction_ratio(&instrs[1..], &instrs), 1.0 / 3.0);
+}
@@ -0,0 +1,84 @@
+//! Helpers for checking that optimisations don't change what a
+//! program does.
+//!
+//! Our soundness tests check individual passes on random programs,
+//! but the passes can still interact badly on real code. These
+//! helpers run whole programs with the interpreter, before and after
+//! optimisation, and compare the output. They're available to other
+//! crates with the `testing` feature.
+
+use bfir::parse;
+use interpreter::interpret;
+use peephole::optimize;
+
+/// Programs that exercise a mix of optimisations, as (name, source,
+/// input) triples.
+pub const FIXTURES: &[(&str, &str, &[u8])] =
+    &[("hello world", include_str!("../sample_programs/hello_world.bf"), b""),
+      ("cat", ",[.,]", b"hello, cat!"),
+      ("counter", "++++++++[>++++++<-]++++++++++[>.+<-]", b"")];
+
+/// Run `source` with `input` without optimising it, and return its
+/// output.
+///
+/// Panics if the program doesn't parse or fails at runtime, as the
+/// comparison would be meaningless.
+pub fn unoptimized_output(source: &str, input: &[u8]) -> Vec<u8> {
+    let instrs = parse(source).unwrap();
+    let mut output = vec![];
+    interpret(&instrs, &mut &input[..], &mut output).unwrap();
+    output
+}
+
+/// Run `source` with `input` after applying `passes` (or all passes,
+/// if None), and return its output.
+pub fn optimized_output(source: &str, input: &[u8], passes: &Option<String>) -> Vec<u8> {
+    let instrs = optimize(parse(source).unwrap(), passes).0;
+    let mut output = vec![];
+    interpret(&instrs, &mut &input[..], &mut output).unwrap();
+    output
+}
+
+/// Assert that optimising `source` with `passes` doesn't change its
+/// output for `input`. Returns the output, so callers can check it
+/// too.
+pub fn assert_optimization_preserves_output(source: &str,
+                                            input: &[u8],
+                                            passes: &Option<String>)
+                                            -> Vec<u8> {
+    let expected = unoptimized_output(source, input);
+    let actual = optimized_output(source, input, passes);
+    assert!(actual == expected,
+            "Optimised program wrote {:?}, but the original wrote {:?}",
+            String::from_utf8_lossy(&actual),
+            String::from_utf8_lossy(&expected));
+    actual
+}
+
+#[test]
+fn fixtures_preserve_output() {
+    for &(name, source, input) in FIXTURES {
+        let output = assert_optimization_preserves_output(source, input, &None);
+        assert!(!output.is_empty(), "{} wrote nothing", name);
+    }
+}
+
+#[test]
+fn fixtures_preserve_output_with_each_pass() {
+    let passes = ["combine_inc", "combine_ptr", "known_zero", "multiply", "copy_temp",
+                  "zeroing_loop", "scan_zero", "combine_set", "const_prefix", "dead_loop",
+                  "known_guard", "redundant_set", "read_clobber", "pure_removal",
+                  "offset_sort", "prune_noops"];
+    for pass in &passes {
+        for &(_, source, input) in FIXTURES {
+            assert_optimization_preserves_output(source, input, &Some(pass.to_string()));
+        }
+    }
+}
+
+#[test]
+fn fixture_outputs() {
+    assert_eq!(unoptimized_output(FIXTURES[0].1, b""), b"Hello World!\n".to_vec());
+    assert_eq!(unoptimized_output(",[.,]", b"abc"), b"abc".to_vec());
+    assert_eq!(unoptimized_output(FIXTURES[2].1, b""), b"0123456789".to_vec());
+}
@@ -0,0 +1,228 @@
+//! The WAT module converts a BF AST to the WebAssembly text format,
+//! so programs can run in a browser.
+//!
+//! The generated module imports two functions for I/O, which the
+//! host must provide:
+//!
+//! ```text
+//! (import "env" "read" (func $read (result i32)))
+//! (import "env" "write" (func $write (param i32)))
+//! ```
+//!
+//! `read` returns the next byte of input, and only its lowest byte
+//! is stored, so returning -1 at EOF behaves like compiled
+//! programs. `write` is called with the current cell value, between
+//! 0 and 255.
+//!
+//! The module exports a `run` function that executes the program,
+//! and the `memory` used for the cells.
+
+use bfir::{AstNode, Cell};
+use bfir::AstNode::*;
+
+use bounds::highest_cell_index;
+
+#[cfg(test)]
+use bfir::parse;
+
+/// WebAssembly memory is allocated in pages of 64 KiB.
+const PAGE_SIZE: usize = 65536;
+
+fn push_line(line: &str, indent: usize, out: &mut String) {
+    for _ in 0..indent {
+        out.push_str("  ");
+    }
+    out.push_str(line);
+    out.push('\n');
+}
+
+/// The WAT expression for the address of the cell at `offset` from
+/// the cell pointer.
+fn address(offset: isize) -> String {
+    if offset == 0 {
+        "(local.get $p)".to_owned()
+    } else {
+        format!("(i32.add (local.get $p) (i32.const {}))", offset)
+    }
+}
+
+fn load(offset: isize) -> String {
+    format!("(i32.load8_u {})", address(offset))
+}
+
+fn store(offset: isize, value: &str) -> String {
+    format!("(i32.store8 {} {})", address(offset), value)
+}
+
+/// Cells are bytes in linear memory, so we only need the unsigned
+/// value of each amount. Stores truncate to 8 bits, so the
+/// arithmetic wraps around.
+fn cell_value(amount: Cell) -> u8 {
+    amount.0 as u8
+}
+
+fn compile_instr(instr: &AstNode, indent: usize, out: &mut String) {
+    match *instr {
+        Increment { amount, offset, .. } => {
+            let sum = format!("(i32.add {} (i32.const {}))", load(offset), cell_value(amount));
+            push_line(&store(offset, &sum), indent, out);
+        }
+        PointerIncrement { amount, .. } => {
+            let line = format!("(local.set $p (i32.add (local.get $p) (i32.const {})))",
+                               amount);
+            push_line(&line, indent, out);
+        }
+        Set { amount, offset, .. } => {
+            let value = format!("(i32.const {})", cell_value(amount));
+            push_line(&store(offset, &value), indent, out);
+        }
+        MultiplyMove { ref changes, .. } |
+        MultiplyCopy { ref changes, .. } => {
+            push_line(&format!("(if {}", load(0)), indent, out);
+            push_line("(then", indent + 1, out);
+
+            for (offset, factor) in changes {
+                let product = format!("(i32.mul {} (i32.const {}))", load(0), cell_value(*factor));
+                let sum = format!("(i32.add {} {})", load(*offset), product);
+                push_line(&store(*offset, &sum), indent + 2, out);
+            }
+
+            if let MultiplyMove { .. } = *instr {
+                push_line(&format!("{}))", store(0, "(i32.const 0)")), indent + 2, out);
+            } else {
+                push_line("))", indent + 1, out);
+            }
+        }
+        Move { offset, .. } => {
+            push_line(&format!("(if {}", load(0)), indent, out);
+            push_line("(then", indent + 1, out);
+            let sum = format!("(i32.add {} {})", load(offset), load(0));
+            push_line(&store(offset, &sum), indent + 2, out);
+            push_line(&format!("{}))", store(0, "(i32.const 0)")), indent + 2, out);
+        }
+        Read { .. } => {
+            push_line(&store(0, "(call $read)"), indent, out);
+        }
+        Write { .. } => {
+            push_line(&format!("(call $write {})", load(0)), indent, out);
+        }
+        ScanZero { step, .. } => {
+            push_line("(block", indent, out);
+            push_line("(loop", indent + 1, out);
+            push_line(&format!("(br_if 1 (i32.eqz {}))", load(0)), indent + 2, out);
+            let line = format!("(local.set $p (i32.add (local.get $p) (i32.const {})))", step);
+            push_line(&line, indent + 2, out);
+            push_line("(br 0)))", indent + 2, out);
+        }
+        Breakpoint { .. } => {
+            // A block comment, as we close the function on the same
+            // line as the last instruction.
+            push_line("(; Breakpoint ;)", indent, out);
+        }
+        Loop { ref body, .. } => {
+            // Branch depth 1 is the enclosing block, so br_if exits
+            // the loop and br 0 jumps back to the start.
+            push_line("(block", indent, out);
+            push_line("(loop", indent + 1, out);
+            push_line(&format!("(br_if 1 (i32.eqz {}))", load(0)), indent + 2, out);
+            for loop_instr in body {
+                compile_instr(loop_instr, indent + 2, out);
+            }
+            push_line("(br 0)))", indent + 2, out);
+        }
+    }
+}
+
+/// Convert the instructions given to a WAT module. We allocate
+/// enough pages of memory for all the cells the program uses.
+pub fn codegen_wat(instrs: &[AstNode]) -> String {
+    let num_cells = highest_cell_index(instrs) + 1;
+    let num_pages = num_cells.div_ceil(PAGE_SIZE);
+
+    let mut out = String::new();
+    push_line("(module", 0, &mut out);
+    push_line("(import \"env\" \"read\" (func $read (result i32)))", 1, &mut out);
+    push_line("(import \"env\" \"write\" (func $write (param i32)))", 1, &mut out);
+    push_line(&format!("(memory (export \"memory\") {})", num_pages), 1, &mut out);
+    push_line("(func (export \"run\")", 1, &mut out);
+    push_line("(local $p i32)", 2, &mut out);
+
+    for instr in instrs {
+        compile_instr(instr, 2, &mut out);
+    }
+
+    // Close the function and the module.
+    out.pop();
+    out.push_str("))\n");
+    out
+}
+
+#[test]
+fn codegen_wat_complex_loop() {
+    let instrs = parse(".[,+]-").unwrap();
+    let expected = "(module
+  (import \"env\" \"read\" (func $read (result i32)))
+  (import \"env\" \"write\" (func $write (param i32)))
+  (memory (export \"memory\") 1)
+  (func (export \"run\")
+    (local $p i32)
+    (call $write (i32.load8_u (local.get $p)))
+    (block
+      (loop
+        (br_if 1 (i32.eqz (i32.load8_u (local.get $p))))
+        (i32.store8 (local.get $p) (call $read))
+        (i32.store8 (local.get $p) (i32.add (i32.load8_u (local.get $p)) (i32.const 1)))
+        (br 0)))
+    (i32.store8 (local.get $p) (i32.add (i32.load8_u (local.get $p)) (i32.const 255)))))
+";
+    assert_eq!(codegen_wat(&instrs), expected);
+}
+
+#[test]
+fn codegen_wat_offsets() {
+    use std::num::Wrapping;
+
+    let instrs = [Set {
+                      amount: Wrapping(2),
+                      offset: -1,
+                      position: None,
+                  }];
+    assert!(codegen_wat(&instrs).contains("(i32.store8 (i32.add (local.get $p) (i32.const -1)) \
+                                           (i32.const 2))"));
+}
+
+#[test]
+fn codegen_wat_multiply_move() {
+    use std::collections::BTreeMap;
+    use std::num::Wrapping;
+
+    let mut changes = BTreeMap::new();
+    changes.insert(1, Wrapping(2));
+    let instrs = [MultiplyMove {
+                      changes: changes,
+                      position: None,
+                  }];
+
+    let expected_body = "    (if (i32.load8_u (local.get $p))
+      (then
+        (i32.store8 (i32.add (local.get $p) (i32.const 1)) (i32.add (i32.load8_u (i32.add \
+                         (local.get $p) (i32.const 1))) (i32.mul (i32.load8_u (local.get $p)) \
+                         (i32.const 2))))
+        (i32.store8 (local.get $p) (i32.const 0))))";
+    assert!(codegen_wat(&instrs).contains(expected_body));
+}
+
+#[test]
+fn codegen_wat_ends_with_breakpoint() {
+    use bfir::{parse_with_options, ParseOptions};
+
+    let options = ParseOptions { recognize_breakpoints: true, ..ParseOptions::default() };
+    let (instrs, _) = parse_with_options("+#", &options).unwrap();
+    assert!(codegen_wat(&instrs).ends_with("    (; Breakpoint ;)))\n"));
+}
+
+#[test]
+fn codegen_wat_memory_pages() {
+    let instrs = parse(&">".repeat(PAGE_SIZE)).unwrap();
+    assert!(codegen_wat(&instrs).contains("(memory (export \"memory\") 2)"));
+}
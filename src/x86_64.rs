@@ -0,0 +1,259 @@
+//! The x86_64 module converts a BF AST to x86-64 assembly in AT&T
+//! syntax, so programs can be built with just an assembler and a
+//! linker.
+//!
+//! The generated code targets Linux. It keeps the cell pointer in
+//! %rbx and performs I/O with the read and write syscalls, so it
+//! doesn't need libc.
+
+use bfir::{AstNode, Cell};
+use bfir::AstNode::*;
+
+use bounds::highest_cell_index;
+
+#[cfg(test)]
+use bfir::parse;
+
+const SYS_READ: usize = 0;
+const SYS_WRITE: usize = 1;
+const SYS_EXIT: usize = 60;
+
+/// The operand for the cell at `offset` from the cell pointer.
+fn cell_at(offset: isize) -> String {
+    if offset == 0 {
+        "(%rbx)".to_owned()
+    } else {
+        format!("{}(%rbx)", offset)
+    }
+}
+
+fn cell_value(amount: Cell) -> i8 {
+    amount.0
+}
+
+struct AsmWriter {
+    out: String,
+    next_label: usize,
+}
+
+impl AsmWriter {
+    /// Return a label that hasn't been used yet.
+    fn fresh_label(&mut self, name: &str) -> String {
+        let label = format!(".L{}{}", name, self.next_label);
+        self.next_label += 1;
+        label
+    }
+
+    fn instr(&mut self, line: &str) {
+        self.out.push_str("    ");
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+
+    fn label(&mut self, label: &str) {
+        self.out.push_str(label);
+        self.out.push_str(":\n");
+    }
+
+    /// Call `syscall_number` with the current cell as the buffer, for
+    /// one byte on stdin or stdout.
+    fn cell_syscall(&mut self, syscall_number: usize, fd: usize) {
+        self.instr(&format!("movq ${}, %rax", syscall_number));
+        self.instr(&format!("movq ${}, %rdi", fd));
+        self.instr("movq %rbx, %rsi");
+        self.instr("movq $1, %rdx");
+        self.instr("syscall");
+    }
+
+    fn compile_instr(&mut self, instr: &AstNode) {
+        match *instr {
+            Increment { amount, offset, .. } => {
+                self.instr(&format!("addb ${}, {}", cell_value(amount), cell_at(offset)));
+            }
+            PointerIncrement { amount, .. } => {
+                self.instr(&format!("addq ${}, %rbx", amount));
+            }
+            Set { amount, offset, .. } => {
+                self.instr(&format!("movb ${}, {}", cell_value(amount), cell_at(offset)));
+            }
+            MultiplyMove { ref changes, .. } |
+            MultiplyCopy { ref changes, .. } => {
+                // Like the loop it replaced, we must not touch other
+                // cells when the current cell is zero, as they may be
+                // outside the tape.
+                let end_label = self.fresh_label("multiply");
+                self.instr("movzbl (%rbx), %eax");
+                self.instr("testl %eax, %eax");
+                self.instr(&format!("je {}", end_label));
+
+                for (offset, factor) in changes {
+                    self.instr(&format!("imull ${}, %eax, %ecx", cell_value(*factor)));
+                    self.instr(&format!("addb %cl, {}", cell_at(*offset)));
+                }
+
+                if let MultiplyMove { .. } = *instr {
+                    self.instr("movb $0, (%rbx)");
+                }
+                self.label(&end_label);
+            }
+            Move { offset, .. } => {
+                let end_label = self.fresh_label("move");
+                self.instr("movb (%rbx), %al");
+                self.instr("testb %al, %al");
+                self.instr(&format!("je {}", end_label));
+                self.instr(&format!("addb %al, {}", cell_at(offset)));
+                self.instr("movb $0, (%rbx)");
+                self.label(&end_label);
+            }
+            Read { .. } => {
+                // read leaves the buffer untouched at EOF, so we
+                // get -1 like getchar() in the C and LLVM backends.
+                self.instr("movb $-1, (%rbx)");
+                self.cell_syscall(SYS_READ, 0);
+            }
+            Write { .. } => {
+                self.cell_syscall(SYS_WRITE, 1);
+            }
+            ScanZero { step, .. } => {
+                let label = self.fresh_label("scan");
+                let start_label = format!("{}_start", label);
+                let end_label = format!("{}_end", label);
+
+                self.label(&start_label);
+                self.instr("cmpb $0, (%rbx)");
+                self.instr(&format!("je {}", end_label));
+                self.instr(&format!("addq ${}, %rbx", step));
+                self.instr(&format!("jmp {}", start_label));
+                self.label(&end_label);
+            }
+            Breakpoint { .. } => {
+                self.instr("# Breakpoint");
+            }
+            Loop { ref body, .. } => {
+                let label = self.fresh_label("loop");
+                let start_label = format!("{}_start", label);
+                let end_label = format!("{}_end", label);
+
+                self.label(&start_label);
+                self.instr("cmpb $0, (%rbx)");
+                self.instr(&format!("je {}", end_label));
+                for loop_instr in body {
+                    self.compile_instr(loop_instr);
+                }
+                self.instr(&format!("jmp {}", start_label));
+                self.label(&end_label);
+            }
+        }
+    }
+}
+
+/// Convert the instructions given to a complete assembly program,
+/// with a `_start` entry point. We only allocate as many cells as the
+/// program needs, just like the LLVM backend.
+pub fn codegen_x86_64(instrs: &[AstNode]) -> String {
+    let num_cells = highest_cell_index(instrs) + 1;
+
+    let mut writer = AsmWriter {
+        out: String::new(),
+        next_label: 0,
+    };
+    writer.instr(".bss");
+    writer.instr(&format!(".lcomm tape, {}", num_cells));
+    writer.instr(".text");
+    writer.instr(".globl _start");
+    writer.label("_start");
+    writer.instr("leaq tape(%rip), %rbx");
+
+    for instr in instrs {
+        writer.compile_instr(instr);
+    }
+
+    writer.instr(&format!("movq ${}, %rax", SYS_EXIT));
+    writer.instr("xorq %rdi, %rdi");
+    writer.instr("syscall");
+    writer.out
+}
+
+#[test]
+fn codegen_x86_64_complex_loop() {
+    let instrs = parse(".[,+]-").unwrap();
+    let expected = "    .bss
+    .lcomm tape, 1
+    .text
+    .globl _start
+_start:
+    leaq tape(%rip), %rbx
+    movq $1, %rax
+    movq $1, %rdi
+    movq %rbx, %rsi
+    movq $1, %rdx
+    syscall
+.Lloop0_start:
+    cmpb $0, (%rbx)
+    je .Lloop0_end
+    movb $-1, (%rbx)
+    movq $0, %rax
+    movq $0, %rdi
+    movq %rbx, %rsi
+    movq $1, %rdx
+    syscall
+    addb $1, (%rbx)
+    jmp .Lloop0_start
+.Lloop0_end:
+    addb $-1, (%rbx)
+    movq $60, %rax
+    xorq %rdi, %rdi
+    syscall
+";
+    assert_eq!(codegen_x86_64(&instrs), expected);
+}
+
+#[test]
+fn codegen_x86_64_nested_loop_labels() {
+    let instrs = parse("[[]>[]]").unwrap();
+    let asm = codegen_x86_64(&instrs);
+
+    // Keep only the lines that jump or define labels.
+    let control_lines: Vec<_> = asm.lines()
+        .map(|line| line.trim())
+        .filter(|line| line.starts_with(".Lloop") || line.starts_with('j'))
+        .collect();
+    assert_eq!(control_lines,
+               vec![".Lloop0_start:",
+                    "je .Lloop0_end",
+                    ".Lloop1_start:",
+                    "je .Lloop1_end",
+                    "jmp .Lloop1_start",
+                    ".Lloop1_end:",
+                    ".Lloop2_start:",
+                    "je .Lloop2_end",
+                    "jmp .Lloop2_start",
+                    ".Lloop2_end:",
+                    "jmp .Lloop0_start",
+                    ".Lloop0_end:"]);
+}
+
+#[test]
+fn codegen_x86_64_offsets() {
+    use std::collections::BTreeMap;
+    use std::num::Wrapping;
+
+    let mut changes = BTreeMap::new();
+    changes.insert(2, Wrapping(3));
+    changes.insert(-1, Wrapping(-1));
+    let instrs = [Set {
+                      amount: Wrapping(5),
+                      offset: -1,
+                      position: None,
+                  },
+                  MultiplyMove {
+                      changes: changes,
+                      position: None,
+                  }];
+    let asm = codegen_x86_64(&instrs);
+    assert!(asm.contains("    movb $5, -1(%rbx)\n"));
+    assert!(asm.contains("    movzbl (%rbx), %eax\n    testl %eax, %eax\n    je .Lmultiply0\n    \
+                          imull $-1, %eax, %ecx\n    addb %cl, -1(%rbx)\n    \
+                          imull $3, %eax, %ecx\n    addb %cl, 2(%rbx)\n    \
+                          movb $0, (%rbx)\n.Lmultiply0:\n"));
+}